@@ -0,0 +1,228 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Atomic replacement of compile outputs.
+//!
+//! The new output is written to a temporary sibling of the real output and
+//! only swapped into place once it is complete, so that anything looking at
+//! the output path sees either the entire old output or the entire new one.
+//! If the compile fails (or the [AtomicOutput] is otherwise dropped without
+//! being committed), the temporary output is thrown away and the old output
+//! is left untouched.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use nix::fcntl::renameat2;
+use nix::fcntl::RenameFlags;
+use tracing::warn;
+
+#[derive(Debug)]
+pub(crate) struct AtomicOutput {
+    dst: PathBuf,
+    tmp: PathBuf,
+    committed: bool,
+}
+
+impl AtomicOutput {
+    /// Prepare to replace `dst`. The new output must be written to
+    /// [AtomicOutput::path] before calling [AtomicOutput::commit].
+    pub(crate) fn new(dst: &Path) -> Result<Self> {
+        let name = dst
+            .file_name()
+            .with_context(|| format!("output '{}' has no filename", dst.display()))?;
+        let parent = match dst.parent() {
+            Some(p) if p != Path::new("") => p,
+            _ => Path::new("."),
+        };
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(format!(".antlir2-tmp.{}", std::process::id()));
+        let tmp = parent.join(tmp_name);
+
+        // rename(2) can only swap entries on the same filesystem, which the
+        // temporary sibling is not if the output itself is a mountpoint
+        let parent_dev = std::fs::metadata(parent)
+            .with_context(|| format!("while statting output dir '{}'", parent.display()))?
+            .dev();
+        match std::fs::symlink_metadata(dst) {
+            Ok(meta) if meta.dev() != parent_dev => {
+                return Err(anyhow!(
+                    "atomic output requires '{}' to be on the same filesystem as '{}', but it is a mountpoint",
+                    dst.display(),
+                    tmp.display(),
+                ));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("while statting '{}'", dst.display()));
+            }
+        }
+
+        // leftovers from a previous (crashed) compile
+        remove(&tmp)?;
+        Ok(Self {
+            dst: dst.to_owned(),
+            tmp,
+            committed: false,
+        })
+    }
+
+    /// Temporary path that the new output should be written to
+    pub(crate) fn path(&self) -> &Path {
+        &self.tmp
+    }
+
+    /// Swap the new output into place and delete the old one
+    pub(crate) fn commit(mut self) -> Result<()> {
+        self.committed = true;
+        match renameat2(
+            None,
+            &self.tmp,
+            None,
+            &self.dst,
+            RenameFlags::RENAME_EXCHANGE,
+        ) {
+            // the old output is now at the temporary path
+            Ok(()) => remove(&self.tmp),
+            Err(nix::errno::Errno::ENOENT) if !self.dst.exists() => {
+                std::fs::rename(&self.tmp, &self.dst).with_context(|| {
+                    format!(
+                        "while moving '{}' to '{}'",
+                        self.tmp.display(),
+                        self.dst.display()
+                    )
+                })
+            }
+            Err(nix::errno::Errno::EXDEV) => Err(anyhow!(
+                "atomic output requires '{}' and '{}' to be on the same filesystem",
+                self.tmp.display(),
+                self.dst.display(),
+            )),
+            Err(e) => Err(std::io::Error::from(e)).with_context(|| {
+                format!(
+                    "while swapping '{}' with '{}'",
+                    self.tmp.display(),
+                    self.dst.display()
+                )
+            }),
+        }
+    }
+}
+
+impl Drop for AtomicOutput {
+    fn drop(&mut self) {
+        if !self.committed {
+            if let Err(e) = remove(&self.tmp) {
+                warn!("failed to clean up incomplete output: {e:#}");
+            }
+        }
+    }
+}
+
+fn remove(path: &Path) -> Result<()> {
+    let res = match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    };
+    res.with_context(|| format!("while removing '{}'", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn populate(dir: &Path, contents: &str) {
+        std::fs::create_dir_all(dir.join("etc")).expect("failed to create dir");
+        std::fs::write(dir.join("etc/file"), contents).expect("failed to write");
+    }
+
+    #[test]
+    fn test_failure_leaves_original_intact() {
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        let dst = dir.path().join("out");
+        populate(&dst, "original");
+
+        let out = AtomicOutput::new(&dst).expect("failed to prepare output");
+        // compile fails halfway through populating the new output
+        std::fs::create_dir_all(out.path().join("etc")).expect("failed to create dir");
+        std::fs::write(out.path().join("etc/partial"), "").expect("failed to write");
+        drop(out);
+
+        assert_eq!(
+            std::fs::read_to_string(dst.join("etc/file")).expect("failed to read"),
+            "original"
+        );
+        assert!(!dst.join("etc/partial").exists());
+        assert_eq!(
+            std::fs::read_dir(dir.path())
+                .expect("failed to read dir")
+                .count(),
+            1,
+            "temporary output was not cleaned up"
+        );
+    }
+
+    #[test]
+    fn test_commit() {
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        let dst = dir.path().join("out");
+
+        // nothing to replace
+        let out = AtomicOutput::new(&dst).expect("failed to prepare output");
+        populate(out.path(), "first");
+        out.commit().expect("failed to commit");
+        assert_eq!(
+            std::fs::read_to_string(dst.join("etc/file")).expect("failed to read"),
+            "first"
+        );
+
+        // replace a directory
+        let out = AtomicOutput::new(&dst).expect("failed to prepare output");
+        populate(out.path(), "second");
+        out.commit().expect("failed to commit");
+        assert_eq!(
+            std::fs::read_to_string(dst.join("etc/file")).expect("failed to read"),
+            "second"
+        );
+
+        // replace a directory with a symlink
+        let target = dir.path().join("target");
+        populate(&target, "third");
+        let out = AtomicOutput::new(&dst).expect("failed to prepare output");
+        std::os::unix::fs::symlink(&target, out.path()).expect("failed to symlink");
+        out.commit().expect("failed to commit");
+        assert_eq!(std::fs::read_link(&dst).expect("not a symlink"), target);
+        // only the link was replaced, not what it pointed to
+        assert!(target.join("etc/file").exists());
+
+        assert_eq!(
+            std::fs::read_dir(dir.path())
+                .expect("failed to read dir")
+                .count(),
+            2,
+            "old output was not cleaned up"
+        );
+    }
+
+    #[test]
+    fn test_different_filesystem() {
+        // /proc is always a different filesystem than /
+        let err = AtomicOutput::new(Path::new("/proc")).expect_err("should have failed");
+        assert!(
+            err.to_string().contains("same filesystem"),
+            "unexpected error: {err:#}"
+        );
+    }
+}
@@ -5,7 +5,10 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -13,7 +16,10 @@ use antlir2_btrfs::Subvolume;
 use antlir2_compile::Arch;
 use antlir2_compile::CompileFeature;
 use antlir2_compile::CompilerContext;
+use antlir2_depgraph::Explanation;
+use antlir2_depgraph::Graph;
 use antlir2_features::Feature;
+use antlir2_overlayfs::BaseOverlay;
 use antlir2_overlayfs::OverlayFs;
 use antlir2_rootless::Rootless;
 use antlir2_working_volume::WorkingVolume;
@@ -24,10 +30,26 @@ use clap::Parser;
 use clap::ValueEnum;
 use fbinit::FacebookInit;
 use json_arg::JsonFile;
+use sha2::Digest;
+use sha2::Sha256;
 use tracing::debug;
 use tracing::trace;
 use tracing::warn;
 
+use crate::atomic::AtomicOutput;
+use crate::incremental;
+use crate::incremental::Start;
+use crate::incremental::State;
+use crate::output_hash::TreeHash;
+use crate::pack::Pack;
+use crate::progress;
+use crate::progress::Progress;
+use crate::resume;
+use crate::resume::FeatureSelector;
+use crate::sbom::Sbom;
+use crate::size_budget::SizeKind;
+use crate::size_budget::TreeSize;
+use crate::trace::Tracer;
 use crate::Error;
 use crate::Result;
 
@@ -47,6 +69,11 @@ pub(crate) struct Compile {
     #[clap(long)]
     /// Path to a subvolume to use as the starting point
     parent: Option<PathBuf>,
+    #[clap(long, conflicts_with_all = ["parent", "state"])]
+    /// Mount this directory read-only as the base of the image instead of
+    /// copying it, so that only the changes made by features are written.
+    /// --output will point to just those changes.
+    base_overlay: Option<PathBuf>,
     #[clap(long)]
     /// buck-out path to store the reference to this volume
     output: PathBuf,
@@ -65,7 +92,118 @@ pub(crate) struct Compile {
 
     #[clap(long)]
     /// Pre-computed plans for this compilation phase
-    plans: JsonFile<HashMap<String, PathBuf>>,
+    plans: Option<JsonFile<HashMap<String, PathBuf>>>,
+    #[clap(long)]
+    /// Directory of pre-computed plans, one `<feature id>.json` file per
+    /// feature. May be given multiple times and combined with `--plans`, but
+    /// each feature id must only have one plan.
+    plan_dir: Vec<PathBuf>,
+    #[clap(long)]
+    /// Write every plan, as loaded by the compiler, to this path as a single
+    /// JSON object keyed by feature id, then continue compiling. Keys are
+    /// sorted so that dumps can be diffed.
+    dump_plan: Option<PathBuf>,
+    #[clap(long)]
+    /// Make everything except the image being built read-only while compiling
+    /// features, so that a feature writing outside of the image fails loudly
+    /// instead of modifying the host
+    sandbox_writes: bool,
+    #[clap(long)]
+    /// Write a digest of the compiled image (contents, modes and ownership,
+    /// but not timestamps) to this path
+    output_hash: Option<PathBuf>,
+    #[clap(long, requires = "output_hash")]
+    /// Also write the digest of every file in the image to this path
+    output_hash_manifest: Option<PathBuf>,
+    #[clap(long, requires = "state")]
+    /// Start from the previous output and only compile features that were
+    /// added since then, if everything else is unchanged
+    incremental: bool,
+    #[clap(long)]
+    /// Directory to record what went into this compile in, for use by the
+    /// next `--incremental` compile (which may be this one)
+    state: Option<PathBuf>,
+    #[clap(long)]
+    /// Fail the compile if any feature emitted a warning (after reporting
+    /// all of them)
+    fail_on_warning: bool,
+    #[clap(long)]
+    /// After compiling, verify that everything features created is still
+    /// owned by the user and group they declared
+    strict_ownership: bool,
+    #[clap(long)]
+    /// After compiling, verify that every xattr set by a feature was
+    /// preserved by the filesystem backing the image
+    preserve_xattrs: bool,
+    #[clap(long)]
+    /// Build the output next to the existing one and only swap it into place
+    /// once the compile has completely succeeded, so that a failed compile
+    /// never leaves a partial output behind
+    atomic: bool,
+    #[clap(long, default_value_t = 0)]
+    /// Compile a feature up to this many more times if it fails with a
+    /// transient io error (like a timeout)
+    feature_retries: u32,
+    #[clap(long, conflicts_with = "incremental")]
+    /// Write a CycloneDX software bill of materials, listing every package
+    /// that features installed into the image, to this path
+    sbom: Option<PathBuf>,
+    #[clap(long)]
+    /// Fail the compile if the image is larger than this many bytes, listing
+    /// the largest paths in it
+    max_output_bytes: Option<u64>,
+    #[clap(value_enum, long, default_value_t = SizeKind::Apparent, requires = "max_output_bytes")]
+    /// How to measure the size of the image for --max-output-bytes
+    output_size: SizeKind,
+    #[clap(long, value_name = "DIR")]
+    /// Trace the syscalls made while compiling each feature with strace, and
+    /// write them to a file per feature in this directory. Useful to find out
+    /// what a restricted environment denied when a feature fails in it.
+    trace_syscalls: Option<PathBuf>,
+    #[clap(value_enum, long, default_value_t = Pack::None)]
+    /// Also pack the compiled image into a filesystem image at --pack-output
+    pack: Pack,
+    #[clap(long, required_if_eq_any = [("pack", "squashfs"), ("pack", "erofs")])]
+    /// Where to write the packed image
+    pack_output: Option<PathBuf>,
+    #[clap(long, value_name = "DEPGRAPH")]
+    /// Print the order that the features in this depgraph (as produced for
+    /// this layer by `antlir2 depgraph`) are compiled in, along with the
+    /// dependencies that forced each one into its position, then exit without
+    /// compiling anything
+    explain: Option<PathBuf>,
+    #[clap(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "auto",
+        default_value_t = progress::When::Never
+    )]
+    /// Print which feature is being compiled (X of N) to stderr. `--progress`
+    /// alone only does so if stderr is a terminal.
+    progress: progress::When,
+    #[clap(
+        long,
+        value_name = "FEATURE",
+        conflicts_with_all = [
+            "incremental",
+            "base_overlay",
+            "sbom",
+            "strict_ownership",
+            "preserve_xattrs",
+        ]
+    )]
+    /// Start from the existing --output and skip every feature before this
+    /// one (a label, or a position as printed by --explain). Only safe if the
+    /// output already has everything that the skipped features produce.
+    /// Checks that need to see every feature (--sbom, --strict-ownership and
+    /// --preserve-xattrs) can't be combined with this.
+    continue_from: Option<FeatureSelector>,
+    #[clap(long, value_name = "DEPGRAPH", requires = "continue_from")]
+    /// Before continuing, check that every path provided by a skipped feature
+    /// in this depgraph exists in the existing output
+    verify_continue: Option<PathBuf>,
 }
 
 #[derive(Debug, ValueEnum, Clone, Copy)]
@@ -78,6 +216,7 @@ enum WorkingFormat {
 enum WorkingLayer {
     Btrfs(Subvolume),
     OverlayFs(OverlayFs),
+    BaseOverlay(BaseOverlay),
 }
 
 impl WorkingLayer {
@@ -85,6 +224,7 @@ impl WorkingLayer {
         match self {
             WorkingLayer::Btrfs(subvol) => subvol.path(),
             WorkingLayer::OverlayFs(fs) => fs.mountpoint(),
+            WorkingLayer::BaseOverlay(fs) => fs.mountpoint(),
         }
     }
 }
@@ -92,6 +232,12 @@ impl WorkingLayer {
 impl Compile {
     #[tracing::instrument(name = "compile", skip_all, ret, err)]
     pub(crate) fn run(self, rootless: Rootless, fb: FacebookInit) -> Result<()> {
+        if let Some(depgraph) = &self.explain {
+            let graph = Graph::open(depgraph)?;
+            print!("{}", render_explanation(&graph.explain()?));
+            return Ok(());
+        }
+
         // this must happen before unshare
         let working_volume = match self.working_format {
             WorkingFormat::Btrfs => Some(WorkingVolume::ensure(self.working_dir.clone())?),
@@ -107,65 +253,159 @@ impl Compile {
             antlir2_rootless::unshare_new_userns().context("while setting up userns")?;
         }
 
+        let plans = load_plans(self.plans.as_ref().map(JsonFile::as_inner), &self.plan_dir)?;
+        if let Some(dst) = &self.dump_plan {
+            dump_plans(&plans, dst)?;
+        }
+        let packer = self.pack.packer()?;
+
+        let (fingerprints, start) = match &self.state {
+            Some(state_dir) => {
+                let (fingerprints, start) = self.incremental_start(state_dir, &plans)?;
+                // without --incremental, the state is only recorded for next time
+                let start = if self.incremental {
+                    start
+                } else {
+                    Start::Scratch
+                };
+                (Some(fingerprints), start)
+            }
+            None => (None, Start::Scratch),
+        };
+        let start = match &self.continue_from {
+            Some(selector) => {
+                if !matches!(self.working_format, WorkingFormat::Btrfs) {
+                    return Err(anyhow!("--continue-from is only supported for btrfs").into());
+                }
+                let (subvol, skip) =
+                    resume::start(self.features.as_inner(), selector, &self.output)?;
+                if let Some(depgraph) = &self.verify_continue {
+                    let listed = Graph::open(depgraph)?.list()?;
+                    let root_guard = rootless.map(|r| r.escalate()).transpose()?;
+                    let missing = resume::missing_outputs(
+                        &listed,
+                        &self.features.as_inner()[..skip],
+                        &subvol,
+                    );
+                    drop(root_guard);
+                    if !missing.is_empty() {
+                        return Err(anyhow!(
+                            "cannot continue from {selector}, {} is missing outputs of earlier features: {missing:?}",
+                            subvol.display()
+                        )
+                        .into());
+                    }
+                }
+                Start::Previous { subvol, skip }
+            }
+            None => start,
+        };
+        let (start_from, skip) = match &start {
+            Start::Scratch => (None, 0),
+            Start::Previous { subvol, skip } => {
+                debug!("incremental compile: skipping {skip} already compiled features");
+                (Some(subvol.as_path()), *skip)
+            }
+        };
+
+        let tracer = self
+            .trace_syscalls
+            .as_deref()
+            .map(|dir| {
+                Tracer::new(
+                    dir,
+                    self.features
+                        .as_inner()
+                        .iter()
+                        .map(|feature| &feature.label)
+                        .enumerate()
+                        .skip(skip),
+                )
+            })
+            .transpose()?;
+
         let root_guard = rootless.map(|r| r.escalate()).transpose()?;
 
         antlir2_isolate::unshare_and_privatize_mount_ns().context("while isolating mount ns")?;
 
-        let layer = self.create_new_layer(working_volume.as_ref(), &rootless)?;
+        let layer = self.create_new_layer(working_volume.as_ref(), &rootless, start_from)?;
 
         drop(root_guard);
 
-        let plans = self
-            .plans
+        let root_guard = rootless.map(|r| r.escalate()).transpose()?;
+        let sandbox = match self.sandbox_writes {
+            true => Some(
+                antlir2_isolate::sandbox_writes(layer.path()).context("while sandboxing writes")?,
+            ),
+            false => None,
+        };
+        // this must be created after the sandbox so that it refers to the
+        // writable image root
+        let ctx = self.compiler_context(layer.path().to_owned(), plans)?;
+        let mut progress = Progress::new(
+            self.progress,
+            self.features.as_inner().len().saturating_sub(skip),
+        );
+        let res = self
+            .features
             .as_inner()
             .iter()
-            .map(|(id, path)| {
-                let plan = std::fs::read_to_string(path)
-                    .with_context(|| format!("while reading plan '{}'", path.display()))?;
-                let plan: serde_json::Value = serde_json::from_str(&plan)
-                    .with_context(|| format!("while parsing plan '{}'", path.display()))?;
-                Result::Ok((id.to_owned(), plan))
-            })
-            .collect::<Result<_>>()?;
-        let ctx = self.compiler_context(layer.path().to_owned(), plans)?;
+            .enumerate()
+            .skip(skip)
+            .try_for_each(|(idx, feature)| -> Result<()> {
+                let compile = || feature.compile_with_retries(&ctx, self.feature_retries);
+                match &tracer {
+                    Some(tracer) => tracer.trace(idx, compile)??,
+                    None => compile()?,
+                }
+                progress.feature_done(&feature.label);
+                Ok(())
+            });
+        // leaving the sandbox requires privileges, so this must happen before
+        // de-escalating
+        drop(sandbox);
+        drop(root_guard);
+        res?;
+        ctx.check_warnings(self.fail_on_warning)?;
+        if self.strict_ownership {
+            ctx.check_ownership()?;
+        }
+        if self.preserve_xattrs {
+            ctx.check_xattrs()?;
+        }
+        if let Some(sbom) = &self.sbom {
+            Sbom::new(&self.label, ctx.installed_packages()).write(sbom)?;
+        }
 
-        let root_guard = rootless.map(|r| r.escalate()).transpose()?;
-        for feature in self.features.as_inner() {
-            feature.compile(&ctx)?;
+        if let Some(max_output_bytes) = self.max_output_bytes {
+            let root_guard = rootless.map(|r| r.escalate()).transpose()?;
+            let size = TreeSize::compute(layer.path(), self.output_size)
+                .context("while measuring output size")?;
+            drop(root_guard);
+            size.check(max_output_bytes)?;
+        }
+        if let Some(output_hash) = &self.output_hash {
+            let root_guard = rootless.map(|r| r.escalate()).transpose()?;
+            let hash = TreeHash::compute(layer.path()).context("while hashing output")?;
+            drop(root_guard);
+            hash.write(output_hash, self.output_hash_manifest.as_deref())?;
+        }
+        if let (Some(packer), Some(pack_output)) = (&packer, &self.pack_output) {
+            let root_guard = rootless.map(|r| r.escalate()).transpose()?;
+            packer.pack(layer.path(), pack_output)?;
+            drop(root_guard);
         }
-        drop(root_guard);
 
         match layer {
             WorkingLayer::Btrfs(mut subvol) => {
                 let root_guard = rootless.map(|r| r.escalate()).transpose()?;
-                if self.output.exists() {
-                    trace!("removing existing output {}", self.output.display());
-                    // Don't fail if the old subvol couldn't be deleted, just print
-                    // a warning. We really don't want to fail someone's build if
-                    // the only thing that went wrong is not being able to delete
-                    // the last version of it.
-                    match Subvolume::open(&self.output) {
-                        Ok(old_subvol) => {
-                            if let Err((mut old_subvol, e)) = old_subvol.delete() {
-                                warn!(
-                                    "couldn't delete old subvol '{}': {e:?}",
-                                    old_subvol.path().display()
-                                );
-                                let _ = old_subvol.set_readonly(false);
-                                if let Err(e) = std::fs::remove_dir_all(old_subvol.path()) {
-                                    warn!(
-                                        "couldn't delete contents of old subvol '{}': {e:?}",
-                                        old_subvol.path().display()
-                                    );
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            warn!(
-                                "couldn't open old subvol '{}': {e:?}",
-                                self.output.display()
-                            );
-                        }
+                // with --atomic, the old output stays in place until the new
+                // one is ready to replace it
+                let old_subvol = self.output.canonicalize().ok();
+                if !self.atomic {
+                    if let Some(old) = &old_subvol {
+                        trace!("removing existing output {}", self.output.display());
+                        delete_old_subvol(old);
                     }
                 }
 
@@ -182,9 +422,21 @@ impl Compile {
                 );
                 drop(root_guard);
 
-                let _ = std::fs::remove_file(&self.output);
-                std::os::unix::fs::symlink(subvol.path(), &self.output)
-                    .context("while making symlink")?;
+                if self.atomic {
+                    let output = AtomicOutput::new(&self.output)?;
+                    std::os::unix::fs::symlink(subvol.path(), output.path())
+                        .context("while making symlink")?;
+                    output.commit()?;
+                    if let Some(old) = &old_subvol {
+                        trace!("removing previous output {}", old.display());
+                        let _root_guard = rootless.map(|r| r.escalate()).transpose()?;
+                        delete_old_subvol(old);
+                    }
+                } else {
+                    let _ = std::fs::remove_file(&self.output);
+                    std::os::unix::fs::symlink(subvol.path(), &self.output)
+                        .context("while making symlink")?;
+                }
 
                 #[cfg(facebook)]
                 working_volume
@@ -201,10 +453,49 @@ impl Compile {
                     warn!("failed to gc old subvols: {e:#?}")
                 }
                 drop(root_guard);
+
+                if let (Some(state_dir), Some((base, features))) = (&self.state, fingerprints) {
+                    State {
+                        base,
+                        features,
+                        subvol: subvol
+                            .path()
+                            .canonicalize()
+                            .context("while canonicalizing subvol path")?,
+                    }
+                    .save(state_dir)?;
+                }
             }
             WorkingLayer::OverlayFs(fs) => {
                 drop(ctx);
-                fs.finalize().context("while finalizing overlayfs")?;
+                if self.atomic {
+                    let (manifest, data_dir) = fs.outputs();
+                    let manifest = AtomicOutput::new(manifest)?;
+                    let data_dir = AtomicOutput::new(data_dir)?;
+                    fs.finalize_to(manifest.path(), data_dir.path())
+                        .context("while finalizing overlayfs")?;
+                    manifest.commit()?;
+                    data_dir.commit()?;
+                } else {
+                    fs.finalize().context("while finalizing overlayfs")?;
+                }
+            }
+            WorkingLayer::BaseOverlay(fs) => {
+                drop(ctx);
+                let root_guard = rootless.map(|r| r.escalate()).transpose()?;
+                let upper = fs.finalize().context("while unmounting base overlay")?;
+                drop(root_guard);
+                debug!("linking {} -> {}", self.output.display(), upper.display());
+                if self.atomic {
+                    let output = AtomicOutput::new(&self.output)?;
+                    std::os::unix::fs::symlink(&upper, output.path())
+                        .context("while making symlink")?;
+                    output.commit()?;
+                } else {
+                    let _ = std::fs::remove_file(&self.output);
+                    std::os::unix::fs::symlink(&upper, &self.output)
+                        .context("while making symlink")?;
+                }
             }
         }
 
@@ -220,13 +511,79 @@ impl Compile {
             .map_err(Error::Compile)
     }
 
+    /// Fingerprint everything that goes into this compile and figure out how
+    /// much of the previous compile (if any) can be reused
+    fn incremental_start(
+        &self,
+        state_dir: &Path,
+        plans: &HashMap<String, serde_json::Value>,
+    ) -> Result<((String, Vec<String>), Start)> {
+        if !matches!(self.working_format, WorkingFormat::Btrfs) {
+            return Err(anyhow!("--state is only supported for btrfs").into());
+        }
+        let mut base = Sha256::new();
+        base.update(self.label.to_string().as_bytes());
+        base.update(self.target_arch.to_string().as_bytes());
+        if let Some(parent) = &self.parent {
+            // parent layers are never modified in place, so a different
+            // parent build always has a different path
+            let parent = parent
+                .canonicalize()
+                .context("while canonicalizing parent")?;
+            base.update(parent.as_os_str().as_bytes());
+        }
+        base.update(
+            serde_json::to_vec(&plans.iter().collect::<BTreeMap<_, _>>())
+                .context("while serializing plans")?,
+        );
+        // a new version of antlir2 might compile the same features differently
+        let exe = std::env::current_exe().context("while getting current exe")?;
+        std::io::copy(
+            &mut File::open(&exe).context("while opening current exe")?,
+            &mut base,
+        )
+        .context("while hashing current exe")?;
+        let base = hex::encode(base.finalize());
+
+        let features = self
+            .features
+            .as_inner()
+            .iter()
+            .map(incremental::fingerprint_feature)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let start = State::start(
+            State::load(state_dir).as_ref(),
+            &base,
+            &features,
+            &self.output,
+        );
+        // if this compile fails, the state must not claim that the old output
+        // is up to date with anything
+        State::clear(state_dir)?;
+        Ok(((base, features), start))
+    }
+
     /// Create a new mutable subvolume
     #[tracing::instrument(skip(self), ret, err)]
     fn create_new_layer(
         &self,
         working_volume: Option<&WorkingVolume>,
         rootless: &Option<antlir2_rootless::Rootless>,
+        start_from: Option<&Path>,
     ) -> Result<WorkingLayer> {
+        if let Some(base) = &self.base_overlay {
+            if !matches!(self.working_format, WorkingFormat::Btrfs) {
+                return Err(anyhow!("--base-overlay is not supported with overlayfs").into());
+            }
+            let dst = working_volume
+                .context("working_volume must have been created for btrfs")?
+                .allocate_new_path()
+                .context("while allocating new path for base overlay")?;
+            let _guard = rootless.map(|r| r.escalate()).transpose()?;
+            trace!("mounting base {base:?} under {dst:?}");
+            let fs = BaseOverlay::mount(base, &dst).context("while mounting base overlay")?;
+            return Ok(WorkingLayer::BaseOverlay(fs));
+        }
         match self.working_format {
             WorkingFormat::Btrfs => {
                 let dst = working_volume
@@ -234,7 +591,7 @@ impl Compile {
                     .allocate_new_path()
                     .context("while allocating new path for subvol")?;
                 let _guard = rootless.map(|r| r.escalate()).transpose()?;
-                let subvol = match &self.parent {
+                let subvol = match start_from.or(self.parent.as_deref()) {
                     Some(parent) => {
                         trace!("snapshotting parent {parent:?}");
                         let parent = Subvolume::open(parent)?;
@@ -260,3 +617,182 @@ impl Compile {
         }
     }
 }
+
+/// Delete the subvolume produced by a previous compile.
+/// Don't fail if the old subvol couldn't be deleted, just print a warning. We
+/// really don't want to fail someone's build if the only thing that went wrong
+/// is not being able to delete the last version of it.
+fn delete_old_subvol(path: &Path) {
+    match Subvolume::open(path) {
+        Ok(old_subvol) => {
+            if let Err((mut old_subvol, e)) = old_subvol.delete() {
+                warn!(
+                    "couldn't delete old subvol '{}': {e:?}",
+                    old_subvol.path().display()
+                );
+                let _ = old_subvol.set_readonly(false);
+                if let Err(e) = std::fs::remove_dir_all(old_subvol.path()) {
+                    warn!(
+                        "couldn't delete contents of old subvol '{}': {e:?}",
+                        old_subvol.path().display()
+                    );
+                }
+            }
+        }
+        Err(e) => {
+            warn!("couldn't open old subvol '{}': {e:?}", path.display());
+        }
+    }
+}
+
+/// Render the compile order as a numbered list, with the features that each
+/// one has to come after (and the item that it needs from them) below it
+fn render_explanation(explained: &[Explanation]) -> String {
+    let mut out = String::new();
+    let width = explained.len().to_string().len();
+    for (idx, explanation) in explained.iter().enumerate() {
+        let feature = &explanation.feature;
+        out.push_str(&format!(
+            "{:>width$}. {} {}\n",
+            idx + 1,
+            feature.feature_type,
+            feature.label
+        ));
+        for (key, dep) in &explanation.after {
+            let dep_feature = &explained[*dep].feature;
+            out.push_str(&format!(
+                "{:width$}    after {}. {} {} for {key:?}\n",
+                "",
+                dep + 1,
+                dep_feature.feature_type,
+                dep_feature.label,
+            ));
+        }
+    }
+    out
+}
+
+/// Read every plan, either listed explicitly in `plans` or found in one of
+/// `plan_dirs`, into a single map keyed by feature id
+fn load_plans(
+    plans: Option<&HashMap<String, PathBuf>>,
+    plan_dirs: &[PathBuf],
+) -> Result<HashMap<String, serde_json::Value>> {
+    let mut paths: HashMap<String, PathBuf> = plans.cloned().unwrap_or_default();
+    for dir in plan_dirs {
+        for entry in std::fs::read_dir(dir)
+            .with_context(|| format!("while reading plan dir '{}'", dir.display()))?
+        {
+            let path = entry
+                .with_context(|| format!("while reading plan dir '{}'", dir.display()))?
+                .path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let id = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .with_context(|| format!("plan filename '{}' is not utf8", path.display()))?
+                .to_owned();
+            if let Some(existing) = paths.get(&id) {
+                return Err(anyhow!(
+                    "feature '{id}' has multiple plans: '{}' and '{}'",
+                    existing.display(),
+                    path.display()
+                )
+                .into());
+            }
+            paths.insert(id, path);
+        }
+    }
+    paths
+        .into_iter()
+        .map(|(id, path)| {
+            let plan = std::fs::read_to_string(&path)
+                .with_context(|| format!("while reading plan '{}'", path.display()))?;
+            let plan: serde_json::Value = serde_json::from_str(&plan)
+                .with_context(|| format!("while parsing plan '{}'", path.display()))?;
+            Ok((id, plan))
+        })
+        .collect()
+}
+
+/// Write `plans` to `dst` as pretty-printed JSON with sorted keys
+fn dump_plans(plans: &HashMap<String, serde_json::Value>, dst: &Path) -> Result<()> {
+    let mut out = serde_json::to_string_pretty(&plans.iter().collect::<BTreeMap<_, _>>())
+        .context("while serializing plans")?;
+    out.push('\n');
+    std::fs::write(dst, out).with_context(|| format!("while writing '{}'", dst.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_load_plans() {
+        let single = tempfile::NamedTempFile::new().expect("failed to create tempfile");
+        std::fs::write(single.path(), r#"{"from":"file"}"#).expect("failed to write");
+        let dir_a = tempfile::TempDir::new().expect("failed to create tempdir");
+        std::fs::write(dir_a.path().join("rpm.json"), r#"{"from":"a"}"#).expect("failed to write");
+        std::fs::write(dir_a.path().join("README"), "not a plan").expect("failed to write");
+        let dir_b = tempfile::TempDir::new().expect("failed to create tempdir");
+        std::fs::write(dir_b.path().join("users.json"), r#"{"from":"b"}"#)
+            .expect("failed to write");
+
+        let plans = load_plans(
+            Some(&HashMap::from([(
+                "extract".to_owned(),
+                single.path().to_owned(),
+            )])),
+            &[dir_a.path().to_owned(), dir_b.path().to_owned()],
+        )
+        .expect("failed to load plans");
+        assert_eq!(
+            plans,
+            HashMap::from([
+                ("extract".to_owned(), json!({"from": "file"})),
+                ("rpm".to_owned(), json!({"from": "a"})),
+                ("users".to_owned(), json!({"from": "b"})),
+            ])
+        );
+
+        // same feature planned in two places
+        std::fs::write(dir_b.path().join("rpm.json"), r#"{"from":"b"}"#).expect("failed to write");
+        let err = load_plans(None, &[dir_a.path().to_owned(), dir_b.path().to_owned()])
+            .expect_err("duplicate plans should fail");
+        assert!(
+            err.to_string().contains("feature 'rpm' has multiple plans"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_dump_plans() {
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        std::fs::write(
+            dir.path().join("rpm.json"),
+            r#"{"txs":[{"name":"foo","action":"install"}],"arch":"x86_64"}"#,
+        )
+        .expect("failed to write");
+        std::fs::write(dir.path().join("extract.json"), r#"{"from":"a"}"#)
+            .expect("failed to write");
+        let plans = load_plans(None, &[dir.path().to_owned()]).expect("failed to load plans");
+
+        let out = tempfile::NamedTempFile::new().expect("failed to create tempfile");
+        dump_plans(&plans, out.path()).expect("failed to dump plans");
+        let dumped = std::fs::read_to_string(out.path()).expect("failed to read dump");
+        let reloaded: HashMap<String, serde_json::Value> =
+            serde_json::from_str(&dumped).expect("dump is not valid json");
+        assert_eq!(reloaded, plans);
+        // feature ids and fields are sorted regardless of how they were loaded
+        let order: Vec<_> = ["\"extract\"", "\"rpm\"", "\"arch\"", "\"txs\""]
+            .iter()
+            .map(|key| dumped.find(key).expect("key missing from dump"))
+            .collect();
+        assert!(order.is_sorted(), "{dumped}");
+    }
+}
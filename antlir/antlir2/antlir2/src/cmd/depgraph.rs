@@ -15,6 +15,7 @@ use antlir2_depgraph::Graph;
 use antlir2_depgraph_if::AnalyzedFeature;
 use antlir2_facts::RwDatabase;
 use anyhow::Context;
+use buck_label::Label;
 use clap::Parser;
 use json_arg::JsonFile;
 
@@ -32,6 +33,10 @@ pub(crate) struct Depgraph {
     db_out: PathBuf,
     #[clap(long)]
     topo_features_out: PathBuf,
+    #[clap(long)]
+    /// Only output the features that came from this target, failing if they
+    /// depend on a feature from any other target in this layer
+    from_label: Option<Label>,
 }
 
 impl Depgraph {
@@ -54,7 +59,10 @@ impl Depgraph {
         }
         let depgraph = depgraph.build()?;
 
-        let features: Vec<_> = depgraph.pending_features()?.collect();
+        let features: Vec<_> = match &self.from_label {
+            Some(label) => depgraph.pending_features_from(label)?,
+            None => depgraph.pending_features()?.collect(),
+        };
         let mut out = BufWriter::new(
             File::create(&self.topo_features_out)
                 .context("while creating topological features file")?,
@@ -0,0 +1,131 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use antlir2_depgraph::Graph;
+use antlir2_depgraph::Listing;
+use antlir2_depgraph_if::item::Item;
+use anyhow::Context;
+use clap::Parser;
+use serde::Serialize;
+
+use crate::Result;
+
+#[derive(Parser, Debug)]
+/// Print the features that would be compiled from a depgraph (in the order
+/// they would be compiled in) as JSON, without compiling anything
+pub(crate) struct ListFeatures {
+    #[clap(long)]
+    /// Depgraph for the layer, as produced by `antlir2 depgraph`
+    depgraph: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+struct ListedFeature<'a> {
+    kind: &'a str,
+    label: String,
+    outputs: &'a [Item],
+}
+
+impl ListFeatures {
+    #[tracing::instrument(name = "list", skip(self))]
+    pub(crate) fn run(self) -> Result<()> {
+        let graph = Graph::open(&self.depgraph)?;
+        let listed = list(&graph)?;
+        let mut out = std::io::stdout().lock();
+        serde_json::to_writer_pretty(&mut out, &listed).context("while writing features")?;
+        writeln!(out).context("while writing features")?;
+        Ok(())
+    }
+}
+
+fn list(graph: &Graph) -> Result<serde_json::Value> {
+    let listed = graph.list()?;
+    let listed: Vec<_> = listed
+        .iter()
+        .map(|Listing { feature, provides }| ListedFeature {
+            kind: &feature.feature_type,
+            label: feature.label.to_string(),
+            outputs: provides,
+        })
+        .collect();
+    Ok(serde_json::to_value(listed).context("while serializing features")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use antlir2_depgraph_if::item::ItemKey;
+    use antlir2_depgraph_if::item::User;
+    use antlir2_depgraph_if::AnalyzedFeature;
+    use antlir2_depgraph_if::Requirement;
+    use antlir2_depgraph_if::Validator;
+    use antlir2_facts::RwDatabase;
+    use antlir2_features::Feature;
+    use serde_json::json;
+
+    use super::*;
+
+    fn user_feature(label: &str, requires: &[&str], provides: &[&str]) -> AnalyzedFeature {
+        let feature: Feature = serde_json::from_value(json!({
+            "label": label,
+            "feature_type": "user",
+            "data": {},
+            "plugin": {"plugin": "/nonexistent", "libs": "/nonexistent"},
+        }))
+        .expect("failed to deserialize feature");
+        AnalyzedFeature::new(
+            feature,
+            requires
+                .iter()
+                .map(|name| {
+                    Requirement::ordered(ItemKey::User(name.to_string()), Validator::Exists)
+                })
+                .collect(),
+            provides
+                .iter()
+                .map(|name| {
+                    Item::User(User {
+                        name: name.to_string(),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_list() {
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        let path = dir.path().join("depgraph");
+        let db = RwDatabase::create(&path).expect("failed to create db");
+        let mut builder = Graph::builder(db).expect("failed to create GraphBuilder");
+        builder
+            .add_feature(user_feature("antlir//image:app", &["base"], &["b", "a"]))
+            .expect("failed to add feature")
+            .add_feature(user_feature("antlir//image:base", &[], &["base"]))
+            .expect("failed to add feature");
+        builder.build().expect("failed to build graph");
+
+        let graph = Graph::open(&path).expect("failed to open graph");
+        assert_eq!(
+            list(&graph).expect("failed to list features"),
+            json!([
+                {
+                    "kind": "user",
+                    "label": "antlir//image:base",
+                    "outputs": [{"user": {"name": "base"}}],
+                },
+                {
+                    "kind": "user",
+                    "label": "antlir//image:app",
+                    "outputs": [{"user": {"name": "a"}}, {"user": {"name": "b"}}],
+                },
+            ])
+        );
+    }
+}
@@ -7,5 +7,7 @@
 
 mod compile;
 mod depgraph;
+mod list_features;
 pub(crate) use compile::Compile;
 pub(crate) use depgraph::Depgraph;
+pub(crate) use list_features::ListFeatures;
@@ -0,0 +1,365 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Opt-in incremental compilation.
+//!
+//! After a successful compile, the fingerprint of everything that went into it
+//! is recorded in a state directory along with the subvolume it produced. The
+//! next compile can then start from a snapshot of that subvolume and skip
+//! every feature that was already compiled into it.
+//!
+//! Since the effects of a feature can't be undone, this is only possible when
+//! the previously compiled features are an unchanged prefix of the features
+//! being compiled now. Anything else (a changed base, a changed or removed
+//! feature, a missing or replaced output, unreadable state) falls back to a
+//! full compile.
+
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use antlir2_features::Feature;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::warn;
+
+use crate::output_hash::TreeHash;
+
+const STATE_FILE: &str = "incremental.json";
+
+/// Everything that was compiled into a previous output
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct State {
+    /// Fingerprint of everything that is not a feature (parent layer, plans,
+    /// etc)
+    pub(crate) base: String,
+    /// Fingerprint of each feature, in the order they were compiled
+    pub(crate) features: Vec<String>,
+    /// Subvolume that the features were compiled into
+    pub(crate) subvol: PathBuf,
+}
+
+/// Where a compile should start from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Start {
+    /// Compile every feature on top of the parent layer
+    Scratch,
+    /// Snapshot `subvol` and only compile the features after the first `skip`
+    Previous { subvol: PathBuf, skip: usize },
+}
+
+impl State {
+    /// Load the state from a previous compile. Missing or unreadable state is
+    /// not an error, it just means that nothing can be reused.
+    pub(crate) fn load(dir: &Path) -> Option<Self> {
+        let path = dir.join(STATE_FILE);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("failed to read {}, doing full compile: {e}", path.display());
+                return None;
+            }
+        };
+        match serde_json::from_str(&contents) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                warn!(
+                    "failed to parse {}, doing full compile: {e}",
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("while creating state dir {}", dir.display()))?;
+        let path = dir.join(STATE_FILE);
+        let tmp = dir.join(format!("{STATE_FILE}.tmp"));
+        std::fs::write(
+            &tmp,
+            serde_json::to_vec(self).context("while serializing state")?,
+        )
+        .with_context(|| format!("while writing {}", tmp.display()))?;
+        std::fs::rename(&tmp, &path)
+            .with_context(|| format!("while moving state to {}", path.display()))
+    }
+
+    /// Forget any previous state, so that a compile that fails partway
+    /// through can never be mistaken for a complete one
+    pub(crate) fn clear(dir: &Path) -> Result<()> {
+        let path = dir.join(STATE_FILE);
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("while removing {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Decide where to start compiling `features` from. `output` is the
+    /// output path that buck expects to contain the previous compile.
+    pub(crate) fn start(
+        previous: Option<&Self>,
+        base: &str,
+        features: &[String],
+        output: &Path,
+    ) -> Start {
+        let Some(previous) = previous else {
+            return Start::Scratch;
+        };
+        if previous.base != base {
+            return Start::Scratch;
+        }
+        if !features.starts_with(&previous.features) {
+            return Start::Scratch;
+        }
+        // the previous output must still exist and be exactly what is at the
+        // output path, otherwise it might have been modified or replaced
+        match output.canonicalize() {
+            Ok(current) if current == previous.subvol && previous.subvol.is_dir() => {
+                Start::Previous {
+                    subvol: previous.subvol.clone(),
+                    skip: previous.features.len(),
+                }
+            }
+            _ => Start::Scratch,
+        }
+    }
+}
+
+/// Fingerprint a feature by its definition and the contents of every path on
+/// disk that it refers to
+pub(crate) fn fingerprint_feature(feature: &Feature) -> Result<String> {
+    let json = serde_json::to_value(feature).context("while serializing feature")?;
+    let mut hasher = Sha256::new();
+    hasher.update(json.to_string().as_bytes());
+    let mut inputs = BTreeMap::new();
+    collect_inputs(&json, &mut inputs)?;
+    for (path, digest) in inputs {
+        hasher.update(path.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(digest.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Find every buck input of the feature and hash it, since the same path may
+/// have different contents between builds.
+///
+/// Features are only known to the compiler as json, in which a
+/// [antlir2_features::types::BuckOutSource] or the paths of a
+/// [antlir2_features::types::LayerInfo] are just strings, so every relative
+/// string that names something on disk is considered an input (buck gives us
+/// inputs relative to the repo, while absolute paths are destinations inside
+/// the image). Hashing something that is not really an input can only cause an
+/// unnecessary full compile, while missing one would reuse a stale output.
+fn collect_inputs(value: &serde_json::Value, inputs: &mut BTreeMap<String, String>) -> Result<()> {
+    match value {
+        serde_json::Value::String(s) => {
+            if Path::new(s).is_relative()
+                && !inputs.contains_key(s)
+                && std::fs::symlink_metadata(s).is_ok()
+            {
+                let digest = hash_input(Path::new(s))
+                    .with_context(|| format!("while hashing feature input {s}"))?;
+                inputs.insert(s.clone(), digest);
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for v in values {
+                collect_inputs(v, inputs)?;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for v in map.values() {
+                collect_inputs(v, inputs)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Hash a single input. A symlink to a directory is a layer, which is
+/// identified by the subvolume it points to (the same as the parent layer),
+/// since every build of a layer is a new subvolume and hashing all of its
+/// contents would take about as long as compiling it. Everything else is
+/// hashed by its contents.
+fn hash_input(path: &Path) -> Result<String> {
+    if path.is_symlink() && path.is_dir() {
+        let subvol = path.canonicalize().context("while canonicalizing layer")?;
+        return Ok(hex::encode(Sha256::digest(subvol.as_os_str().as_bytes())));
+    }
+    Ok(TreeHash::compute(path)?.digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprints(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_start() {
+        let out = tempfile::TempDir::new().expect("failed to create tempdir");
+        let subvol = out.path().join("subvol");
+        std::fs::create_dir(&subvol).expect("failed to create dir");
+        let output = out.path().join("output");
+        std::os::unix::fs::symlink(&subvol, &output).expect("failed to symlink");
+        let subvol = subvol.canonicalize().expect("failed to canonicalize");
+        let previous = State {
+            base: "base".into(),
+            features: fingerprints(&["a", "b"]),
+            subvol: subvol.clone(),
+        };
+
+        assert_eq!(
+            State::start(None, "base", &fingerprints(&["a", "b"]), &output),
+            Start::Scratch,
+        );
+        // nothing changed, so nothing needs to be compiled
+        assert_eq!(
+            State::start(Some(&previous), "base", &fingerprints(&["a", "b"]), &output),
+            Start::Previous {
+                subvol: subvol.clone(),
+                skip: 2
+            },
+        );
+        // only the new feature is compiled
+        assert_eq!(
+            State::start(
+                Some(&previous),
+                "base",
+                &fingerprints(&["a", "b", "c"]),
+                &output
+            ),
+            Start::Previous {
+                subvol: subvol.clone(),
+                skip: 2
+            },
+        );
+        // changed feature
+        assert_eq!(
+            State::start(
+                Some(&previous),
+                "base",
+                &fingerprints(&["a", "B", "c"]),
+                &output
+            ),
+            Start::Scratch,
+        );
+        // removed feature
+        assert_eq!(
+            State::start(Some(&previous), "base", &fingerprints(&["a"]), &output),
+            Start::Scratch,
+        );
+        // changed base
+        assert_eq!(
+            State::start(
+                Some(&previous),
+                "other base",
+                &fingerprints(&["a", "b"]),
+                &output
+            ),
+            Start::Scratch,
+        );
+        // output no longer points at the previous subvol
+        std::fs::remove_file(&output).expect("failed to remove");
+        std::os::unix::fs::symlink(out.path(), &output).expect("failed to symlink");
+        assert_eq!(
+            State::start(Some(&previous), "base", &fingerprints(&["a", "b"]), &output),
+            Start::Scratch,
+        );
+        // output is gone entirely
+        std::fs::remove_file(&output).expect("failed to remove");
+        assert_eq!(
+            State::start(Some(&previous), "base", &fingerprints(&["a", "b"]), &output),
+            Start::Scratch,
+        );
+    }
+
+    #[test]
+    fn test_save_load_clear() {
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        let state_dir = dir.path().join("state");
+        assert_eq!(State::load(&state_dir), None);
+        let state = State {
+            base: "base".into(),
+            features: fingerprints(&["a"]),
+            subvol: "/subvol".into(),
+        };
+        state.save(&state_dir).expect("failed to save");
+        assert_eq!(State::load(&state_dir), Some(state));
+        State::clear(&state_dir).expect("failed to clear");
+        assert_eq!(State::load(&state_dir), None);
+        State::clear(&state_dir).expect("clearing twice is fine");
+
+        std::fs::write(state_dir.join(STATE_FILE), "garbage").expect("failed to write");
+        assert_eq!(State::load(&state_dir), None);
+    }
+
+    #[test]
+    fn test_inputs_change_fingerprint() {
+        let dir = tempfile::TempDir::new_in(".").expect("failed to create tempdir");
+        // buck gives us paths relative to the repo root (cwd)
+        let dir = dir
+            .path()
+            .strip_prefix(std::env::current_dir().expect("no cwd"))
+            .expect("tempdir is in cwd");
+        let rel = |name: &str| dir.join(name).to_str().expect("utf8").to_owned();
+        std::fs::write(rel("src"), "before").expect("failed to write");
+        std::fs::write(rel("subjects"), "[]").expect("failed to write");
+        std::fs::write(rel("facts"), "facts").expect("failed to write");
+        for subvol in ["subvol1", "subvol2"] {
+            std::fs::create_dir(rel(subvol)).expect("failed to mkdir");
+        }
+        std::os::unix::fs::symlink("subvol1", rel("layer")).expect("failed to symlink");
+        let value = serde_json::json!({
+            "src": rel("src"),
+            // exists on the host, but refers to a path in the image
+            "dst": "/tmp",
+            "subjects_src": [rel("subjects")],
+            "src_layer": {
+                "label": "//some:layer",
+                "facts_db": rel("facts"),
+                "contents": {"subvol_symlink": rel("layer")},
+            },
+        });
+        let fingerprint = |value: &serde_json::Value| {
+            let mut inputs = BTreeMap::new();
+            collect_inputs(value, &mut inputs).expect("failed to collect inputs");
+            inputs
+        };
+        let before = fingerprint(&value);
+        assert_eq!(
+            before.keys().cloned().collect::<Vec<_>>(),
+            [rel("facts"), rel("layer"), rel("src"), rel("subjects")]
+        );
+
+        std::fs::write(rel("src"), "after").expect("failed to write");
+        let changed_src = fingerprint(&value);
+        assert_ne!(before[&rel("src")], changed_src[&rel("src")]);
+
+        // a rebuilt layer is a new subvolume
+        std::fs::remove_file(rel("layer")).expect("failed to remove");
+        std::os::unix::fs::symlink("subvol2", rel("layer")).expect("failed to symlink");
+        let rebuilt = fingerprint(&value);
+        assert_ne!(changed_src[&rel("layer")], rebuilt[&rel("layer")]);
+        assert_eq!(changed_src[&rel("facts")], rebuilt[&rel("facts")]);
+    }
+}
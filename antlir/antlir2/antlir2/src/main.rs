@@ -18,7 +18,17 @@ use thiserror::Error;
 use tracing::error;
 use tracing_subscriber::prelude::*;
 
+mod atomic;
 mod cmd;
+mod incremental;
+mod output_hash;
+mod pack;
+mod progress;
+mod resume;
+mod sbom;
+mod size_budget;
+mod trace;
+mod which;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -87,6 +97,8 @@ impl LogArgs {
 enum Subcommand {
     Compile(cmd::Compile),
     Depgraph(cmd::Depgraph),
+    #[clap(name = "list")]
+    ListFeatures(cmd::ListFeatures),
 }
 
 impl Error {
@@ -100,6 +112,13 @@ impl Error {
             _ => None,
         }
     }
+
+    fn compile_error_kind(&self) -> Option<antlir2_compile::CompileErrorKind> {
+        match self {
+            Error::Compile(e) => Some(e.kind()),
+            _ => None,
+        }
+    }
 }
 
 #[fbinit::main]
@@ -120,11 +139,15 @@ fn main(fb: FacebookInit) -> Result<()> {
     let result = match args.subcommand {
         Subcommand::Compile(x) => x.run(rootless, fb),
         Subcommand::Depgraph(x) => x.run(),
+        Subcommand::ListFeatures(x) => x.run(),
     };
     if let Err(e) = result {
         error!("{e:#?}");
         // sentinel wrapper so that it can be extracted by CI
         eprintln!("ANTLIR ERROR:");
+        if let Some(kind) = e.compile_error_kind() {
+            eprintln!("error kind: {kind} ({})", kind.code());
+        }
         eprintln!("{}", format!("{e:#?}").red());
         eprintln!("{}", e.to_string().red());
         if let Some(category) = e.category() {
@@ -0,0 +1,182 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Content hash of a compiled image, used to detect non-determinism between
+//! builds of the same features.
+//!
+//! Every entry in the tree contributes its path, type, permission bits,
+//! ownership and contents (file data, symlink target or device number).
+//! Timestamps are deliberately not included, since they are never going to
+//! be identical between two builds.
+
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use sha2::Digest;
+use sha2::Sha256;
+use walkdir::WalkDir;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TreeHash {
+    /// Digest covering the entire tree
+    pub(crate) digest: String,
+    /// Digest of each entry, keyed by path relative to the root, in the
+    /// order they were hashed
+    pub(crate) entries: Vec<(PathBuf, String)>,
+}
+
+impl TreeHash {
+    /// Hash the tree rooted at `root`. Entries are visited in a canonical
+    /// (sorted by name) order so that the result does not depend on directory
+    /// iteration order.
+    pub(crate) fn compute(root: &Path) -> Result<Self> {
+        let mut entries = Vec::new();
+        let mut tree = Sha256::new();
+        for entry in WalkDir::new(root).follow_links(false).sort_by_file_name() {
+            let entry = entry.with_context(|| format!("while walking {}", root.display()))?;
+            let relpath = entry
+                .path()
+                .strip_prefix(root)
+                .expect("walkdir entries are always under root")
+                .to_owned();
+            let digest = hash_entry(entry.path())
+                .with_context(|| format!("while hashing {}", entry.path().display()))?;
+            tree.update(relpath.as_os_str().as_bytes());
+            tree.update(b"\0");
+            tree.update(digest.as_bytes());
+            tree.update(b"\n");
+            entries.push((relpath, digest));
+        }
+        Ok(Self {
+            digest: hex::encode(tree.finalize()),
+            entries,
+        })
+    }
+
+    /// Write the digest of the whole tree to `path`, and optionally a
+    /// `sha256sum`-style manifest with the digest of each entry to `manifest`
+    pub(crate) fn write(&self, path: &Path, manifest: Option<&Path>) -> Result<()> {
+        std::fs::write(path, format!("{}\n", self.digest))
+            .with_context(|| format!("while writing {}", path.display()))?;
+        if let Some(manifest) = manifest {
+            let mut f = File::create(manifest)
+                .with_context(|| format!("while creating {}", manifest.display()))?;
+            for (relpath, digest) in &self.entries {
+                writeln!(f, "{digest}  /{}", relpath.display())
+                    .with_context(|| format!("while writing {}", manifest.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hash_entry(path: &Path) -> Result<String> {
+    let meta = std::fs::symlink_metadata(path)?;
+    let mut hasher = Sha256::new();
+    let file_type = meta.file_type();
+    // mode includes both the file type and permission bits
+    hasher.update(meta.mode().to_le_bytes());
+    hasher.update(meta.uid().to_le_bytes());
+    hasher.update(meta.gid().to_le_bytes());
+    if file_type.is_file() {
+        let mut f = File::open(path)?;
+        std::io::copy(&mut f, &mut hasher)?;
+    } else if file_type.is_symlink() {
+        hasher.update(std::fs::read_link(path)?.as_os_str().as_bytes());
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        hasher.update(meta.rdev().to_le_bytes());
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::FileTimes;
+    use std::fs::Permissions;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    use super::*;
+
+    fn make_tree(root: &Path, mtime: SystemTime) {
+        std::fs::create_dir_all(root.join("etc/foo")).expect("failed to create dirs");
+        std::fs::write(root.join("etc/foo/bar"), "bar\n").expect("failed to write");
+        std::fs::write(root.join("etc/baz"), "baz\n").expect("failed to write");
+        std::os::unix::fs::symlink("foo/bar", root.join("etc/qux")).expect("failed to symlink");
+        File::options()
+            .write(true)
+            .open(root.join("etc/foo/bar"))
+            .expect("failed to open")
+            .set_times(FileTimes::new().set_modified(mtime))
+            .expect("failed to set mtime");
+    }
+
+    #[test]
+    fn test_tree_hash_is_reproducible() {
+        let a = tempfile::TempDir::new().expect("failed to create tempdir");
+        let b = tempfile::TempDir::new().expect("failed to create tempdir");
+        make_tree(a.path(), SystemTime::UNIX_EPOCH);
+        make_tree(
+            b.path(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000),
+        );
+        let hash_a = TreeHash::compute(a.path()).expect("failed to hash");
+        let hash_b = TreeHash::compute(b.path()).expect("failed to hash");
+        assert_eq!(hash_a, hash_b);
+        assert_eq!(
+            hash_a
+                .entries
+                .iter()
+                .map(|(p, _)| p.to_str().expect("utf8"))
+                .collect::<Vec<_>>(),
+            vec!["", "etc", "etc/baz", "etc/foo", "etc/foo/bar", "etc/qux"],
+        );
+
+        std::fs::set_permissions(b.path().join("etc/baz"), Permissions::from_mode(0o600))
+            .expect("failed to chmod");
+        let hash_b = TreeHash::compute(b.path()).expect("failed to hash");
+        assert_ne!(hash_a.digest, hash_b.digest);
+
+        std::fs::set_permissions(b.path().join("etc/baz"), Permissions::from_mode(0o644))
+            .expect("failed to chmod");
+        std::fs::write(b.path().join("etc/foo/bar"), "not bar\n").expect("failed to write");
+        let hash_b = TreeHash::compute(b.path()).expect("failed to hash");
+        assert_ne!(hash_a.digest, hash_b.digest);
+    }
+
+    #[test]
+    fn test_write_manifest() {
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        make_tree(root.path(), SystemTime::UNIX_EPOCH);
+        let out = tempfile::TempDir::new().expect("failed to create tempdir");
+        let hash = TreeHash::compute(root.path()).expect("failed to hash");
+        hash.write(
+            &out.path().join("digest"),
+            Some(&out.path().join("manifest")),
+        )
+        .expect("failed to write");
+        assert_eq!(
+            std::fs::read_to_string(out.path().join("digest")).expect("failed to read"),
+            format!("{}\n", hash.digest)
+        );
+        let manifest =
+            std::fs::read_to_string(out.path().join("manifest")).expect("failed to read");
+        assert_eq!(manifest.lines().count(), hash.entries.len());
+        assert!(
+            manifest.ends_with(&format!("{}  /etc/qux\n", hash.entries[5].1)),
+            "{manifest}"
+        );
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Pack the compiled image into a filesystem image right after compiling, so
+//! that downstream doesn't need a separate packaging step.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::anyhow;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use clap::ValueEnum;
+use tracing::debug;
+
+use crate::which::which;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Pack {
+    /// Only produce the directory tree
+    None,
+    Squashfs,
+    Erofs,
+}
+
+impl Pack {
+    fn tool(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Squashfs => Some("mksquashfs"),
+            Self::Erofs => Some("mkfs.erofs"),
+        }
+    }
+
+    /// Find the packer in $PATH, so that a missing tool fails the build before
+    /// compiling anything instead of after
+    pub(crate) fn packer(self) -> Result<Option<Packer>> {
+        let Some(tool) = self.tool() else {
+            return Ok(None);
+        };
+        which(tool)
+            .map(|tool| Some(Packer { pack: self, tool }))
+            .ok_or_else(|| anyhow!("--pack={self:?} needs '{tool}', but it is not in $PATH"))
+    }
+}
+
+/// A packer binary that was found on the host
+#[derive(Debug, Clone)]
+pub(crate) struct Packer {
+    pack: Pack,
+    tool: PathBuf,
+}
+
+impl Packer {
+    /// Pack the directory `root` into a new image at `dst`, replacing
+    /// anything that was there before
+    pub(crate) fn pack(&self, root: &Path, dst: &Path) -> Result<()> {
+        match std::fs::remove_file(dst) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("while removing '{}'", dst.display()));
+            }
+            _ => {}
+        }
+        let mut cmd = Command::new(&self.tool);
+        match self.pack {
+            Pack::None => unreachable!("there is no packer for Pack::None"),
+            Pack::Squashfs => cmd.arg(root).arg(dst).arg("-noappend").arg("-quiet"),
+            Pack::Erofs => cmd.arg("--quiet").arg(dst).arg(root),
+        };
+        debug!("packing image: {cmd:?}");
+        let out = cmd
+            .output()
+            .with_context(|| format!("while running {}", self.tool.display()))?;
+        ensure!(
+            out.status.success(),
+            "{} failed ({}): {}",
+            self.tool.display(),
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim(),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_none() {
+        assert!(Pack::None.packer().expect("nothing to find").is_none());
+    }
+
+    /// Pack a small tree and read it back with `extract`, which is called
+    /// with the image and the directory to extract it to
+    fn pack_and_extract(pack: Pack, extract: impl Fn(&Path, &Path) -> Command) {
+        let packer = pack
+            .packer()
+            .expect("packer is installed")
+            .expect("has a packer");
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        std::fs::create_dir(root.path().join("etc")).expect("failed to create dir");
+        std::fs::write(root.path().join("etc/os-release"), "ID=antlir\n").expect("failed to write");
+        let out = tempfile::TempDir::new().expect("failed to create tempdir");
+        let image = out.path().join("image");
+        packer.pack(root.path(), &image).expect("failed to pack");
+        // packing again replaces the old image instead of appending to it
+        std::fs::write(root.path().join("etc/os-release"), "ID=repacked\n")
+            .expect("failed to write");
+        packer.pack(root.path(), &image).expect("failed to pack");
+
+        let extracted = out.path().join("extracted");
+        let res = extract(&image, &extracted)
+            .output()
+            .expect("failed to run extractor");
+        assert!(res.status.success(), "{res:?}");
+        assert_eq!(
+            std::fs::read_to_string(extracted.join("etc/os-release"))
+                .expect("failed to read packed file"),
+            "ID=repacked\n"
+        );
+    }
+
+    // the packers are only guaranteed to be installed in the image_test
+    // layer
+    #[test]
+    #[cfg_attr(not(image_test), ignore = "needs squashfs-tools")]
+    fn test_pack_squashfs() {
+        pack_and_extract(Pack::Squashfs, |image, dst| {
+            let mut cmd = Command::new("unsquashfs");
+            cmd.arg("-no-progress").arg("-d").arg(dst).arg(image);
+            cmd
+        });
+    }
+
+    #[test]
+    #[cfg_attr(not(image_test), ignore = "needs erofs-utils")]
+    fn test_pack_erofs() {
+        pack_and_extract(Pack::Erofs, |image, dst| {
+            let mut cmd = Command::new("fsck.erofs");
+            cmd.arg(format!("--extract={}", dst.display())).arg(image);
+            cmd
+        });
+    }
+
+    #[test]
+    fn test_pack_error() {
+        let packer = Packer {
+            pack: Pack::Squashfs,
+            tool: "false".into(),
+        };
+        let err = packer
+            .pack(Path::new("/nonexistent"), Path::new("/nonexistent/image"))
+            .expect_err("packer failed");
+        assert!(err.to_string().starts_with("false failed"), "{err}");
+    }
+}
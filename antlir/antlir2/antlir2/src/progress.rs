@@ -0,0 +1,92 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Feature-by-feature progress of a compile for interactive builds.
+//!
+//! Progress is written to stderr as whole lines, so that it never garbles the
+//! tracing output on stdout.
+
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::io::Write;
+
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum When {
+    /// Only if stderr is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug)]
+pub(crate) struct Progress<W> {
+    total: usize,
+    done: usize,
+    out: Option<W>,
+}
+
+impl Progress<std::io::Stderr> {
+    pub(crate) fn new(when: When, total: usize) -> Self {
+        let enabled = match when {
+            When::Auto => std::io::stderr().is_terminal(),
+            When::Always => true,
+            When::Never => false,
+        };
+        Self::with_writer(total, enabled.then(std::io::stderr))
+    }
+}
+
+impl<W: Write> Progress<W> {
+    fn with_writer(total: usize, out: Option<W>) -> Self {
+        Self {
+            total,
+            done: 0,
+            out,
+        }
+    }
+
+    /// Record that the feature from `label` finished compiling
+    pub(crate) fn feature_done(&mut self, label: impl Display) {
+        self.done += 1;
+        if let Some(out) = &mut self.out {
+            let percent = match self.total {
+                0 => 100,
+                total => self.done * 100 / total,
+            };
+            // progress is best effort and must never fail the compile
+            let _ = writeln!(out, "[{}/{}] {percent:>3}% {label}", self.done, self.total);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let mut progress = Progress::with_writer(3, Some(Vec::new()));
+        for label in ["//image:base", "//image:rpms", "//image:users"] {
+            progress.feature_done(label);
+        }
+        let out = String::from_utf8(progress.out.expect("enabled")).expect("utf8");
+        assert_eq!(
+            out.lines().collect::<Vec<_>>(),
+            vec![
+                "[1/3]  33% //image:base",
+                "[2/3]  66% //image:rpms",
+                "[3/3] 100% //image:users",
+            ]
+        );
+
+        let mut progress = Progress::new(When::Never, 3);
+        progress.feature_done("//image:base");
+        assert!(progress.out.is_none());
+    }
+}
@@ -0,0 +1,218 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Resume a compile partway through its features.
+//!
+//! When a long compile fails near the end, the features before the broken one
+//! don't need to be compiled again, as long as the existing output already
+//! has everything they produced. This starts from a snapshot of that output
+//! and skips every feature before a chosen one. The paths that the skipped
+//! features provide can optionally be checked for, but nothing else about
+//! them can be, so this is only meant for local debugging.
+
+use std::fmt::Display;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use antlir2_depgraph::Listing;
+use antlir2_depgraph_if::item::Item;
+use antlir2_depgraph_if::item::Path as PathItem;
+use antlir2_features::Feature;
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use buck_label::Label;
+use tracing::warn;
+
+/// Which feature to continue compiling from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FeatureSelector {
+    /// Position in the compile order, starting at 1 (as printed by
+    /// `--explain`)
+    Position(usize),
+    /// The first feature from this label
+    Label(Label),
+}
+
+impl FromStr for FeatureSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.parse::<usize>() {
+            Ok(0) => Err(anyhow!("feature positions start at 1")),
+            Ok(position) => Ok(Self::Position(position)),
+            Err(_) => {
+                Ok(Self::Label(Label::new(s).with_context(|| {
+                    format!("'{s}' is not a position or label")
+                })?))
+            }
+        }
+    }
+}
+
+impl Display for FeatureSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Position(position) => write!(f, "feature #{position}"),
+            Self::Label(label) => write!(f, "{label}"),
+        }
+    }
+}
+
+impl FeatureSelector {
+    /// Index of the selected feature in `features`
+    fn index(&self, features: &[Feature]) -> Result<usize> {
+        match self {
+            Self::Position(position) if *position <= features.len() => Ok(position - 1),
+            Self::Position(position) => Err(anyhow!(
+                "there are only {} features, so there is no feature #{position}",
+                features.len()
+            )),
+            Self::Label(label) => {
+                let label = label.as_unconfigured();
+                features
+                    .iter()
+                    .position(|f| f.label.as_unconfigured() == label)
+                    .with_context(|| format!("no feature comes from {label}"))
+            }
+        }
+    }
+}
+
+/// Find the previous compile at `output` to snapshot, and how many of
+/// `features` to skip so that compiling starts from the one chosen by
+/// `selector`
+pub(crate) fn start(
+    features: &[Feature],
+    selector: &FeatureSelector,
+    output: &Path,
+) -> Result<(PathBuf, usize)> {
+    let skip = selector.index(features)?;
+    let subvol = output
+        .canonicalize()
+        .with_context(|| format!("while resolving previous output {}", output.display()))?;
+    warn!(
+        "continuing from {selector}: assuming that the {skip} features before it are already compiled into {}",
+        subvol.display()
+    );
+    Ok((subvol, skip))
+}
+
+/// Paths that should have been created by one of the `skipped` features, but
+/// are not in the image at `root`. Users and groups are not checked.
+pub(crate) fn missing_outputs(
+    listed: &[Listing],
+    skipped: &[Feature],
+    root: &Path,
+) -> Vec<PathBuf> {
+    let mut missing: Vec<_> = listed
+        .iter()
+        .filter(|listing| skipped.contains(&listing.feature))
+        .flat_map(|listing| &listing.provides)
+        .filter_map(|item| match item {
+            Item::Path(PathItem::Entry(entry)) => Some(&entry.path),
+            Item::Path(PathItem::Symlink { link, .. }) => Some(link),
+            // mounts are only set up at runtime
+            _ => None,
+        })
+        .filter(|path| {
+            std::fs::symlink_metadata(root.join(path.strip_prefix("/").unwrap_or(path))).is_err()
+        })
+        .cloned()
+        .collect();
+    missing.sort();
+    missing.dedup();
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use antlir2_depgraph_if::item::FileType;
+    use antlir2_depgraph_if::item::FsEntry;
+    use serde_json::json;
+
+    use super::*;
+
+    fn feature(label: &str, data: serde_json::Value) -> Feature {
+        serde_json::from_value(json!({
+            "label": label,
+            "feature_type": "test",
+            "data": data,
+            "plugin": {"plugin": "/nonexistent", "libs": "/nonexistent"},
+        }))
+        .expect("failed to deserialize feature")
+    }
+
+    #[test]
+    fn test_start() {
+        let features = vec![
+            feature("antlir//image:base", json!(1)),
+            feature("antlir//image:app", json!(2)),
+            feature("antlir//image:app", json!(3)),
+            feature("antlir//image:late", json!(4)),
+        ];
+        let out = tempfile::TempDir::new().expect("failed to create tempdir");
+        let output = out.path().join("output");
+        std::os::unix::fs::symlink(out.path(), &output).expect("failed to symlink");
+        let subvol = out.path().canonicalize().expect("failed to canonicalize");
+
+        let compiled = |selector: &str| -> Vec<serde_json::Value> {
+            let selector: FeatureSelector = selector.parse().expect("failed to parse selector");
+            let (start_from, skip) = start(&features, &selector, &output).expect("failed to start");
+            assert_eq!(start_from, subvol);
+            features[skip..].iter().map(|f| f.data.clone()).collect()
+        };
+        // the first feature from the label and everything after it runs
+        assert_eq!(
+            compiled("antlir//image:app"),
+            [json!(2), json!(3), json!(4)],
+        );
+        assert_eq!(compiled("4"), [json!(4)]);
+        assert_eq!(compiled("1").len(), 4);
+
+        assert!("0".parse::<FeatureSelector>().is_err());
+        let selector: FeatureSelector = "5".parse().expect("failed to parse selector");
+        assert!(start(&features, &selector, &output).is_err());
+        let selector: FeatureSelector = "antlir//image:other"
+            .parse()
+            .expect("failed to parse selector");
+        assert!(start(&features, &selector, &output).is_err());
+        // there is nothing to continue from
+        let selector: FeatureSelector = "1".parse().expect("failed to parse selector");
+        assert!(start(&features, &selector, &out.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_missing_outputs() {
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        std::fs::create_dir(root.path().join("present")).expect("failed to create dir");
+        let entry = |path: &str| {
+            Item::Path(PathItem::Entry(FsEntry {
+                path: path.into(),
+                file_type: FileType::Directory,
+                mode: 0o755,
+            }))
+        };
+        let skipped = feature("antlir//image:base", json!(1));
+        let compiled = feature("antlir//image:app", json!(2));
+        let listed = vec![
+            Listing {
+                feature: skipped.clone(),
+                provides: vec![entry("/present"), entry("/absent")],
+            },
+            Listing {
+                feature: compiled,
+                provides: vec![entry("/not-compiled-yet")],
+            },
+        ];
+        assert_eq!(
+            missing_outputs(&listed, &[skipped], root.path()),
+            vec![PathBuf::from("/absent")],
+        );
+    }
+}
@@ -0,0 +1,188 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Software bill of materials of the packages installed into an image, in the
+//! CycloneDX (https://cyclonedx.org/specification/overview/) JSON format.
+//!
+//! Only packages that features declared as installed are listed, anything
+//! else in the image (or inherited from a parent layer) is not.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+use antlir2_compile::InstalledPackage;
+use anyhow::Context;
+use anyhow::Result;
+use buck_label::Label;
+use serde::Serialize;
+
+const SPEC_VERSION: &str = "1.5";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Sbom {
+    bom_format: &'static str,
+    spec_version: &'static str,
+    version: u32,
+    metadata: Metadata,
+    components: Vec<Component>,
+}
+
+#[derive(Debug, Serialize)]
+struct Metadata {
+    component: Component,
+}
+
+#[derive(Debug, Serialize)]
+struct Component {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    purl: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    properties: Vec<Property>,
+}
+
+#[derive(Debug, Serialize)]
+struct Property {
+    name: &'static str,
+    value: String,
+}
+
+impl Sbom {
+    /// Describe the image `label` as made up of `packages`
+    pub(crate) fn new(label: &Label, packages: Vec<InstalledPackage>) -> Self {
+        Self {
+            bom_format: "CycloneDX",
+            spec_version: SPEC_VERSION,
+            version: 1,
+            metadata: Metadata {
+                component: Component {
+                    kind: "container",
+                    name: label.to_string(),
+                    version: None,
+                    purl: None,
+                    properties: vec![],
+                },
+            },
+            components: packages
+                .into_iter()
+                .map(|package| Component {
+                    kind: "library",
+                    name: package.name,
+                    version: Some(package.version),
+                    purl: Some(package.purl),
+                    properties: package
+                        .source
+                        .map(|value| Property {
+                            name: "antlir2:source",
+                            value,
+                        })
+                        .into_iter()
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Write the SBOM as pretty-printed JSON to `path`
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        let mut f = BufWriter::new(
+            File::create(path).with_context(|| format!("while creating {}", path.display()))?,
+        );
+        serde_json::to_writer_pretty(&mut f, self).context("while serializing sbom")?;
+        writeln!(f)?;
+        f.flush()
+            .with_context(|| format!("while writing {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use antlir2_compile::Arch;
+    use antlir2_compile::CompileFeature;
+    use antlir2_compile::CompilerContext;
+    use serde_json::json;
+
+    use super::*;
+
+    /// Stands in for the rpm feature, which needs a build appliance and repos
+    struct InstallsRpm;
+
+    impl CompileFeature for InstallsRpm {
+        fn compile(&self, ctx: &CompilerContext) -> antlir2_compile::Result<()> {
+            ctx.declare_package(InstalledPackage {
+                name: "bash".to_owned(),
+                version: "1:5.1.8-6.el9".to_owned(),
+                source: Some("baseos".to_owned()),
+                purl: "pkg:rpm/bash@5.1.8-6.el9?arch=x86_64&epoch=1".to_owned(),
+            });
+            Ok(())
+        }
+    }
+
+    /// A feature that does not install any packages
+    struct Noop;
+
+    impl CompileFeature for Noop {
+        fn compile(&self, _ctx: &CompilerContext) -> antlir2_compile::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_sbom() {
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        let label = Label::new("test//test:image").expect("valid label");
+        let ctx = CompilerContext::new(
+            label.clone(),
+            Arch::X86_64,
+            root.path().to_owned(),
+            HashMap::new(),
+        )
+        .expect("failed to create ctx");
+        let features: Vec<Box<dyn CompileFeature>> = vec![Box::new(Noop), Box::new(InstallsRpm)];
+        for feature in &features {
+            feature.compile(&ctx).expect("failed to compile");
+        }
+
+        let out = tempfile::NamedTempFile::new().expect("failed to create tempfile");
+        Sbom::new(&label, ctx.installed_packages())
+            .write(out.path())
+            .expect("failed to write sbom");
+        let sbom: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(out.path()).expect("failed to read sbom"),
+        )
+        .expect("sbom is not valid json");
+        assert_eq!(sbom["bomFormat"], "CycloneDX");
+        assert_eq!(sbom["metadata"]["component"]["name"], "test//test:image");
+        assert_eq!(
+            sbom["components"],
+            json!([{
+                "type": "library",
+                "name": "bash",
+                "version": "1:5.1.8-6.el9",
+                "purl": "pkg:rpm/bash@5.1.8-6.el9?arch=x86_64&epoch=1",
+                "properties": [{"name": "antlir2:source", "value": "baseos"}],
+            }])
+        );
+    }
+
+    #[test]
+    fn test_empty_sbom() {
+        let label = Label::new("test//test:image").expect("valid label");
+        let sbom = serde_json::to_value(Sbom::new(&label, vec![])).expect("failed to serialize");
+        assert_eq!(sbom["components"], json!([]));
+    }
+}
@@ -0,0 +1,198 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Size limit for a compiled image, so that an image that has grown past
+//! what it will be deployed into fails to build instead of failing much later
+//! in the pipeline.
+//!
+//! Hardlinked files only count once, no matter how many paths lead to them.
+
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use clap::ValueEnum;
+use walkdir::WalkDir;
+
+/// How many of the largest paths to list when the budget is exceeded
+const TOP_N: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SizeKind {
+    /// Length of the contents, as reported by `du --apparent-size`
+    Apparent,
+    /// Space allocated on disk, which counts filesystem blocks and so is
+    /// smaller for sparse files and larger for lots of small ones
+    Disk,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TreeSize {
+    /// Size of the whole tree
+    pub(crate) total: u64,
+    /// Size of every entry that is not a directory, keyed by path relative
+    /// to the root, largest first
+    pub(crate) entries: Vec<(PathBuf, u64)>,
+}
+
+impl TreeSize {
+    /// Add up the size of everything under `root`
+    pub(crate) fn compute(root: &Path, kind: SizeKind) -> Result<Self> {
+        let mut total = 0;
+        let mut entries = Vec::new();
+        let mut seen_inodes = HashSet::new();
+        for entry in WalkDir::new(root).follow_links(false).sort_by_file_name() {
+            let entry = entry.with_context(|| format!("while walking {}", root.display()))?;
+            let meta = entry
+                .metadata()
+                .with_context(|| format!("while statting {}", entry.path().display()))?;
+            if meta.nlink() > 1 && !meta.is_dir() && !seen_inodes.insert((meta.dev(), meta.ino())) {
+                continue;
+            }
+            let size = match kind {
+                SizeKind::Apparent => meta.len(),
+                // st_blocks is always in 512 byte units
+                SizeKind::Disk => meta.blocks() * 512,
+            };
+            total += size;
+            if !meta.is_dir() {
+                let relpath = entry
+                    .path()
+                    .strip_prefix(root)
+                    .expect("walkdir entries are always under root")
+                    .to_owned();
+                entries.push((relpath, size));
+            }
+        }
+        entries.sort_by(|(a_path, a), (b_path, b)| b.cmp(a).then_with(|| a_path.cmp(b_path)));
+        Ok(Self { total, entries })
+    }
+
+    /// Fail if the tree is larger than `max_bytes`, listing the largest paths
+    /// in it to help figure out what to trim
+    pub(crate) fn check(&self, max_bytes: u64) -> Result<()> {
+        if self.total <= max_bytes {
+            return Ok(());
+        }
+        let mut msg = format!(
+            "image is {} bytes, which is over the budget of {max_bytes} bytes by {} bytes. Largest paths:",
+            self.total,
+            self.total - max_bytes,
+        );
+        for (relpath, size) in self.entries.iter().take(TOP_N) {
+            msg.push_str(&format!("\n  {size:>12}  /{}", relpath.display()));
+        }
+        bail!(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use antlir2_compile::Arch;
+    use antlir2_compile::CompileFeature;
+    use antlir2_compile::CompilerContext;
+    use buck_label::Label;
+
+    use super::*;
+
+    /// Installs a file of `len` bytes at `dst`
+    struct InstallFile {
+        dst: &'static str,
+        len: usize,
+    }
+
+    impl CompileFeature for InstallFile {
+        fn compile(&self, ctx: &CompilerContext) -> antlir2_compile::Result<()> {
+            let dst = ctx.dst_path(self.dst)?;
+            std::fs::create_dir_all(dst.parent().expect("dst is never /"))?;
+            std::fs::write(dst, vec![b'a'; self.len])?;
+            Ok(())
+        }
+    }
+
+    fn compile(features: &[InstallFile]) -> tempfile::TempDir {
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        let ctx = CompilerContext::new(
+            Label::new("test//test:image").expect("valid label"),
+            Arch::X86_64,
+            root.path().to_owned(),
+            HashMap::new(),
+        )
+        .expect("failed to create ctx");
+        for feature in features {
+            feature.compile(&ctx).expect("failed to compile");
+        }
+        root
+    }
+
+    #[test]
+    fn test_over_budget() {
+        let root = compile(&[
+            InstallFile {
+                dst: "/etc/small",
+                len: 10,
+            },
+            InstallFile {
+                dst: "/usr/lib/huge",
+                len: 1 << 20,
+            },
+        ]);
+        let size = TreeSize::compute(root.path(), SizeKind::Apparent).expect("failed to size");
+        assert_eq!(
+            size.entries,
+            vec![
+                (PathBuf::from("usr/lib/huge"), 1 << 20),
+                (PathBuf::from("etc/small"), 10),
+            ]
+        );
+        assert!(size.total >= (1 << 20) + 10);
+
+        size.check(size.total).expect("exactly at the budget");
+        let e = size.check(4096).expect_err("over budget");
+        let msg = e.to_string();
+        assert!(msg.contains("over the budget of 4096 bytes"), "{msg}");
+        assert!(
+            msg.contains(&format!("{:>12}  /usr/lib/huge", 1 << 20)),
+            "{msg}"
+        );
+        assert!(
+            msg.find("/usr/lib/huge") < msg.find("/etc/small"),
+            "largest path must be listed first: {msg}"
+        );
+    }
+
+    #[test]
+    fn test_hardlinks_count_once() {
+        let root = compile(&[InstallFile {
+            dst: "/a",
+            len: 1000,
+        }]);
+        std::fs::hard_link(root.path().join("a"), root.path().join("b"))
+            .expect("failed to hardlink");
+        let size = TreeSize::compute(root.path(), SizeKind::Apparent).expect("failed to size");
+        assert_eq!(size.entries, vec![(PathBuf::from("a"), 1000)]);
+    }
+
+    #[test]
+    fn test_disk_size_of_sparse_file() {
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        std::fs::File::create(root.path().join("sparse"))
+            .expect("failed to create")
+            .set_len(1 << 30)
+            .expect("failed to truncate");
+        let apparent = TreeSize::compute(root.path(), SizeKind::Apparent).expect("failed to size");
+        let disk = TreeSize::compute(root.path(), SizeKind::Disk).expect("failed to size");
+        assert_eq!(apparent.entries, vec![(PathBuf::from("sparse"), 1 << 30)]);
+        assert!(disk.entries[0].1 < 1 << 30, "{disk:?}");
+    }
+}
@@ -0,0 +1,265 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Trace the syscalls made while compiling each feature, to find out what a
+//! restricted environment denied when a feature fails in it.
+//!
+//! Features are compiled in-process, so strace is attached to this process
+//! (and follows any children the feature spawns) for the duration of a single
+//! feature, and detached again afterwards. Each feature gets its own trace
+//! file, named after its position and label.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use buck_label::Label;
+use nix::sys::signal::kill;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use tracing::debug;
+use tracing::warn;
+
+use crate::which::which;
+
+/// How long strace gets to attach before giving up
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub(crate) struct Tracer {
+    strace: PathBuf,
+    /// Trace file for each feature, keyed by its position in the features
+    /// being compiled
+    traces: HashMap<usize, (PathBuf, File)>,
+}
+
+impl Tracer {
+    /// Find strace in $PATH and create a trace file in `dir` for each of
+    /// `features`. The files are all created up front, since nothing outside
+    /// of the image is writable while compiling with --sandbox-writes.
+    pub(crate) fn new<'a>(
+        dir: &Path,
+        features: impl IntoIterator<Item = (usize, &'a Label)>,
+    ) -> Result<Self> {
+        let strace = which("strace")
+            .ok_or_else(|| anyhow!("--trace-syscalls needs 'strace', but it is not in $PATH"))?;
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("while creating {}", dir.display()))?;
+        let traces = features
+            .into_iter()
+            .map(|(idx, label)| {
+                let path = dir.join(trace_filename(idx, label));
+                let f = File::create(&path)
+                    .with_context(|| format!("while creating {}", path.display()))?;
+                Ok((idx, (path, f)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { strace, traces })
+    }
+
+    /// Run `f` (which compiles the feature at position `idx`) with strace
+    /// attached
+    pub(crate) fn trace<T>(&self, idx: usize, f: impl FnOnce() -> T) -> Result<T> {
+        let (path, trace) = self
+            .traces
+            .get(&idx)
+            .with_context(|| format!("no trace file was created for feature {idx}"))?;
+        debug!("tracing feature {idx} to {}", path.display());
+        // with no -o, strace writes the trace to stderr
+        let child = Command::new(&self.strace)
+            .arg("-f")
+            .arg("-tt")
+            .arg("-yy")
+            .arg("-s")
+            .arg("256")
+            .arg("-p")
+            .arg(std::process::id().to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(
+                trace
+                    .try_clone()
+                    .with_context(|| format!("while cloning fd of {}", path.display()))?,
+            )
+            .spawn()
+            .with_context(|| format!("while spawning {}", self.strace.display()))?;
+        let mut attached = Attached(child);
+        attached
+            .wait_for_attach()
+            .with_context(|| format!("while attaching strace (see {})", path.display()))?;
+        let res = f();
+        attached.detach()?;
+        Ok(res)
+    }
+}
+
+/// A running strace, which is detached from this process when dropped
+struct Attached(Child);
+
+impl Attached {
+    fn wait_for_attach(&mut self) -> Result<()> {
+        let deadline = Instant::now() + ATTACH_TIMEOUT;
+        while !is_traced()? {
+            if let Some(status) = self.0.try_wait().context("while checking on strace")? {
+                bail!("strace exited before attaching: {status}");
+            }
+            if Instant::now() >= deadline {
+                bail!("strace did not attach within {ATTACH_TIMEOUT:?}");
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    fn detach(&mut self) -> Result<()> {
+        if self
+            .0
+            .try_wait()
+            .context("while checking on strace")?
+            .is_some()
+        {
+            return Ok(());
+        }
+        // strace detaches from everything it is tracing on SIGINT
+        kill(Pid::from_raw(self.0.id() as i32), Signal::SIGINT)
+            .context("while signaling strace")?;
+        self.0.wait().context("while waiting for strace")?;
+        Ok(())
+    }
+}
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        if let Err(e) = self.detach() {
+            warn!("failed to detach strace: {e:#}");
+        }
+    }
+}
+
+/// Whether this thread currently has a tracer attached
+fn is_traced() -> Result<bool> {
+    let status = std::fs::read_to_string("/proc/thread-self/status")
+        .context("while reading /proc/thread-self/status")?;
+    Ok(status
+        .lines()
+        .find_map(|line| line.strip_prefix("TracerPid:"))
+        .is_some_and(|pid| pid.trim() != "0"))
+}
+
+/// Name of the trace file for the feature at position `idx`, which keeps
+/// them in compile order and makes it clear which feature each one is for
+fn trace_filename(idx: usize, label: &Label) -> String {
+    let label: String = label
+        .to_string()
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    format!("{idx:04}-{label}.strace")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use antlir2_compile::Arch;
+    use antlir2_compile::CompileFeature;
+    use antlir2_compile::CompilerContext;
+
+    use super::*;
+
+    /// Writes a file into the image
+    struct InstallFile;
+
+    impl CompileFeature for InstallFile {
+        fn compile(&self, ctx: &CompilerContext) -> antlir2_compile::Result<()> {
+            std::fs::write(ctx.dst_path("/traced")?, "hello\n")?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace_filename() {
+        assert_eq!(
+            trace_filename(
+                3,
+                &Label::new("fbcode//antlir/test:feature--1.2").expect("valid label")
+            ),
+            "0003-fbcode__antlir_test_feature--1.2.strace"
+        );
+    }
+
+    // strace is only guaranteed to be installed in the image_test layer
+    #[test]
+    #[cfg_attr(not(image_test), ignore = "needs strace")]
+    fn test_trace() {
+        let label = Label::new("test//test:image").expect("valid label");
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        let traces = dir.path().join("traces");
+        let tracer = Tracer::new(&traces, [(0, &label), (1, &label)]).expect("strace is installed");
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        let ctx = CompilerContext::new(
+            label.clone(),
+            Arch::X86_64,
+            root.path().to_owned(),
+            HashMap::new(),
+        )
+        .expect("failed to create ctx");
+        tracer
+            .trace(0, || InstallFile.compile(&ctx))
+            .expect("failed to trace")
+            .expect("failed to compile");
+        assert!(
+            !is_traced().expect("failed to check"),
+            "strace still attached"
+        );
+
+        let mut files: Vec<_> = std::fs::read_dir(&traces)
+            .expect("failed to read dir")
+            .map(|entry| entry.expect("failed to read entry").file_name())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "0000-test__test_image.strace",
+                "0001-test__test_image.strace"
+            ]
+        );
+        let trace = std::fs::read_to_string(traces.join("0000-test__test_image.strace"))
+            .expect("failed to read trace");
+        let installed = root.path().join("traced");
+        assert!(
+            trace
+                .lines()
+                .any(|line| line.contains("open")
+                    && line.contains(installed.to_str().expect("utf8"))),
+            "{trace}"
+        );
+        // features that were not compiled have nothing in their trace
+        assert_eq!(
+            std::fs::read_to_string(traces.join("0001-test__test_image.strace"))
+                .expect("failed to read trace"),
+            ""
+        );
+        assert!(tracer.trace(2, || ()).is_err(), "no trace file for 2");
+    }
+}
@@ -0,0 +1,37 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Find the host tools that some compile options need.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+/// First executable file named `tool` in $PATH
+pub(crate) fn which(tool: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(tool))
+        .find(|candidate| {
+            std::fs::metadata(candidate)
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_which() {
+        let sh = which("sh").expect("sh is always in $PATH");
+        assert_eq!(sh.file_name(), Some("sh".as_ref()));
+        assert!(sh.is_file(), "{}", sh.display());
+        // directories are not executables
+        assert_eq!(which("."), None);
+        assert_eq!(which("antlir2-no-such-tool"), None);
+    }
+}
@@ -7,17 +7,24 @@
 
 #![feature(io_error_more)]
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::ErrorKind;
 use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use antlir2_features::Feature;
+use antlir2_users::GroupId;
+use antlir2_users::UserId;
 use buck_label::Label;
 use cap_std::fs::Dir;
 use nix::libc;
@@ -50,10 +57,100 @@ pub enum Error {
     // just context information).
     #[error("{0:?}")]
     Other(#[from] anyhow::Error),
+    #[error("{} warning(s) emitted while compiling:\n{}", .0.len(), .0.join("\n"))]
+    Warnings(Vec<String>),
+    #[error("{} path(s) are not owned as declared by their feature:\n{}", .0.len(), .0.join("\n"))]
+    OwnershipMismatch(Vec<String>),
+    #[error("{} xattr(s) were not preserved as declared by their feature:\n{}", .0.len(), .0.join("\n"))]
+    XattrMismatch(Vec<String>),
+}
+
+impl Error {
+    /// Whether compiling the feature again has a chance of succeeding
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::IO(e) => matches!(
+                e.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::NetworkUnreachable
+                    | ErrorKind::HostUnreachable
+            ),
+            _ => false,
+        }
+    }
+
+    /// Broad category of this error, for aggregating failures across builds
+    pub fn kind(&self) -> CompileErrorKind {
+        match self {
+            Self::NoSuchUser(_) | Self::NoSuchGroup(_) => CompileErrorKind::MissingInput,
+            Self::IO(e) => CompileErrorKind::from_io(e),
+            Self::ExtractConflict(_) => CompileErrorKind::Conflict,
+            // features mostly fail with some context attached to an
+            // underlying io error, so look through the whole chain for one
+            Self::Other(e) => e
+                .chain()
+                .find_map(|e| e.downcast_ref::<std::io::Error>())
+                .map_or(CompileErrorKind::Other, CompileErrorKind::from_io),
+            _ => CompileErrorKind::Other,
+        }
+    }
+}
+
+/// Stable classification of compile failures.
+///
+/// The discriminants are reported to build infrastructure, so existing ones
+/// must never be changed or reused.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u16)]
+pub enum CompileErrorKind {
+    Other = 0,
+    /// Something the feature needs (a source file, user, group, etc) does not
+    /// exist
+    MissingInput = 1,
+    Permission = 2,
+    /// The feature would clobber something that is already in the image
+    Conflict = 3,
+    OutOfSpace = 4,
+}
+
+impl CompileErrorKind {
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+
+    fn from_io(e: &std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::NotFound => Self::MissingInput,
+            ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => Self::Permission,
+            ErrorKind::AlreadyExists => Self::Conflict,
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded => Self::OutOfSpace,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl Display for CompileErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Other => "other",
+            Self::MissingInput => "missing_input",
+            Self::Permission => "permission",
+            Self::Conflict => "conflict",
+            Self::OutOfSpace => "out_of_space",
+        })
+    }
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// How long to wait before the first retry of a feature, doubled for every
+/// retry after that
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Arch {
@@ -82,6 +179,20 @@ impl Display for Arch {
     }
 }
 
+/// A package that a feature installed into the image, as recorded for the
+/// software bill of materials
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct InstalledPackage {
+    pub name: String,
+    /// Full version string, as understood by the package manager
+    pub version: String,
+    /// Where the package was installed from (eg the repo id), if known
+    pub source: Option<String>,
+    /// Package URL (https://github.com/package-url/purl-spec) uniquely
+    /// identifying the package
+    pub purl: String,
+}
+
 #[derive(Debug)]
 pub struct CompilerContext {
     /// Buck label of the image being built
@@ -94,6 +205,16 @@ pub struct CompilerContext {
     /// Open fd to the image root directory
     root: Dir,
     plans: HashMap<String, serde_json::Value>,
+    /// Warnings emitted by features while compiling
+    warnings: Mutex<Vec<String>>,
+    /// Owner that features intended for the paths they created, relative to
+    /// the image root
+    ownership: Mutex<BTreeMap<PathBuf, (UserId, GroupId)>>,
+    /// Xattrs that features set on the paths they created, relative to the
+    /// image root
+    xattrs: Mutex<BTreeMap<PathBuf, BTreeMap<String, Vec<u8>>>>,
+    /// Packages that features installed into the image
+    packages: Mutex<BTreeSet<InstalledPackage>>,
 }
 
 fn parse_file<T, E>(f: File) -> Result<T>
@@ -119,6 +240,10 @@ impl CompilerContext {
             root_path: root,
             root: root_fd,
             plans,
+            warnings: Mutex::new(Vec::new()),
+            ownership: Mutex::new(BTreeMap::new()),
+            xattrs: Mutex::new(BTreeMap::new()),
+            packages: Mutex::new(BTreeSet::new()),
         })
     }
 
@@ -147,6 +272,145 @@ impl CompilerContext {
         self.plans.get(id).cloned().map(serde_json::from_value)
     }
 
+    /// Emit a warning about something a feature did that is likely to be a
+    /// mistake, but does not prevent the image from being built.
+    pub fn warn(&self, message: impl Into<String>) {
+        let message = message.into();
+        tracing::warn!("{message}");
+        self.warnings
+            .lock()
+            .expect("warnings lock poisoned")
+            .push(message);
+    }
+
+    /// All the warnings emitted so far, in the order they were emitted
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings
+            .lock()
+            .expect("warnings lock poisoned")
+            .clone()
+    }
+
+    /// Fail if any warnings were emitted and `fatal` is set
+    pub fn check_warnings(&self, fatal: bool) -> Result<()> {
+        let warnings = self.warnings();
+        if fatal && !warnings.is_empty() {
+            Err(Error::Warnings(warnings))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Record the owner that a feature set on `dst` (a path returned by
+    /// [CompilerContext::dst_path]), to be verified by
+    /// [CompilerContext::check_ownership]
+    pub fn declare_ownership(&self, dst: &Path, uid: UserId, gid: GroupId) {
+        let path = dst.strip_prefix(&self.root_path).unwrap_or(dst);
+        self.ownership
+            .lock()
+            .expect("ownership lock poisoned")
+            .insert(path.to_owned(), (uid, gid));
+    }
+
+    /// Verify that every path that has a declared owner is still owned that
+    /// way, to catch host umask or uid mapping problems messing with the image
+    pub fn check_ownership(&self) -> Result<()> {
+        let mut mismatches = Vec::new();
+        for (path, (uid, gid)) in self
+            .ownership
+            .lock()
+            .expect("ownership lock poisoned")
+            .iter()
+        {
+            let meta = match std::fs::symlink_metadata(self.root_path.join(path)) {
+                Ok(meta) => meta,
+                // later features are allowed to remove things
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if UserId::from(meta.uid()) != *uid || GroupId::from(meta.gid()) != *gid {
+                mismatches.push(format!(
+                    "/{}: declared {uid}:{gid}, found {}:{}",
+                    path.display(),
+                    meta.uid(),
+                    meta.gid(),
+                ));
+            }
+        }
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::OwnershipMismatch(mismatches))
+        }
+    }
+
+    /// Record an xattr that a feature set on `dst` (a path returned by
+    /// [CompilerContext::dst_path]), to be verified by
+    /// [CompilerContext::check_xattrs]
+    pub fn declare_xattr(&self, dst: &Path, name: impl Into<String>, value: impl Into<Vec<u8>>) {
+        let path = dst.strip_prefix(&self.root_path).unwrap_or(dst);
+        self.xattrs
+            .lock()
+            .expect("xattrs lock poisoned")
+            .entry(path.to_owned())
+            .or_default()
+            .insert(name.into(), value.into());
+    }
+
+    /// Verify that every declared xattr can be read back with the same value.
+    /// Some filesystems (or mount options) silently drop xattrs, which would
+    /// otherwise only be noticed when something like a file capability is
+    /// missing at runtime.
+    pub fn check_xattrs(&self) -> Result<()> {
+        let mut mismatches = Vec::new();
+        for (path, xattrs) in self.xattrs.lock().expect("xattrs lock poisoned").iter() {
+            let full_path = self.root_path.join(path);
+            match std::fs::symlink_metadata(&full_path) {
+                // later features are allowed to remove things
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+                Ok(_) => {}
+            }
+            for (name, value) in xattrs {
+                match xattr::get(&full_path, name) {
+                    Ok(Some(actual)) if &actual == value => {}
+                    Ok(Some(actual)) => mismatches.push(format!(
+                        "/{}: {name} is {actual:?}, not {value:?}",
+                        path.display()
+                    )),
+                    Ok(None) => mismatches.push(format!("/{}: {name} is missing", path.display())),
+                    Err(e) => mismatches.push(format!(
+                        "/{}: {name} could not be read: {e}",
+                        path.display()
+                    )),
+                }
+            }
+        }
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::XattrMismatch(mismatches))
+        }
+    }
+
+    /// Record a package that a feature installed into the image
+    pub fn declare_package(&self, package: InstalledPackage) {
+        self.packages
+            .lock()
+            .expect("packages lock poisoned")
+            .insert(package);
+    }
+
+    /// All the packages installed by features so far, sorted by name
+    pub fn installed_packages(&self) -> Vec<InstalledPackage> {
+        self.packages
+            .lock()
+            .expect("packages lock poisoned")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
     /// Join a (possibly absolute) path with the root directory of the image
     /// being built.
     pub fn dst_path<P>(&self, path: P) -> std::io::Result<PathBuf>
@@ -271,6 +535,26 @@ enum ResolveMode {
 
 pub trait CompileFeature {
     fn compile(&self, ctx: &CompilerContext) -> Result<()>;
+
+    /// Like [CompileFeature::compile], but compile it again (up to `retries`
+    /// more times) after errors that are [Error::is_retryable]. The feature
+    /// must cope with whatever a failed attempt left behind in the image.
+    fn compile_with_retries(&self, ctx: &CompilerContext, retries: u32) -> Result<()> {
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 1..=retries {
+            match self.compile(ctx) {
+                Err(e) if e.is_retryable() => {
+                    tracing::warn!(
+                        "retrying feature in {backoff:?} after attempt {attempt} failed: {e}"
+                    );
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                res => return res,
+            }
+        }
+        self.compile(ctx)
+    }
 }
 
 static_assertions::assert_obj_safe!(CompileFeature);
@@ -303,3 +587,320 @@ impl CompileFeature for Feature {
         feat.compile(ctx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    struct Warns(&'static str);
+
+    impl CompileFeature for Warns {
+        fn compile(&self, ctx: &CompilerContext) -> Result<()> {
+            ctx.warn(self.0);
+            Ok(())
+        }
+    }
+
+    struct Quiet;
+
+    /// Fails with `err` the first `failures` times it's compiled
+    struct Flaky {
+        failures: u32,
+        attempts: std::sync::atomic::AtomicU32,
+        err: fn() -> Error,
+    }
+
+    impl Flaky {
+        fn new(failures: u32, err: fn() -> Error) -> Self {
+            Self {
+                failures,
+                attempts: Default::default(),
+                err,
+            }
+        }
+
+        fn attempts(&self) -> u32 {
+            self.attempts.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    impl CompileFeature for Flaky {
+        fn compile(&self, _ctx: &CompilerContext) -> Result<()> {
+            let attempt = self
+                .attempts
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            match attempt < self.failures {
+                true => Err((self.err)()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl CompileFeature for Quiet {
+        fn compile(&self, _ctx: &CompilerContext) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Stands in for a package manager feature
+    struct InstallsPackage(&'static str, &'static str);
+
+    impl CompileFeature for InstallsPackage {
+        fn compile(&self, ctx: &CompilerContext) -> Result<()> {
+            ctx.declare_package(InstalledPackage {
+                name: self.0.to_owned(),
+                version: self.1.to_owned(),
+                source: Some("test-repo".to_owned()),
+                purl: format!("pkg:rpm/{}@{}", self.0, self.1),
+            });
+            Ok(())
+        }
+    }
+
+    fn new_ctx(root: &Path) -> CompilerContext {
+        CompilerContext::new(
+            Label::new("test//test:image").expect("valid label"),
+            Arch::X86_64,
+            root.to_owned(),
+            HashMap::new(),
+        )
+        .expect("failed to create ctx")
+    }
+
+    #[test]
+    fn test_retries() {
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        let ctx = new_ctx(root.path());
+        let transient = || Error::IO(std::io::ErrorKind::TimedOut.into());
+
+        let flaky = Flaky::new(1, transient);
+        flaky
+            .compile_with_retries(&ctx, 2)
+            .expect("second attempt succeeds");
+        assert_eq!(flaky.attempts(), 2);
+
+        // no retries by default
+        let flaky = Flaky::new(1, transient);
+        assert!(flaky.compile_with_retries(&ctx, 0).is_err());
+        assert_eq!(flaky.attempts(), 1);
+
+        let flaky = Flaky::new(3, transient);
+        assert!(flaky.compile_with_retries(&ctx, 2).is_err());
+        assert_eq!(flaky.attempts(), 3);
+
+        let flaky = Flaky::new(1, || Error::NoSuchUser("antlir".into()));
+        assert!(matches!(
+            flaky.compile_with_retries(&ctx, 2),
+            Err(Error::NoSuchUser(_))
+        ));
+        assert_eq!(flaky.attempts(), 1, "not retryable");
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(Error::IO(std::io::ErrorKind::TimedOut.into()).is_retryable());
+        assert!(!Error::IO(std::io::ErrorKind::NotFound.into()).is_retryable());
+        assert!(!Error::Other(anyhow::anyhow!("broken")).is_retryable());
+    }
+
+    /// Fails by creating `path`, which should be set up to fail the way we
+    /// want to simulate
+    struct Creates(PathBuf);
+
+    impl CompileFeature for Creates {
+        fn compile(&self, _ctx: &CompilerContext) -> Result<()> {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&self.0)
+                .with_context(|| format!("while creating {}", self.0.display()))?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        let ctx = new_ctx(root.path());
+        std::fs::write(root.path().join("exists"), "").expect("failed to write");
+
+        for (path, kind) in [
+            (
+                root.path().join("missing/file"),
+                CompileErrorKind::MissingInput,
+            ),
+            (root.path().join("exists"), CompileErrorKind::Conflict),
+            // a file cannot have children, which is not one of our categories
+            (root.path().join("exists/file"), CompileErrorKind::Other),
+        ] {
+            let err = Creates(path).compile(&ctx).expect_err("compile failed");
+            assert_eq!(err.kind(), kind, "{err}");
+        }
+        assert_eq!(
+            Error::IO(std::io::Error::from_raw_os_error(libc::ENOSPC)).kind(),
+            CompileErrorKind::OutOfSpace,
+        );
+        assert_eq!(
+            Error::Other(
+                anyhow::Error::from(std::io::Error::from_raw_os_error(libc::EACCES))
+                    .context("while fetching")
+            )
+            .kind(),
+            CompileErrorKind::Permission,
+        );
+        assert_eq!(
+            Error::NoSuchUser("antlir".into()).kind(),
+            CompileErrorKind::MissingInput,
+        );
+        assert_eq!(
+            Error::Warnings(vec!["careful".into()]).kind(),
+            CompileErrorKind::Other,
+        );
+
+        // the codes are reported externally and must never change
+        assert_eq!(
+            [
+                CompileErrorKind::Other,
+                CompileErrorKind::MissingInput,
+                CompileErrorKind::Permission,
+                CompileErrorKind::Conflict,
+                CompileErrorKind::OutOfSpace,
+            ]
+            .map(CompileErrorKind::code),
+            [0, 1, 2, 3, 4],
+        );
+    }
+
+    #[test]
+    fn test_check_ownership() {
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        let ctx = new_ctx(root.path());
+        let dst = ctx.dst_path("/installed").expect("failed to get dst path");
+        std::fs::write(&dst, "").expect("failed to write");
+        let meta = std::fs::metadata(&dst).expect("failed to stat");
+        let (uid, gid) = (UserId::from(meta.uid()), GroupId::from(meta.gid()));
+
+        ctx.declare_ownership(&dst, uid, gid);
+        ctx.declare_ownership(&ctx.dst_path("/removed").expect("dst path"), uid, gid);
+        ctx.check_ownership().expect("ownership is as declared");
+
+        // simulate the host messing up the owner by declaring a different one
+        let wrong = UserId::from(meta.uid() + 1);
+        ctx.declare_ownership(&dst, wrong, gid);
+        match ctx.check_ownership() {
+            Err(Error::OwnershipMismatch(mismatches)) => assert_eq!(
+                mismatches,
+                vec![format!(
+                    "/installed: declared {wrong}:{gid}, found {uid}:{gid}"
+                )]
+            ),
+            other => panic!("expected ownership mismatch, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_check_xattrs() {
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        let ctx = new_ctx(root.path());
+        let dst = ctx
+            .dst_path("/usr/bin/ping")
+            .expect("failed to get dst path");
+        std::fs::create_dir_all(dst.parent().expect("has parent")).expect("failed to mkdir");
+        std::fs::write(&dst, "").expect("failed to write");
+
+        // setting a capability requires privileges, so simulate a filesystem
+        // that dropped it by declaring it without ever setting it
+        let cap =
+            b"\x01\x00\x00\x02\x00\x20\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        ctx.declare_xattr(&dst, "security.capability", cap.as_slice());
+        match ctx.check_xattrs() {
+            Err(Error::XattrMismatch(mismatches)) => {
+                assert_eq!(
+                    mismatches,
+                    vec!["/usr/bin/ping: security.capability is missing"]
+                )
+            }
+            other => panic!("expected xattr mismatch, got {other:?}"),
+        }
+    }
+
+    // /work in the image_test layer is on a filesystem that supports user
+    // xattrs, which tmpfs only does on recent kernels
+    #[test]
+    #[cfg_attr(not(image_test), ignore = "needs user xattrs")]
+    fn test_check_user_xattrs() {
+        let root = tempfile::TempDir::new_in("/work").expect("failed to create tempdir");
+        let ctx = new_ctx(root.path());
+        let dst = ctx
+            .dst_path("/usr/bin/ping")
+            .expect("failed to get dst path");
+        std::fs::create_dir_all(dst.parent().expect("has parent")).expect("failed to mkdir");
+        std::fs::write(&dst, "").expect("failed to write");
+
+        xattr::set(&dst, "user.antlir", b"yes").expect("failed to set xattr");
+        ctx.declare_xattr(&dst, "user.antlir", "yes");
+        ctx.check_xattrs().expect("xattr was preserved");
+        ctx.declare_xattr(&dst, "user.antlir", "no");
+        match ctx.check_xattrs() {
+            Err(Error::XattrMismatch(mismatches)) => assert_eq!(
+                mismatches,
+                vec!["/usr/bin/ping: user.antlir is [121, 101, 115], not [110, 111]"]
+            ),
+            other => panic!("expected xattr mismatch, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_warnings() {
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+
+        let ctx = new_ctx(root.path());
+        Quiet.compile(&ctx).expect("failed to compile");
+        assert!(ctx.warnings().is_empty());
+        ctx.check_warnings(true).expect("no warnings to fail on");
+
+        let ctx = new_ctx(root.path());
+        let features: Vec<Box<dyn CompileFeature>> = vec![
+            Box::new(Warns("first")),
+            Box::new(Quiet),
+            Box::new(Warns("second")),
+        ];
+        for feature in &features {
+            feature.compile(&ctx).expect("failed to compile");
+        }
+        assert_eq!(ctx.warnings(), vec!["first", "second"]);
+        ctx.check_warnings(false)
+            .expect("warnings are not fatal by default");
+        match ctx.check_warnings(true) {
+            Err(Error::Warnings(warnings)) => assert_eq!(warnings, vec!["first", "second"]),
+            other => panic!("expected warnings error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_installed_packages() {
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        let ctx = new_ctx(root.path());
+        let features: Vec<Box<dyn CompileFeature>> = vec![
+            Box::new(InstallsPackage("zsh", "5.8-9")),
+            Box::new(Quiet),
+            Box::new(InstallsPackage("bash", "1:5.1.8-6")),
+            Box::new(InstallsPackage("zsh", "5.8-9")),
+        ];
+        for feature in &features {
+            feature.compile(&ctx).expect("failed to compile");
+        }
+        assert_eq!(
+            ctx.installed_packages()
+                .into_iter()
+                .map(|p| (p.name, p.version))
+                .collect::<Vec<_>>(),
+            vec![
+                ("bash".to_owned(), "1:5.1.8-6".to_owned()),
+                ("zsh".to_owned(), "5.8-9".to_owned()),
+            ]
+        );
+    }
+}
@@ -34,6 +34,15 @@ pub enum Error {
         validator: Validator,
         required_by: Feature,
     },
+    #[error(
+        "{key:?} is required by {required_by:#?} but is provided by {provided_by:#?}, which is not from {label}"
+    )]
+    FilteredDependency {
+        key: ItemKey,
+        label: String,
+        required_by: Feature,
+        provided_by: Feature,
+    },
     #[error("failure determining 'provides': {0}")]
     Provides(String),
     #[error("failure determining 'requires': {0}")]
@@ -21,6 +21,7 @@ use antlir2_facts::fact::Fact as _;
 use antlir2_facts::RoDatabase;
 use antlir2_facts::RwDatabase;
 use antlir2_features::Feature;
+use buck_label::Label;
 use fxhash::FxHashMap;
 use rusqlite::OptionalExtension as _;
 use serde::Deserialize;
@@ -511,6 +512,22 @@ pub struct Graph {
     db: RoDatabase,
 }
 
+/// A pending feature, in the position it will be compiled in
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    pub feature: Feature,
+    /// Items this feature requires, each with the position (in the same
+    /// order as [Graph::explain]) of the pending feature that provides it
+    pub after: Vec<(ItemKey, usize)>,
+}
+
+/// A pending feature, along with the items that it provides
+#[derive(Debug, Clone)]
+pub struct Listing {
+    pub feature: Feature,
+    pub provides: Vec<Item>,
+}
+
 impl Graph {
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
         let db = RoDatabase::open(path)?;
@@ -525,7 +542,167 @@ impl Graph {
     /// features that require them).
     pub fn pending_features(&self) -> Result<impl Iterator<Item = Feature>> {
         let features = toposort::toposort(self.db.as_ref())?;
-        Ok(features.into_iter())
+        Ok(features.into_iter().map(|(_, feature)| feature))
+    }
+
+    /// Like [Graph::pending_features], but along with the ordered
+    /// dependencies that forced each feature into its position.
+    pub fn explain(&self) -> Result<Vec<Explanation>> {
+        let sorted = toposort::toposort(self.db.as_ref())?;
+        let positions: FxHashMap<i64, usize> = sorted
+            .iter()
+            .enumerate()
+            .map(|(idx, (id, _))| (*id, idx))
+            .collect();
+        let mut after: FxHashMap<i64, Vec<(ItemKey, usize)>> = FxHashMap::default();
+        for row in self
+            .db
+            .as_ref()
+            .prepare(
+                r#"
+                SELECT
+                    requires.feature AS required_by,
+                    requires.item_key,
+                    provides.feature AS provided_by
+                FROM requires
+                INNER JOIN feature AS requirer ON requirer.id=requires.feature
+                INNER JOIN item ON item.key=requires.item_key
+                INNER JOIN provides ON provides.item=item.id
+                INNER JOIN feature AS provider ON provider.id=provides.feature
+                WHERE requires.ordered=1 AND requirer.pending=1 AND provider.pending=1
+                ORDER BY requires.feature, provides.feature
+                "#,
+            )?
+            .query_and_then([], |row| {
+                let required_by: i64 = row.get("required_by")?;
+                let item_key: ItemKey = serde_json::from_str(
+                    row.get_ref("item_key")?
+                        .as_str()
+                        .map_err(rusqlite::Error::from)?,
+                )
+                .map_err(Error::GraphSerde)?;
+                let provided_by: i64 = row.get("provided_by")?;
+                Result::Ok((required_by, item_key, provided_by))
+            })?
+        {
+            let (required_by, item_key, provided_by) = row?;
+            if let Some(position) = positions.get(&provided_by) {
+                after
+                    .entry(required_by)
+                    .or_default()
+                    .push((item_key, *position));
+            }
+        }
+        Ok(sorted
+            .into_iter()
+            .map(|(id, feature)| Explanation {
+                feature,
+                after: after.remove(&id).unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    /// Like [Graph::pending_features], but along with the (sorted) items that
+    /// each feature provides.
+    pub fn list(&self) -> Result<Vec<Listing>> {
+        let sorted = toposort::toposort(self.db.as_ref())?;
+        let mut provides: FxHashMap<i64, Vec<Item>> = FxHashMap::default();
+        for row in self
+            .db
+            .as_ref()
+            .prepare(
+                r#"
+                SELECT
+                    provides.feature,
+                    item.value
+                FROM provides
+                INNER JOIN item ON item.id=provides.item
+                INNER JOIN feature ON feature.id=provides.feature
+                WHERE feature.pending=1
+                "#,
+            )?
+            .query_and_then([], |row| {
+                let feature: i64 = row.get("feature")?;
+                let item: Item = serde_json::from_str(
+                    row.get_ref("value")?
+                        .as_str()
+                        .map_err(rusqlite::Error::from)?,
+                )
+                .map_err(Error::GraphSerde)?;
+                Result::Ok((feature, item))
+            })?
+        {
+            let (feature, item) = row?;
+            provides.entry(feature).or_default().push(item);
+        }
+        Ok(sorted
+            .into_iter()
+            .map(|(id, feature)| {
+                let mut provides = provides.remove(&id).unwrap_or_default();
+                provides.sort();
+                Listing { feature, provides }
+            })
+            .collect())
+    }
+
+    /// Like [Graph::pending_features], but only the features that came from
+    /// `label`.
+    /// It is an error for one of those features to depend on an item that is
+    /// provided by a pending feature from some other label, since that feature
+    /// would never be compiled. Items provided by the parent layer are fine.
+    pub fn pending_features_from(&self, label: &Label) -> Result<Vec<Feature>> {
+        let label = label.as_unconfigured();
+        let from_label = |feature: &Feature| feature.label.as_unconfigured() == label;
+        for row in self
+            .db
+            .as_ref()
+            .prepare(
+                r#"
+                SELECT
+                    requires.item_key,
+                    requirer.value AS required_by,
+                    provider.value AS provided_by
+                FROM requires
+                INNER JOIN feature AS requirer ON requirer.id=requires.feature
+                INNER JOIN item ON item.key=requires.item_key
+                INNER JOIN provides ON provides.item=item.id
+                INNER JOIN feature AS provider ON provider.id=provides.feature
+                WHERE requirer.pending=1 AND provider.pending=1
+                "#,
+            )?
+            .query_and_then([], |row| {
+                let item_key: ItemKey = serde_json::from_str(
+                    row.get_ref("item_key")?
+                        .as_str()
+                        .map_err(rusqlite::Error::from)?,
+                )
+                .map_err(Error::GraphSerde)?;
+                let required_by: Feature = serde_json::from_str(
+                    row.get_ref("required_by")?
+                        .as_str()
+                        .map_err(rusqlite::Error::from)?,
+                )
+                .map_err(Error::GraphSerde)?;
+                let provided_by: Feature = serde_json::from_str(
+                    row.get_ref("provided_by")?
+                        .as_str()
+                        .map_err(rusqlite::Error::from)?,
+                )
+                .map_err(Error::GraphSerde)?;
+                Result::Ok((item_key, required_by, provided_by))
+            })?
+        {
+            let (key, required_by, provided_by) = row?;
+            if from_label(&required_by) && !from_label(&provided_by) {
+                return Err(Error::FilteredDependency {
+                    key,
+                    label: label.to_string(),
+                    required_by,
+                    provided_by,
+                });
+            }
+        }
+        Ok(self.pending_features()?.filter(from_label).collect())
     }
 }
 
@@ -536,3 +713,131 @@ impl GraphBuilder {
         Self::new(db)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use antlir2_depgraph_if::Requirement;
+
+    use super::*;
+
+    fn user_feature(label: &str, requires: &[&str], provides: &[&str]) -> AnalyzedFeature {
+        let feature: Feature = serde_json::from_value(serde_json::json!({
+            "label": label,
+            "feature_type": "user",
+            "data": {"provides": provides},
+            "plugin": {"plugin": "/nonexistent", "libs": "/nonexistent"},
+        }))
+        .expect("failed to deserialize feature");
+        AnalyzedFeature::new(
+            feature,
+            requires
+                .iter()
+                .map(|name| {
+                    Requirement::ordered(ItemKey::User(name.to_string()), Validator::Exists)
+                })
+                .collect(),
+            provides
+                .iter()
+                .map(|name| {
+                    Item::User(item::User {
+                        name: name.to_string(),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn pending_features_from_label() {
+        let mut graph = GraphBuilder::new_in_memory().expect("failed to create GraphBuilder");
+        graph
+            .add_feature(user_feature("antlir//image:base", &[], &["root"]))
+            .expect("failed to add feature")
+            .add_feature(user_feature("antlir//image:app", &["root"], &["app"]))
+            .expect("failed to add feature")
+            .add_feature(user_feature("antlir//image:app", &[], &["other"]))
+            .expect("failed to add feature");
+        let graph = graph.build().expect("failed to build graph");
+
+        let base = graph
+            .pending_features_from(&Label::new("antlir//image:base").expect("valid label"))
+            .expect("base has no dependencies on other labels");
+        assert_eq!(
+            base.iter().map(|f| f.data.clone()).collect::<Vec<_>>(),
+            vec![serde_json::json!({"provides": ["root"]})],
+        );
+
+        match graph.pending_features_from(&Label::new("antlir//image:app").expect("valid label")) {
+            Err(Error::FilteredDependency {
+                key, provided_by, ..
+            }) => {
+                assert_eq!(key, ItemKey::User("root".into()));
+                assert_eq!(provided_by.label.to_string(), "antlir//image:base");
+            }
+            other => panic!("expected FilteredDependency, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn explain() {
+        let mut graph = GraphBuilder::new_in_memory().expect("failed to create GraphBuilder");
+        graph
+            .add_feature(user_feature(
+                "antlir//image:app",
+                &["svc", "base"],
+                &["app"],
+            ))
+            .expect("failed to add feature")
+            .add_feature(user_feature("antlir//image:svc", &["base"], &["svc"]))
+            .expect("failed to add feature")
+            .add_feature(user_feature("antlir//image:base", &[], &["base"]))
+            .expect("failed to add feature")
+            .add_feature(user_feature("antlir//image:other", &[], &["other"]))
+            .expect("failed to add feature");
+        let graph = graph.build().expect("failed to build graph");
+
+        let explained = graph.explain().expect("failed to explain");
+        let labels: Vec<_> = explained
+            .iter()
+            .map(|e| e.feature.label.to_string())
+            .collect();
+        let position = |label: &str| {
+            labels
+                .iter()
+                .position(|l| l == label)
+                .expect("feature is explained")
+        };
+        assert_eq!(labels.len(), 4);
+        assert!(position("antlir//image:base") < position("antlir//image:svc"));
+        assert!(position("antlir//image:svc") < position("antlir//image:app"));
+        assert_eq!(
+            labels,
+            graph
+                .pending_features()
+                .expect("failed to sort")
+                .map(|f| f.label.to_string())
+                .collect::<Vec<_>>(),
+        );
+
+        let after = |label: &str| {
+            explained[position(label)]
+                .after
+                .iter()
+                .map(|(key, idx)| (key.clone(), labels[*idx].as_str()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            after("antlir//image:app"),
+            vec![
+                (ItemKey::User("svc".into()), "antlir//image:svc"),
+                (ItemKey::User("base".into()), "antlir//image:base"),
+            ]
+        );
+        assert_eq!(
+            after("antlir//image:svc"),
+            vec![(ItemKey::User("base".into()), "antlir//image:base")]
+        );
+        assert!(after("antlir//image:base").is_empty());
+        assert!(after("antlir//image:other").is_empty());
+    }
+}
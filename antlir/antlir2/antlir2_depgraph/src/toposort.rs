@@ -17,8 +17,9 @@ use crate::Cycle;
 use crate::Error;
 use crate::Result;
 
-/// Topologically sort pending features in dependency order
-pub(crate) fn toposort(db: &Connection) -> Result<Vec<Feature>> {
+/// Topologically sort pending features (along with their ids) in dependency
+/// order
+pub(crate) fn toposort(db: &Connection) -> Result<Vec<(i64, Feature)>> {
     let mut nodes: FxHashMap<_, _> = Default::default();
     let mut graph: DiGraph<i64, ()> = DiGraph::new();
     // All we have to do is find ordered feature dependencies (and features with
@@ -77,7 +78,7 @@ pub(crate) fn toposort(db: &Connection) -> Result<Vec<Feature>> {
     match petgraph::algo::toposort(&graph, None) {
         Ok(sorted) => Ok(sorted
             .into_iter()
-            .filter_map(|nx| features.remove(&graph[nx]))
+            .filter_map(|nx| features.remove_entry(&graph[nx]))
             .collect()),
         Err(node_in_cycle) => {
             // there might be multiple cycles, we really only need to find
@@ -5,8 +5,16 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use nix::libc;
 use nix::mount::mount;
 use nix::mount::MsFlags;
+use nix::sched::setns;
 use nix::sched::unshare;
 use nix::sched::CloneFlags;
 
@@ -29,3 +37,90 @@ pub fn unshare_and_privatize_mount_ns() -> std::io::Result<()> {
     )?;
     Ok(())
 }
+
+// from linux/mount.h
+const MOUNT_ATTR_RDONLY: u64 = 0x00000001;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct mount_attr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// Recursively set and/or clear mount attributes on the mount at `path` and
+/// everything below it
+fn mount_setattr_recursive(path: &Path, attr_set: u64, attr_clr: u64) -> std::io::Result<()> {
+    let path_c = CString::new(path.as_os_str().as_bytes())?;
+    let attr = mount_attr {
+        attr_set,
+        attr_clr,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    // SAFETY: path_c and attr outlive the syscall and size matches the struct
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            path_c.as_ptr(),
+            (libc::AT_SYMLINK_NOFOLLOW | libc::AT_RECURSIVE) as libc::c_uint,
+            &attr as *const mount_attr,
+            std::mem::size_of::<mount_attr>(),
+        )
+    };
+    if res == -1 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Mount namespace in which everything except one directory is read-only.
+/// Dropping this switches the process back to the mount namespace it was in
+/// before [sandbox_writes] was called.
+#[derive(Debug)]
+pub struct WriteSandbox {
+    parent_ns: File,
+    cwd: PathBuf,
+}
+
+/// Move this process into a new mount namespace where every mount is
+/// read-only, except for `writable` (and anything mounted below it), so that
+/// any attempt to write outside of it fails with EROFS.
+///
+/// This does not change the propagation of any mounts, so it should be called
+/// after [unshare_and_privatize_mount_ns]. Like all mount namespace
+/// operations, the calling process must be single threaded.
+pub fn sandbox_writes(writable: &Path) -> std::io::Result<WriteSandbox> {
+    let parent_ns = File::open("/proc/self/ns/mnt")?;
+    let cwd = std::env::current_dir()?;
+    unshare(CloneFlags::CLONE_NEWNS)?;
+    // make sure that `writable` is its own mount, so that it can be made rw
+    // again without affecting the rest of the filesystem it lives on
+    mount(
+        Some(writable),
+        writable,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )?;
+    mount_setattr_recursive(Path::new("/"), MOUNT_ATTR_RDONLY, 0)?;
+    mount_setattr_recursive(writable, 0, MOUNT_ATTR_RDONLY)?;
+    Ok(WriteSandbox { parent_ns, cwd })
+}
+
+impl Drop for WriteSandbox {
+    fn drop(&mut self) {
+        // there is no way to recover from not being able to get out of the
+        // sandbox, and silently leaving everything read-only would just
+        // cause confusing failures later
+        setns(&self.parent_ns, CloneFlags::CLONE_NEWNS)
+            .expect("failed to leave write sandbox mount namespace");
+        // setns resets the cwd to the root of the namespace
+        std::env::set_current_dir(&self.cwd)
+            .expect("failed to restore cwd after leaving write sandbox");
+    }
+}
@@ -9,10 +9,16 @@
 
 use std::path::Path;
 
+use antlir2_isolate::sandbox_writes;
 use antlir2_isolate::unshare;
+use antlir2_isolate::unshare_and_privatize_mount_ns;
 use antlir2_isolate::IsolationContext;
 use nix::mount::mount;
 use nix::mount::MsFlags;
+use nix::sys::wait::waitpid;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::fork;
+use nix::unistd::ForkResult;
 use tempfile::TempDir;
 
 fn assert_cmd_success(out: &std::process::Output) {
@@ -184,3 +190,44 @@ fn loopback_interface() {
     );
     std::net::TcpListener::bind("[::1]:0").expect("failed to bind to socket");
 }
+
+/// Anything that tries to write outside of a write sandbox (like a buggy
+/// feature) fails, while the sandboxed directory remains writable.
+#[test]
+fn sandbox_writes_outside_root_fail() {
+    let root = TempDir::new().expect("failed to create tempdir");
+    let outside = TempDir::new().expect("failed to create tempdir");
+    // mount namespaces can only be changed by a single-threaded process, which
+    // the test harness is not
+    match unsafe { fork() }.expect("failed to fork") {
+        ForkResult::Child => {
+            let compile = || -> std::io::Result<()> {
+                unshare_and_privatize_mount_ns()?;
+                let _sandbox = sandbox_writes(root.path())?;
+                std::fs::write(root.path().join("inside"), "inside\n")?;
+                std::fs::write(outside.path().join("escaped"), "escaped\n")
+            };
+            let code = match compile() {
+                Err(e) if e.kind() == std::io::ErrorKind::ReadOnlyFilesystem => 0,
+                Err(e) => {
+                    eprintln!("unexpected error: {e}");
+                    1
+                }
+                Ok(()) => 2,
+            };
+            unsafe { nix::libc::_exit(code) }
+        }
+        ForkResult::Parent { child } => {
+            assert_eq!(
+                waitpid(child, None).expect("failed to wait for child"),
+                WaitStatus::Exited(child, 0),
+                "write outside of sandbox was not blocked"
+            );
+        }
+    }
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("inside")).expect("failed to read"),
+        "inside\n"
+    );
+    assert!(!outside.path().join("escaped").exists());
+}
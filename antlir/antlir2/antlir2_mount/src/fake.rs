@@ -0,0 +1,323 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! In-memory [Mounter] for testing code that mounts things without needing
+//! privileges. Other crates get it by depending on
+//! `//antlir/antlir2/antlir2_mount:antlir_mount-testing` instead of
+//! `:antlir_mount`, which builds this crate with the `testing` feature.
+//!
+//! Unlike [crate::MockMounter], which only checks calls against expectations
+//! set up front, this keeps track of what is mounted where, so tests can
+//! assert on the resulting state (and on unmounts done by [crate::BoundMounter]
+//! handles) without spelling out every call.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::path::PathBuf;
+
+use nix::errno::Errno;
+use nix::mount::MsFlags;
+
+use crate::MountError;
+use crate::MountHandle;
+use crate::Mounter;
+use crate::Propagation;
+use crate::PropagationFlags;
+
+/// A single call made to a [FakeMounter]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MountCall {
+    Mount {
+        source: PathBuf,
+        target: PathBuf,
+        fstype: Option<String>,
+        flags: MsFlags,
+        data: Option<String>,
+    },
+    Umount {
+        mountpoint: PathBuf,
+        force: bool,
+    },
+    SetPropagation {
+        mountpoint: PathBuf,
+        flags: PropagationFlags,
+    },
+}
+
+/// Records every call made to it and keeps track of what is "mounted" so that
+/// unmounting something that isn't mounted fails with EINVAL just like the
+/// kernel would. Failures can be injected with [FakeMounter::fail_next_mount]
+/// and [FakeMounter::fail_next_umount].
+#[derive(Debug, Default)]
+pub struct FakeMounter {
+    calls: RefCell<Vec<MountCall>>,
+    mounted: RefCell<Vec<PathBuf>>,
+    propagation: RefCell<HashMap<PathBuf, Propagation>>,
+    mount_errors: RefCell<VecDeque<Errno>>,
+    umount_errors: RefCell<VecDeque<Errno>>,
+}
+
+impl FakeMounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every call made so far, including ones that failed
+    pub fn calls(&self) -> Vec<MountCall> {
+        self.calls.borrow().clone()
+    }
+
+    /// Mountpoints that are currently mounted, in the order they were mounted
+    pub fn mounted(&self) -> Vec<PathBuf> {
+        self.mounted.borrow().clone()
+    }
+
+    /// Propagation of a mountpoint, if it has been explicitly set
+    pub fn propagation(&self, mountpoint: &Path) -> Option<Propagation> {
+        self.propagation.borrow().get(mountpoint).copied()
+    }
+
+    /// Make the next call to `mount` fail with `errno`. Can be called
+    /// repeatedly to queue up multiple failures.
+    pub fn fail_next_mount(&self, errno: Errno) {
+        self.mount_errors.borrow_mut().push_back(errno);
+    }
+
+    /// Make the next call to `umount` fail with `errno` (eg EBUSY). Can be
+    /// called repeatedly to queue up multiple failures.
+    pub fn fail_next_umount(&self, errno: Errno) {
+        self.umount_errors.borrow_mut().push_back(errno);
+    }
+}
+
+impl Mounter for FakeMounter {
+    fn mount<'a, 'b>(
+        &'a self,
+        source: &'b Path,
+        target: &'b Path,
+        fstype: Option<&'b str>,
+        flags: MsFlags,
+        data: Option<&'b str>,
+    ) -> Result<MountHandle<'a, Self>, MountError> {
+        self.calls.borrow_mut().push(MountCall::Mount {
+            source: source.to_path_buf(),
+            target: target.to_path_buf(),
+            fstype: fstype.map(str::to_owned),
+            flags,
+            data: data.map(str::to_owned),
+        });
+        crate::validate_flags(flags)?;
+        if let Some(errno) = self.mount_errors.borrow_mut().pop_front() {
+            return Err(errno.into());
+        }
+        self.mounted.borrow_mut().push(target.to_path_buf());
+        Ok(MountHandle::new(target.to_path_buf(), self))
+    }
+
+    fn umount(&self, mountpoint: &Path, force: bool) -> Result<(), Errno> {
+        self.calls.borrow_mut().push(MountCall::Umount {
+            mountpoint: mountpoint.to_path_buf(),
+            force,
+        });
+        if let Some(errno) = self.umount_errors.borrow_mut().pop_front() {
+            return Err(errno);
+        }
+        let mut mounted = self.mounted.borrow_mut();
+        // the most recent mount on top of this mountpoint is the one that
+        // gets removed
+        match mounted.iter().rposition(|m| m == mountpoint) {
+            Some(idx) => {
+                mounted.remove(idx);
+                if !mounted.iter().any(|m| m == mountpoint) {
+                    self.propagation.borrow_mut().remove(mountpoint);
+                }
+                Ok(())
+            }
+            None => Err(Errno::EINVAL),
+        }
+    }
+
+    fn set_propagation(&self, mountpoint: &Path, flags: PropagationFlags) -> Result<(), Errno> {
+        self.calls.borrow_mut().push(MountCall::SetPropagation {
+            mountpoint: mountpoint.to_path_buf(),
+            flags,
+        });
+        let mounted = self.mounted.borrow();
+        // like the kernel, this only works on mountpoints
+        if !mounted.iter().any(|m| m == mountpoint) {
+            return Err(Errno::EINVAL);
+        }
+        let mut propagation = self.propagation.borrow_mut();
+        for m in mounted.iter() {
+            if m == mountpoint || (flags.recursive && m.starts_with(mountpoint)) {
+                propagation.insert(m.clone(), flags.propagation);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::BoundMounter;
+
+    #[test]
+    fn test_records_calls() {
+        let mounter = FakeMounter::new();
+        let handle = mounter
+            .mount(
+                Path::new("/dev/vda"),
+                Path::new("/mnt"),
+                Some("btrfs"),
+                MsFlags::MS_RDONLY,
+                Some("subvol=volume"),
+            )
+            .expect("mount failed");
+        assert_eq!(mounter.mounted(), vec![PathBuf::from("/mnt")]);
+        handle.umount(false).expect("umount failed");
+        assert!(mounter.mounted().is_empty());
+        assert_eq!(
+            mounter.calls(),
+            vec![
+                MountCall::Mount {
+                    source: "/dev/vda".into(),
+                    target: "/mnt".into(),
+                    fstype: Some("btrfs".into()),
+                    flags: MsFlags::MS_RDONLY,
+                    data: Some("subvol=volume".into()),
+                },
+                MountCall::Umount {
+                    mountpoint: "/mnt".into(),
+                    force: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_injected_errors() {
+        let mounter = FakeMounter::new();
+        mounter.fail_next_mount(Errno::ENOENT);
+        assert!(matches!(
+            mounter.mount(
+                Path::new("/src"),
+                Path::new("/dst"),
+                None,
+                MsFlags::MS_BIND,
+                None
+            ),
+            Err(MountError::Unknown(Errno::ENOENT))
+        ));
+        assert!(mounter.mounted().is_empty());
+
+        let handle = mounter
+            .mount(
+                Path::new("/src"),
+                Path::new("/dst"),
+                None,
+                MsFlags::MS_BIND,
+                None,
+            )
+            .expect("mount failed");
+        std::mem::forget(handle);
+        mounter.fail_next_umount(Errno::EBUSY);
+        assert_eq!(mounter.umount(Path::new("/dst"), false), Err(Errno::EBUSY));
+        assert_eq!(mounter.umount(Path::new("/dst"), false), Ok(()));
+        // already unmounted
+        assert_eq!(mounter.umount(Path::new("/dst"), false), Err(Errno::EINVAL));
+        assert_eq!(mounter.calls().len(), 5);
+    }
+
+    #[test]
+    fn test_set_propagation() {
+        let mounter = FakeMounter::new();
+        let outer = mounter
+            .mount(
+                Path::new("tmpfs"),
+                Path::new("/root"),
+                Some("tmpfs"),
+                MsFlags::empty(),
+                None,
+            )
+            .expect("mount failed");
+        let inner = outer
+            .mount_relative(
+                Path::new("tmpfs"),
+                Path::new("nested"),
+                Some("tmpfs"),
+                MsFlags::empty(),
+                None,
+            )
+            .expect("mount failed");
+
+        outer
+            .set_propagation(PropagationFlags::new(Propagation::Shared))
+            .expect("set_propagation failed");
+        assert_eq!(
+            mounter.propagation(Path::new("/root")),
+            Some(Propagation::Shared)
+        );
+        assert_eq!(mounter.propagation(inner.mountpoint()), None);
+
+        outer
+            .set_propagation(PropagationFlags::recursive(Propagation::Private))
+            .expect("set_propagation failed");
+        assert_eq!(
+            mounter.propagation(Path::new("/root")),
+            Some(Propagation::Private)
+        );
+        assert_eq!(
+            mounter.propagation(inner.mountpoint()),
+            Some(Propagation::Private)
+        );
+
+        inner
+            .set_propagation(PropagationFlags::new(Propagation::Slave))
+            .expect("set_propagation failed");
+        assert_eq!(
+            mounter.propagation(Path::new("/root/nested")),
+            Some(Propagation::Slave)
+        );
+
+        assert_eq!(
+            mounter.set_propagation(
+                Path::new("/not/mounted"),
+                PropagationFlags::new(Propagation::Private)
+            ),
+            Err(Errno::EINVAL)
+        );
+    }
+
+    #[test]
+    fn test_bound_mounter_auto_umount() {
+        let fake = FakeMounter::new();
+        {
+            let bound = BoundMounter::new(&fake);
+            let _handle = bound
+                .mount(
+                    Path::new("/src"),
+                    Path::new("/dst"),
+                    None,
+                    MsFlags::MS_BIND,
+                    None,
+                )
+                .expect("mount failed");
+            assert_eq!(fake.mounted(), vec![PathBuf::from("/dst")]);
+        }
+        assert!(fake.mounted().is_empty());
+        assert_eq!(
+            fake.calls().last(),
+            Some(&MountCall::Umount {
+                mountpoint: "/dst".into(),
+                force: true,
+            })
+        );
+    }
+}
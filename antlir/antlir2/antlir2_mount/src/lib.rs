@@ -13,6 +13,9 @@ use nix::mount::MsFlags;
 use proc_mounts::MountIter;
 use tracing::info;
 
+#[cfg(any(test, feature = "testing"))]
+pub mod fake;
+
 #[derive(thiserror::Error, Debug)]
 pub enum MountError {
     #[error("No such file or directory: Mount source {0:?} doesn't exist")]
@@ -21,6 +24,11 @@ pub enum MountError {
     MissingTarget(PathBuf),
     #[error("No such file or directory: Unknown reason - both source/target exist")]
     MissingUnknown,
+    #[error(
+        "MS_REC is only meaningful for bind mounts and propagation changes (use it together with \
+        MS_BIND, MS_PRIVATE, MS_SHARED, MS_SLAVE or MS_UNBINDABLE)"
+    )]
+    RecursiveWithoutBind,
     #[error("Path {0:?} provided as subvolume path is not valid unicode")]
     InvalidSubvolume(PathBuf),
     #[error("Cannot parse /proc/mounts: {0:?}")]
@@ -42,6 +50,76 @@ pub fn source_mounted_at(source: &Path, target: &Path) -> Result<bool, MountErro
     Ok(false)
 }
 
+/// Propagation type of a mount, see mount_namespaces(7)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    /// Mount events do not propagate to or from this mount
+    Private,
+    /// Mount events propagate to and from other members of the peer group
+    Shared,
+    /// Mount events propagate into this mount from its master, but not back
+    Slave,
+    /// Like [Propagation::Private], and the mount cannot be bind mounted
+    Unbindable,
+}
+
+/// How to change the propagation of a mountpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PropagationFlags {
+    pub propagation: Propagation,
+    /// Also apply to every mount below the mountpoint (MS_REC). Without this
+    /// only the mountpoint itself is changed, and any mounts that already
+    /// exist underneath keep their current propagation.
+    pub recursive: bool,
+}
+
+impl PropagationFlags {
+    pub fn new(propagation: Propagation) -> Self {
+        Self {
+            propagation,
+            recursive: false,
+        }
+    }
+
+    /// Same as `new`, but applied to the whole mount tree
+    pub fn recursive(propagation: Propagation) -> Self {
+        Self {
+            propagation,
+            recursive: true,
+        }
+    }
+
+    pub fn ms_flags(&self) -> MsFlags {
+        let mut flags = match self.propagation {
+            Propagation::Private => MsFlags::MS_PRIVATE,
+            Propagation::Shared => MsFlags::MS_SHARED,
+            Propagation::Slave => MsFlags::MS_SLAVE,
+            Propagation::Unbindable => MsFlags::MS_UNBINDABLE,
+        };
+        if self.recursive {
+            flags.insert(MsFlags::MS_REC);
+        }
+        flags
+    }
+}
+
+/// Reject flag combinations where the kernel would silently ignore MS_REC,
+/// which only applies to bind mounts and changes of propagation
+pub(crate) fn validate_flags(flags: MsFlags) -> Result<(), MountError> {
+    if flags.contains(MsFlags::MS_REC)
+        && !flags.intersects(
+            MsFlags::MS_BIND
+                | MsFlags::MS_PRIVATE
+                | MsFlags::MS_SHARED
+                | MsFlags::MS_SLAVE
+                | MsFlags::MS_UNBINDABLE,
+        )
+    {
+        return Err(MountError::RecursiveWithoutBind);
+    }
+    Ok(())
+}
+
 #[mockall::automock]
 pub trait Mounter: Sized {
     fn mount<'a, 'b>(
@@ -54,6 +132,30 @@ pub trait Mounter: Sized {
     ) -> Result<MountHandle<'a, Self>, MountError>;
 
     fn umount(&self, mountpoint: &Path, force: bool) -> Result<(), nix::errno::Errno>;
+
+    /// Bind mount `source` onto `target`. When `recursive` is set, any mounts
+    /// below `source` are carried over as well (like `mount --rbind`),
+    /// otherwise only the directory tree of the filesystem `source` is on is
+    /// visible at `target`.
+    fn bind<'a, 'b>(
+        &'a self,
+        source: &'b Path,
+        target: &'b Path,
+        recursive: bool,
+    ) -> Result<MountHandle<'a, Self>, MountError> {
+        let mut flags = MsFlags::MS_BIND;
+        if recursive {
+            flags.insert(MsFlags::MS_REC);
+        }
+        self.mount(source, target, None, flags, None)
+    }
+
+    /// Change the propagation type of an existing mountpoint
+    fn set_propagation(
+        &self,
+        mountpoint: &Path,
+        flags: PropagationFlags,
+    ) -> Result<(), nix::errno::Errno>;
 }
 
 // RealMounter is an implementation of the Mounter trait that calls nix::mount::mount for real.
@@ -68,6 +170,7 @@ impl Mounter for RealMounter {
         flags: MsFlags,
         data: Option<&'b str>,
     ) -> Result<MountHandle<'a, Self>, MountError> {
+        validate_flags(flags)?;
         info!(
             "Mounting {} to {} with fstype {:?}, flags {:?} and options {:?}",
             source.display(),
@@ -97,6 +200,25 @@ impl Mounter for RealMounter {
         info!("Unmounting {} with flags {:?}", mountpoint.display(), flags);
         nix::mount::umount2(mountpoint, flags)
     }
+
+    fn set_propagation(
+        &self,
+        mountpoint: &Path,
+        flags: PropagationFlags,
+    ) -> Result<(), nix::errno::Errno> {
+        info!(
+            "Setting propagation of {} to {:?}",
+            mountpoint.display(),
+            flags
+        );
+        nix::mount::mount(
+            None::<&str>,
+            mountpoint,
+            None::<&str>,
+            flags.ms_flags(),
+            None::<&str>,
+        )
+    }
 }
 
 /// This mounter is bounded to live at most as long as the
@@ -141,6 +263,14 @@ where
     fn umount(&self, mountpoint: &Path, force: bool) -> Result<(), nix::errno::Errno> {
         self.0.umount(mountpoint, force)
     }
+
+    fn set_propagation(
+        &self,
+        mountpoint: &Path,
+        flags: PropagationFlags,
+    ) -> Result<(), nix::errno::Errno> {
+        self.0.set_propagation(mountpoint, flags)
+    }
 }
 
 pub struct MountHandle<'a, M>
@@ -194,6 +324,12 @@ where
         &self.target
     }
 
+    /// Change the propagation type of this mount (and optionally everything
+    /// mounted below it)
+    pub fn set_propagation(&self, flags: PropagationFlags) -> Result<(), nix::errno::Errno> {
+        self.mounter.set_propagation(&self.target, flags)
+    }
+
     pub fn mount_relative<'b, 'c>(
         &'a self,
         source: &'c Path,
@@ -226,3 +362,189 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_propagation_flags() {
+        assert_eq!(
+            PropagationFlags::new(Propagation::Private).ms_flags(),
+            MsFlags::MS_PRIVATE
+        );
+        assert_eq!(
+            PropagationFlags::recursive(Propagation::Slave).ms_flags(),
+            MsFlags::MS_SLAVE | MsFlags::MS_REC
+        );
+    }
+
+    /// Optional fields of the mountinfo line for `mountpoint`, which is where
+    /// the kernel reports propagation (eg `shared:1`)
+    fn mountinfo_tags(mountpoint: &Path) -> Vec<String> {
+        let mountinfo =
+            std::fs::read_to_string("/proc/self/mountinfo").expect("failed to read mountinfo");
+        let line = mountinfo
+            .lines()
+            .rfind(|line| line.split(' ').nth(4) == mountpoint.to_str())
+            .expect("mountpoint not in mountinfo");
+        line.split(' ')
+            .skip(6)
+            .take_while(|field| *field != "-")
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_flags() {
+        assert!(validate_flags(MsFlags::MS_BIND).is_ok());
+        assert!(validate_flags(MsFlags::MS_BIND | MsFlags::MS_REC).is_ok());
+        assert!(validate_flags(MsFlags::MS_PRIVATE | MsFlags::MS_REC).is_ok());
+        assert!(validate_flags(MsFlags::MS_UNBINDABLE | MsFlags::MS_REC).is_ok());
+        assert!(matches!(
+            validate_flags(MsFlags::MS_RDONLY | MsFlags::MS_REC),
+            Err(MountError::RecursiveWithoutBind)
+        ));
+    }
+
+    #[test]
+    fn test_real_rbind() {
+        let src = tempfile::TempDir::new().expect("failed to create tempdir");
+        let plain = tempfile::TempDir::new().expect("failed to create tempdir");
+        let recursive = tempfile::TempDir::new().expect("failed to create tempdir");
+        std::fs::create_dir(src.path().join("nested")).expect("failed to create dir");
+        let mounter = RealMounter;
+        let bound = BoundMounter::new(&mounter);
+        let nested = match bound.mount(
+            Path::new("tmpfs"),
+            &src.path().join("nested"),
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None,
+        ) {
+            Ok(handle) => handle,
+            Err(MountError::Unknown(nix::errno::Errno::EPERM)) => {
+                eprintln!("skipping test that needs CAP_SYS_ADMIN");
+                return;
+            }
+            Err(e) => panic!("failed to mount tmpfs: {e}"),
+        };
+        nested
+            .set_propagation(PropagationFlags::new(Propagation::Private))
+            .expect("failed to make private");
+        std::fs::write(nested.mountpoint().join("hello"), "world").expect("failed to write");
+
+        let plain_handle = bound
+            .bind(src.path(), plain.path(), false)
+            .expect("failed to bind");
+        let recursive_handle = bound
+            .bind(src.path(), recursive.path(), true)
+            .expect("failed to rbind");
+        // a plain bind mount only has the (empty) directory that the nested
+        // mount is on top of
+        assert!(!plain.path().join("nested/hello").exists());
+        assert_eq!(
+            std::fs::read_to_string(recursive.path().join("nested/hello"))
+                .expect("nested mount missing from rbind"),
+            "world"
+        );
+
+        drop(plain_handle);
+        // auto-umount is not recursive, so clean up the carried over mount
+        mounter
+            .umount(&recursive.path().join("nested"), false)
+            .expect("failed to umount");
+        drop(recursive_handle);
+    }
+
+    #[test]
+    fn test_recursive_propagation_flags() {
+        let flags = MsFlags::MS_REC | MsFlags::MS_PRIVATE;
+        let fake = crate::fake::FakeMounter::new();
+        fake.mount(Path::new("none"), Path::new("/mnt"), None, flags, None)
+            .expect("fake rejected recursive propagation change");
+
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        let mounter = RealMounter;
+        let bound = BoundMounter::new(&mounter);
+        let outer = match bound.mount(
+            Path::new("tmpfs"),
+            dir.path(),
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None,
+        ) {
+            Ok(handle) => handle,
+            Err(MountError::Unknown(nix::errno::Errno::EPERM)) => {
+                eprintln!("skipping test that needs CAP_SYS_ADMIN");
+                return;
+            }
+            Err(e) => panic!("failed to mount tmpfs: {e}"),
+        };
+        // only changes propagation, so there is nothing to unmount
+        mounter
+            .mount(Path::new("none"), outer.mountpoint(), None, flags, None)
+            .expect("failed to make recursively private");
+        assert!(!mountinfo_tags(outer.mountpoint())
+            .iter()
+            .any(|tag| tag.starts_with("shared:")));
+    }
+
+    #[test]
+    fn test_real_set_propagation() {
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        let mounter = RealMounter;
+        let bound = BoundMounter::new(&mounter);
+        let outer = match bound.mount(
+            Path::new("tmpfs"),
+            dir.path(),
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None,
+        ) {
+            Ok(handle) => handle,
+            Err(MountError::Unknown(nix::errno::Errno::EPERM)) => {
+                eprintln!("skipping test that needs CAP_SYS_ADMIN");
+                return;
+            }
+            Err(e) => panic!("failed to mount tmpfs: {e}"),
+        };
+        // make sure nothing leaks out to the rest of the system, regardless
+        // of the propagation of the parent mount
+        outer
+            .set_propagation(PropagationFlags::new(Propagation::Private))
+            .expect("failed to make private");
+        std::fs::create_dir(dir.path().join("nested")).expect("failed to create dir");
+        let inner = outer
+            .mount_relative(
+                Path::new("tmpfs"),
+                Path::new("nested"),
+                Some("tmpfs"),
+                MsFlags::empty(),
+                None,
+            )
+            .expect("failed to mount nested tmpfs");
+
+        let is_shared = |path: &Path| {
+            mountinfo_tags(path)
+                .iter()
+                .any(|tag| tag.starts_with("shared:"))
+        };
+        assert!(!is_shared(outer.mountpoint()));
+        assert!(!is_shared(inner.mountpoint()));
+
+        outer
+            .set_propagation(PropagationFlags::recursive(Propagation::Shared))
+            .expect("failed to make shared");
+        assert!(is_shared(outer.mountpoint()));
+        assert!(is_shared(inner.mountpoint()));
+
+        outer
+            .set_propagation(PropagationFlags::new(Propagation::Private))
+            .expect("failed to make private");
+        assert!(!is_shared(outer.mountpoint()));
+        assert!(is_shared(inner.mountpoint()));
+        // the nested mount has to go first
+        drop(inner);
+    }
+}
@@ -0,0 +1,148 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::ffi::OsString;
+use std::fs::create_dir_all;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use nix::mount::mount;
+use nix::mount::umount;
+use nix::mount::MsFlags;
+use tracing::error;
+use tracing::trace;
+
+use crate::Error;
+use crate::Result;
+
+/// An existing directory mounted as the (never modified) lowerdir of an
+/// overlayfs, so that building on top of it only has to write the changes
+/// instead of copying the whole base first.
+///
+/// Everything lives under `root`:
+/// * `upper` - the delta on top of the base, which is the only thing left
+///   behind after [BaseOverlay::finalize]
+/// * `work` - overlayfs workdir
+/// * `mountpoint` - the merged view of the base and the delta
+#[derive(Debug)]
+pub struct BaseOverlay {
+    mountpoint: PathBuf,
+    upper: PathBuf,
+    mounted: bool,
+}
+
+impl BaseOverlay {
+    pub fn mount(lower: &Path, root: &Path) -> Result<Self> {
+        let mountpoint = root.join("mountpoint");
+        let upper = root.join("upper");
+        let work = root.join("work");
+        for dir in [&mountpoint, &upper, &work] {
+            create_dir_all(dir)
+                .with_context(|| format!("while creating '{}'", dir.display()))
+                .map_err(Error::ScratchSetup)?;
+        }
+
+        let mut options = OsString::from("uuid=off");
+        options.push(",lowerdir=");
+        options.push(lower);
+        options.push(",upperdir=");
+        options.push(&upper);
+        options.push(",workdir=");
+        options.push(&work);
+        trace!(
+            "mounting at {} with options {}",
+            mountpoint.display(),
+            String::from_utf8_lossy(options.as_bytes())
+        );
+        mount(
+            Some("overlay"),
+            &mountpoint,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(options.as_os_str()),
+        )
+        .map_err(std::io::Error::from)
+        .map_err(Error::Mount)?;
+        Ok(Self {
+            mountpoint,
+            upper,
+            mounted: true,
+        })
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Unmount the overlay and return the path of the upperdir, which holds
+    /// only what was changed on top of the base
+    pub fn finalize(mut self) -> Result<PathBuf> {
+        umount(self.mountpoint())
+            .map_err(std::io::Error::from)
+            .map_err(Error::Mount)?;
+        self.mounted = false;
+        Ok(std::mem::take(&mut self.upper))
+    }
+}
+
+impl Drop for BaseOverlay {
+    fn drop(&mut self) {
+        if self.mounted {
+            if let Err(e) = umount(self.mountpoint()).map_err(std::io::Error::from) {
+                error!("failed to umount: '{e}'");
+            }
+        }
+    }
+}
+
+// mounting requires privileges that are only available inside an image_test
+#[cfg(all(test, image_test))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_go_to_upper() {
+        let base = tempfile::TempDir::new().expect("failed to create tempdir");
+        std::fs::write(base.path().join("existing"), "base").expect("failed to write");
+        std::fs::write(base.path().join("removed"), "base").expect("failed to write");
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+
+        let overlay = BaseOverlay::mount(base.path(), root.path()).expect("failed to mount");
+        let mnt = overlay.mountpoint();
+        assert_eq!(
+            std::fs::read_to_string(mnt.join("existing")).expect("failed to read"),
+            "base"
+        );
+        std::fs::write(mnt.join("existing"), "changed").expect("failed to write");
+        std::fs::write(mnt.join("new"), "new").expect("failed to write");
+        std::fs::remove_file(mnt.join("removed")).expect("failed to remove");
+        let upper = overlay.finalize().expect("failed to finalize");
+
+        assert_eq!(
+            std::fs::read_to_string(upper.join("existing")).expect("failed to read"),
+            "changed"
+        );
+        assert_eq!(
+            std::fs::read_to_string(upper.join("new")).expect("failed to read"),
+            "new"
+        );
+        // overlayfs records the deletion as a whiteout in the upper
+        assert!(upper.join("removed").symlink_metadata().is_ok());
+
+        assert_eq!(
+            std::fs::read_to_string(base.path().join("existing")).expect("failed to read"),
+            "base"
+        );
+        assert_eq!(
+            std::fs::read_to_string(base.path().join("removed")).expect("failed to read"),
+            "base"
+        );
+        assert!(!base.path().join("new").exists());
+    }
+}
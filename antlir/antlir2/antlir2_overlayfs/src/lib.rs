@@ -21,10 +21,12 @@ use nix::mount::MsFlags;
 use tracing::error;
 use tracing::trace;
 
+mod base;
 mod buck;
 mod data_dir;
 mod manifest;
 mod scratch;
+pub use base::BaseOverlay;
 pub use buck::OverlayFs as BuckModel;
 use scratch::Scratch;
 
@@ -130,21 +132,34 @@ impl OverlayFs {
         self.scratch.mountpoint()
     }
 
+    /// Paths of the manifest and data dir that [OverlayFs::finalize] writes
+    pub fn outputs(&self) -> (&Path, &Path) {
+        (&self.model.top.manifest, &self.model.top.data_dir)
+    }
+
     pub fn finalize(self) -> Result<()> {
+        let manifest = self.model.top.manifest.clone();
+        let data_dir = self.model.top.data_dir.clone();
+        self.finalize_to(&manifest, &data_dir)
+    }
+
+    /// Like [OverlayFs::finalize], but write the manifest and data dir to
+    /// somewhere other than the paths in the model
+    pub fn finalize_to(self, manifest_path: &Path, data_dir: &Path) -> Result<()> {
         umount(self.mountpoint())
             .map_err(std::io::Error::from)
             .map_err(Error::Mount)?;
         let manifest =
             Manifest::from_directory(self.scratch.upperdir()).map_err(Error::Dehydrate)?;
         let mut f = BufWriter::new(
-            File::create(&self.model.top.manifest)
+            File::create(manifest_path)
                 .context("while creating manifest output file")
                 .map_err(Error::Dehydrate)?,
         );
         serde_json::to_writer_pretty(&mut f, &manifest)
             .context("while serializing manifest output")
             .map_err(Error::Dehydrate)?;
-        data_dir::mangle(self.scratch.upperdir(), &self.model.top.data_dir)
+        data_dir::mangle(self.scratch.upperdir(), data_dir)
             .context("while mangling data_dir output")
             .map_err(Error::Dehydrate)?;
         Ok(())
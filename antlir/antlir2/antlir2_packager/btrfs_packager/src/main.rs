@@ -17,6 +17,8 @@ use std::process::Command;
 use antlir2_btrfs::Subvolume;
 use antlir_mount::BoundMounter;
 use antlir_mount::Mounter;
+use antlir_mount::Propagation;
+use antlir_mount::PropagationFlags;
 use antlir_mount::RealMounter;
 use anyhow::anyhow;
 use anyhow::ensure;
@@ -516,6 +518,11 @@ where
             ),
         )
         .context("Failed to mount output btrfs")?;
+    // this is only a scratch mount, don't let it (or anything mounted below
+    // it) show up in any peer mount namespaces
+    mount_handle
+        .set_propagation(PropagationFlags::recursive(Propagation::Private))
+        .context("Failed to make output btrfs mount private")?;
 
     let subvols =
         receive_subvols(mount_handle.mountpoint(), subvols).context("failed to recv subvols")?;
@@ -61,6 +61,7 @@ def _machine_json(ctx: AnalysisContext) -> (Artifact, typing.Any):
             "num_nics": ctx.attrs.num_nics,
             "serial_index": ctx.attrs.serial_index,
             "sidecar_services": ctx.attrs.sidecar_services,
+            "use_hugepages": ctx.attrs.use_hugepages,
             "use_legacy_share": ctx.attrs.use_legacy_share,
             "use_tpm": ctx.attrs.use_tpm,
         },
@@ -129,6 +130,10 @@ _vm_host = rule(
             attrs.string(default = TTY_NAME),
             doc = "arch dependent name of the console device",
         ),
+        "use_hugepages": attrs.bool(
+            default = False,
+            doc = "back guest memory with hugepages; mem_mib must be a multiple of the hugepage size",
+        ),
         "use_legacy_share": attrs.bool(
             default = False,
             doc = "use 9p instead of virtiofs for sharing for older kernels",
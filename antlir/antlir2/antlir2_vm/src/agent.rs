@@ -0,0 +1,197 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Control channel to an agent running inside the guest. The channel is a
+//! virtio-serial port that qemu exposes as a unix socket on the host. The
+//! protocol is line based: every command sent is a single line and the agent
+//! answers with a single line.
+
+use std::ffi::OsString;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::types::QemuDevice;
+
+/// Name of the virtio-serial port in the guest, which shows up as
+/// /dev/virtio-ports/org.antlir.agent.0
+pub(crate) const AGENT_PORT_NAME: &str = "org.antlir.agent.0";
+
+#[derive(Debug, Error)]
+pub(crate) enum GuestAgentError {
+    #[error("Failed to connect to guest agent socket {path}: {err}")]
+    ConnectError { path: PathBuf, err: std::io::Error },
+    #[error("Guest agent commands must be a single line: `{0}`")]
+    InvalidCommandError(String),
+    #[error("Failed to talk to guest agent: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("Guest agent closed the channel before responding to `{0}`")]
+    ClosedError(String),
+}
+
+type Result<T> = std::result::Result<T, GuestAgentError>;
+
+/// virtio-serial port for the guest agent, attached to the `virtio-serial`
+/// controller that every VM already has
+#[derive(Debug)]
+pub(crate) struct GuestAgentChannel {
+    /// Host side of the channel. qemu is listening on it.
+    socket_path: PathBuf,
+}
+
+impl GuestAgentChannel {
+    pub(crate) fn new(state_dir: &Path) -> Self {
+        Self {
+            socket_path: state_dir.join("agent.sock"),
+        }
+    }
+
+    pub(crate) fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Connect to the channel. Responses that take longer than `timeout` are
+    /// treated as errors.
+    pub(crate) fn connect(&self, timeout: Option<Duration>) -> Result<GuestAgent> {
+        let stream = UnixStream::connect(&self.socket_path).map_err(|err| {
+            GuestAgentError::ConnectError {
+                path: self.socket_path.clone(),
+                err,
+            }
+        })?;
+        stream.set_read_timeout(timeout)?;
+        Ok(GuestAgent {
+            stream: BufReader::new(stream),
+        })
+    }
+}
+
+impl QemuDevice for GuestAgentChannel {
+    fn qemu_args(&self) -> Vec<OsString> {
+        [
+            "-chardev",
+            &format!(
+                "socket,path={},id=agent,server=on,wait=off",
+                self.socket_path.to_str().expect("Invalid socket path")
+            ),
+            "-device",
+            &format!("virtserialport,chardev=agent,name={AGENT_PORT_NAME}"),
+        ]
+        .iter()
+        .map(|x| x.into())
+        .collect()
+    }
+}
+
+/// Connected client of the guest agent
+#[derive(Debug)]
+pub(crate) struct GuestAgent {
+    stream: BufReader<UnixStream>,
+}
+
+impl GuestAgent {
+    /// Send `command` and wait for the agent's response
+    pub(crate) fn send(&mut self, command: &str) -> Result<String> {
+        if command.contains('\n') {
+            return Err(GuestAgentError::InvalidCommandError(command.to_owned()));
+        }
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\n")?;
+        stream.flush()?;
+
+        let mut response = String::new();
+        if self.stream.read_line(&mut response)? == 0 {
+            return Err(GuestAgentError::ClosedError(command.to_owned()));
+        }
+        Ok(response.trim_end_matches('\n').to_owned())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_qemu_args() {
+        let channel = GuestAgentChannel::new(Path::new("/state"));
+        assert_eq!(channel.socket_path(), Path::new("/state/agent.sock"));
+        assert_eq!(
+            channel.qemu_args().join(OsStr::new(" ")),
+            "-chardev socket,path=/state/agent.sock,id=agent,server=on,wait=off \
+            -device virtserialport,chardev=agent,name=org.antlir.agent.0"
+        );
+    }
+
+    #[test]
+    fn test_send_command() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let channel = GuestAgentChannel::new(dir.path());
+        // stands in for qemu forwarding the port to an agent in the guest
+        let listener = UnixListener::bind(channel.socket_path()).expect("Failed to bind");
+        let mock_agent = thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("Failed to accept");
+            let mut stream = BufReader::new(stream);
+            let mut received = vec![];
+            let mut line = String::new();
+            while stream.read_line(&mut line).expect("Failed to read") > 0 {
+                received.push(line.trim_end().to_owned());
+                writeln!(stream.get_mut(), "ok {}", line.trim_end()).expect("Failed to write");
+                line.clear();
+            }
+            received
+        });
+
+        let mut agent = channel
+            .connect(Some(Duration::from_secs(10)))
+            .expect("Failed to connect");
+        assert_eq!(
+            agent.send("probe network").expect("Failed to send"),
+            "ok probe network"
+        );
+        assert_eq!(agent.send("uptime").expect("Failed to send"), "ok uptime");
+        assert!(matches!(
+            agent.send("two\nlines"),
+            Err(GuestAgentError::InvalidCommandError(_))
+        ));
+        drop(agent);
+        assert_eq!(
+            mock_agent.join().expect("Mock agent panicked"),
+            vec!["probe network", "uptime"]
+        );
+    }
+
+    #[test]
+    fn test_agent_closed() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let channel = GuestAgentChannel::new(dir.path());
+        let listener = UnixListener::bind(channel.socket_path()).expect("Failed to bind");
+        let mock_agent = thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("Failed to accept");
+            let mut line = String::new();
+            BufReader::new(stream)
+                .read_line(&mut line)
+                .expect("Failed to read");
+        });
+        let mut agent = channel.connect(None).expect("Failed to connect");
+        assert!(matches!(
+            agent.send("hello"),
+            Err(GuestAgentError::ClosedError(_))
+        ));
+        mock_agent.join().expect("Mock agent panicked");
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Throughput benchmark for shares, run from inside the guest. Each writable
+//! share gets a sequential and a random read/write workload driven by `dd`,
+//! since that is available in every image, unlike fio. Results are printed as
+//! one JSON object per line, for example
+//! `{"tag":"fs0","path":"/data","workload":"seq_read","mib_per_sec":812.3}`.
+
+use serde_json::json;
+
+use crate::share::Share;
+
+/// Scratch file created on each share for the duration of the benchmark
+const BENCH_FILE: &str = ".antlir2_vm_bench";
+
+/// Helpers shared by every share. The scratch file is 64MiB, written in 1MiB
+/// blocks sequentially and in 256 4KiB blocks at random offsets.
+const PRELUDE: &str = r#"set -u
+drop_caches() {
+    sync
+    { echo 3 > /proc/sys/vm/drop_caches; } 2>/dev/null
+}
+rand_write() {
+    for _ in $(seq 256); do
+        dd if=/dev/zero of="$1" bs=4k count=1 seek=$((RANDOM % 16384)) conv=notrunc status=none || return 1
+    done
+    sync "$1"
+}
+rand_read() {
+    for _ in $(seq 256); do
+        dd if="$1" of=/dev/null bs=4k count=1 skip=$((RANDOM % 16384)) status=none || return 1
+    done
+}
+# bench <json fields of the share> <workload> <bytes> <command...>
+bench() {
+    local share=$1 workload=$2 bytes=$3 start end
+    shift 3
+    start=$(date +%s%N)
+    if ! "$@"; then
+        printf '{%s,"workload":"%s","error":"failed"}\n' "$share" "$workload"
+        return
+    fi
+    end=$(date +%s%N)
+    awk -v share="$share" -v workload="$workload" -v bytes="$bytes" -v ns=$((end - start)) \
+        'BEGIN { printf "{%s,\"workload\":\"%s\",\"mib_per_sec\":%.1f}\n", share, workload, bytes / 1048576 / (ns / 1e9) }'
+}
+"#;
+
+fn quote(s: &str) -> String {
+    shlex::try_quote(s)
+        .expect("share paths and tags can't contain nul bytes")
+        .into_owned()
+}
+
+/// Generate a bash script that benchmarks every share at its mountpoint in the
+/// guest. Read-only shares can't be benchmarked without writing a scratch
+/// file, so they are reported as skipped.
+pub(crate) fn bench_script<T: Share>(shares: &[T]) -> String {
+    let mut script = PRELUDE.to_string();
+    for share in shares {
+        let path = share.get_opts().path.to_string_lossy();
+        let fields = format!(
+            "\"tag\":{},\"path\":{}",
+            json!(share.mount_tag()),
+            json!(path),
+        );
+        if share.get_opts().read_only {
+            script.push_str(&format!(
+                "printf '{{%s,\"skipped\":\"read-only\"}}\\n' {}\n",
+                quote(&fields),
+            ));
+            continue;
+        }
+        let file = quote(&format!("{}/{BENCH_FILE}", path.trim_end_matches('/')));
+        let fields = quote(&fields);
+        script.push_str(&format!(
+            r#"bench {fields} seq_write 67108864 dd if=/dev/zero of={file} bs=1M count=64 conv=fsync status=none
+drop_caches
+bench {fields} seq_read 67108864 dd if={file} of=/dev/null bs=1M status=none
+bench {fields} rand_write 1048576 rand_write {file}
+drop_caches
+bench {fields} rand_read 1048576 rand_read {file}
+rm -f {file}
+"#
+        ));
+    }
+    script
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::share::VirtiofsShare;
+    use crate::types::ShareOpts;
+
+    fn share(path: impl Into<PathBuf>, read_only: bool, id: usize) -> VirtiofsShare {
+        let opts = ShareOpts {
+            path: path.into(),
+            read_only,
+            ..Default::default()
+        };
+        VirtiofsShare::new(opts, id, PathBuf::from("/state"))
+    }
+
+    #[test]
+    fn test_bench_script() {
+        let script = bench_script(&[share("/my data", false, 0), share("/ro", true, 1)]);
+        assert!(script.contains(
+            r#"bench '"tag":"fs0","path":"/my data"' seq_write 67108864 dd if=/dev/zero of='/my data/.antlir2_vm_bench' bs=1M count=64 conv=fsync status=none"#
+        ));
+        for workload in ["seq_read", "rand_write", "rand_read"] {
+            assert!(
+                script.contains(&format!(
+                    r#"bench '"tag":"fs0","path":"/my data"' {workload} "#
+                )),
+                "{workload} missing from {script}",
+            );
+        }
+        assert!(script.contains(r#"rm -f '/my data/.antlir2_vm_bench'"#));
+        assert!(
+            script.contains(r#"printf '{%s,"skipped":"read-only"}\n' '"tag":"fs1","path":"/ro"'"#)
+        );
+        assert!(!script.contains("/ro/.antlir2_vm_bench"));
+    }
+
+    #[test]
+    fn test_bench_script_runs() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(bench_script(&[share(dir.path(), false, 0)]))
+            .output()
+            .expect("Failed to run bench script");
+        assert!(output.status.success(), "{output:?}");
+        let workloads: Vec<_> = String::from_utf8(output.stdout)
+            .expect("Invalid utf8")
+            .lines()
+            .map(|line| {
+                let result: serde_json::Value =
+                    serde_json::from_str(line).expect("Each line is a JSON object");
+                assert_eq!(result["tag"], "fs0");
+                assert!(result["mib_per_sec"].as_f64().is_some(), "{line}");
+                result["workload"].as_str().expect("workload").to_owned()
+            })
+            .collect();
+        assert_eq!(
+            workloads,
+            vec!["seq_write", "seq_read", "rand_write", "rand_read"]
+        );
+        assert!(!dir.path().join(BENCH_FILE).exists());
+    }
+}
@@ -0,0 +1,309 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! VMs that keep running after the launcher exits. Once the guest has booted,
+//! everything needed to reach it (and to clean up after it) is written out as
+//! JSON, and the launcher lets go of the qemu process and all the files it
+//! uses instead of tearing them down. qemu is then reparented to (and reaped
+//! by) the nearest subreaper, normally the init process of the container the
+//! launcher was run in, so it only lives as long as that container does. The
+//! pid and paths in the info file are as seen from inside that container.
+//!
+//! This is only supported by `run`: `isolate` and `test` create their own
+//! container, which goes away (and takes qemu with it) as soon as the
+//! launcher exits.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::debug;
+use tracing::warn;
+
+use crate::qmp::QmpChannel;
+use crate::qmp::QmpError;
+use crate::teardown::RemoveFile;
+use crate::teardown::Resource;
+
+/// Name of the unix socket in the state dir that the guest serial console of
+/// a detached VM is attached to, since there is no stdio to use
+pub(crate) const SERIAL_SOCKET: &str = "serial.sock";
+/// How long qemu gets to exit after being asked to
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub(crate) enum DetachError {
+    #[error("Failed to write VM info to {path}: {err}")]
+    WriteInfoError { path: PathBuf, err: std::io::Error },
+    #[error("Failed to signal qemu (pid {pid}): {err}")]
+    KillError { pid: u32, err: std::io::Error },
+    #[error("pid {pid} is {exe}, not qemu, refusing to kill it")]
+    NotQemu { pid: u32, exe: PathBuf },
+    #[error("qemu (pid {0}) did not exit within {STOP_TIMEOUT:?}")]
+    StopTimeout(u32),
+    #[error("Failed to clean up {path}: {err}")]
+    CleanupError { path: PathBuf, err: std::io::Error },
+}
+
+type Result<T> = std::result::Result<T, DetachError>;
+
+/// How to reach a detached VM, as written to the `--detach` info file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DetachedVm {
+    /// Pid of the qemu process
+    pub(crate) pid: u32,
+    /// Unix socket connected to the guest serial console
+    pub(crate) serial_socket: PathBuf,
+    /// Unix socket of the QMP monitor
+    pub(crate) qmp_socket: PathBuf,
+    /// Guest address to ssh to. It is link-local, so it is only reachable
+    /// from inside the container the VM was started in.
+    pub(crate) ssh_host: String,
+    /// Port sshd in the guest listens on
+    pub(crate) ssh_port: u16,
+    /// Disk overlays, sockets and other files the VM uses, which are removed
+    /// once it is stopped
+    pub(crate) files: Vec<PathBuf>,
+}
+
+impl DetachedVm {
+    /// Write the info as pretty-printed JSON to `path`
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        let map_err = |err| DetachError::WriteInfoError {
+            path: path.to_owned(),
+            err,
+        };
+        let mut f = BufWriter::new(File::create(path).map_err(map_err)?);
+        serde_json::to_writer_pretty(&mut f, self).map_err(|e| map_err(e.into()))?;
+        writeln!(f).map_err(map_err)?;
+        f.flush().map_err(map_err)
+    }
+
+    /// Ask qemu to exit over QMP (or with a signal if that doesn't work),
+    /// wait for it to be gone and then remove the files it was using
+    pub(crate) fn stop(&self) -> Result<()> {
+        if !is_running(self.pid) {
+            debug!("qemu (pid {}) is already gone", self.pid);
+        } else if let Err(e) = self.quit() {
+            warn!("Failed to quit qemu over QMP, killing it instead: {e}");
+            self.kill()?;
+        }
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while is_running(self.pid) {
+            if Instant::now() >= deadline {
+                return Err(DetachError::StopTimeout(self.pid));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        for path in &self.files {
+            RemoveFile(path.clone())
+                .teardown()
+                .map_err(|err| DetachError::CleanupError {
+                    path: path.clone(),
+                    err,
+                })?;
+        }
+        Ok(())
+    }
+
+    fn quit(&self) -> std::result::Result<(), QmpError> {
+        QmpChannel::from_socket(self.qmp_socket.clone())
+            .connect(Some(STOP_TIMEOUT))?
+            .quit()
+    }
+
+    /// Send SIGTERM to qemu, which shuts down cleanly on it. The pid might
+    /// have been reused by now, so nothing is signaled unless it is still
+    /// qemu.
+    fn kill(&self) -> Result<()> {
+        let map_err = |err| DetachError::KillError { pid: self.pid, err };
+        let exe = std::fs::read_link(format!("/proc/{}/exe", self.pid));
+        let exe = match exe {
+            Ok(exe) => exe,
+            // it exited in the meantime
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(map_err(e)),
+        };
+        if !exe
+            .file_name()
+            .is_some_and(|name| name.as_bytes().starts_with(b"qemu"))
+        {
+            return Err(DetachError::NotQemu { pid: self.pid, exe });
+        }
+        let status = Command::new("kill")
+            .arg(self.pid.to_string())
+            .status()
+            .map_err(map_err)?;
+        if !status.success() {
+            return Err(map_err(std::io::Error::other(format!(
+                "kill exited with {status}"
+            ))));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `pid` is alive. A zombie has already exited, it is just waiting to
+/// be reaped by its parent.
+pub(crate) fn is_running(pid: u32) -> bool {
+    match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+        // the state follows the command name, which may contain anything
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .is_some_and(|(_, rest)| !rest.trim_start().starts_with('Z')),
+        Err(_) => false,
+    }
+}
+
+/// Stands in for the QMP monitor of the qemu with `pid`, which it kills on
+/// `quit`. Returns the commands it received.
+#[cfg(test)]
+pub(crate) fn mock_qmp(socket: &Path, pid: u32) -> thread::JoinHandle<Vec<String>> {
+    use std::io::BufRead;
+
+    let listener = std::os::unix::net::UnixListener::bind(socket).expect("Failed to bind");
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().expect("Failed to accept");
+        let mut stream = std::io::BufReader::new(stream);
+        writeln!(stream.get_mut(), r#"{{"QMP": {{"capabilities": []}}}}"#)
+            .expect("Failed to write");
+        let mut received = vec![];
+        let mut line = String::new();
+        while stream.read_line(&mut line).expect("Failed to read") > 0 {
+            let request: serde_json::Value = serde_json::from_str(&line).expect("Invalid request");
+            let command = request["execute"].as_str().expect("No command").to_owned();
+            line.clear();
+            if command == "quit" {
+                // qemu exits without responding
+                let status = Command::new("kill")
+                    .arg(pid.to_string())
+                    .status()
+                    .expect("Failed to run kill");
+                assert!(status.success());
+                received.push(command);
+                break;
+            }
+            writeln!(stream.get_mut(), r#"{{"return": {{}}}}"#).expect("Failed to write");
+            received.push(command);
+        }
+        received
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::process::ExitStatusExt;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_write() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        let info = DetachedVm {
+            pid: 42,
+            serial_socket: PathBuf::from("/run/vm_state/serial.sock"),
+            qmp_socket: PathBuf::from("/run/vm_state/qmp.sock"),
+            ssh_host: "root@fe80::200:ff:fe00:1%vm0".into(),
+            ssh_port: 22,
+            files: vec![PathBuf::from("/run/vm_state/disk0.qcow2")],
+        };
+        let path = dir.path().join("vm.json");
+        info.write(&path).expect("Failed to write info");
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).expect("Failed to read"))
+                .expect("Info is not valid json");
+        assert_eq!(written["serial_socket"], "/run/vm_state/serial.sock");
+        assert_eq!(written["qmp_socket"], "/run/vm_state/qmp.sock");
+        assert_eq!(written["pid"], 42);
+        assert_eq!(
+            serde_json::from_value::<DetachedVm>(written).expect("Failed to parse info"),
+            info
+        );
+    }
+
+    fn detached(dir: &Path, pid: u32) -> DetachedVm {
+        DetachedVm {
+            pid,
+            serial_socket: dir.join("serial.sock"),
+            qmp_socket: dir.join("qmp.sock"),
+            ssh_host: "root@fe80::200:ff:fe00:1%vm0".into(),
+            ssh_port: 22,
+            files: vec![dir.join("disk0.qcow2"), dir.join("missing")],
+        }
+    }
+
+    #[test]
+    fn test_stop() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        let file = dir.path().join("disk0.qcow2");
+        std::fs::write(&file, "").expect("Failed to write");
+        let mut child = Command::new("sleep")
+            .arg("600")
+            .spawn()
+            .expect("Failed to spawn");
+        let info = detached(dir.path(), child.id());
+        let qmp = mock_qmp(&info.qmp_socket, info.pid);
+        assert!(is_running(info.pid));
+        info.stop().expect("Failed to stop");
+        assert!(!is_running(info.pid));
+        assert!(!file.exists());
+        assert_eq!(
+            qmp.join().expect("QMP thread panicked"),
+            ["qmp_capabilities", "quit"]
+        );
+        child.wait().expect("Failed to reap");
+
+        // stopping again is harmless
+        info.stop().expect("Failed to stop");
+    }
+
+    #[test]
+    fn test_stop_without_qmp() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        // without a QMP monitor to ask, qemu is killed instead
+        let qemu = dir.path().join("qemu-system-x86_64");
+        std::fs::copy("/bin/sleep", &qemu).expect("Failed to copy");
+        let mut child = Command::new(&qemu)
+            .arg("600")
+            .spawn()
+            .expect("Failed to spawn");
+        let info = detached(dir.path(), child.id());
+        info.stop().expect("Failed to stop");
+        // SIGTERM
+        assert_eq!(child.wait().expect("Failed to reap").signal(), Some(15));
+
+        // but a reused pid is left alone
+        let mut child = Command::new("sleep")
+            .arg("600")
+            .spawn()
+            .expect("Failed to spawn");
+        let file = dir.path().join("disk0.qcow2");
+        std::fs::write(&file, "").expect("Failed to write");
+        let info = detached(dir.path(), child.id());
+        assert!(matches!(
+            info.stop(),
+            Err(DetachError::NotQemu { pid, .. }) if pid == info.pid
+        ));
+        assert!(is_running(info.pid));
+        assert!(file.exists());
+        child.kill().expect("Failed to kill");
+        child.wait().expect("Failed to reap");
+    }
+}
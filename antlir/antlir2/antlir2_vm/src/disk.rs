@@ -6,6 +6,7 @@
  */
 
 use std::ffi::OsString;
+use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
@@ -17,6 +18,8 @@ use tracing::debug;
 use crate::isolation::IsolationError;
 use crate::isolation::Platform;
 use crate::pci::PCIBridges;
+use crate::types::BlockDevOpts;
+use crate::types::DiskCacheMode;
 use crate::types::QCow2DiskOpts;
 use crate::types::QemuDevice;
 use crate::utils::run_command_capture_output;
@@ -38,8 +41,22 @@ pub(crate) struct QCow2Disk {
     id: usize,
     /// State directory
     state_dir: PathBuf,
+    /// Host cache mode. Uses qemu's default if None.
+    #[builder(default)]
+    cache: Option<DiskCacheMode>,
+    /// Preallocate the disk file instead of growing it lazily
+    #[builder(default)]
+    prealloc: bool,
+    /// Use the disk file left in the state directory by a previous run
+    /// instead of creating a new one
+    #[builder(default)]
+    reuse: bool,
 }
 
+/// O_DIRECT requires I/O to be aligned to the host's logical block size, which
+/// is at least this
+const DIRECT_IO_ALIGNMENT: usize = 512;
+
 #[derive(Debug, Error)]
 pub(crate) enum QCow2DiskError {
     #[error(transparent)]
@@ -50,6 +67,13 @@ pub(crate) enum QCow2DiskError {
     DiskCreationError(std::io::Error),
     #[error("qemu-img failed to upsize the disk: {0}")]
     DiskUpsizeError(std::io::Error),
+    #[error("Disk overlay {0} from a previous run is missing")]
+    MissingOverlayError(PathBuf),
+    #[error(
+        "cache=none requires block sizes aligned to {DIRECT_IO_ALIGNMENT} bytes, \
+        got logical {logical} and physical {physical}"
+    )]
+    UnalignedDirectIoError { logical: usize, physical: usize },
 }
 
 type Result<T> = std::result::Result<T, QCow2DiskError>;
@@ -58,12 +82,33 @@ impl QCow2DiskBuilder {
     // Create and track the temp disk before expose QCow2Disk for use
     pub(crate) fn build(&self) -> Result<QCow2Disk> {
         let mut disk = self.build_internal()?;
-        disk.create_temp_disk()?;
+        disk.validate()?;
+        if disk.reuse {
+            if !disk.disk_file_name().exists() {
+                return Err(QCow2DiskError::MissingOverlayError(disk.disk_file_name()));
+            }
+        } else {
+            disk.create_temp_disk()?;
+        }
         Ok(disk)
     }
 }
 
 impl QCow2Disk {
+    /// Check that the combination of options is usable
+    fn validate(&self) -> Result<()> {
+        if self.cache == Some(DiskCacheMode::None)
+            && (self.opts.logical_block_size % DIRECT_IO_ALIGNMENT != 0
+                || self.opts.physical_block_size % DIRECT_IO_ALIGNMENT != 0)
+        {
+            return Err(QCow2DiskError::UnalignedDirectIoError {
+                logical: self.opts.logical_block_size,
+                physical: self.opts.physical_block_size,
+            });
+        }
+        Ok(())
+    }
+
     /// Create a temporary disk with qemu-img inside state directory.
     fn create_temp_disk(&mut self) -> Result<()> {
         let mut cmd = Command::new("qemu-img");
@@ -74,15 +119,26 @@ impl QCow2Disk {
             .arg("-F")
             .arg("raw");
         if let Some(image) = &self.opts.base_image {
-            cmd.arg("-b").arg(self.format_image_path(image)?);
+            cmd.arg("-b").arg(Self::format_image_path(image)?);
+        }
+        if self.prealloc {
+            // qcow2 only allows preallocating on top of a backing file with
+            // subclusters
+            match self.opts.base_image {
+                Some(_) => cmd.arg("-o").arg("preallocation=falloc,extended_l2=on"),
+                None => cmd.arg("-o").arg("preallocation=falloc"),
+            };
         }
         run_command_capture_output(&mut cmd).map_err(QCow2DiskError::DiskCreationError)?;
 
         if let Some(size) = self.opts.free_mib {
             if size != 0 {
                 let mut cmd = Command::new("qemu-img");
-                cmd.arg("resize")
-                    .arg(self.disk_file_name().as_os_str())
+                cmd.arg("resize");
+                if self.prealloc {
+                    cmd.arg("--preallocation=falloc");
+                }
+                cmd.arg(self.disk_file_name().as_os_str())
                     .arg(&format!("+{}M", size));
                 run_command_capture_output(&mut cmd).map_err(QCow2DiskError::DiskUpsizeError)?;
             }
@@ -116,26 +172,26 @@ impl QCow2Disk {
     /// resulting image file. Override relative path to be absolute with our
     /// repo root, because all base images should be build artifacts relative
     /// to the repo root.
-    fn format_image_path(&self, path: &PathBuf) -> Result<PathBuf> {
+    pub(crate) fn format_image_path(path: &Path) -> Result<PathBuf> {
         if path.is_relative() {
             Ok(Platform::repo_root()?.join(path))
         } else {
-            Ok(path.clone())
+            Ok(path.to_path_buf())
         }
     }
 }
 
 impl QemuDevice for QCow2Disk {
     fn qemu_args(&self) -> Vec<OsString> {
-        let mut args = vec![
-            "-blockdev".into(),
-            format!(
-                "driver=qcow2,node-name={},file.driver=file,file.filename={}",
-                self.name(),
-                self.disk_file_name().to_str().expect("Invalid filename"),
-            )
-            .into(),
-        ];
+        let mut blockdev = format!(
+            "driver=qcow2,node-name={},file.driver=file,file.filename={}",
+            self.name(),
+            self.disk_file_name().to_str().expect("Invalid filename"),
+        );
+        if self.cache == Some(DiskCacheMode::None) {
+            blockdev.push_str(",cache.direct=on");
+        }
+        let mut args = vec!["-blockdev".into(), blockdev.into()];
         let mut bus = self.bus.clone();
         // Create AHCI controller for SATA drives
         if self.opts.interface == "ide-hd" {
@@ -143,8 +199,7 @@ impl QemuDevice for QCow2Disk {
             args.push(format!("ahci,id=ahci-{},bus={}", self.name(), bus).into());
             bus = format!("ahci-{}.0", self.name());
         }
-        args.push("-device".into());
-        args.push(format!(
+        let mut device = format!(
             "{driver},bus={bus},drive={name},serial={serial},physical_block_size={pbs},logical_block_size={lbs}",
             driver = self.opts.interface,
             bus = bus,
@@ -152,7 +207,14 @@ impl QemuDevice for QCow2Disk {
             serial = self.serial(),
             pbs = self.opts.physical_block_size,
             lbs = self.opts.logical_block_size,
-        ).into());
+        );
+        // -drive's cache=writethrough is the guest-visible write cache being
+        // disabled on the device
+        if self.cache == Some(DiskCacheMode::Writethrough) {
+            device.push_str(",write-cache=off");
+        }
+        args.push("-device".into());
+        args.push(device.into());
         args
     }
 }
@@ -165,6 +227,9 @@ impl QCow2Disks {
         opts: &[QCow2DiskOpts],
         pci_bridges: &PCIBridges,
         state_dir: &Path,
+        cache: Option<DiskCacheMode>,
+        prealloc: bool,
+        reuse: bool,
     ) -> Result<Self> {
         let disks: Result<Vec<_>> = opts
             .iter()
@@ -175,11 +240,19 @@ impl QCow2Disks {
                     .id(i)
                     .bus(pci_bridges.bridge_for_device_id(i).name())
                     .state_dir(state_dir.to_path_buf())
+                    .cache(cache)
+                    .prealloc(prealloc)
+                    .reuse(reuse)
                     .build()
             })
             .collect();
         Ok(Self(disks?))
     }
+
+    /// Disk overlay files in the state directory
+    pub(crate) fn files(&self) -> Vec<PathBuf> {
+        self.0.iter().map(|disk| disk.disk_file_name()).collect()
+    }
 }
 
 impl QemuDevice for QCow2Disks {
@@ -188,6 +261,107 @@ impl QemuDevice for QCow2Disks {
     }
 }
 
+/// A host file or block device passed through to the VM as-is. Unlike
+/// [QCow2Disk], writes go directly to the host path.
+#[derive(Debug)]
+pub(crate) struct RawBlockDev {
+    opts: BlockDevOpts,
+    /// The bus name to attach the device to
+    bus: String,
+    /// Unique id of this -drive
+    id: usize,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum RawBlockDevError {
+    #[error("Block device `{path}` is missing or inaccessible: {err}")]
+    MissingError { path: PathBuf, err: std::io::Error },
+    #[error("Block device `{0}` must be a regular file or a block device")]
+    InvalidTypeError(PathBuf),
+}
+
+impl RawBlockDev {
+    pub(crate) fn new(
+        opts: BlockDevOpts,
+        bus: String,
+        id: usize,
+    ) -> std::result::Result<Self, RawBlockDevError> {
+        let file_type = std::fs::metadata(&opts.path)
+            .map_err(|err| RawBlockDevError::MissingError {
+                path: opts.path.clone(),
+                err,
+            })?
+            .file_type();
+        if !file_type.is_file() && !file_type.is_block_device() {
+            return Err(RawBlockDevError::InvalidTypeError(opts.path));
+        }
+        Ok(Self { opts, bus, id })
+    }
+
+    fn name(&self) -> String {
+        format!("blockdev{}", self.id)
+    }
+}
+
+impl QemuDevice for RawBlockDev {
+    fn qemu_args(&self) -> Vec<OsString> {
+        let mut drive = OsString::from("file=");
+        // commas in the filename must be escaped by doubling them
+        drive.push(
+            self.opts
+                .path
+                .to_str()
+                .expect("Invalid filename")
+                .replace(',', ",,"),
+        );
+        drive.push(format!(",if=none,format=raw,id={}", self.name()));
+        if self.opts.read_only {
+            drive.push(",readonly=on");
+        }
+        vec![
+            "-drive".into(),
+            drive,
+            "-device".into(),
+            format!(
+                "virtio-blk,bus={bus},drive={name},serial={name}",
+                bus = self.bus,
+                name = self.name(),
+            )
+            .into(),
+        ]
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RawBlockDevs(Vec<RawBlockDev>);
+
+impl RawBlockDevs {
+    /// Attach `opts` after the first `first_device_id` PCI devices
+    pub(crate) fn new(
+        opts: &[BlockDevOpts],
+        pci_bridges: &PCIBridges,
+        first_device_id: usize,
+    ) -> std::result::Result<Self, RawBlockDevError> {
+        opts.iter()
+            .enumerate()
+            .map(|(i, x)| {
+                RawBlockDev::new(
+                    x.clone(),
+                    pci_bridges.bridge_for_device_id(first_device_id + i).name(),
+                    i,
+                )
+            })
+            .collect::<std::result::Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl QemuDevice for RawBlockDevs {
+    fn qemu_args(&self) -> Vec<OsString> {
+        self.0.iter().flat_map(|x| x.qemu_args()).collect()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::ffi::OsStr;
@@ -253,6 +427,82 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_qcow2disk_cache() {
+        let mut disk = build_test_qcow2disk(3);
+        [
+            (
+                None,
+                "-blockdev \
+                driver=qcow2,node-name=test-device3,file.driver=file,file.filename=/tmp/test/test-device3.qcow2 \
+                -device virtio-blk,bus=pci0,drive=test-device3,serial=test-device3,\
+                physical_block_size=512,logical_block_size=512",
+            ),
+            (
+                Some(DiskCacheMode::None),
+                "-blockdev \
+                driver=qcow2,node-name=test-device3,file.driver=file,file.filename=/tmp/test/test-device3.qcow2,\
+                cache.direct=on \
+                -device virtio-blk,bus=pci0,drive=test-device3,serial=test-device3,\
+                physical_block_size=512,logical_block_size=512",
+            ),
+            (
+                Some(DiskCacheMode::Writeback),
+                "-blockdev \
+                driver=qcow2,node-name=test-device3,file.driver=file,file.filename=/tmp/test/test-device3.qcow2 \
+                -device virtio-blk,bus=pci0,drive=test-device3,serial=test-device3,\
+                physical_block_size=512,logical_block_size=512",
+            ),
+            (
+                Some(DiskCacheMode::Writethrough),
+                "-blockdev \
+                driver=qcow2,node-name=test-device3,file.driver=file,file.filename=/tmp/test/test-device3.qcow2 \
+                -device virtio-blk,bus=pci0,drive=test-device3,serial=test-device3,\
+                physical_block_size=512,logical_block_size=512,write-cache=off",
+            ),
+        ]
+        .into_iter()
+        .for_each(|(cache, expected)| {
+            disk.cache = cache;
+            assert!(disk.validate().is_ok());
+            assert_eq!(&disk.qemu_args().join(OsStr::new(" ")), expected);
+        });
+
+        // O_DIRECT needs aligned I/O
+        disk.cache = Some(DiskCacheMode::None);
+        disk.opts.logical_block_size = 520;
+        assert!(matches!(
+            disk.validate(),
+            Err(QCow2DiskError::UnalignedDirectIoError {
+                logical: 520,
+                physical: 512
+            })
+        ));
+    }
+
+    #[test]
+    fn test_qcow2disk_reuse() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let mut builder = QCow2DiskBuilder::default();
+        builder
+            .opts(QCow2DiskOpts::default())
+            .bus("pci0".to_string())
+            .id(0)
+            .state_dir(dir.path().to_owned())
+            .reuse(true);
+        assert!(matches!(
+            builder.build(),
+            Err(QCow2DiskError::MissingOverlayError(path)) if path == dir.path().join("vd0.qcow2")
+        ));
+        // the previous run's disk, and any snapshots in it, are kept as-is
+        std::fs::write(dir.path().join("vd0.qcow2"), "snapshots").expect("Failed to write");
+        builder.build().expect("Failed to reuse disk");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("vd0.qcow2")).expect("Failed to read"),
+            "snapshots"
+        );
+    }
+
     #[test]
     fn test_qcow2disks() {
         let disk1 = build_test_qcow2disk(0);
@@ -271,4 +521,63 @@ mod test {
             physical_block_size=512,logical_block_size=512"
         );
     }
+
+    #[test]
+    fn test_raw_blockdevs() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let rw = dir.path().join("rw.img");
+        let ro = dir.path().join("ro,img");
+        std::fs::write(&rw, "").expect("Failed to create file");
+        std::fs::write(&ro, "").expect("Failed to create file");
+        let pci_bridges = PCIBridges::new(40).expect("Failed to create PCIBridges");
+        let blockdevs = RawBlockDevs::new(
+            &[
+                BlockDevOpts {
+                    path: rw.clone(),
+                    read_only: false,
+                },
+                BlockDevOpts {
+                    path: ro.clone(),
+                    read_only: true,
+                },
+            ],
+            &pci_bridges,
+            31,
+        )
+        .expect("Failed to create block devices");
+        assert_eq!(
+            blockdevs.qemu_args().join(OsStr::new(" ")),
+            OsString::from(format!(
+                "-drive file={rw},if=none,format=raw,id=blockdev0 \
+                -device virtio-blk,bus=pci0,drive=blockdev0,serial=blockdev0 \
+                -drive file={ro},if=none,format=raw,id=blockdev1,readonly=on \
+                -device virtio-blk,bus=pci1,drive=blockdev1,serial=blockdev1",
+                rw = rw.display(),
+                ro = ro.display().to_string().replace(',', ",,"),
+            ))
+        );
+
+        assert!(matches!(
+            RawBlockDev::new(
+                BlockDevOpts {
+                    path: dir.path().join("missing"),
+                    read_only: false,
+                },
+                "pci0".into(),
+                0,
+            ),
+            Err(RawBlockDevError::MissingError { .. })
+        ));
+        assert!(matches!(
+            RawBlockDev::new(
+                BlockDevOpts {
+                    path: dir.path().to_path_buf(),
+                    read_only: false,
+                },
+                "pci0".into(),
+                0,
+            ),
+            Err(RawBlockDevError::InvalidTypeError(_))
+        ));
+    }
 }
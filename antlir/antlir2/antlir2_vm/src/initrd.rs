@@ -0,0 +1,281 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Files added to the initrd the VM boots with, for things that must exist
+//! before the root filesystem is mounted, which is too early for any share.
+//!
+//! The kernel unpacks every cpio archive concatenated in the initrd, one
+//! after another, so the files are appended to a copy of the initrd as an
+//! uncompressed cpio archive instead of unpacking and repacking it.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::types::InitrdFile;
+
+/// newc format, which is what the kernel understands
+const CPIO_MAGIC: &str = "070701";
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+#[derive(Debug, Error)]
+pub(crate) enum InitrdError {
+    #[error("Failed to read initrd file {path}: {err}")]
+    ReadError { path: PathBuf, err: std::io::Error },
+    #[error("Failed to write initrd {path}: {err}")]
+    WriteError { path: PathBuf, err: std::io::Error },
+}
+
+type Result<T> = std::result::Result<T, InitrdError>;
+
+/// Copy of the initrd with extra files appended, in the VM state dir
+#[derive(Debug)]
+pub(crate) struct InitrdOverlay {
+    path: PathBuf,
+}
+
+impl InitrdOverlay {
+    /// Write `base` followed by a cpio archive of `files` to `state_dir`
+    pub(crate) fn new(base: &Path, files: &[InitrdFile], state_dir: &Path) -> Result<Self> {
+        let files = files
+            .iter()
+            .map(|file| {
+                let read_err = |err| InitrdError::ReadError {
+                    path: file.src.clone(),
+                    err,
+                };
+                let mode = fs::metadata(&file.src)
+                    .map_err(read_err)?
+                    .permissions()
+                    .mode();
+                let contents = fs::read(&file.src).map_err(read_err)?;
+                Ok((file.dst.as_path(), mode & 0o7777, contents))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut base = File::open(base).map_err(|err| InitrdError::ReadError {
+            path: base.to_owned(),
+            err,
+        })?;
+        let path = state_dir.join("initrd.img");
+        Self::write(&path, &mut base, &files).map_err(|err| InitrdError::WriteError {
+            path: path.clone(),
+            err,
+        })?;
+        Ok(Self { path })
+    }
+
+    fn write(path: &Path, base: &mut File, files: &[(&Path, u32, Vec<u8>)]) -> std::io::Result<()> {
+        let mut f = BufWriter::new(File::create(path)?);
+        let base_len = std::io::copy(base, &mut f)?;
+        // archives start on a 4 byte boundary, and the kernel skips the zeros
+        // in between
+        f.write_all(&[0; 3][..padding(base_len as usize)])?;
+        write_cpio(&mut f, files)?;
+        f.flush()
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Write a cpio archive of `(path, permissions, contents)` files along with
+/// all of their parent directories
+fn write_cpio(out: &mut impl Write, files: &[(&Path, u32, Vec<u8>)]) -> std::io::Result<()> {
+    let dirs: BTreeSet<_> = files
+        .iter()
+        .flat_map(|(path, _, _)| path.ancestors().skip(1))
+        .filter(|dir| *dir != Path::new("/"))
+        .collect();
+    let mut ino = 0;
+    // parents sort before their children, so they are created first
+    for dir in dirs {
+        ino += 1;
+        write_entry(out, ino, dir, S_IFDIR | 0o755, &[])?;
+    }
+    for (path, mode, contents) in files {
+        ino += 1;
+        write_entry(out, ino, path, S_IFREG | mode, contents)?;
+    }
+    write_header(out, 0, CPIO_TRAILER.as_bytes(), 0, 0)
+}
+
+fn write_entry(
+    out: &mut impl Write,
+    ino: u32,
+    path: &Path,
+    mode: u32,
+    contents: &[u8],
+) -> std::io::Result<()> {
+    // names are relative to the root of the initramfs
+    let name = path.strip_prefix("/").unwrap_or(path);
+    write_header(out, ino, name.as_os_str().as_bytes(), mode, contents.len())?;
+    out.write_all(contents)?;
+    out.write_all(&[0; 3][..padding(contents.len())])
+}
+
+fn write_header(
+    out: &mut impl Write,
+    ino: u32,
+    name: &[u8],
+    mode: u32,
+    filesize: usize,
+) -> std::io::Result<()> {
+    let nlink = match mode & S_IFDIR {
+        0 => 1,
+        _ => 2,
+    };
+    // everything is owned by root and has an mtime of 0, so that the same
+    // files always produce the same archive
+    write!(
+        out,
+        "{CPIO_MAGIC}{ino:08x}{mode:08x}{uid:08x}{gid:08x}{nlink:08x}{mtime:08x}\
+        {filesize:08x}{devmajor:08x}{devminor:08x}{rdevmajor:08x}{rdevminor:08x}\
+        {namesize:08x}{check:08x}",
+        uid = 0,
+        gid = 0,
+        mtime = 0,
+        devmajor = 0,
+        devminor = 0,
+        rdevmajor = 0,
+        rdevminor = 0,
+        namesize = name.len() + 1,
+        check = 0,
+    )?;
+    out.write_all(name)?;
+    out.write_all(b"\0")?;
+    // the header is 110 bytes, and the name has to end on a 4 byte boundary
+    out.write_all(&[0; 3][..padding(110 + name.len() + 1)])
+}
+
+/// Bytes needed after `len` bytes to get to a 4 byte boundary
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    /// Entry read back from a cpio archive
+    #[derive(Debug, PartialEq, Eq)]
+    struct Entry {
+        name: String,
+        mode: u32,
+        contents: Vec<u8>,
+    }
+
+    /// Minimal newc reader, returning everything up to the trailer
+    fn read_cpio(mut buf: &[u8]) -> Vec<Entry> {
+        let field = |header: &[u8], idx: usize| {
+            let start = 6 + idx * 8;
+            let hex = std::str::from_utf8(&header[start..start + 8]).expect("Invalid header");
+            usize::from_str_radix(hex, 16).expect("Invalid header field")
+        };
+        let mut entries = vec![];
+        loop {
+            assert_eq!(&buf[..6], CPIO_MAGIC.as_bytes(), "Bad magic");
+            let mode = field(buf, 1) as u32;
+            let filesize = field(buf, 6);
+            let namesize = field(buf, 11);
+            let name = String::from_utf8(buf[110..110 + namesize - 1].to_vec()).expect("utf8");
+            let data_start = 110 + namesize + padding(110 + namesize);
+            if name == CPIO_TRAILER {
+                return entries;
+            }
+            entries.push(Entry {
+                name,
+                mode,
+                contents: buf[data_start..data_start + filesize].to_vec(),
+            });
+            buf = &buf[data_start + filesize + padding(filesize)..];
+        }
+    }
+
+    #[test]
+    fn test_initrd_overlay() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        let base = dir.path().join("base.img");
+        // not a multiple of 4, so that the archive has to be padded
+        fs::write(&base, b"compressed").expect("Failed to write");
+        let key = dir.path().join("key");
+        fs::write(&key, b"secret\n").expect("Failed to write");
+        fs::set_permissions(&key, fs::Permissions::from_mode(0o600)).expect("Failed to chmod");
+        let script = dir.path().join("hook");
+        fs::write(&script, b"#!/bin/sh\n").expect("Failed to write");
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).expect("Failed to chmod");
+
+        let files = vec![
+            InitrdFile {
+                src: key,
+                dst: "/etc/early/key".into(),
+            },
+            InitrdFile {
+                src: script,
+                dst: "/usr/lib/hook".into(),
+            },
+        ];
+        let overlay =
+            InitrdOverlay::new(&base, &files, dir.path()).expect("Failed to create overlay");
+        assert_eq!(overlay.path(), dir.path().join("initrd.img"));
+
+        let written = fs::read(overlay.path()).expect("Failed to read");
+        assert_eq!(&written[..10], b"compressed");
+        assert_eq!(&written[10..12], &[0, 0]);
+        let dir_entry = |name: &str| Entry {
+            name: name.to_owned(),
+            mode: S_IFDIR | 0o755,
+            contents: vec![],
+        };
+        assert_eq!(
+            read_cpio(&written[12..]),
+            vec![
+                dir_entry("etc"),
+                dir_entry("etc/early"),
+                dir_entry("usr"),
+                dir_entry("usr/lib"),
+                Entry {
+                    name: "etc/early/key".to_owned(),
+                    mode: S_IFREG | 0o600,
+                    contents: b"secret\n".to_vec(),
+                },
+                Entry {
+                    name: "usr/lib/hook".to_owned(),
+                    mode: S_IFREG | 0o755,
+                    contents: b"#!/bin/sh\n".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        let base = dir.path().join("base.img");
+        fs::write(&base, b"").expect("Failed to write");
+        let files = vec![InitrdFile {
+            src: dir.path().join("missing"),
+            dst: "/etc/key".into(),
+        }];
+        assert!(matches!(
+            InitrdOverlay::new(&base, &files, dir.path()),
+            Err(InitrdError::ReadError { path, .. }) if path == dir.path().join("missing"),
+        ));
+    }
+}
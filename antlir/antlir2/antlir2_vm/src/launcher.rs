@@ -0,0 +1,80 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Abstraction over actually starting the VMM process, so that everything
+//! leading up to it can be exercised without a real qemu.
+
+use std::fmt::Debug;
+use std::process::Child;
+use std::process::Command;
+
+use tracing::debug;
+
+use crate::utils::log_command_redacted;
+use crate::utils::qemu_args_to_shell;
+use crate::utils::REDACTED;
+
+/// Launches the VMM process from a fully assembled `Command`
+pub(crate) trait VmmLauncher: Debug + Send {
+    /// Spawn the VMM. The command already has all qemu args and stdio
+    /// redirection applied.
+    fn launch(&self, command: &mut Command) -> std::io::Result<Child>;
+}
+
+/// Spawns qemu for real
+#[derive(Debug, Default)]
+pub(crate) struct RealQemuLauncher;
+
+impl VmmLauncher for RealQemuLauncher {
+    fn launch(&self, command: &mut Command) -> std::io::Result<Child> {
+        let args: Vec<_> = command
+            .get_args()
+            .map(|arg| match is_qemu_secret(&arg.to_string_lossy()) {
+                true => REDACTED.into(),
+                false => arg.to_owned(),
+            })
+            .collect();
+        debug!(
+            "To reproduce: {}",
+            qemu_args_to_shell(command.get_program(), &args)
+        );
+        log_command_redacted(command, is_qemu_secret).spawn()
+    }
+}
+
+/// qemu takes secrets (like disk encryption keys) inline as
+/// `-object secret,id=..,data=..`, which must not end up in logs
+fn is_qemu_secret(arg: &str) -> bool {
+    arg.starts_with("secret,") && arg.split(',').any(|opt| opt.starts_with("data="))
+}
+
+/// Program and args of a command seen by `FakeLauncher`
+#[cfg(test)]
+pub(crate) type LaunchedCommand = (std::ffi::OsString, Vec<std::ffi::OsString>);
+
+#[cfg(test)]
+/// Records every command it is asked to launch and spawns `true` in its place.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct FakeLauncher {
+    pub(crate) launched: std::sync::Arc<std::sync::Mutex<Vec<LaunchedCommand>>>,
+    /// Spawn a long `sleep` instead, for a VM that has to stay up
+    pub(crate) keep_running: bool,
+}
+
+#[cfg(test)]
+impl VmmLauncher for FakeLauncher {
+    fn launch(&self, command: &mut Command) -> std::io::Result<Child> {
+        self.launched.lock().expect("Poisoned lock").push((
+            command.get_program().to_owned(),
+            command.get_args().map(|x| x.to_owned()).collect(),
+        ));
+        match self.keep_running {
+            true => Command::new("sleep").arg("600").spawn(),
+            false => Command::new("true").spawn(),
+        }
+    }
+}
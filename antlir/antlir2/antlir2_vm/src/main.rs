@@ -5,16 +5,25 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+mod agent;
+mod bench;
+mod detach;
 mod disk;
+mod initrd;
 mod isolation;
+mod launcher;
 mod net;
 mod pci;
+mod preflight;
+mod qmp;
 mod share;
 mod ssh;
+mod teardown;
 mod tpm;
 mod types;
 mod utils;
 mod vm;
+mod watchdog;
 
 use std::collections::HashSet;
 use std::env;
@@ -37,8 +46,10 @@ use tracing::debug;
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::prelude::*;
 
+use crate::detach::DetachedVm;
 use crate::isolation::isolated;
 use crate::isolation::Platform;
+use crate::preflight::preflight;
 use crate::share::NinePShare;
 use crate::share::VirtiofsShare;
 use crate::types::MachineOpts;
@@ -67,6 +78,8 @@ enum Commands {
     Isolate(IsolateCmdArgs),
     /// Run VM tests inside container.
     Test(IsolateCmdArgs),
+    /// Stop a VM started with `run --detach` and clean up after it.
+    Stop(StopCmdArgs),
 }
 
 /// Execute the VM
@@ -82,6 +95,16 @@ struct RunCmdArgs {
     /// available at env $CONSOLE_OUTPUT.
     #[clap(long)]
     postmortem: bool,
+    /// Check that the environment can run the VM (KVM, qemu and virtiofsd
+    /// binaries, state dir, memory backend) before creating anything, and
+    /// fail with a report of every problem found.
+    #[clap(long)]
+    preflight: bool,
+    /// Print the unit files generated for the shares instead of booting the
+    /// VM, to check the share configuration. They are also left in the given
+    /// directory, if any.
+    #[clap(long, value_name = "DIR", num_args = 0..=1)]
+    dump_unit_files: Option<Option<PathBuf>>,
     #[clap(flatten)]
     vm_args: VMArgs,
 }
@@ -106,6 +129,14 @@ struct IsolateCmdArgs {
     run_cmd_args: RunCmdArgs,
 }
 
+/// Stop a detached VM
+#[derive(Debug, Args)]
+struct StopCmdArgs {
+    /// Info file written by `--detach`
+    #[arg(long)]
+    info: JsonFile<DetachedVm>,
+}
+
 /// Actually starting the VM. This needs to be inside an ephemeral container as
 /// lots of resources relies on container for clean up.
 fn run(args: &RunCmdArgs) -> Result<()> {
@@ -115,6 +146,40 @@ fn run(args: &RunCmdArgs) -> Result<()> {
     // Respect user's decision whether to use host's platform or not.
     Platform::set(&args.machine_spec.mount_platform)?;
 
+    if let Some(dir) = &args.dump_unit_files {
+        let state_dir = tempdir().context("Failed to create temp dir for shares")?;
+        let unit_files_dir = dir
+            .clone()
+            .unwrap_or_else(|| state_dir.path().join("mount_units"));
+        let mut out = std::io::stdout().lock();
+        if args.machine_spec.use_legacy_share {
+            VM::<NinePShare>::dump_unit_files(
+                &args.machine_spec,
+                &args.vm_args,
+                state_dir.path(),
+                unit_files_dir,
+                &mut out,
+            )?;
+        } else {
+            VM::<VirtiofsShare>::dump_unit_files(
+                &args.machine_spec,
+                &args.vm_args,
+                state_dir.path(),
+                unit_files_dir,
+                &mut out,
+            )?;
+        }
+        return Ok(());
+    }
+
+    if args.preflight {
+        let report = preflight(&args.machine_spec);
+        if !report.is_ok() {
+            bail!("{report}");
+        }
+        debug!("{report}");
+    }
+
     let mut vm_args = args.vm_args.clone();
     if args.postmortem {
         if args.vm_args.console_output_file.is_none() {
@@ -145,6 +210,7 @@ fn run(args: &RunCmdArgs) -> Result<()> {
                 VMError::BootError { .. }
                 | VMError::EarlyTerminationError(_)
                 | VMError::SSHCommandResultError(_)
+                | VMError::WatchdogError(_)
                 | VMError::RunError(_) => debug!("VM failed with expected error: {:?}", e),
                 _ => bail!("VM failed with unexpected error: {:?}", e),
             },
@@ -190,12 +256,27 @@ fn respawn(args: &IsolateCmdArgs) -> Result<()> {
     // It may then decide whether to use host's platform for the actual test.
     Platform::set(&MountPlatformDecision(true))?;
 
+    // the container only lives as long as this process, and qemu dies with it
+    if args.run_cmd_args.vm_args.mode.detach.is_some() {
+        bail!(
+            "--detach is only supported by `run` inside an existing container, \
+            the container created here would stop the VM as soon as it exits"
+        );
+    }
+
     let mut vm_args = args.run_cmd_args.vm_args.clone();
     let envs = env_names_to_kvpairs(args.passenv.clone());
     vm_args.command_envs = envs.clone();
     if let Some(scratch_dir) = args.scratch_dir.as_ref() {
         vm_args.output_dirs.push(scratch_dir.clone());
     }
+    // shares have to be absolute, and the working directory inside the
+    // container can't be relied on to resolve them
+    for dir in vm_args.output_dirs.iter_mut().filter(|d| d.is_relative()) {
+        *dir = dir
+            .canonicalize()
+            .with_context(|| format!("while canonicalizing output dir {}", dir.display()))?;
+    }
 
     // Let's always capture console output unless it's console mode
     let _console_dir;
@@ -205,6 +286,27 @@ fn respawn(args: &IsolateCmdArgs) -> Result<()> {
         _console_dir = dir;
     }
 
+    // the guest's results and the disks holding snapshots have to make it out
+    // of the container, and they are mounted at the same (absolute) path
+    // inside it
+    for dir in [&mut vm_args.results_dir, &mut vm_args.snapshot_dir]
+        .into_iter()
+        .flatten()
+    {
+        std::fs::create_dir_all(&*dir)
+            .with_context(|| format!("while creating {}", dir.display()))?;
+        *dir = dir
+            .canonicalize()
+            .with_context(|| format!("while canonicalizing {}", dir.display()))?;
+    }
+
+    // the unit files have to make it out of the container
+    let dump_dir = args.run_cmd_args.dump_unit_files.clone().flatten();
+    if let Some(dir) = &dump_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("while creating {}", dir.display()))?;
+    }
+
     antlir2_rootless::unshare_new_userns()?;
     antlir2_isolate::unshare_and_privatize_mount_ns().context("while isolating mount ns")?;
 
@@ -215,6 +317,7 @@ fn respawn(args: &IsolateCmdArgs) -> Result<()> {
             .get_container_output_dirs()
             .into_iter()
             .chain(writable_devices())
+            .chain(dump_dir.clone())
             .collect(),
     )?;
     let exe = env::current_exe().context("while getting argv[0]")?;
@@ -222,8 +325,20 @@ fn respawn(args: &IsolateCmdArgs) -> Result<()> {
     command
         .arg("run")
         .arg("--machine-spec")
-        .arg(args.run_cmd_args.machine_spec.path())
-        .args(vm_args.to_args());
+        .arg(args.run_cmd_args.machine_spec.path());
+    if args.run_cmd_args.preflight {
+        command.arg("--preflight");
+    }
+    match &args.run_cmd_args.dump_unit_files {
+        Some(Some(dir)) => {
+            command.arg("--dump-unit-files").arg(dir);
+        }
+        Some(None) => {
+            command.arg("--dump-unit-files");
+        }
+        None => {}
+    }
+    command.args(vm_args.to_args());
 
     let status = log_command(&mut command).status()?;
     if !status.success() {
@@ -380,6 +495,9 @@ fn vm_test_command(args: &IsolateCmdArgs, validated_args: &ValidatedVMArgs) -> R
     if args.run_cmd_args.postmortem {
         command.arg("--postmortem");
     }
+    if args.run_cmd_args.preflight {
+        command.arg("--preflight");
+    }
     command.args(validated_args.inner.to_args());
     Ok(command)
 }
@@ -435,6 +553,7 @@ fn main() -> Result<()> {
         Commands::Isolate(args) => respawn(args),
         Commands::Run(args) => run(args),
         Commands::Test(args) => test(args),
+        Commands::Stop(args) => Ok(args.info.stop()?),
     }
 }
 
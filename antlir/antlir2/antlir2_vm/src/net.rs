@@ -8,6 +8,7 @@
 //! This file contains structs that create network interfaces for the VM. All code
 //! here should only be run inside a container.
 
+use std::collections::HashSet;
 use std::ffi::OsString;
 use std::net::Ipv6Addr;
 use std::ops::Index;
@@ -17,10 +18,15 @@ use std::process::Command;
 
 use thiserror::Error;
 
+use crate::types::NetdevBackend;
+use crate::types::NicOpts;
 use crate::types::QemuDevice;
 use crate::utils::format_command;
 use crate::utils::log_command;
 
+/// Device model used unless another one is requested
+const DEFAULT_MODEL: &str = "virtio-net-pci";
+
 #[derive(Debug)]
 /// Create and presents a virtual NIC to VM
 pub(crate) struct VirtualNIC {
@@ -30,6 +36,12 @@ pub(crate) struct VirtualNIC {
     max_combined_channels: usize,
     /// Dump interface traffic to this file. This is not supported for multi-queue NICs.
     dump_file: Option<PathBuf>,
+    /// Overrides the default MAC derived from the ID
+    mac: Option<String>,
+    /// qemu device model presented to the VM
+    model: String,
+    /// Host side of the NIC
+    backend: NetdevBackend,
 }
 
 #[derive(Error, Debug)]
@@ -40,6 +52,10 @@ pub(crate) enum VirtualNICError {
     IPCmdReturnError(String),
     #[error("Traffic is not dumpable: `{0}` ")]
     TrafficDumpingNotSupported(String),
+    #[error("MAC address {0} is used by more than one NIC")]
+    DuplicateMac(String),
+    #[error("NIC id {0} is used by more than one NIC")]
+    DuplicateId(String),
 }
 
 type Result<T> = std::result::Result<T, VirtualNICError>;
@@ -47,19 +63,35 @@ type Result<T> = std::result::Result<T, VirtualNICError>;
 impl VirtualNIC {
     /// Create new VirtualNIC instance with assigned ID and max combined channels. The virtual NIC won't be
     /// created yet.
+    #[cfg(test)]
     pub(crate) fn new(id: usize, max_combined_channels: usize) -> Self {
+        Self::from_opts(id, max_combined_channels, &NicOpts::default())
+    }
+
+    /// Like [VirtualNIC::new], but with the model, MAC and backend from `opts`
+    pub(crate) fn from_opts(id: usize, max_combined_channels: usize, opts: &NicOpts) -> Self {
         Self {
             id,
             max_combined_channels,
             dump_file: None,
+            mac: opts.mac.map(|mac| mac.to_string()),
+            model: opts
+                .model
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MODEL.to_owned()),
+            backend: opts.netdev.unwrap_or_default(),
         }
     }
 
-    /// Create the virtual NIC and assign an IP
+    /// Create the virtual NIC and assign an IP. Only tap backed NICs have
+    /// anything to create on the host.
     pub(crate) fn create_dev(&self) -> Result<()> {
+        if self.backend != NetdevBackend::Tap {
+            return Ok(());
+        }
         let dev_name = self.dev_name();
         let mut ip_command = vec!["tuntap", "add", "dev", &dev_name, "mode", "tap"];
-        if self.max_combined_channels > 1 {
+        if self.queues() > 1 {
             ip_command.push("multi_queue");
         }
         self.ip_command(&ip_command)?;
@@ -98,10 +130,27 @@ impl VirtualNIC {
             })
     }
 
+    /// MAC address presented to the VM. If it was overridden, the VM won't be
+    /// able to pre-configure its network based on the MAC anymore.
+    fn mac(&self) -> String {
+        match &self.mac {
+            Some(mac) => mac.clone(),
+            None => self.guest_mac(),
+        }
+    }
+
+    /// Number of queue pairs. Only tap supports multi-queue.
+    fn queues(&self) -> usize {
+        match self.backend {
+            NetdevBackend::Tap => self.max_combined_channels,
+            NetdevBackend::User => 1,
+        }
+    }
+
     /// Set the file to dump interface traffic to.
     /// Set to None to disable dumping traffic.
     pub(crate) fn try_dump_file(&mut self, path: Option<PathBuf>) -> Result<&mut Self> {
-        if path.is_some() && self.max_combined_channels > 1 {
+        if path.is_some() && self.queues() > 1 {
             return Err(VirtualNICError::TrafficDumpingNotSupported(
                 "Can not dump traffic for multi-queue NIC: https://fburl.com/dmblggwc".into(),
             ));
@@ -134,32 +183,37 @@ impl VirtualNIC {
 
 impl QemuDevice for VirtualNIC {
     fn qemu_args(&self) -> Vec<OsString> {
-        let mut vec: Vec<_> = [
-            "-netdev",
-            &format!(
+        let netdev = match self.backend {
+            NetdevBackend::Tap => format!(
                 "tap,id={dev_id},ifname={dev_name},script=no,downscript=no,queues={queues}",
                 dev_id = self.dev_id(),
                 dev_name = self.dev_name(),
-                queues = self.max_combined_channels,
+                queues = self.queues(),
             ),
-            "-device",
-            &format!(
-                "virtio-net-pci,netdev={dev_id},mac={mac},mq={mq},vectors={vectors}",
-                dev_id = self.dev_id(),
-                mac = self.guest_mac(),
-                mq = if self.max_combined_channels > 1 {
-                    "on"
-                } else {
-                    "off"
-                },
+            NetdevBackend::User => format!("user,id={}", self.dev_id()),
+        };
+        let mut device = format!(
+            "{model},netdev={dev_id},mac={mac}",
+            model = self.model,
+            dev_id = self.dev_id(),
+            mac = self.mac(),
+        );
+        // multi-queue options only exist for virtio NICs
+        if self.model.starts_with("virtio-net") {
+            device.push_str(&format!(
+                ",mq={mq},vectors={vectors}",
+                mq = if self.queues() > 1 { "on" } else { "off" },
                 // N for TX queues, N for RX queues, 2 for config, and 1 for possible control vq, where N = max_combined_channels
                 // https://fburl.com/knmbw1a1
-                vectors = self.max_combined_channels * 2 + 2,
-            ),
-        ]
-        .iter()
-        .map(|x| x.into())
-        .collect();
+                vectors = self.queues() * 2 + 2,
+            ));
+        }
+        let mut vec: Vec<OsString> = vec![
+            "-netdev".into(),
+            netdev.into(),
+            "-device".into(),
+            device.into(),
+        ];
         if let Some(path) = &self.dump_file {
             vec.extend(
                 [
@@ -184,15 +238,33 @@ impl QemuDevice for VirtualNIC {
 pub(crate) struct VirtualNICs(Vec<VirtualNIC>);
 
 impl VirtualNICs {
-    pub(crate) fn new(count: usize, max_combined_channels: usize) -> Result<Self> {
-        let nics: Result<Vec<_>> = (0..count)
-            .map(|x| -> Result<VirtualNIC> {
-                let nic = VirtualNIC::new(x, max_combined_channels);
-                nic.create_dev()?;
-                Ok(nic)
-            })
-            .collect();
-        Ok(Self(nics?))
+    /// Create one NIC for each of `opts`, in order
+    pub(crate) fn new(opts: &[NicOpts], max_combined_channels: usize) -> Result<Self> {
+        let nics = Self(
+            opts.iter()
+                .enumerate()
+                .map(|(id, opts)| VirtualNIC::from_opts(id, max_combined_channels, opts))
+                .collect(),
+        );
+        nics.validate()?;
+        nics.0.iter().try_for_each(|nic| nic.create_dev())?;
+        Ok(nics)
+    }
+
+    /// Every NIC needs its own id and MAC, or qemu and the guest will mix
+    /// them up
+    fn validate(&self) -> Result<()> {
+        let mut ids = HashSet::new();
+        let mut macs = HashSet::new();
+        for nic in &self.0 {
+            if !ids.insert(nic.dev_id()) {
+                return Err(VirtualNICError::DuplicateId(nic.dev_id()));
+            }
+            if !macs.insert(nic.mac()) {
+                return Err(VirtualNICError::DuplicateMac(nic.mac()));
+            }
+        }
+        Ok(())
     }
 
     pub(crate) fn len(&self) -> usize {
@@ -254,6 +326,23 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_qemu_args_with_mac() {
+        let nic = VirtualNIC::from_opts(
+            0,
+            1,
+            &NicOpts {
+                mac: Some("02:00:5e:10:00:01".parse().expect("Invalid MAC")),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            nic.qemu_args().join(OsStr::new(" ")),
+            "-netdev tap,id=net0,ifname=vm0,script=no,downscript=no,queues=1 \
+            -device virtio-net-pci,netdev=net0,mac=02:00:5e:10:00:01,mq=off,vectors=4"
+        )
+    }
+
     // This test is to make sure that the dump file is added to the qemu args when it's supported (single-queue nic)
     // and the dump file is not None.
     #[test]
@@ -338,6 +427,57 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_multiple_nics() {
+        let opts: Vec<NicOpts> = ["", "model=e1000,mac=02:00:5e:10:00:01,netdev=user"]
+            .iter()
+            .map(|x| x.parse().expect("Invalid NIC"))
+            .collect();
+        let nics = VirtualNICs(
+            opts.iter()
+                .enumerate()
+                .map(|(id, opts)| VirtualNIC::from_opts(id, 4, opts))
+                .collect(),
+        );
+        assert!(nics.validate().is_ok());
+        assert_eq!(
+            nics.qemu_args().join(OsStr::new(" ")),
+            "-netdev tap,id=net0,ifname=vm0,script=no,downscript=no,queues=4 \
+             -device virtio-net-pci,netdev=net0,mac=00:00:00:00:00:01,mq=on,vectors=10 \
+             -netdev user,id=net1 \
+             -device e1000,netdev=net1,mac=02:00:5e:10:00:01"
+        );
+        // user mode networking has nothing to create on the host
+        assert!(nics[1].create_dev().is_ok());
+    }
+
+    #[test]
+    fn test_nics_validate() {
+        // the second NIC explicitly uses the default MAC of the first one
+        let nics = VirtualNICs(vec![
+            VirtualNIC::new(0, 1),
+            VirtualNIC::from_opts(1, 1, &"mac=00:00:00:00:00:01".parse().expect("Invalid NIC")),
+        ]);
+        assert!(matches!(
+            nics.validate(),
+            Err(VirtualNICError::DuplicateMac(mac)) if mac == "00:00:00:00:00:01"
+        ));
+
+        let nics = VirtualNICs(vec![VirtualNIC::new(0, 1), VirtualNIC::new(0, 1)]);
+        assert!(matches!(
+            nics.validate(),
+            Err(VirtualNICError::DuplicateMac(_)) | Err(VirtualNICError::DuplicateId(_))
+        ));
+        let nics = VirtualNICs(vec![
+            VirtualNIC::new(0, 1),
+            VirtualNIC::from_opts(0, 1, &"mac=02:00:5e:10:00:01".parse().expect("Invalid NIC")),
+        ]);
+        assert!(matches!(
+            nics.validate(),
+            Err(VirtualNICError::DuplicateId(id)) if id == "net0"
+        ));
+    }
+
     #[test]
     fn test_nics_access() {
         let nics = VirtualNICs(vec![VirtualNIC::new(0, 1), VirtualNIC::new(1, 128)]);
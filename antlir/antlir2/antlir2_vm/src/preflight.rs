@@ -0,0 +1,299 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Check that the environment is able to run the VM before creating any
+//! resources. Otherwise problems like a missing `/dev/kvm` only show up as an
+//! obscure qemu error after everything else has been set up.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::share::hugepage_size_mib;
+use crate::share::HUGEPAGES_PATH;
+use crate::share::MEMINFO_PATH;
+use crate::share::VIRTIOFSD_PATH;
+use crate::types::CpuIsa;
+use crate::types::MachineOpts;
+use crate::vm::STATE_DIR;
+
+/// Check everything that `machine` needs to run
+pub(crate) fn preflight(machine: &MachineOpts) -> PreflightReport {
+    Preflight::new(machine).run()
+}
+
+/// Everything that preflight checks, with the paths that the VM will actually
+/// use. Tests can point these elsewhere.
+#[derive(Debug, Clone)]
+pub(crate) struct Preflight {
+    /// KVM device, if the VM will be hardware accelerated
+    kvm: Option<PathBuf>,
+    /// Name or path of the qemu binary
+    qemu: PathBuf,
+    /// virtiofsd binary, if virtiofs shares are used
+    virtiofsd: Option<PathBuf>,
+    /// State dir that will be created for the VM
+    state_dir: PathBuf,
+    /// Memory size of the VM
+    mem_mib: usize,
+    /// Hugepages mount, if VM memory is backed by hugepages
+    hugepages: Option<PathBuf>,
+    /// Used to find the size of the hugepages
+    meminfo: PathBuf,
+    /// Used to check that enough free hugepages of that size are reserved
+    sys_hugepages: PathBuf,
+}
+
+/// Every problem found by [Preflight::run]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct PreflightReport {
+    pub(crate) problems: Vec<String>,
+}
+
+impl PreflightReport {
+    pub(crate) fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "VM preflight checks passed");
+        }
+        write!(f, "VM preflight found {} problem(s):", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Preflight {
+    /// Checks for everything that `machine` is going to need
+    pub(crate) fn new(machine: &MachineOpts) -> Self {
+        let host_arch = CpuIsa::from_str(env::consts::ARCH).expect("unknown cpu architecture");
+        Self {
+            // kvm is only used when not emulating another architecture
+            kvm: (host_arch == machine.arch).then(|| "/dev/kvm".into()),
+            qemu: machine.arch.qemu_binary().into(),
+            virtiofsd: (!machine.use_legacy_share).then(|| VIRTIOFSD_PATH.into()),
+            state_dir: STATE_DIR.into(),
+            mem_mib: machine.mem_mib,
+            hugepages: machine.use_hugepages.then(|| HUGEPAGES_PATH.into()),
+            meminfo: MEMINFO_PATH.into(),
+            sys_hugepages: "/sys/kernel/mm/hugepages".into(),
+        }
+    }
+
+    /// Run every check, collecting all the problems instead of stopping at
+    /// the first one
+    pub(crate) fn run(&self) -> PreflightReport {
+        let mut problems = vec![];
+        if let Some(kvm) = &self.kvm {
+            if let Err(e) = OpenOptions::new().read(true).write(true).open(kvm) {
+                problems.push(format!("KVM is not available at {}: {e}", kvm.display()));
+            }
+        }
+        if let Err(e) = check_executable(&self.qemu) {
+            problems.push(format!("qemu binary {}: {e}", self.qemu.display()));
+        }
+        if let Some(virtiofsd) = &self.virtiofsd {
+            if let Err(e) = check_executable(virtiofsd) {
+                problems.push(format!("virtiofsd binary {}: {e}", virtiofsd.display()));
+            }
+        }
+        if let Err(e) = self.check_state_dir() {
+            problems.push(format!("state dir {}: {e}", self.state_dir.display()));
+        }
+        if let Some(hugepages) = &self.hugepages {
+            if let Err(e) = self.check_hugepages(hugepages) {
+                problems.push(format!("hugepages memory backend: {e}"));
+            }
+        }
+        PreflightReport { problems }
+    }
+
+    /// The state dir must not exist yet and its parent must be writable
+    fn check_state_dir(&self) -> std::result::Result<(), String> {
+        if self.state_dir.exists() {
+            return Err("already exists".into());
+        }
+        let parent = self
+            .state_dir
+            .parent()
+            .ok_or_else(|| "has no parent".to_string())?;
+        tempfile::tempdir_in(parent)
+            .map(|_| ())
+            .map_err(|e| format!("{} is not writable: {e}", parent.display()))
+    }
+
+    fn check_hugepages(&self, hugepages: &Path) -> std::result::Result<(), String> {
+        if !hugepages.is_dir() {
+            return Err(format!("{} is not mounted", hugepages.display()));
+        }
+        let size = hugepage_size_mib(&self.meminfo).map_err(|e| e.to_string())?;
+        if self.mem_mib % size != 0 {
+            return Err(format!(
+                "memory size {}M is not a multiple of the hugepage size {size}M",
+                self.mem_mib
+            ));
+        }
+        let needed = self.mem_mib / size;
+        let free_hugepages = self
+            .sys_hugepages
+            .join(format!("hugepages-{}kB/free_hugepages", size * 1024));
+        let free: usize = fs::read_to_string(&free_hugepages)
+            .map_err(|e| format!("failed to read {}: {e}", free_hugepages.display()))?
+            .trim()
+            .parse()
+            .map_err(|e| format!("failed to parse {}: {e}", free_hugepages.display()))?;
+        if free < needed {
+            return Err(format!(
+                "{needed} free hugepages are needed for {}M of memory, but only {free} are available",
+                self.mem_mib
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Check that `bin` is an executable file, looking it up in PATH if it is
+/// just a name
+fn check_executable(bin: &Path) -> std::result::Result<(), String> {
+    let is_path = bin.components().count() > 1;
+    let candidates: Vec<PathBuf> = if is_path {
+        vec![bin.to_path_buf()]
+    } else {
+        env::var_os("PATH")
+            .map(|path| env::split_paths(&path).map(|dir| dir.join(bin)).collect())
+            .unwrap_or_default()
+    };
+    for candidate in &candidates {
+        if let Ok(meta) = fs::metadata(candidate) {
+            if meta.is_file() && meta.permissions().mode() & 0o111 != 0 {
+                return Ok(());
+            }
+        }
+    }
+    if !is_path {
+        Err("not found in PATH".into())
+    } else if bin.exists() {
+        Err("is not an executable file".into())
+    } else {
+        Err("does not exist".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn test_preflight(dir: &Path) -> Preflight {
+        Preflight {
+            kvm: None,
+            qemu: "true".into(),
+            virtiofsd: Some("/bin/true".into()),
+            state_dir: dir.join("vm_state"),
+            mem_mib: 4096,
+            hugepages: None,
+            meminfo: dir.join("meminfo"),
+            sys_hugepages: dir.to_path_buf(),
+        }
+    }
+
+    #[test]
+    fn test_preflight_ok() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        let report = test_preflight(dir.path()).run();
+        assert!(report.is_ok(), "{report}");
+    }
+
+    #[test]
+    fn test_preflight_missing_binary() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        let preflight = Preflight {
+            qemu: "qemu-system-does-not-exist".into(),
+            virtiofsd: Some(dir.path().join("virtiofsd")),
+            ..test_preflight(dir.path())
+        };
+        let report = preflight.run();
+        assert_eq!(
+            report.problems,
+            vec![
+                "qemu binary qemu-system-does-not-exist: not found in PATH".to_string(),
+                format!(
+                    "virtiofsd binary {}: does not exist",
+                    dir.path().join("virtiofsd").display()
+                ),
+            ]
+        );
+        assert!(report
+            .to_string()
+            .starts_with("VM preflight found 2 problem(s):"));
+
+        // exists, but can't be run
+        fs::write(dir.path().join("virtiofsd"), "").expect("Failed to write");
+        let report = preflight.run();
+        assert!(
+            report.problems[1].ends_with("is not an executable file"),
+            "{report}"
+        );
+    }
+
+    #[test]
+    fn test_preflight_state_dir_and_memory() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        fs::create_dir(dir.path().join("vm_state")).expect("Failed to create dir");
+        fs::write(dir.path().join("meminfo"), "Hugepagesize:       2048 kB\n")
+            .expect("Failed to write");
+        let free_hugepages = dir.path().join("hugepages-2048kB/free_hugepages");
+        fs::create_dir(dir.path().join("hugepages-2048kB")).expect("Failed to create dir");
+        fs::write(&free_hugepages, "1024\n").expect("Failed to write");
+        let preflight = Preflight {
+            kvm: Some(dir.path().join("kvm")),
+            hugepages: Some(dir.path().to_path_buf()),
+            ..test_preflight(dir.path())
+        };
+        let report = preflight.run();
+        assert_eq!(report.problems.len(), 3, "{report}");
+        assert!(report.problems[0].starts_with("KVM is not available"));
+        assert!(report.problems[1].ends_with("already exists"));
+        assert_eq!(
+            report.problems[2],
+            "hugepages memory backend: 2048 free hugepages are needed for 4096M of memory, but only 1024 are available"
+        );
+
+        fs::write(&free_hugepages, "2048\n").expect("Failed to write");
+        let report = Preflight {
+            mem_mib: 4095,
+            ..preflight.clone()
+        }
+        .run();
+        assert!(report.problems[2].contains("not a multiple"), "{report}");
+        let report = preflight.run();
+        assert_eq!(report.problems.len(), 2, "{report}");
+
+        // the hugepages are whatever size the host uses by default
+        fs::write(dir.path().join("meminfo"), "Hugepagesize:    1048576 kB\n")
+            .expect("Failed to write");
+        fs::create_dir(dir.path().join("hugepages-1048576kB")).expect("Failed to create dir");
+        fs::write(dir.path().join("hugepages-1048576kB/free_hugepages"), "2\n")
+            .expect("Failed to write");
+        assert_eq!(
+            preflight.run().problems[2],
+            "hugepages memory backend: 4 free hugepages are needed for 4096M of memory, but only 2 are available"
+        );
+    }
+}
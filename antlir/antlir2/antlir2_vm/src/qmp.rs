@@ -0,0 +1,397 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Client for the QEMU Machine Protocol (QMP), which qemu exposes as a unix
+//! socket in the VM state dir. Every message is a single line of JSON. Only
+//! the parts needed to save and load VM snapshots are implemented. QMP has no
+//! dedicated synchronous command for that, so it goes through the
+//! `human-monitor-command` passthrough to `savevm` and `loadvm`, which report
+//! failures as non-empty output.
+
+use std::ffi::OsString;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::json;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::types::QemuDevice;
+
+#[derive(Debug, Error)]
+pub(crate) enum QmpError {
+    #[error("Failed to connect to QMP socket {path}: {err}")]
+    ConnectError { path: PathBuf, err: std::io::Error },
+    #[error("Failed to talk to QMP: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("Invalid QMP message: {0}")]
+    ProtocolError(String),
+    #[error("QMP closed the connection before responding to `{0}`")]
+    ClosedError(String),
+    #[error("QMP command `{command}` failed: {desc}")]
+    CommandError { command: String, desc: String },
+    #[error("Invalid snapshot name `{0}`, it must be non-empty and have no whitespace")]
+    InvalidSnapshotName(String),
+    #[error("Snapshot `{0}` does not exist on the VM's disks")]
+    SnapshotNotFound(String),
+}
+
+type Result<T> = std::result::Result<T, QmpError>;
+
+/// Snapshot names are passed through the human monitor, which splits its
+/// arguments on whitespace
+pub(crate) fn validate_snapshot_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(QmpError::InvalidSnapshotName(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// QMP monitor of the VM. A monitor only serves one client at a time, so
+/// every user that stays connected needs its own.
+#[derive(Debug)]
+pub(crate) struct QmpChannel {
+    /// qemu is listening on it
+    socket_path: PathBuf,
+}
+
+impl QmpChannel {
+    pub(crate) fn new(state_dir: &Path, name: &str) -> Self {
+        Self {
+            socket_path: state_dir.join(format!("{name}.sock")),
+        }
+    }
+
+    /// Channel to the monitor of a VM that is already running
+    pub(crate) fn from_socket(socket_path: PathBuf) -> Self {
+        Self { socket_path }
+    }
+
+    pub(crate) fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Connect and negotiate capabilities. Responses that take longer than
+    /// `timeout` are treated as errors.
+    pub(crate) fn connect(&self, timeout: Option<Duration>) -> Result<Qmp> {
+        let stream =
+            UnixStream::connect(&self.socket_path).map_err(|err| QmpError::ConnectError {
+                path: self.socket_path.clone(),
+                err,
+            })?;
+        stream.set_read_timeout(timeout)?;
+        let mut qmp = Qmp {
+            stream: BufReader::new(stream),
+        };
+        let greeting = qmp.read_message("greeting")?;
+        if greeting.get("QMP").is_none() {
+            return Err(QmpError::ProtocolError(greeting.to_string()));
+        }
+        qmp.execute("qmp_capabilities", None)?;
+        Ok(qmp)
+    }
+}
+
+impl QemuDevice for QmpChannel {
+    fn qemu_args(&self) -> Vec<OsString> {
+        [
+            "-qmp",
+            &format!(
+                "unix:{},server=on,wait=off",
+                self.socket_path.to_str().expect("Invalid socket path")
+            ),
+        ]
+        .iter()
+        .map(|x| x.into())
+        .collect()
+    }
+}
+
+/// Connected QMP client
+#[derive(Debug)]
+pub(crate) struct Qmp {
+    stream: BufReader<UnixStream>,
+}
+
+impl Qmp {
+    fn read_message(&mut self, context: &str) -> Result<Value> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(QmpError::ClosedError(context.to_owned()));
+        }
+        serde_json::from_str(&line).map_err(|_| QmpError::ProtocolError(line.trim().to_owned()))
+    }
+
+    /// Run a QMP command and return what it returned
+    fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        let stream = self.stream.get_mut();
+        writeln!(stream, "{request}")?;
+        stream.flush()?;
+        loop {
+            let mut response = self.read_message(command)?;
+            // asynchronous events can arrive at any time
+            if response.get("event").is_some() {
+                continue;
+            }
+            if let Some(ret) = response.get_mut("return") {
+                return Ok(ret.take());
+            }
+            let desc = response
+                .pointer("/error/desc")
+                .and_then(Value::as_str)
+                .ok_or_else(|| QmpError::ProtocolError(response.to_string()))?;
+            return Err(QmpError::CommandError {
+                command: command.to_owned(),
+                desc: desc.to_owned(),
+            });
+        }
+    }
+
+    /// Wait for the next asynchronous event, like
+    /// `{"event": "WATCHDOG", "data": {"action": "reset"}, ...}`
+    pub(crate) fn next_event(&mut self) -> Result<Value> {
+        loop {
+            let message = self.read_message("event")?;
+            if message.get("event").is_some() {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Run a human monitor command. These report errors as output instead of
+    /// failing.
+    fn human_monitor_command(&mut self, command_line: &str) -> Result<String> {
+        let output = self.execute(
+            "human-monitor-command",
+            Some(json!({ "command-line": command_line })),
+        )?;
+        Ok(output.as_str().unwrap_or_default().trim().to_owned())
+    }
+
+    /// Run `savevm`/`loadvm`, which print nothing on success
+    fn snapshot_command(&mut self, command: &str, name: &str) -> Result<()> {
+        validate_snapshot_name(name)?;
+        let command_line = format!("{command} {name}");
+        match self.human_monitor_command(&command_line)? {
+            output if output.is_empty() => Ok(()),
+            desc => Err(QmpError::CommandError {
+                command: command_line,
+                desc,
+            }),
+        }
+    }
+
+    /// Save the state of the whole VM into the qcow2 disks as `name`,
+    /// replacing any snapshot of the same name
+    pub(crate) fn save_snapshot(&mut self, name: &str) -> Result<()> {
+        self.snapshot_command("savevm", name)
+    }
+
+    /// Names of the snapshots that exist on every disk
+    fn snapshots(&mut self) -> Result<Vec<String>> {
+        // The table looks like
+        //   List of snapshots present on all disks:
+        //   ID        TAG               VM SIZE                DATE     VM CLOCK     ICOUNT
+        //   --        booted            ...
+        Ok(self
+            .human_monitor_command("info snapshots")?
+            .lines()
+            .skip_while(|line| !line.starts_with("ID"))
+            .skip(1)
+            .filter_map(|line| line.split_whitespace().nth(1))
+            .map(|tag| tag.to_owned())
+            .collect())
+    }
+
+    /// Restore the VM to the snapshot `name`. The VM stays paused if it was
+    /// paused before.
+    pub(crate) fn load_snapshot(&mut self, name: &str) -> Result<()> {
+        validate_snapshot_name(name)?;
+        if !self.snapshots()?.iter().any(|tag| tag == name) {
+            return Err(QmpError::SnapshotNotFound(name.to_owned()));
+        }
+        self.snapshot_command("loadvm", name)
+    }
+
+    /// Resume a paused VM
+    pub(crate) fn cont(&mut self) -> Result<()> {
+        self.execute("cont", None).map(|_| ())
+    }
+
+    /// Make qemu exit right away. It may close the connection before it
+    /// gets to respond.
+    pub(crate) fn quit(&mut self) -> Result<()> {
+        match self.execute("quit", None) {
+            Ok(_) | Err(QmpError::ClosedError(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+    use std::thread::JoinHandle;
+
+    use super::*;
+
+    const SNAPSHOTS: &str = "List of snapshots present on all disks:\r\n\
+        ID        TAG               VM SIZE                DATE     VM CLOCK     ICOUNT\r\n\
+        --        booted            512 MiB 2024-01-01 00:00:00 00:00:12.345\r\n";
+
+    /// Stands in for qemu. Answers every command with what `respond` returns
+    /// for it and records the commands it received.
+    fn mock_qmp(
+        channel: &QmpChannel,
+        respond: impl Fn(&Value) -> Value + Send + 'static,
+    ) -> JoinHandle<Vec<Value>> {
+        let listener = UnixListener::bind(channel.socket_path()).expect("Failed to bind");
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("Failed to accept");
+            let mut stream = BufReader::new(stream);
+            writeln!(stream.get_mut(), r#"{{"QMP": {{"capabilities": []}}}}"#)
+                .expect("Failed to write");
+            let mut received = vec![];
+            let mut line = String::new();
+            while stream.read_line(&mut line).expect("Failed to read") > 0 {
+                let request: Value = serde_json::from_str(&line).expect("Invalid request");
+                writeln!(stream.get_mut(), r#"{{"event": "RESUME", "data": {{}}}}"#)
+                    .expect("Failed to write");
+                writeln!(stream.get_mut(), "{}", respond(&request)).expect("Failed to write");
+                received.push(request);
+                line.clear();
+            }
+            received
+        })
+    }
+
+    fn hmp(command_line: &str) -> Value {
+        json!({
+            "execute": "human-monitor-command",
+            "arguments": { "command-line": command_line },
+        })
+    }
+
+    fn respond(request: &Value) -> Value {
+        match request
+            .pointer("/arguments/command-line")
+            .and_then(Value::as_str)
+        {
+            Some("info snapshots") => json!({ "return": SNAPSHOTS }),
+            Some("savevm broken") => {
+                json!({ "return": "Error: Device 'fs0' is not migratable\r\n" })
+            }
+            _ => json!({ "return": {} }),
+        }
+    }
+
+    #[test]
+    fn test_qemu_args() {
+        let channel = QmpChannel::new(Path::new("/state"), "qmp");
+        assert_eq!(channel.socket_path(), Path::new("/state/qmp.sock"));
+        assert_eq!(
+            channel.qemu_args().join(OsStr::new(" ")),
+            "-qmp unix:/state/qmp.sock,server=on,wait=off"
+        );
+    }
+
+    #[test]
+    fn test_validate_snapshot_name() {
+        assert!(validate_snapshot_name("booted").is_ok());
+        assert!(validate_snapshot_name("").is_err());
+        assert!(validate_snapshot_name("two words").is_err());
+        assert!(validate_snapshot_name("line\nbreak").is_err());
+    }
+
+    #[test]
+    fn test_save_snapshot() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let channel = QmpChannel::new(dir.path(), "qmp");
+        let qemu = mock_qmp(&channel, respond);
+        let mut qmp = channel
+            .connect(Some(Duration::from_secs(10)))
+            .expect("Failed to connect");
+        qmp.save_snapshot("booted").expect("Failed to save");
+        match qmp.save_snapshot("broken") {
+            Err(QmpError::CommandError { command, desc }) => {
+                assert_eq!(command, "savevm broken");
+                assert_eq!(desc, "Error: Device 'fs0' is not migratable");
+            }
+            other => panic!("Unexpected result: {other:?}"),
+        }
+        drop(qmp);
+        assert_eq!(
+            qemu.join().expect("Mock qemu panicked"),
+            vec![
+                json!({ "execute": "qmp_capabilities" }),
+                hmp("savevm booted"),
+                hmp("savevm broken"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_snapshot() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let channel = QmpChannel::new(dir.path(), "qmp");
+        let qemu = mock_qmp(&channel, respond);
+        let mut qmp = channel
+            .connect(Some(Duration::from_secs(10)))
+            .expect("Failed to connect");
+        qmp.load_snapshot("booted").expect("Failed to load");
+        qmp.cont().expect("Failed to resume");
+        assert!(matches!(
+            qmp.load_snapshot("missing"),
+            Err(QmpError::SnapshotNotFound(name)) if name == "missing"
+        ));
+        drop(qmp);
+        assert_eq!(
+            qemu.join().expect("Mock qemu panicked"),
+            vec![
+                json!({ "execute": "qmp_capabilities" }),
+                hmp("info snapshots"),
+                hmp("loadvm booted"),
+                json!({ "execute": "cont" }),
+                // a missing snapshot is never loaded
+                hmp("info snapshots"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_command_error() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let channel = QmpChannel::new(dir.path(), "qmp");
+        let qemu = mock_qmp(&channel, |request| match request["execute"].as_str() {
+            Some("cont") => json!({
+                "error": { "class": "GenericError", "desc": "Resetting the VM failed" }
+            }),
+            _ => json!({ "return": {} }),
+        });
+        let mut qmp = channel.connect(None).expect("Failed to connect");
+        match qmp.cont() {
+            Err(QmpError::CommandError { command, desc }) => {
+                assert_eq!(command, "cont");
+                assert_eq!(desc, "Resetting the VM failed");
+            }
+            other => panic!("Unexpected result: {other:?}"),
+        }
+        drop(qmp);
+        qemu.join().expect("Mock qemu panicked");
+    }
+}
@@ -8,19 +8,35 @@
 use std::env;
 use std::ffi::OsString;
 use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Read;
 use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::Child;
 use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
 use std::str::FromStr;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
 
+use serde::Serialize;
 use thiserror::Error;
+use tracing::warn;
 use tracing::Level;
 use tracing_subscriber::filter::LevelFilter;
 
+use crate::teardown::ChildProcess;
 use crate::types::QemuDevice;
 use crate::types::ShareOpts;
+use crate::types::INVALID_ID;
 use crate::utils::log_command;
+use crate::utils::ThrottledLog;
 
 #[derive(Debug, Error)]
 pub(crate) enum ShareError {
@@ -28,21 +44,81 @@ pub(crate) enum ShareError {
     InvalidMountTagError(String),
     #[error("Virtiofsd failed to start: `{0}`")]
     VirtiofsdError(std::io::Error),
+    #[error("virtiofsd binary `{0}` does not exist, the VM runtime may be misconfigured")]
+    VirtiofsdNotFound(PathBuf),
     #[error("Failed to generate mount unit file for shares: `{0}`")]
     MountUnitGenerationError(std::io::Error),
     #[error("No directory is being shared")]
     EmptyShareError,
+    #[error("Memory size {0}M is not a multiple of the hugepage size {1}M")]
+    InvalidHugepageMemorySize(usize, usize),
+    #[error("Failed to find the hugepage size in {path}: {reason}")]
+    HugepageSizeError { path: PathBuf, reason: String },
+    #[error("Share state directory `{0}` does not exist")]
+    MissingStateDirError(PathBuf),
+    #[error("Share state directory `{path}` is not writable: {err}")]
+    StateDirNotWritableError { path: PathBuf, err: std::io::Error },
+    #[error("Socket path `{0}` is longer than the {UNIX_SOCKET_PATH_MAX} bytes allowed for unix sockets")]
+    SocketPathTooLongError(PathBuf),
+    #[error("Can't squash share ownership to id {0}, it is reserved as the invalid id")]
+    InvalidSquashId(u32),
+    #[error("Share path `{path}` can't be used in a mount unit: {reason}")]
+    InvalidMountPath { path: PathBuf, reason: &'static str },
+    #[error("Share source `{path}` does not exist: {err}")]
+    ShareSourceMissing { path: PathBuf, err: std::io::Error },
+    #[error("Share source `{0}` is not a directory")]
+    ShareSourceNotADirectory(PathBuf),
+    #[error("Failed to create virtiofsd log `{path}`: {err}")]
+    VirtiofsdLogError { path: PathBuf, err: std::io::Error },
+    #[error(
+        "virtiofsd for `{tag}` exited with {status} before it was ready. End of its log:\n{log}"
+    )]
+    VirtiofsdExited {
+        tag: String,
+        status: ExitStatus,
+        log: String,
+    },
 }
 
 type Result<T> = std::result::Result<T, ShareError>;
 
+/// Reports the size of the default hugepages, which are the ones mounted at
+/// [HUGEPAGES_PATH]
+pub(crate) const MEMINFO_PATH: &str = "/proc/meminfo";
+/// Where hugepages used to back VM memory are mounted
+pub(crate) const HUGEPAGES_PATH: &str = "/dev/hugepages";
+/// virtiofsd binary that serves each virtiofs share
+pub(crate) const VIRTIOFSD_PATH: &str = "/usr/libexec/virtiofsd";
+/// `sun_path` is 108 bytes, including the trailing NUL
+const UNIX_SOCKET_PATH_MAX: usize = 107;
+/// How long repeats of the same line from a share daemon are coalesced for
+const DAEMON_LOG_WINDOW: Duration = Duration::from_secs(10);
+/// How long virtiofsd gets to create its socket before qemu is started anyway
+const VIRTIOFSD_START_TIMEOUT: Duration = Duration::from_secs(10);
+/// How much of the virtiofsd log is included when it exits early
+const LOG_TAIL_LINES: usize = 20;
+
 pub(crate) trait Share: QemuDevice {
     /// Create Share based on full set of ShareOpts
     fn new(opts: ShareOpts, id: usize, state_dir: PathBuf) -> Self;
-    /// Run any necessary setup to enable the Share
-    fn setup(&self) -> Result<()>;
+    /// Run any necessary setup to enable the Share. Returns the daemon
+    /// serving the share, if there is one.
+    fn setup(&self) -> Result<Option<Child>>;
+    /// Files created while the Share is in use that should be removed once
+    /// the VM is gone
+    fn runtime_files(&self) -> Vec<PathBuf> {
+        vec![]
+    }
+    /// Check that the Share can be set up before anything is started
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
     /// Mount `Options` string for the mount unit
     fn mount_options(&self) -> String;
+    /// Socket of the daemon serving the share, if there is one
+    fn daemon_socket_path(&self) -> Option<PathBuf> {
+        None
+    }
 
     // Boilerplate getters
     fn get_mount_type(&self) -> &str;
@@ -77,28 +153,185 @@ pub(crate) trait Share: QemuDevice {
         }
     }
 
-    /// Generate .mount unit content
-    fn mount_unit_content(&self) -> String {
-        format!(
+    /// Resolved configuration of the share
+    fn info(&self) -> ShareInfo {
+        ShareInfo {
+            tag: self.mount_tag(),
+            path: self.get_opts().path.to_string_lossy().into_owned(),
+            // shares are mounted at the same path in the guest
+            mountpoint: self.get_opts().path.to_string_lossy().into_owned(),
+            read_only: self.get_opts().read_only,
+            protocol: self.get_mount_type().to_owned(),
+            socket_path: self
+                .daemon_socket_path()
+                .map(|path| path.to_string_lossy().into_owned()),
+        }
+    }
+
+    /// Generate .mount unit content. systemd expands specifiers like `%n` in
+    /// most settings, so every `%` that comes from a user provided value has
+    /// to be escaped.
+    fn mount_unit_content(&self) -> Result<String> {
+        let mountpoint = escape_specifiers(validate_mount_path(&self.get_opts().path)?);
+        let tag = escape_specifiers(&self.mount_tag());
+        // the mount is already done by the time the retry service is up, so
+        // this unit then only has to notice it
+        let retry = match self.get_opts().mount_retries {
+            Some(_) => {
+                let name = self.retry_unit_name()?;
+                format!("Requires={name}\nAfter={name}\n")
+            }
+            None => String::new(),
+        };
+        Ok(format!(
             r#"[Unit]
 Description=Mount {tag} at {mountpoint}
 Requires=systemd-modules-load.service
 After=systemd-modules-load.service
-Before=local-fs.target
+{retry}Before=local-fs.target
 
 [Mount]
 What={tag}
 Where={mountpoint}
 Type={mount_type}
 Options={mount_options}"#,
-            tag = self.mount_tag(),
-            mountpoint = self.get_opts().path.to_str().expect("Invalid UTF-8"),
             mount_type = self.get_mount_type(),
             mount_options = self.mount_options(),
-        )
+        ))
+    }
+
+    /// Name of the service that retries mounting the share
+    fn retry_unit_name(&self) -> Result<String> {
+        let mount_unit = self.mount_unit_name()?;
+        Ok(format!(
+            "{}-retry.service",
+            mount_unit.strip_suffix(".mount").unwrap_or(&mount_unit)
+        ))
+    }
+
+    /// Generate the content of a oneshot service that mounts the share,
+    /// restarting until it succeeds or `mount_retries` is exhausted, if
+    /// retries are enabled. A `.mount` unit can't be restarted on failure by
+    /// itself, so this is what the mount unit waits for instead.
+    fn retry_unit_content(&self) -> Result<Option<String>> {
+        let Some(retries) = self.get_opts().mount_retries else {
+            return Ok(None);
+        };
+        let mountpoint = validate_mount_path(&self.get_opts().path)?;
+        let tag = self.mount_tag();
+        // not ordered after local-fs.target like services are by default,
+        // since the mount unit is ordered before it
+        Ok(Some(format!(
+            r#"[Unit]
+Description=Mount {} at {}
+DefaultDependencies=no
+Requires=systemd-modules-load.service
+After=systemd-modules-load.service
+StartLimitIntervalSec=infinity
+StartLimitBurst={attempts}
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStartPre=/usr/bin/mkdir -p {mountpoint}
+ExecStart=/usr/bin/mount -t {mount_type} -o {mount_options} {} {mountpoint}
+Restart=on-failure
+RestartSec=1"#,
+            escape_specifiers(&tag),
+            escape_specifiers(mountpoint),
+            quote_exec_arg(&tag),
+            attempts = retries.saturating_add(1),
+            mount_type = self.get_mount_type(),
+            mount_options = self.mount_options(),
+            // the mount unit creates its mountpoint, but this runs first
+            mountpoint = quote_exec_arg(mountpoint),
+        )))
     }
 }
 
+/// Everything about a share that is useful when debugging its configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct ShareInfo {
+    pub(crate) tag: String,
+    /// Directory on the host
+    pub(crate) path: String,
+    /// Where the share is mounted in the guest
+    pub(crate) mountpoint: String,
+    pub(crate) read_only: bool,
+    pub(crate) protocol: String,
+    pub(crate) socket_path: Option<String>,
+}
+
+/// Size in MiB of the default hugepages, from `Hugepagesize` in `meminfo`
+pub(crate) fn hugepage_size_mib(meminfo: &Path) -> Result<usize> {
+    let invalid = |reason: String| ShareError::HugepageSizeError {
+        path: meminfo.to_owned(),
+        reason,
+    };
+    let contents = std::fs::read_to_string(meminfo).map_err(|e| invalid(e.to_string()))?;
+    // looks like `Hugepagesize:       2048 kB`
+    let size = contents
+        .lines()
+        .find_map(|line| line.strip_prefix("Hugepagesize:"))
+        .ok_or_else(|| invalid("Hugepagesize is missing".into()))?
+        .trim();
+    match size
+        .strip_suffix(" kB")
+        .and_then(|kib| kib.parse::<usize>().ok())
+    {
+        Some(kib) if kib >= 1024 && kib % 1024 == 0 => Ok(kib / 1024),
+        _ => Err(invalid(format!("unsupported Hugepagesize '{size}'"))),
+    }
+}
+
+/// Escape `%` so that systemd doesn't treat it as a specifier
+pub(crate) fn escape_specifiers(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+/// Quote `value` as a single argument of an `Exec*=` command line, where
+/// systemd also expands `$` variables and splits on whitespace
+fn quote_exec_arg(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        escape_specifiers(value)
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "$$")
+    )
+}
+
+/// Check that `path` is something systemd accepts for `Where=`, returning it
+/// as a string
+pub(crate) fn validate_mount_path(path: &Path) -> Result<&str> {
+    let invalid = |reason| ShareError::InvalidMountPath {
+        path: path.to_owned(),
+        reason,
+    };
+    let path_str = path.to_str().ok_or_else(|| invalid("not valid UTF-8"))?;
+    if !path.is_absolute() {
+        return Err(invalid("not absolute"));
+    }
+    // systemd requires normalized paths, which Path::components would
+    // silently normalize for us
+    if path_str.contains("//")
+        || path_str
+            .split('/')
+            .any(|component| component == "." || component == "..")
+    {
+        return Err(invalid("not normalized"));
+    }
+    if path_str.chars().any(char::is_control) {
+        return Err(invalid("contains control characters"));
+    }
+    // a trailing backslash continues the line in unit files and surrounding
+    // whitespace is stripped
+    if path_str.ends_with('\\') || path_str.trim() != path_str {
+        return Err(invalid("would be mangled by the unit file parser"));
+    }
+    Ok(path_str)
+}
+
 macro_rules! share_getters {
     () => {
         fn get_id(&self) -> usize {
@@ -139,8 +372,42 @@ impl Share for VirtiofsShare {
         }
     }
 
-    fn setup(&self) -> Result<()> {
-        self.start_virtiofsd()?;
+    fn setup(&self) -> Result<Option<Child>> {
+        self.validate_source()?;
+        Ok(Some(self.start_virtiofsd()?))
+    }
+
+    fn runtime_files(&self) -> Vec<PathBuf> {
+        vec![self.socket_path()]
+    }
+
+    fn daemon_socket_path(&self) -> Option<PathBuf> {
+        Some(self.socket_path())
+    }
+
+    // the thread pool size is only checked by ShareOpts::validate, which
+    // every ShareOpts passes through before getting here
+    fn validate(&self) -> Result<()> {
+        if let Some((uid, gid)) = self.opts.squash_to {
+            if let Some(id) = [uid, gid].into_iter().find(|id| *id == INVALID_ID) {
+                return Err(ShareError::InvalidSquashId(id));
+            }
+        }
+        if !self.state_dir.is_dir() {
+            return Err(ShareError::MissingStateDirError(self.state_dir.clone()));
+        }
+        // Permission bits don't tell the whole story (eg root or read-only
+        // mounts), so just try to create something in there
+        tempfile::tempfile_in(&self.state_dir).map_err(|err| {
+            ShareError::StateDirNotWritableError {
+                path: self.state_dir.clone(),
+                err,
+            }
+        })?;
+        let socket_path = self.socket_path();
+        if socket_path.as_os_str().len() > UNIX_SOCKET_PATH_MAX {
+            return Err(ShareError::SocketPathTooLongError(socket_path));
+        }
         Ok(())
     }
 
@@ -187,6 +454,25 @@ impl VirtiofsShare {
         self.state_dir.join(self.mount_tag())
     }
 
+    /// Everything virtiofsd writes to stderr ends up here, unthrottled
+    fn log_path(&self) -> PathBuf {
+        self.state_dir.join(format!("virtiofsd{}.log", self.id))
+    }
+
+    /// Check that the directory being shared exists. virtiofsd would only
+    /// fail to open it after starting, with a much less obvious error.
+    fn validate_source(&self) -> Result<()> {
+        let meta =
+            std::fs::metadata(&self.opts.path).map_err(|err| ShareError::ShareSourceMissing {
+                path: self.opts.path.clone(),
+                err,
+            })?;
+        if !meta.is_dir() {
+            return Err(ShareError::ShareSourceNotADirectory(self.opts.path.clone()));
+        }
+        Ok(())
+    }
+
     /// Rust virtiofsd seems to print out every request it gets on debug level,
     /// rendering terminal unusable. While users can craft the `RUST_LOG` env to
     /// suppress it manually, let's just set a more sensible level for it by
@@ -212,25 +498,152 @@ impl VirtiofsShare {
         }
     }
 
+    /// Full virtiofsd command line for this share
+    fn virtiofsd_command(&self) -> Command {
+        let mut command = Command::new(VIRTIOFSD_PATH);
+        if let Some(level) = self.opts.log_level {
+            // virtiofsd ignores --log-level if RUST_LOG is set
+            command
+                .env_remove("RUST_LOG")
+                .arg(format!("--log-level={level}"));
+        } else if let Some(lv) = self.virtiofsd_log_level() {
+            // Override logging level for virtiofsd
+            command.env("RUST_LOG", lv);
+        }
+        command
+            .arg("--socket-path")
+            .arg(self.socket_path())
+            .arg("--shared-dir")
+            .arg(&self.opts.path)
+            .arg("--cache")
+            .arg("always");
+        if let Some(size) = self.opts.thread_pool_size {
+            command.arg(format!("--thread-pool-size={size}"));
+        }
+        if let Some(sandbox) = self.opts.sandbox {
+            command.arg(format!("--sandbox={sandbox}"));
+        }
+        if let Some((uid, gid)) = self.opts.squash_to {
+            // map every valid host id to the same one in the guest
+            command.arg(format!("--translate-uid=squash-host:0:{uid}:{INVALID_ID}"));
+            command.arg(format!("--translate-gid=squash-host:0:{gid}:{INVALID_ID}"));
+        }
+        command
+    }
+
     /// Virtiofs requires one virtiofsd for each shared path. This command assumes
     /// it's running as root inside container.
     pub(crate) fn start_virtiofsd(&self) -> Result<Child> {
-        let mut command = Command::new("/usr/libexec/virtiofsd");
-        if let Some(lv) = self.virtiofsd_log_level() {
-            // Override logging level for virtiofsd
-            command.env("RUST_LOG", lv);
+        let child = spawn_virtiofsd(self.virtiofsd_command().stderr(Stdio::piped()))?;
+        self.monitor_virtiofsd(child)
+    }
+
+    /// Log what the freshly spawned `child` writes to stderr, and wait for it
+    /// to be ready to serve qemu
+    fn monitor_virtiofsd(&self, mut child: Child) -> Result<Child> {
+        let log_path = self.log_path();
+        let log = File::create(&log_path).map_err(|err| ShareError::VirtiofsdLogError {
+            path: log_path,
+            err,
+        })?;
+        let forwarder = child.stderr.take().map(|stderr| {
+            let daemon = format!("virtiofsd for {}", self.mount_tag());
+            // ends once virtiofsd exits. For a detached VM that is after the
+            // launcher is gone, and virtiofsd just can't log anymore.
+            thread::spawn(move || forward_daemon_logs(&daemon, stderr, log))
+        });
+        self.wait_for_socket(&mut child, forwarder)?;
+        Ok(child)
+    }
+
+    /// qemu can only connect to virtiofsd once it has created its socket. If
+    /// virtiofsd exits before that, it logged why.
+    fn wait_for_socket(&self, child: &mut Child, forwarder: Option<JoinHandle<()>>) -> Result<()> {
+        let deadline = Instant::now() + VIRTIOFSD_START_TIMEOUT;
+        while !self.socket_path().exists() {
+            if let Some(status) = child.try_wait().map_err(ShareError::VirtiofsdError)? {
+                // stderr is closed, so the rest of the log is about to be
+                // written out
+                if let Some(forwarder) = forwarder {
+                    let _ = forwarder.join();
+                }
+                return Err(ShareError::VirtiofsdExited {
+                    tag: self.mount_tag(),
+                    status,
+                    log: log_tail(&self.log_path()),
+                });
+            }
+            if Instant::now() >= deadline {
+                warn!(
+                    "virtiofsd for {} did not create {} within {VIRTIOFSD_START_TIMEOUT:?}",
+                    self.mount_tag(),
+                    self.socket_path().display(),
+                );
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
         }
-        log_command(
-            command
-                .arg("--socket-path")
-                .arg(self.socket_path())
-                .arg("--shared-dir")
-                .arg(&self.opts.path)
-                .arg("--cache")
-                .arg("always"),
-        )
-        .spawn()
-        .map_err(ShareError::VirtiofsdError)
+        Ok(())
+    }
+}
+
+/// The last `LOG_TAIL_LINES` lines of the log at `path`
+fn log_tail(path: &Path) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(log) => {
+            let lines: Vec<_> = log.lines().collect();
+            lines[lines.len().saturating_sub(LOG_TAIL_LINES)..].join("\n")
+        }
+        Err(e) => format!("<failed to read {}: {e}>", path.display()),
+    }
+}
+
+/// A missing virtiofsd is a problem with the runtime rather than with any
+/// share, so it gets called out separately from other spawn errors
+fn spawn_virtiofsd(command: &mut Command) -> Result<Child> {
+    log_command(command).spawn().map_err(|e| match e.kind() {
+        ErrorKind::NotFound => ShareError::VirtiofsdNotFound(command.get_program().into()),
+        _ => ShareError::VirtiofsdError(e),
+    })
+}
+
+/// Append every file under `dir` to `files`, descending into drop-in dirs
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Copy every line `daemon` writes to `stderr` into `log_file` and log it
+/// until the daemon exits. virtiofsd logs each request that fails, so a share
+/// that keeps failing would otherwise flood the logs with the same error.
+fn forward_daemon_logs(daemon: &str, stderr: impl Read, mut log_file: File) {
+    let mut log = ThrottledLog::new(DAEMON_LOG_WINDOW, |msg: &str| warn!("{daemon}: {msg}"));
+    for line in BufReader::new(stderr)
+        .lines()
+        .map_while(std::io::Result::ok)
+    {
+        if let Err(e) = writeln!(log_file, "{line}") {
+            warn!("{daemon}: failed to write log: {e}");
+        }
+        log.log(&strip_log_timestamp(&line));
+    }
+}
+
+/// virtiofsd starts every line with `[<timestamp> <level> <target>]`, which
+/// would make every repeat of a message look different
+fn strip_log_timestamp(line: &str) -> String {
+    match line.strip_prefix('[').and_then(|rest| rest.split_once(' ')) {
+        Some((timestamp, rest)) if timestamp.starts_with(|c: char| c.is_ascii_digit()) => {
+            format!("[{rest}")
+        }
+        _ => line.to_owned(),
     }
 }
 
@@ -257,8 +670,8 @@ impl Share for NinePShare {
         }
     }
 
-    fn setup(&self) -> Result<()> {
-        Ok(())
+    fn setup(&self) -> Result<Option<Child>> {
+        Ok(None)
     }
     fn mount_options(&self) -> String {
         format!(
@@ -295,38 +708,120 @@ pub(crate) struct Shares<T: Share> {
     /// Memory size of the qemu VM. This should match -m parameter.
     /// This is used for memory-backend-file for virtiofsd shares.
     mem_mb: usize,
+    /// Back the shared memory with hugepages instead of memfd
+    hugepages: bool,
     /// Directory that holds unit files for other shares
     unit_files_dir: PathBuf,
 }
 
 impl<T: Share> Shares<T> {
-    pub(crate) fn new(shares: Vec<T>, mem_mb: usize, unit_files_dir: PathBuf) -> Result<Self> {
+    pub(crate) fn new(
+        shares: impl IntoIterator<Item = T>,
+        mem_mb: usize,
+        hugepages: bool,
+        unit_files_dir: PathBuf,
+    ) -> Result<Self> {
+        let shares: Vec<_> = shares.into_iter().collect();
         if shares.is_empty() {
             return Err(ShareError::EmptyShareError);
         }
+        shares.iter().try_for_each(|share| share.validate())?;
+        if hugepages {
+            let size = hugepage_size_mib(Path::new(MEMINFO_PATH))?;
+            if mem_mb % size != 0 {
+                return Err(ShareError::InvalidHugepageMemorySize(mem_mb, size));
+            }
+        }
         Ok(Self {
             shares,
             mem_mb,
+            hugepages,
             unit_files_dir,
         })
     }
 
+    /// All shares, in the order their ids were assigned
+    pub(crate) fn shares(&self) -> &[T] {
+        &self.shares
+    }
+
+    /// Resolved configuration of all shares
+    pub(crate) fn info(&self) -> Vec<ShareInfo> {
+        self.shares.iter().map(|share| share.info()).collect()
+    }
+
     /// Write all unit files in the unit files directory
     pub(crate) fn generate_unit_files(&self) -> Result<()> {
         self.shares.iter().try_for_each(|share| {
             let name = share.mount_unit_name()?;
-            let content = share.mount_unit_content().into_bytes();
-            let mut file = File::create(self.unit_files_dir.join(name))
-                .map_err(ShareError::MountUnitGenerationError)?;
-            file.write_all(&content)
-                .map_err(ShareError::MountUnitGenerationError)?;
-            Ok(())
+            let content = share.mount_unit_content()?;
+            self.write_unit_file(&name, &content)?;
+            if let Some(content) = share.retry_unit_content()? {
+                self.write_unit_file(&share.retry_unit_name()?, &content)?;
+            }
+            share
+                .get_opts()
+                .unit_files
+                .iter()
+                .try_for_each(|unit_file| self.write_unit_file(&unit_file.path, &unit_file.content))
         })
     }
 
-    pub(crate) fn start_shares(&self) -> Result<()> {
-        self.shares.iter().try_for_each(|share| share.setup())?;
-        Ok(())
+    /// Write `content` to `path` (relative to the unit files dir), creating
+    /// any subdirectory like `foo.service.d` it goes into
+    fn write_unit_file(&self, path: impl AsRef<Path>, content: &str) -> Result<()> {
+        let path = self.unit_files_dir.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(ShareError::MountUnitGenerationError)?;
+        }
+        let mut file = File::create(path).map_err(ShareError::MountUnitGenerationError)?;
+        file.write_all(content.as_bytes())
+            .map_err(ShareError::MountUnitGenerationError)
+    }
+
+    /// Print the name and content of every file in the unit files directory
+    /// to `out`, in the order of their names
+    pub(crate) fn dump_unit_files(&self, out: &mut impl Write) -> Result<()> {
+        let mut dump = || -> std::io::Result<()> {
+            let mut files = vec![];
+            list_files(&self.unit_files_dir, &mut files)?;
+            files.sort();
+            for path in files {
+                let content = std::fs::read_to_string(&path)?;
+                let name = path
+                    .strip_prefix(&self.unit_files_dir)
+                    .expect("listed files are under the unit files dir");
+                writeln!(out, "# {}\n{}\n", name.display(), content.trim_end())?;
+            }
+            Ok(())
+        };
+        dump().map_err(ShareError::MountUnitGenerationError)
+    }
+
+    /// Set up all shares, returning the daemons that were started for them
+    pub(crate) fn start_shares(&self) -> Result<Vec<ChildProcess>> {
+        let mut daemons = vec![];
+        for share in &self.shares {
+            if let Some(child) = share.setup()? {
+                daemons.push(ChildProcess::new(
+                    format!(
+                        "{} daemon for {}",
+                        share.get_mount_type(),
+                        share.mount_tag()
+                    ),
+                    child,
+                ));
+            }
+        }
+        Ok(daemons)
+    }
+
+    /// Files created by the shares while they are in use
+    pub(crate) fn runtime_files(&self) -> Vec<PathBuf> {
+        self.shares
+            .iter()
+            .flat_map(|share| share.runtime_files())
+            .collect()
     }
 
     /// Qemu args for 9p read-only share for antlir/vm/mount-generator. Keeping
@@ -345,17 +840,21 @@ impl<T: Share> Shares<T> {
         .collect()
     }
 
-    /// Required by virtiofsd shares
+    /// Required by virtiofsd shares. Hugepages reduce TLB pressure for large
+    /// VMs, but require the host to have enough of them reserved.
     fn memory_file_qemu_args(&self) -> Vec<OsString> {
-        [
-            "-object",
-            &format!("memory-backend-memfd,id=mem,share=on,size={}M", self.mem_mb,),
-            "-numa",
-            "node,memdev=mem",
-        ]
-        .iter()
-        .map(|x| x.into())
-        .collect()
+        let backend = if self.hugepages {
+            format!(
+                "memory-backend-file,id=mem,share=on,size={}M,mem-path={HUGEPAGES_PATH},prealloc=on",
+                self.mem_mb,
+            )
+        } else {
+            format!("memory-backend-memfd,id=mem,share=on,size={}M", self.mem_mb)
+        };
+        ["-object", &backend, "-numa", "node,memdev=mem"]
+            .iter()
+            .map(|x| x.into())
+            .collect()
     }
 }
 
@@ -372,11 +871,15 @@ impl<T: Share> QemuDevice for Shares<T> {
 mod test {
     use std::ffi::OsStr;
     use std::fs;
+    use std::path::Path;
 
     use tempfile::tempdir;
     use tracing_subscriber::EnvFilter;
 
     use super::*;
+    use crate::types::UnitFile;
+    use crate::types::VirtiofsdLogLevel;
+    use crate::types::VirtiofsdSandbox;
     use crate::utils::qemu_args_to_string;
 
     #[test]
@@ -386,6 +889,7 @@ mod test {
             path: PathBuf::from("/this/is/a/test"),
             read_only: true,
             mount_tag: None,
+            ..Default::default()
         };
         let share = VirtiofsShare::new(opts, 3, PathBuf::from("/tmp/test"));
 
@@ -409,7 +913,12 @@ What=fs3
 Where=/this/is/a/test
 Type=virtiofs
 Options=ro"#;
-        assert_eq!(&share.mount_unit_content(), mount_unit_content);
+        assert_eq!(
+            &share
+                .mount_unit_content()
+                .expect("Failed to generate mount unit"),
+            mount_unit_content
+        );
         assert_eq!(
             share.qemu_args().join(OsStr::new(" ")),
             "-chardev socket,id=fs_chardev3,path=/tmp/test/fs3 \
@@ -421,6 +930,7 @@ Options=ro"#;
             path: PathBuf::from("/this/is/a/test"),
             read_only: false,
             mount_tag: Some("whatever".to_string()),
+            ..Default::default()
         };
         let share = VirtiofsShare::new(opts, 3, PathBuf::from("/tmp/test"));
 
@@ -442,7 +952,12 @@ What=whatever
 Where=/this/is/a/test
 Type=virtiofs
 Options=rw"#;
-        assert_eq!(&share.mount_unit_content(), mount_unit_content);
+        assert_eq!(
+            &share
+                .mount_unit_content()
+                .expect("Failed to generate mount unit"),
+            mount_unit_content
+        );
         assert_eq!(
             share.qemu_args().join(OsStr::new(" ")),
             "-chardev socket,id=fs_chardev3,path=/tmp/test/whatever \
@@ -450,12 +965,157 @@ Options=rw"#;
         );
     }
 
+    #[test]
+    fn test_mount_unit_escaping() {
+        let opts = ShareOpts {
+            path: PathBuf::from("/data/100%/$HOME"),
+            read_only: true,
+            mount_tag: Some("tag%n".to_string()),
+            ..Default::default()
+        };
+        let share = VirtiofsShare::new(opts, 3, PathBuf::from("/tmp/test"));
+        let mount_unit_content = r#"[Unit]
+Description=Mount tag%%n at /data/100%%/$HOME
+Requires=systemd-modules-load.service
+After=systemd-modules-load.service
+Before=local-fs.target
+
+[Mount]
+What=tag%%n
+Where=/data/100%%/$HOME
+Type=virtiofs
+Options=ro"#;
+        assert_eq!(
+            share
+                .mount_unit_content()
+                .expect("Failed to generate mount unit"),
+            mount_unit_content
+        );
+
+        [
+            ("relative/path", "not absolute"),
+            ("/a//b", "not normalized"),
+            ("/a/./b", "not normalized"),
+            ("/a/../b", "not normalized"),
+            ("/a\nb", "contains control characters"),
+            ("/a/b\\", "would be mangled by the unit file parser"),
+            ("/a/b ", "would be mangled by the unit file parser"),
+        ]
+        .iter()
+        .for_each(|(path, expected)| {
+            let opts = ShareOpts {
+                path: PathBuf::from(path),
+                ..Default::default()
+            };
+            let share = VirtiofsShare::new(opts, 3, PathBuf::from("/tmp/test"));
+            match share.mount_unit_content() {
+                Err(ShareError::InvalidMountPath { reason, .. }) => {
+                    assert_eq!(reason, *expected, "{path}")
+                }
+                other => panic!("{path} should be invalid, got {other:?}"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_mount_retries() {
+        let opts = ShareOpts {
+            path: PathBuf::from("/this/is/a/test"),
+            read_only: true,
+            mount_retries: Some(5),
+            ..Default::default()
+        };
+        let share = VirtiofsShare::new(opts, 3, PathBuf::from("/tmp/test"));
+        assert_eq!(
+            share.retry_unit_name().expect("Invalid retry unit name"),
+            "this-is-a-test-retry.service",
+        );
+        let mount_unit_content = r#"[Unit]
+Description=Mount fs3 at /this/is/a/test
+Requires=systemd-modules-load.service
+After=systemd-modules-load.service
+Requires=this-is-a-test-retry.service
+After=this-is-a-test-retry.service
+Before=local-fs.target
+
+[Mount]
+What=fs3
+Where=/this/is/a/test
+Type=virtiofs
+Options=ro"#;
+        assert_eq!(
+            share
+                .mount_unit_content()
+                .expect("Failed to generate mount unit"),
+            mount_unit_content
+        );
+        let retry_unit_content = r#"[Unit]
+Description=Mount fs3 at /this/is/a/test
+DefaultDependencies=no
+Requires=systemd-modules-load.service
+After=systemd-modules-load.service
+StartLimitIntervalSec=infinity
+StartLimitBurst=6
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStartPre=/usr/bin/mkdir -p "/this/is/a/test"
+ExecStart=/usr/bin/mount -t virtiofs -o ro "fs3" "/this/is/a/test"
+Restart=on-failure
+RestartSec=1"#;
+        assert_eq!(
+            share
+                .retry_unit_content()
+                .expect("Failed to generate retry unit")
+                .as_deref(),
+            Some(retry_unit_content)
+        );
+
+        let opts = ShareOpts {
+            path: PathBuf::from(r#"/data/100%/$HOME "quoted" \x"#),
+            mount_tag: Some("tag".to_string()),
+            mount_retries: Some(1),
+            ..Default::default()
+        };
+        let share = VirtiofsShare::new(opts, 3, PathBuf::from("/tmp/test"));
+        let retry_unit_content = share
+            .retry_unit_content()
+            .expect("Failed to generate retry unit")
+            .expect("Retries are enabled");
+        assert!(
+            retry_unit_content.contains(r#"-o rw "tag" "/data/100%%/$$HOME \"quoted\" \\x""#),
+            "{retry_unit_content}"
+        );
+        assert!(
+            retry_unit_content
+                .contains(r#"ExecStartPre=/usr/bin/mkdir -p "/data/100%%/$$HOME \"quoted\" \\x""#),
+            "{retry_unit_content}"
+        );
+
+        let share = VirtiofsShare::new(
+            ShareOpts {
+                path: PathBuf::from("/this/is/a/test"),
+                ..Default::default()
+            },
+            3,
+            PathBuf::from("/tmp/test"),
+        );
+        assert_eq!(
+            share
+                .retry_unit_content()
+                .expect("Failed to generate retry unit"),
+            None
+        );
+    }
+
     #[test]
     fn test_9p_share() {
         let opts = ShareOpts {
             path: PathBuf::from("/this/is/a/test"),
             read_only: true,
             mount_tag: None,
+            ..Default::default()
         };
         let mut share = NinePShare::new(opts, 3, PathBuf::from("/tmp/test"));
 
@@ -481,16 +1141,81 @@ Options=rw"#;
         );
     }
 
+    #[test]
+    fn test_extra_unit_files() {
+        let opts = ShareOpts {
+            path: PathBuf::from("/this/is/a/test"),
+            unit_files: vec![UnitFile {
+                path: PathBuf::from("foo.service.d/override.conf"),
+                content: "[Service]\nEnvironment=FOO=1\n".to_string(),
+            }],
+            ..Default::default()
+        };
+        let state_dir = tempdir().expect("Failed to create tempdir for testing");
+        let share = VirtiofsShare::new(opts, 3, state_dir.path().to_path_buf());
+        let dir = tempdir().expect("Failed to create tempdir for testing");
+        let shares = Shares::new(vec![share], 1024, false, dir.path().to_path_buf())
+            .expect("Failed to create Shares");
+        shares
+            .generate_unit_files()
+            .expect("Failed to generate unit files");
+
+        assert!(dir.path().join("this-is-a-test.mount").exists());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("foo.service.d/override.conf"))
+                .expect("Failed to read drop-in"),
+            "[Service]\nEnvironment=FOO=1\n",
+        );
+    }
+
+    #[test]
+    fn test_dump_unit_files() {
+        let opts = ShareOpts {
+            path: PathBuf::from("/this/is/a/test"),
+            unit_files: vec![UnitFile {
+                path: PathBuf::from("foo.service.d/override.conf"),
+                content: "[Service]\nEnvironment=FOO=1\n".to_string(),
+            }],
+            ..Default::default()
+        };
+        let state_dir = tempdir().expect("Failed to create tempdir for testing");
+        let share = VirtiofsShare::new(opts, 3, state_dir.path().to_path_buf());
+        let dir = tempdir().expect("Failed to create tempdir for testing");
+        let shares = Shares::new(vec![share], 1024, false, dir.path().to_path_buf())
+            .expect("Failed to create Shares");
+        shares
+            .generate_unit_files()
+            .expect("Failed to generate unit files");
+
+        let mut out = vec![];
+        shares
+            .dump_unit_files(&mut out)
+            .expect("Failed to dump unit files");
+        assert_eq!(
+            String::from_utf8(out).expect("Invalid UTF-8"),
+            format!(
+                "# foo.service.d/override.conf\n[Service]\nEnvironment=FOO=1\n\n\
+                # this-is-a-test.mount\n{}\n\n",
+                shares.shares()[0]
+                    .mount_unit_content()
+                    .expect("Failed to generate mount unit")
+                    .trim_end(),
+            ),
+        );
+    }
+
     #[test]
     fn test_shares() {
         let opts = ShareOpts {
             path: PathBuf::from("/this/is/a/test"),
             read_only: true,
             mount_tag: None,
+            ..Default::default()
         };
-        let share = VirtiofsShare::new(opts, 3, PathBuf::from("/tmp/test"));
+        let state_dir = tempdir().expect("Failed to create tempdir for testing");
+        let share = VirtiofsShare::new(opts.clone(), 3, state_dir.path().to_path_buf());
         let dir = tempdir().expect("Failed to create tempdir for testing");
-        let shares = Shares::new(vec![share], 1024, dir.path().to_path_buf())
+        let shares = Shares::new(vec![share], 1024, false, dir.path().to_path_buf())
             .expect("Failed to create Shares");
 
         shares
@@ -531,6 +1256,389 @@ Options=rw"#;
             let share_args = qemu_args_to_string(&x.qemu_args());
             assert!(qemu_args.contains(&share_args))
         });
+
+        // Missing state directory
+        let missing = state_dir.path().join("missing");
+        let share = VirtiofsShare::new(opts.clone(), 3, missing.clone());
+        assert!(matches!(
+            Shares::new([share], 1024, false, dir.path().to_path_buf()),
+            Err(ShareError::MissingStateDirError(path)) if path == missing,
+        ));
+
+        // Non-writable state directory. procfs doesn't allow creating files
+        // even for root.
+        let share = VirtiofsShare::new(opts.clone(), 3, PathBuf::from("/proc"));
+        assert!(matches!(
+            Shares::new([share], 1024, false, dir.path().to_path_buf()),
+            Err(ShareError::StateDirNotWritableError { path, .. }) if path == Path::new("/proc"),
+        ));
+
+        // Socket path too long for a unix socket
+        let share = VirtiofsShare::new(
+            ShareOpts {
+                mount_tag: Some("t".repeat(UNIX_SOCKET_PATH_MAX)),
+                ..opts
+            },
+            3,
+            state_dir.path().to_path_buf(),
+        );
+        assert!(matches!(
+            Shares::new([share], 1024, false, dir.path().to_path_buf()),
+            Err(ShareError::SocketPathTooLongError(_)),
+        ));
+    }
+
+    #[test]
+    fn test_shares_info() {
+        let state_dir = tempdir().expect("Failed to create tempdir for testing");
+        let shares = Shares::new(
+            [
+                ShareOpts {
+                    path: PathBuf::from("/platform"),
+                    read_only: true,
+                    ..Default::default()
+                },
+                ShareOpts {
+                    path: PathBuf::from("/output"),
+                    read_only: false,
+                    mount_tag: Some("out".to_string()),
+                    ..Default::default()
+                },
+            ]
+            .into_iter()
+            .enumerate()
+            .map(|(i, opts)| VirtiofsShare::new(opts, i, state_dir.path().to_path_buf())),
+            1024,
+            false,
+            PathBuf::from("/tmp/test"),
+        )
+        .expect("Failed to create Shares");
+        let socket = |tag: &str| Some(state_dir.path().join(tag).to_string_lossy().into_owned());
+        assert_eq!(
+            shares.info(),
+            vec![
+                ShareInfo {
+                    tag: "fs0".to_string(),
+                    path: "/platform".to_string(),
+                    mountpoint: "/platform".to_string(),
+                    read_only: true,
+                    protocol: "virtiofs".to_string(),
+                    socket_path: socket("fs0"),
+                },
+                ShareInfo {
+                    tag: "out".to_string(),
+                    path: "/output".to_string(),
+                    mountpoint: "/output".to_string(),
+                    read_only: false,
+                    protocol: "virtiofs".to_string(),
+                    socket_path: socket("out"),
+                },
+            ],
+        );
+
+        let share = NinePShare::new(
+            ShareOpts {
+                path: PathBuf::from("/legacy"),
+                ..Default::default()
+            },
+            2,
+            state_dir.path().to_path_buf(),
+        );
+        assert_eq!(
+            serde_json::to_value(share.info()).expect("Failed to serialize"),
+            serde_json::json!({
+                "tag": "fs2",
+                "path": "/legacy",
+                "mountpoint": "/legacy",
+                "read_only": false,
+                "protocol": "9p",
+                "socket_path": null,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_shares_hugepages() {
+        let opts = ShareOpts {
+            path: PathBuf::from("/this/is/a/test"),
+            read_only: true,
+            mount_tag: None,
+            ..Default::default()
+        };
+        let state_dir = tempdir().expect("Failed to create tempdir for testing");
+        let share = VirtiofsShare::new(opts.clone(), 3, state_dir.path().to_path_buf());
+        let shares = Shares::new(vec![share], 1024, true, PathBuf::from("/tmp/test"))
+            .expect("Failed to create Shares");
+        assert_eq!(
+            shares.memory_file_qemu_args().join(OsStr::new(" ")),
+            "-object memory-backend-file,id=mem,share=on,size=1024M,\
+            mem-path=/dev/hugepages,prealloc=on -numa node,memdev=mem",
+        );
+
+        let share = VirtiofsShare::new(opts, 3, state_dir.path().to_path_buf());
+        assert!(matches!(
+            Shares::new(vec![share], 1023, true, PathBuf::from("/tmp/test")),
+            Err(ShareError::InvalidHugepageMemorySize(1023, _)),
+        ));
+    }
+
+    #[test]
+    fn test_hugepage_size() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        let meminfo = dir.path().join("meminfo");
+        for (contents, size) in [
+            (
+                "MemTotal:       65536000 kB\nHugepagesize:       2048 kB\n",
+                Some(2),
+            ),
+            ("Hugepagesize:    1048576 kB\n", Some(1024)),
+            ("Hugepagesize:         64 kB\n", None),
+            ("MemTotal:       65536000 kB\n", None),
+        ] {
+            std::fs::write(&meminfo, contents).expect("Failed to write");
+            assert_eq!(hugepage_size_mib(&meminfo).ok(), size, "{contents}");
+        }
+        assert!(matches!(
+            hugepage_size_mib(&dir.path().join("missing")),
+            Err(ShareError::HugepageSizeError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_virtiofs_share_source() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let share = |path: PathBuf| {
+            VirtiofsShare::new(
+                ShareOpts {
+                    path,
+                    ..Default::default()
+                },
+                0,
+                dir.path().to_owned(),
+            )
+        };
+        share(dir.path().to_owned())
+            .validate_source()
+            .expect("Directory is a valid source");
+
+        let missing = dir.path().join("missing");
+        // setup checks the source before starting virtiofsd
+        assert!(matches!(
+            share(missing.clone()).setup(),
+            Err(ShareError::ShareSourceMissing { path, .. }) if path == missing,
+        ));
+
+        let file = dir.path().join("file");
+        File::create(&file).expect("Failed to create file");
+        assert!(matches!(
+            share(file.clone()).setup(),
+            Err(ShareError::ShareSourceNotADirectory(path)) if path == file,
+        ));
+    }
+
+    #[test]
+    fn test_strip_log_timestamp() {
+        assert_eq!(
+            strip_log_timestamp(
+                "[2024-05-01T12:00:00Z ERROR virtiofsd::passthrough] No such file or directory"
+            ),
+            "[ERROR virtiofsd::passthrough] No such file or directory"
+        );
+        assert_eq!(strip_log_timestamp("plain message"), "plain message");
+        assert_eq!(strip_log_timestamp("[tag] message"), "[tag] message");
+    }
+
+    #[test]
+    fn test_virtiofsd_thread_pool_size() {
+        let state_dir = tempdir().expect("Failed to create tempdir for testing");
+        let opts = ShareOpts {
+            path: PathBuf::from("/this/is/a/test"),
+            ..Default::default()
+        };
+        let share = VirtiofsShare::new(opts.clone(), 3, state_dir.path().to_path_buf());
+        assert!(
+            !share
+                .virtiofsd_command()
+                .get_args()
+                .any(|x| x.to_string_lossy().starts_with("--thread-pool-size"))
+        );
+
+        let share = VirtiofsShare::new(
+            ShareOpts {
+                thread_pool_size: Some(16),
+                ..opts.clone()
+            },
+            3,
+            state_dir.path().to_path_buf(),
+        );
+        assert!(
+            share
+                .virtiofsd_command()
+                .get_args()
+                .any(|x| x == "--thread-pool-size=16")
+        );
+    }
+
+    #[test]
+    fn test_virtiofsd_squash_to() {
+        let state_dir = tempdir().expect("Failed to create tempdir for testing");
+        let opts = ShareOpts {
+            path: PathBuf::from("/this/is/a/test"),
+            ..Default::default()
+        };
+        let share = VirtiofsShare::new(opts.clone(), 3, state_dir.path().to_path_buf());
+        assert!(
+            !share
+                .virtiofsd_command()
+                .get_args()
+                .any(|x| x.to_string_lossy().starts_with("--translate-"))
+        );
+
+        let share = VirtiofsShare::new(
+            ShareOpts {
+                squash_to: Some((1000, 100)),
+                ..opts.clone()
+            },
+            3,
+            state_dir.path().to_path_buf(),
+        );
+        assert!(share.validate().is_ok());
+        let args: Vec<_> = share
+            .virtiofsd_command()
+            .get_args()
+            .map(OsString::from)
+            .collect();
+        assert!(args.contains(&"--translate-uid=squash-host:0:1000:4294967295".into()));
+        assert!(args.contains(&"--translate-gid=squash-host:0:100:4294967295".into()));
+
+        let share = VirtiofsShare::new(
+            ShareOpts {
+                squash_to: Some((INVALID_ID, 100)),
+                ..opts
+            },
+            3,
+            state_dir.path().to_path_buf(),
+        );
+        assert!(matches!(
+            share.validate(),
+            Err(ShareError::InvalidSquashId(INVALID_ID))
+        ));
+    }
+
+    #[test]
+    fn test_virtiofsd_sandbox() {
+        let state_dir = tempdir().expect("Failed to create tempdir for testing");
+        let opts = ShareOpts {
+            path: PathBuf::from("/this/is/a/test"),
+            ..Default::default()
+        };
+        let share = VirtiofsShare::new(opts.clone(), 3, state_dir.path().to_path_buf());
+        assert!(
+            !share
+                .virtiofsd_command()
+                .get_args()
+                .any(|x| x.to_string_lossy().starts_with("--sandbox"))
+        );
+
+        [
+            (VirtiofsdSandbox::None, "--sandbox=none"),
+            (VirtiofsdSandbox::Namespace, "--sandbox=namespace"),
+            (VirtiofsdSandbox::Chroot, "--sandbox=chroot"),
+        ]
+        .into_iter()
+        .for_each(|(sandbox, arg)| {
+            let share = VirtiofsShare::new(
+                ShareOpts {
+                    sandbox: Some(sandbox),
+                    ..opts.clone()
+                },
+                3,
+                state_dir.path().to_path_buf(),
+            );
+            assert!(share.virtiofsd_command().get_args().any(|x| x == arg));
+        });
+    }
+
+    #[test]
+    fn test_virtiofsd_log_level_opt() {
+        let state_dir = tempdir().expect("Failed to create tempdir for testing");
+        let share = VirtiofsShare::new(
+            ShareOpts {
+                path: PathBuf::from("/this/is/a/test"),
+                log_level: Some(VirtiofsdLogLevel::Debug),
+                ..Default::default()
+            },
+            3,
+            state_dir.path().to_path_buf(),
+        );
+        let command = share.virtiofsd_command();
+        assert!(command.get_args().any(|x| x == "--log-level=debug"));
+        // otherwise RUST_LOG would win over --log-level
+        assert!(
+            command
+                .get_envs()
+                .any(|(key, value)| key == "RUST_LOG" && value.is_none())
+        );
+        assert_eq!(share.log_path(), state_dir.path().join("virtiofsd3.log"));
+    }
+
+    #[test]
+    fn test_virtiofsd_log() {
+        let state_dir = tempdir().expect("Failed to create tempdir for testing");
+        let share = VirtiofsShare::new(
+            ShareOpts {
+                path: PathBuf::from("/this/is/a/test"),
+                ..Default::default()
+            },
+            3,
+            state_dir.path().to_path_buf(),
+        );
+        // stands in for a virtiofsd that fails to start
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg("echo first >&2; echo 'bad things' >&2; exit 3")
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to spawn test process");
+        match share.monitor_virtiofsd(child) {
+            Err(ShareError::VirtiofsdExited { tag, status, log }) => {
+                assert_eq!(tag, share.mount_tag());
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(log, "first\nbad things");
+            }
+            other => panic!("Expected VirtiofsdExited, got {other:?}"),
+        }
+        assert_eq!(
+            fs::read_to_string(share.log_path()).expect("Failed to read log"),
+            "first\nbad things\n",
+        );
+
+        // one that starts up fine
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "echo ready >&2; touch {}; sleep 10",
+                share.socket_path().display()
+            ))
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to spawn test process");
+        let mut child = share
+            .monitor_virtiofsd(child)
+            .expect("Failed to start virtiofsd");
+        assert!(child.try_wait().expect("Failed to check child").is_none());
+        child.kill().expect("Failed to kill child");
+        child.wait().expect("Failed to wait for child");
+    }
+
+    #[test]
+    fn test_log_tail() {
+        let dir = tempdir().expect("Failed to create tempdir for testing");
+        let path = dir.path().join("log");
+        let lines: Vec<_> = (0..30).map(|i| format!("line {i}")).collect();
+        fs::write(&path, lines.join("\n")).expect("Failed to write log");
+        assert_eq!(log_tail(&path), lines[10..].join("\n"));
+        assert!(log_tail(&dir.path().join("missing")).starts_with("<failed to read"));
     }
 
     #[test]
@@ -575,4 +1683,21 @@ Options=rw"#;
         );
         assert_eq!(share.virtiofsd_log_level(), None);
     }
+
+    #[test]
+    fn test_spawn_virtiofsd_not_found() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        let missing = dir.path().join("virtiofsd");
+        match spawn_virtiofsd(&mut Command::new(&missing)) {
+            Err(ShareError::VirtiofsdNotFound(path)) => assert_eq!(path, missing),
+            res => panic!("Expected VirtiofsdNotFound, got {res:?}"),
+        }
+
+        // other spawn failures are not reported as a missing binary
+        fs::write(&missing, "").expect("Failed to write");
+        assert!(matches!(
+            spawn_virtiofsd(&mut Command::new(&missing)),
+            Err(ShareError::VirtiofsdError(_))
+        ));
+    }
 }
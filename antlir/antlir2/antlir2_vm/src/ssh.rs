@@ -77,10 +77,15 @@ impl GuestSSHCommand {
             command.arg("-o").arg(format!("{}={}", name, value));
         });
         command.arg("-i").arg(self.privkey.path());
-        command.arg(format!("root@{}%vm0", self.guest_ipv6_addr_ll()));
+        command.arg(self.host());
         command
     }
 
+    /// User and address to ssh to
+    pub(crate) fn host(&self) -> String {
+        format!("root@{}%vm0", self.guest_ipv6_addr_ll())
+    }
+
     /// Link-local IP address of the first NIC of the guest VM. We always use this
     /// to communicate to VM. We use link-local address so that VM OS doesn't have
     /// to open up firewall for some global address specific for VM testing.
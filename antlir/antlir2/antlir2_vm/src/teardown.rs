@@ -0,0 +1,268 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Ordered teardown of everything the VM leaves running or lying around.
+//!
+//! Resources have to go away in a specific order: qemu first, so the guest
+//! never loses a mount while it is still running, then the virtiofsd daemons
+//! serving those mounts, and only then the files they were using, like disk
+//! overlays and sockets.
+
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::process::Child;
+
+use thiserror::Error;
+use tracing::debug;
+use tracing::error;
+
+/// When a resource is torn down, relative to the others. Stages run in the
+/// order they are declared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Stage {
+    /// The VMM process itself
+    Vmm,
+    /// Daemons serving devices to the VMM
+    Daemons,
+    /// Files used by the VMM or the daemons
+    Files,
+}
+
+/// Something that needs to be released once the VM is done
+pub(crate) trait Resource: Debug + Send {
+    /// Human readable description used in logs and errors
+    fn describe(&self) -> String;
+    /// Release the resource. This is called at most once.
+    fn teardown(&mut self) -> std::io::Result<()>;
+}
+
+#[derive(Debug, Error)]
+#[error(
+    "{} resource(s) failed to tear down: {}",
+    .0.len(),
+    .0.iter().map(|(name, err)| format!("{name}: {err}")).collect::<Vec<_>>().join(", ")
+)]
+pub(crate) struct TeardownError(pub(crate) Vec<(String, std::io::Error)>);
+
+/// Owns the VM resources and tears them down in [Stage] order, either on
+/// [Teardown::shutdown] or when dropped.
+#[derive(Debug, Default)]
+pub(crate) struct Teardown {
+    resources: Vec<(Stage, Box<dyn Resource>)>,
+}
+
+impl Teardown {
+    /// Take ownership of `resource` and tear it down during `stage`
+    pub(crate) fn register(&mut self, stage: Stage, resource: impl Resource + 'static) {
+        self.resources.push((stage, Box::new(resource)));
+    }
+
+    /// Tear down every resource registered so far. Within a stage, resources
+    /// are torn down in reverse registration order. A failure doesn't stop
+    /// the rest of the teardown; all failures are logged and returned together.
+    pub(crate) fn shutdown(&mut self) -> Result<(), TeardownError> {
+        let mut resources = std::mem::take(&mut self.resources);
+        resources.reverse();
+        // stable sort keeps the reversed order within each stage
+        resources.sort_by_key(|(stage, _)| *stage);
+        let mut failures = vec![];
+        for (stage, mut resource) in resources {
+            let name = resource.describe();
+            debug!("Tearing down {name} ({stage:?})");
+            if let Err(err) = resource.teardown() {
+                error!("Failed to tear down {name}: {err}");
+                failures.push((name, err));
+            }
+        }
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(TeardownError(failures)),
+        }
+    }
+
+    /// Let go of every resource registered so far without tearing anything
+    /// down, for when they have to outlive this process
+    pub(crate) fn forget(&mut self) {
+        self.resources.clear();
+    }
+}
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        // failures are already logged, there is nobody left to return them to
+        let _ = self.shutdown();
+    }
+}
+
+/// A child process that is killed if it's still running
+#[derive(Debug)]
+pub(crate) struct ChildProcess {
+    name: String,
+    child: Child,
+}
+
+impl ChildProcess {
+    pub(crate) fn new(name: impl Into<String>, child: Child) -> Self {
+        Self {
+            name: name.into(),
+            child,
+        }
+    }
+}
+
+impl Resource for ChildProcess {
+    fn describe(&self) -> String {
+        format!("{} (pid {})", self.name, self.child.id())
+    }
+
+    fn teardown(&mut self) -> std::io::Result<()> {
+        if self.child.try_wait()?.is_none() {
+            self.child.kill()?;
+        }
+        self.child.wait().map(|_| ())
+    }
+}
+
+/// A file that is removed if it exists
+#[derive(Debug)]
+pub(crate) struct RemoveFile(pub(crate) PathBuf);
+
+impl Resource for RemoveFile {
+    fn describe(&self) -> String {
+        self.0.display().to_string()
+    }
+
+    fn teardown(&mut self) -> std::io::Result<()> {
+        match std::fs::remove_file(&self.0) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct FakeResource {
+        name: &'static str,
+        fail: bool,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Resource for FakeResource {
+        fn describe(&self) -> String {
+            self.name.to_string()
+        }
+
+        fn teardown(&mut self) -> std::io::Result<()> {
+            self.calls.lock().expect("Poisoned lock").push(self.name);
+            match self.fail {
+                true => Err(std::io::Error::other("boom")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    fn register(
+        teardown: &mut Teardown,
+        calls: &Arc<Mutex<Vec<&'static str>>>,
+        stage: Stage,
+        name: &'static str,
+        fail: bool,
+    ) {
+        teardown.register(
+            stage,
+            FakeResource {
+                name,
+                fail,
+                calls: calls.clone(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_teardown_order() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut teardown = Teardown::default();
+        // registered in the order VM::new and VM::run create them
+        register(&mut teardown, &calls, Stage::Files, "overlay", false);
+        register(&mut teardown, &calls, Stage::Files, "socket", true);
+        register(&mut teardown, &calls, Stage::Daemons, "virtiofsd0", false);
+        register(&mut teardown, &calls, Stage::Daemons, "virtiofsd1", true);
+        register(&mut teardown, &calls, Stage::Vmm, "qemu", false);
+
+        let err = teardown.shutdown().expect_err("Teardown should fail");
+        assert_eq!(
+            *calls.lock().expect("Poisoned lock"),
+            vec!["qemu", "virtiofsd1", "virtiofsd0", "socket", "overlay"]
+        );
+        assert_eq!(
+            err.0
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["virtiofsd1", "socket"]
+        );
+        assert_eq!(
+            err.to_string(),
+            "2 resource(s) failed to tear down: virtiofsd1: boom, socket: boom"
+        );
+
+        // everything is only torn down once, even when dropped afterwards
+        assert!(teardown.shutdown().is_ok());
+        drop(teardown);
+        assert_eq!(calls.lock().expect("Poisoned lock").len(), 5);
+    }
+
+    #[test]
+    fn test_teardown_on_drop() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut teardown = Teardown::default();
+        register(&mut teardown, &calls, Stage::Daemons, "virtiofsd0", false);
+        register(&mut teardown, &calls, Stage::Vmm, "qemu", false);
+        drop(teardown);
+        assert_eq!(
+            *calls.lock().expect("Poisoned lock"),
+            vec!["qemu", "virtiofsd0"]
+        );
+    }
+
+    #[test]
+    fn test_forget() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut teardown = Teardown::default();
+        register(&mut teardown, &calls, Stage::Vmm, "qemu", false);
+        teardown.forget();
+        register(&mut teardown, &calls, Stage::Files, "info", false);
+        drop(teardown);
+        assert_eq!(*calls.lock().expect("Poisoned lock"), vec!["info"]);
+    }
+
+    #[test]
+    fn test_real_resources() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let file = dir.path().join("socket");
+        std::fs::write(&file, "").expect("Failed to write file");
+        let child = Command::new("sleep")
+            .arg("60")
+            .spawn()
+            .expect("Failed to spawn");
+
+        let mut teardown = Teardown::default();
+        teardown.register(Stage::Files, RemoveFile(file.clone()));
+        teardown.register(Stage::Files, RemoveFile(dir.path().join("missing")));
+        teardown.register(Stage::Daemons, ChildProcess::new("sleep", child));
+        teardown.shutdown().expect("Teardown failed");
+        assert!(!file.exists());
+    }
+}
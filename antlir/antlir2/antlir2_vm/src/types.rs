@@ -8,16 +8,22 @@
 //! This file contains data structure that mirrors what described in vm bzl files
 //! so that we can directly deserialize a json into Rust structs.
 
+use std::collections::BTreeSet;
 use std::collections::HashSet;
 use std::env;
 use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::fmt;
+use std::path::Component;
+use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use clap::Args;
+use clap::ValueEnum;
+use derive_builder::Builder;
 use image_test_lib::KvPair;
+use json_arg::JsonFile;
 use serde::Deserialize;
 use thiserror::Error;
 
@@ -25,8 +31,49 @@ use thiserror::Error;
 pub(crate) enum TypeError {
     #[error("Failed to parse CpuIsa from string: {0}")]
     InvalidCpuIsa(String),
+    #[error(transparent)]
+    ShareOptsBuilderError(#[from] ShareOptsBuilderError),
+    #[error("Share path `{0}` must be absolute")]
+    RelativeSharePath(PathBuf),
+    #[error("Mount tag `{0}` must be between 1 and {MAX_MOUNT_TAG_LEN} bytes long")]
+    InvalidMountTag(String),
+    #[error("virtiofsd thread pool size must be greater than zero")]
+    InvalidThreadPoolSize,
+    #[error("Can't squash share ownership to id {0}, it is reserved as the invalid id")]
+    InvalidSquashId(u32),
+    #[error("Unit file path `{0}` must be relative and may not contain `..`")]
+    InvalidUnitFilePath(PathBuf),
+    #[error("Invalid block device `{0}`, expected `<host-path>[:ro]`")]
+    InvalidBlockDev(String),
+    #[error("Invalid initrd file `{0}`: {1}")]
+    InvalidInitrdFile(String, &'static str),
+    #[error("Invalid hostname `{0}`, must be a valid RFC 1123 hostname")]
+    InvalidHostname(String),
+    #[error("Invalid machine-id `{0}`, must be 32 hex characters")]
+    InvalidMachineId(String),
+    #[error("Invalid MAC address `{0}`: {1}")]
+    InvalidMacAddress(String, &'static str),
+    #[error("Invalid entry {index} (`{path}`) in shares manifest: {source}")]
+    InvalidShareManifestEntry {
+        index: usize,
+        path: PathBuf,
+        source: Box<TypeError>,
+    },
+    #[error("Invalid NIC `{0}`: {1}")]
+    InvalidNic(String, String),
+    #[error("Invalid cpuset `{0}`: {1}")]
+    InvalidCpuSet(String, String),
+    #[error("Failed to read online CPUs from {ONLINE_CPUS}: {0}")]
+    OnlineCpusError(std::io::Error),
+    #[error("CPUs {cpus} are not online on this host (online: {online})")]
+    OfflineCpus { cpus: CpuSet, online: CpuSet },
 }
 
+/// virtio-fs limits the mount tag to 36 bytes
+const MAX_MOUNT_TAG_LEN: usize = 36;
+/// `(uid_t)-1`, which the kernel uses to mean "no id"
+pub(crate) const INVALID_ID: u32 = u32::MAX;
+
 /// Public interface for implementing a Qemu device
 pub(crate) trait QemuDevice {
     /// Returns a list of qemu args that can be joined with others to eventually
@@ -66,14 +113,147 @@ pub(crate) struct NonDiskBootOpts {
 }
 
 /// `ShareOpts` describes the property of a shared directory.
-#[derive(Debug, Clone, PartialEq, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Default, Builder)]
+#[builder(build_fn(name = "build_internal"))]
 pub(crate) struct ShareOpts {
     /// Path to the directory to share
+    #[builder(setter(into))]
     pub(crate) path: PathBuf,
     /// Read-only mount if true. R/W otherwise.
+    #[builder(default)]
     pub(crate) read_only: bool,
     /// Mount tag override. If None, a unique tag will be generated
+    #[builder(default, setter(into, strip_option))]
     pub(crate) mount_tag: Option<String>,
+    /// Size of the virtiofsd thread pool. If None, virtiofsd's default is used.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub(crate) thread_pool_size: Option<usize>,
+    /// Sandboxing mode for virtiofsd. If None, virtiofsd's default is used.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub(crate) sandbox: Option<VirtiofsdSandbox>,
+    /// Logging level for virtiofsd. If None, only warnings and errors are
+    /// logged, unless `RUST_LOG` sets a level for virtiofsd.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub(crate) log_level: Option<VirtiofsdLogLevel>,
+    /// Make everything in the share appear to be owned by this `[uid, gid]`
+    /// in the guest. If None, host ownership is passed through as-is.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub(crate) squash_to: Option<(u32, u32)>,
+    /// How many more times the guest tries to mount the share, a second
+    /// apart, if mounting fails. If None, a failed mount is not retried.
+    #[serde(default)]
+    #[builder(default, setter(strip_option))]
+    pub(crate) mount_retries: Option<u32>,
+    /// Additional files, like systemd drop-ins, to generate along with the
+    /// mount unit of the share
+    #[serde(default)]
+    #[builder(default)]
+    pub(crate) unit_files: Vec<UnitFile>,
+}
+
+/// A file to install in the guest among the generated unit files
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct UnitFile {
+    /// Path relative to the unit files directory, eg
+    /// `foo.service.d/override.conf` for a drop-in
+    pub(crate) path: PathBuf,
+    pub(crate) content: String,
+}
+
+impl ShareOptsBuilder {
+    /// Build the `ShareOpts`, validating all options together
+    pub(crate) fn build(&self) -> Result<ShareOpts, TypeError> {
+        let opts = self.build_internal()?;
+        opts.validate()?;
+        Ok(opts)
+    }
+}
+
+impl ShareOpts {
+    /// Check the options that can't be expressed in the type system. This is
+    /// done by the builder, but not when deserializing.
+    pub(crate) fn validate(&self) -> Result<(), TypeError> {
+        if !self.path.is_absolute() {
+            return Err(TypeError::RelativeSharePath(self.path.clone()));
+        }
+        if let Some(tag) = &self.mount_tag {
+            if tag.is_empty() || tag.len() > MAX_MOUNT_TAG_LEN {
+                return Err(TypeError::InvalidMountTag(tag.clone()));
+            }
+        }
+        if self.thread_pool_size == Some(0) {
+            return Err(TypeError::InvalidThreadPoolSize);
+        }
+        if let Some((uid, gid)) = self.squash_to {
+            if let Some(id) = [uid, gid].into_iter().find(|id| *id == INVALID_ID) {
+                return Err(TypeError::InvalidSquashId(id));
+            }
+        }
+        if let Some(unit_file) = self.unit_files.iter().find(|unit_file| {
+            unit_file.path.as_os_str().is_empty()
+                || !unit_file
+                    .path
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_)))
+        }) {
+            return Err(TypeError::InvalidUnitFilePath(unit_file.path.clone()));
+        }
+        Ok(())
+    }
+}
+
+/// How virtiofsd sandboxes itself from the rest of the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum VirtiofsdSandbox {
+    /// No sandboxing at all. This is typically required when virtiofsd is
+    /// already running inside a user namespace, where it lacks the privileges
+    /// to set up its own.
+    None,
+    /// Create a new mount, pid and network namespace and pivot_root into the
+    /// shared directory
+    Namespace,
+    /// chroot into the shared directory
+    Chroot,
+}
+
+impl fmt::Display for VirtiofsdSandbox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Namespace => write!(f, "namespace"),
+            Self::Chroot => write!(f, "chroot"),
+        }
+    }
+}
+
+/// How much virtiofsd logs, from most to least verbose
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum VirtiofsdLogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Off,
+}
+
+impl fmt::Display for VirtiofsdLogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Trace => write!(f, "trace"),
+            Self::Debug => write!(f, "debug"),
+            Self::Info => write!(f, "info"),
+            Self::Warn => write!(f, "warn"),
+            Self::Error => write!(f, "error"),
+            Self::Off => write!(f, "off"),
+        }
+    }
 }
 
 /// Operational specific parameters for VM but not related to VM configuration itself
@@ -87,7 +267,9 @@ pub(crate) struct VMArgs {
     /// Redirect console output to file. By default it's suppressed.
     #[clap(long)]
     pub(crate) console_output_file: Option<PathBuf>,
-    /// Output directories that need to be available inside VM
+    /// Output directories that need to be available inside VM. Relative
+    /// paths are resolved against the current directory before entering the
+    /// container.
     #[clap(long)]
     pub(crate) output_dirs: Vec<PathBuf>,
     /// Environment variables for the command
@@ -99,6 +281,93 @@ pub(crate) struct VMArgs {
     /// Dump network traffic on eth0 to output to file. By default it is not dumped.
     #[clap(long)]
     pub(crate) eth0_output_file: Option<PathBuf>,
+    /// Host cache mode for writable disks. qemu's default (writeback) is used
+    /// if unset.
+    #[clap(long, value_enum)]
+    pub(crate) disk_cache: Option<DiskCacheMode>,
+    /// Preallocate writable disks up front instead of growing them lazily
+    #[clap(long)]
+    pub(crate) disk_prealloc: bool,
+    /// Attach a host file or block device as a raw virtio-blk device, as
+    /// `<host-path>[:ro]`
+    #[clap(long)]
+    pub(crate) blockdev: Vec<BlockDevOpts>,
+    /// Add a host file to the initrd, as `<host-path>:<guest-path>`, so that
+    /// it is already there in early userspace, before the root filesystem
+    /// (or any share) is mounted. Requires booting from a kernel and initrd.
+    #[clap(long)]
+    pub(crate) initrd_file: Vec<InitrdFile>,
+    /// Hostname for the guest. The image default is used if unset.
+    #[clap(long)]
+    pub(crate) hostname: Option<Hostname>,
+    /// machine-id for the guest. The image default is used if unset.
+    #[clap(long)]
+    pub(crate) machine_id: Option<MachineId>,
+    /// MAC address for the first NIC instead of the default
+    /// 00:00:00:00:00:01. The default network config of antlir VM images
+    /// matches on the default MAC, so the image must configure the NIC itself.
+    #[clap(long)]
+    pub(crate) mac: Option<MacAddress>,
+    /// Host directory for the guest to leave results (logs, test outputs) in.
+    /// It is created if it doesn't exist, shared read-write and mounted at
+    /// /run/antlir2/results in the guest.
+    #[clap(long)]
+    pub(crate) results_dir: Option<PathBuf>,
+    /// JSON file with a list of additional directories to share into the VM
+    #[clap(long)]
+    pub(crate) shares_manifest: Option<JsonFile<Vec<ShareOpts>>>,
+    /// Add a virtio-serial channel for an agent running in the guest. The
+    /// host side is a unix socket in the VM state dir.
+    #[clap(long)]
+    pub(crate) guest_agent: bool,
+    /// Command to send to the guest agent once the VM has booted, before
+    /// running anything else. Implies `--guest-agent`.
+    #[clap(long)]
+    pub(crate) agent_command: Vec<String>,
+    /// Keep the guest root filesystem read-only after boot. /var is a tmpfs
+    /// so that services can still write their state. Mountpoints of shares
+    /// must already exist in the image, since they can't be created.
+    #[clap(long)]
+    pub(crate) read_only_root: bool,
+    /// Attach a NIC described as `model=<device>,mac=<mac>,netdev=<tap|user>`,
+    /// with every part optional. Repeat for more NICs. If not specified, the
+    /// VM gets the number of default NICs from its machine spec.
+    #[clap(long)]
+    pub(crate) nic: Vec<NicOpts>,
+    /// Pin qemu, including all of its vCPU threads, to these host CPUs, in
+    /// the kernel's list format like `0-3,8`. Every CPU must be online. qemu
+    /// can run on any CPU if unset.
+    #[clap(long)]
+    pub(crate) cpu_affinity: Option<CpuSet>,
+    /// Add a watchdog device that the guest is expected to keep petting, so
+    /// that a hung guest is stopped instead of running until the timeout
+    #[clap(long)]
+    pub(crate) watchdog: bool,
+    /// What happens once the watchdog fires. Defaults to poweroff.
+    #[clap(long, value_enum, requires = "watchdog")]
+    pub(crate) watchdog_action: Option<WatchdogAction>,
+    /// Once the VM has booted, save its state into its qcow2 disks as a
+    /// snapshot with this name, before running anything else
+    #[clap(
+        long,
+        requires = "snapshot_dir",
+        conflicts_with_all = ["first_boot_command", "container", "detach"]
+    )]
+    pub(crate) save_snapshot: Option<String>,
+    /// Restore the snapshot with this name instead of booting the VM. The
+    /// snapshot must exist in the qcow2 disks in `--snapshot-dir`.
+    #[clap(
+        long,
+        requires = "snapshot_dir",
+        conflicts_with_all = ["first_boot_command", "container", "detach"]
+    )]
+    pub(crate) load_snapshot: Option<String>,
+    /// Keep the VM's qcow2 disks in this directory instead of the state dir
+    /// that is removed when the VM exits, so that the snapshots saved into
+    /// them can be loaded by a later run. Loading a snapshot uses the disks
+    /// that are already there.
+    #[clap(long)]
+    pub(crate) snapshot_dir: Option<PathBuf>,
     /// Operation for VM to carry out
     #[clap(flatten)]
     pub(crate) mode: VMModeArgs,
@@ -116,6 +385,20 @@ pub(crate) struct VMModeArgs {
     /// Drop into container shell outside VM.
     #[clap(long)]
     pub(crate) container: bool,
+    /// Measure read and write throughput of every writable share from inside
+    /// the VM and print the results as JSON lines.
+    #[clap(long)]
+    pub(crate) bench_shares: bool,
+    /// Print the resolved configuration of every share as JSON and exit
+    /// without booting the VM.
+    #[clap(long)]
+    pub(crate) list_shares: bool,
+    /// Boot the VM, write how to reach it as JSON to this file and exit,
+    /// leaving the VM running for as long as the container it was started
+    /// in. Use the `stop` command to shut it down. Only supported by `run`,
+    /// since the container of `isolate` and `test` exits with the launcher.
+    #[clap(long, value_name = "INFO_FILE")]
+    pub(crate) detach: Option<PathBuf>,
     /// Execute command through ssh inside VM.
     #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
     pub(crate) command: Option<Vec<OsString>>,
@@ -138,6 +421,78 @@ impl VMArgs {
             args.push("--eth0-output-file".into());
             args.push(path.into());
         }
+        if let Some(cache) = &self.disk_cache {
+            args.push("--disk-cache".into());
+            args.push(cache.to_string().into());
+        }
+        if self.disk_prealloc {
+            args.push("--disk-prealloc".into());
+        }
+        self.blockdev.iter().for_each(|blockdev| {
+            args.push("--blockdev".into());
+            args.push(blockdev.to_string().into());
+        });
+        self.initrd_file.iter().for_each(|file| {
+            args.push("--initrd-file".into());
+            args.push(file.to_string().into());
+        });
+        if let Some(hostname) = &self.hostname {
+            args.push("--hostname".into());
+            args.push(hostname.to_string().into());
+        }
+        if let Some(machine_id) = &self.machine_id {
+            args.push("--machine-id".into());
+            args.push(machine_id.to_string().into());
+        }
+        if let Some(mac) = &self.mac {
+            args.push("--mac".into());
+            args.push(mac.to_string().into());
+        }
+        if let Some(dir) = &self.results_dir {
+            args.push("--results-dir".into());
+            args.push(dir.into());
+        }
+        if let Some(manifest) = &self.shares_manifest {
+            args.push("--shares-manifest".into());
+            args.push(manifest.path().into());
+        }
+        if self.guest_agent {
+            args.push("--guest-agent".into());
+        }
+        self.agent_command.iter().for_each(|command| {
+            args.push("--agent-command".into());
+            args.push(command.into());
+        });
+        if self.read_only_root {
+            args.push("--read-only-root".into());
+        }
+        self.nic.iter().for_each(|nic| {
+            args.push("--nic".into());
+            args.push(nic.to_string().into());
+        });
+        if let Some(cpus) = &self.cpu_affinity {
+            args.push("--cpu-affinity".into());
+            args.push(cpus.to_string().into());
+        }
+        if self.watchdog {
+            args.push("--watchdog".into());
+        }
+        if let Some(action) = &self.watchdog_action {
+            args.push("--watchdog-action".into());
+            args.push(action.to_string().into());
+        }
+        if let Some(name) = &self.save_snapshot {
+            args.push("--save-snapshot".into());
+            args.push(name.into());
+        }
+        if let Some(name) = &self.load_snapshot {
+            args.push("--load-snapshot".into());
+            args.push(name.into());
+        }
+        if let Some(dir) = &self.snapshot_dir {
+            args.push("--snapshot-dir".into());
+            args.push(dir.into());
+        }
         self.command_envs.iter().for_each(|pair| {
             args.push("--command-envs".into());
             let mut kv_str = OsString::new();
@@ -160,6 +515,16 @@ impl VMArgs {
         if self.mode.container {
             args.push("--container".into());
         }
+        if self.mode.bench_shares {
+            args.push("--bench-shares".into());
+        }
+        if self.mode.list_shares {
+            args.push("--list-shares".into());
+        }
+        if let Some(path) = &self.mode.detach {
+            args.push("--detach".into());
+            args.push(path.into());
+        }
         if let Some(command) = &self.mode.command {
             command.iter().for_each(|c| args.push(c.clone()));
         }
@@ -191,8 +556,434 @@ impl VMArgs {
                 outputs.insert(env::current_dir().expect("current dir must be valid"));
             }
         }
+        // qemu opens block devices from inside the container
+        outputs.extend(self.blockdev.iter().map(|b| b.path.clone()));
+        // the initrd overlay is generated from inside the container
+        outputs.extend(self.initrd_file.iter().map(|f| f.src.clone()));
+        // the guest writes results through virtiofsd in the container
+        outputs.extend(self.results_dir.clone());
+        // qemu keeps the disks holding snapshots there
+        outputs.extend(self.snapshot_dir.clone());
+        // virtiofsd serves the manifest shares from inside the container
+        if let Some(manifest) = &self.shares_manifest {
+            outputs.extend(manifest.iter().map(|share| share.path.clone()));
+        }
         outputs
     }
+
+    /// Validated shares from `--shares-manifest`, in manifest order
+    pub(crate) fn get_manifest_shares(&self) -> Result<Vec<ShareOpts>, TypeError> {
+        let Some(manifest) = &self.shares_manifest else {
+            return Ok(vec![]);
+        };
+        manifest
+            .iter()
+            .enumerate()
+            .map(|(index, share)| {
+                share.validate().map(|_| share.clone()).map_err(|e| {
+                    TypeError::InvalidShareManifestEntry {
+                        index,
+                        path: share.path.clone(),
+                        source: Box::new(e),
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+/// Maximum length of a hostname, per RFC 1123
+const MAX_HOSTNAME_LEN: usize = 253;
+/// Maximum length of each dot-separated label of a hostname
+const MAX_HOSTNAME_LABEL_LEN: usize = 63;
+
+/// A hostname that is valid per RFC 1123
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Hostname(String);
+
+impl FromStr for Hostname {
+    type Err = TypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid_label = |label: &str| {
+            !label.is_empty()
+                && label.len() <= MAX_HOSTNAME_LABEL_LEN
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        };
+        if s.is_empty() || s.len() > MAX_HOSTNAME_LEN || !s.split('.').all(valid_label) {
+            return Err(TypeError::InvalidHostname(s.to_owned()));
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl fmt::Display for Hostname {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A machine-id as described in machine-id(5). Stored in lowercase, which is
+/// the only form systemd accepts in /etc/machine-id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MachineId(String);
+
+impl FromStr for MachineId {
+    type Err = TypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 32 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(TypeError::InvalidMachineId(s.to_owned()));
+        }
+        Ok(Self(s.to_ascii_lowercase()))
+    }
+}
+
+impl fmt::Display for MachineId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A unicast MAC address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MacAddress([u8; 6]);
+
+impl FromStr for MacAddress {
+    type Err = TypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason| TypeError::InvalidMacAddress(s.to_owned(), reason);
+        let octets = s
+            .split(':')
+            .map(|octet| match octet.len() {
+                2 => u8::from_str_radix(octet, 16).ok(),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .and_then(|octets| <[u8; 6]>::try_from(octets).ok())
+            .ok_or_else(|| err("expected six colon-separated hex octets"))?;
+        if octets == [0xff; 6] {
+            return Err(err("broadcast address"));
+        }
+        // the least significant bit of the first octet is the group bit
+        if octets[0] & 1 == 1 {
+            return Err(err("multicast address"));
+        }
+        Ok(Self(octets))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// A host file or block device attached to the VM as a raw virtio-blk device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BlockDevOpts {
+    /// Path on the host
+    pub(crate) path: PathBuf,
+    /// Attach the device read-only
+    pub(crate) read_only: bool,
+}
+
+impl FromStr for BlockDevOpts {
+    type Err = TypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, read_only) = match s.strip_suffix(":ro") {
+            Some(path) => (path, true),
+            None => (s, false),
+        };
+        if path.is_empty() {
+            return Err(TypeError::InvalidBlockDev(s.to_owned()));
+        }
+        Ok(Self {
+            path: path.into(),
+            read_only,
+        })
+    }
+}
+
+impl fmt::Display for BlockDevOpts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if self.read_only {
+            write!(f, ":ro")?;
+        }
+        Ok(())
+    }
+}
+
+/// A host file to add to the initrd the VM boots with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct InitrdFile {
+    /// Path on the host
+    pub(crate) src: PathBuf,
+    /// Absolute path in the guest
+    pub(crate) dst: PathBuf,
+}
+
+impl FromStr for InitrdFile {
+    type Err = TypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason| TypeError::InvalidInitrdFile(s.to_owned(), reason);
+        // guest paths are more constrained than host paths, so they are the
+        // ones that are not allowed to contain a `:`
+        let (src, dst) = s
+            .rsplit_once(':')
+            .ok_or_else(|| err("expected `<host-path>:<guest-path>`"))?;
+        if src.is_empty() {
+            return Err(err("host path must not be empty"));
+        }
+        let dst = Path::new(dst);
+        if !dst.is_absolute() {
+            return Err(err("guest path must be absolute"));
+        }
+        let mut components = dst.components().skip(1).peekable();
+        if components.peek().is_none() {
+            return Err(err("guest path must not be `/`"));
+        }
+        if !components.all(|c| matches!(c, Component::Normal(_))) {
+            return Err(err("guest path may not contain `..`"));
+        }
+        Ok(Self {
+            src: src.into(),
+            dst: dst.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for InitrdFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.src.display(), self.dst.display())
+    }
+}
+
+/// Where the traffic of a NIC goes on the host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum NetdevBackend {
+    /// A tap device in the container, reachable from the host side
+    #[default]
+    Tap,
+    /// qemu's user mode networking, which needs no setup on the host
+    User,
+}
+
+impl FromStr for NetdevBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tap" => Ok(Self::Tap),
+            "user" => Ok(Self::User),
+            _ => Err(format!(
+                "unknown netdev backend `{s}`, expected tap or user"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for NetdevBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Tap => write!(f, "tap"),
+            Self::User => write!(f, "user"),
+        }
+    }
+}
+
+/// A NIC specified as `model=<device>,mac=<mac>,netdev=<backend>`. Every part
+/// is optional and falls back to the same defaults as NICs that are not
+/// specified explicitly.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct NicOpts {
+    /// qemu device model, like virtio-net-pci or e1000
+    pub(crate) model: Option<String>,
+    /// MAC address instead of the one derived from the NIC's position
+    pub(crate) mac: Option<MacAddress>,
+    /// Host side of the NIC
+    pub(crate) netdev: Option<NetdevBackend>,
+}
+
+impl FromStr for NicOpts {
+    type Err = TypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason: String| TypeError::InvalidNic(s.to_owned(), reason);
+        let mut opts = Self::default();
+        for part in s.split(',').filter(|part| !part.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| err(format!("expected key=value, got `{part}`")))?;
+            let duplicate = match key {
+                "model" => {
+                    if value.is_empty()
+                        || !value
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    {
+                        return Err(err(format!("invalid model `{value}`")));
+                    }
+                    opts.model.replace(value.to_owned()).is_some()
+                }
+                "mac" => opts
+                    .mac
+                    .replace(value.parse().map_err(|e: TypeError| err(e.to_string()))?)
+                    .is_some(),
+                "netdev" => opts.netdev.replace(value.parse().map_err(err)?).is_some(),
+                _ => return Err(err(format!("unknown key `{key}`"))),
+            };
+            if duplicate {
+                return Err(err(format!("`{key}` is specified more than once")));
+            }
+        }
+        Ok(opts)
+    }
+}
+
+impl fmt::Display for NicOpts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(model) = &self.model {
+            parts.push(format!("model={model}"));
+        }
+        if let Some(mac) = &self.mac {
+            parts.push(format!("mac={mac}"));
+        }
+        if let Some(netdev) = &self.netdev {
+            parts.push(format!("netdev={netdev}"));
+        }
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+/// Host CPUs that are currently online
+const ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+
+/// A set of host CPUs in the kernel's list format, like `0-3,8`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CpuSet(BTreeSet<usize>);
+
+impl CpuSet {
+    /// CPUs that are online on the host
+    pub(crate) fn online() -> Result<Self, TypeError> {
+        std::fs::read_to_string(ONLINE_CPUS)
+            .map_err(TypeError::OnlineCpusError)?
+            .trim()
+            .parse()
+    }
+
+    /// Fail if any of the CPUs is not in `online`
+    pub(crate) fn check_online(&self, online: &Self) -> Result<(), TypeError> {
+        let offline: BTreeSet<_> = self.0.difference(&online.0).copied().collect();
+        if offline.is_empty() {
+            return Ok(());
+        }
+        Err(TypeError::OfflineCpus {
+            cpus: Self(offline),
+            online: online.clone(),
+        })
+    }
+}
+
+impl FromStr for CpuSet {
+    type Err = TypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason: String| TypeError::InvalidCpuSet(s.to_owned(), reason);
+        let cpu = |cpu: &str| {
+            cpu.parse::<usize>()
+                .map_err(|_| err(format!("`{cpu}` is not a CPU number")))
+        };
+        let mut cpus = BTreeSet::new();
+        for part in s.split(',') {
+            match part.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (cpu(first)?, cpu(last)?);
+                    if first > last {
+                        return Err(err(format!("range `{part}` is backwards")));
+                    }
+                    cpus.extend(first..=last);
+                }
+                None => {
+                    cpus.insert(cpu(part)?);
+                }
+            }
+        }
+        Ok(Self(cpus))
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        for &cpu in &self.0 {
+            match ranges.last_mut() {
+                Some((_, last)) if *last + 1 == cpu => *last = cpu,
+                _ => ranges.push((cpu, cpu)),
+            }
+        }
+        let ranges: Vec<_> = ranges
+            .into_iter()
+            .map(|(first, last)| match first == last {
+                true => first.to_string(),
+                false => format!("{first}-{last}"),
+            })
+            .collect();
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+/// Mirrors the `cache=` modes of qemu's -drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum DiskCacheMode {
+    /// Bypass the host page cache with O_DIRECT. This requires I/O to be
+    /// aligned to the host's block size and a state directory on a filesystem
+    /// that supports O_DIRECT.
+    None,
+    /// Use the host page cache and report writes as done once cached
+    Writeback,
+    /// Use the host page cache, but only report writes as done once on disk
+    Writethrough,
+}
+
+impl fmt::Display for DiskCacheMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Writeback => write!(f, "writeback"),
+            Self::Writethrough => write!(f, "writethrough"),
+        }
+    }
+}
+
+/// What qemu does once the watchdog fires
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum WatchdogAction {
+    /// Stop the VM
+    #[default]
+    Poweroff,
+    /// Reset the VM. qemu runs with -no-reboot, so this stops the VM too.
+    Reset,
+    /// Only report that the watchdog fired
+    None,
+}
+
+impl fmt::Display for WatchdogAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Poweroff => write!(f, "poweroff"),
+            Self::Reset => write!(f, "reset"),
+            Self::None => write!(f, "none"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default, Deserialize, PartialEq)]
@@ -204,6 +995,16 @@ pub(crate) enum CpuIsa {
     X86_64,
 }
 
+impl CpuIsa {
+    /// qemu binary that emulates this architecture
+    pub(crate) fn qemu_binary(&self) -> &'static str {
+        match self {
+            Self::X86_64 => "qemu-system-x86_64",
+            Self::AARCH64 => "qemu-system-aarch64",
+        }
+    }
+}
+
 impl fmt::Display for CpuIsa {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -257,6 +1058,9 @@ pub(crate) struct MachineOpts {
     pub(crate) use_tpm: bool,
     /// Use 9p instead of virtiofs for sharing. This is required for kernel older than 5.4.
     pub(crate) use_legacy_share: bool,
+    /// Back guest memory with hugepages from /dev/hugepages instead of memfd.
+    #[serde(default)]
+    pub(crate) use_hugepages: bool,
 }
 
 #[cfg(test)]
@@ -277,8 +1081,71 @@ mod test {
             vec!["bin"],
             vec!["bin", "--console"],
             vec!["bin", "--container"],
+            vec!["bin", "--bench-shares"],
+            vec!["bin", "--list-shares"],
+            vec!["bin", "--detach", "/tmp/vm.json"],
             vec!["bin", "--console-output-file", "/path/to/out"],
             vec!["bin", "--timeout-secs", "10"],
+            vec!["bin", "--disk-cache", "none", "--disk-prealloc"],
+            vec!["bin", "--disk-cache", "writethrough"],
+            vec![
+                "bin",
+                "--blockdev",
+                "/dev/sdb",
+                "--blockdev",
+                "/tmp/disk.img:ro",
+            ],
+            vec![
+                "bin",
+                "--initrd-file",
+                "/tmp/key:/etc/early/key",
+                "--initrd-file",
+                "/tmp/a:b:/c",
+            ],
+            vec![
+                "bin",
+                "--hostname",
+                "vm.example.com",
+                "--machine-id",
+                "0123456789abcdef0123456789abcdef",
+            ],
+            vec!["bin", "--mac", "02:00:5e:10:00:01"],
+            vec!["bin", "--results-dir", "/tmp/results"],
+            vec![
+                "bin",
+                "--guest-agent",
+                "--agent-command",
+                "probe network",
+                "--agent-command",
+                "uptime",
+            ],
+            vec!["bin", "--read-only-root"],
+            vec![
+                "bin",
+                "--nic",
+                "",
+                "--nic",
+                "model=e1000,mac=02:00:5e:10:00:01,netdev=user",
+            ],
+            vec!["bin", "--cpu-affinity", "0-3,8"],
+            vec!["bin", "--watchdog"],
+            vec!["bin", "--watchdog", "--watchdog-action", "none"],
+            vec![
+                "bin",
+                "--save-snapshot",
+                "booted",
+                "--snapshot-dir",
+                "/tmp/snapshots",
+            ],
+            vec![
+                "bin",
+                "--save-snapshot",
+                "setup",
+                "--load-snapshot",
+                "booted",
+                "--snapshot-dir",
+                "/tmp/snapshots",
+            ],
             vec!["bin", "--output-dirs", "/foo", "--output-dirs", "/bar"],
             vec![
                 "bin",
@@ -318,6 +1185,152 @@ mod test {
             );
             assert_eq!(parsed.to_args(), original);
         });
+
+        // snapshots only apply to a VM that actually boots once and is then
+        // used by us, and the watchdog action needs a watchdog
+        [
+            vec![
+                "bin",
+                "--save-snapshot",
+                "booted",
+                "--snapshot-dir",
+                "/tmp/snapshots",
+                "--container",
+            ],
+            vec![
+                "bin",
+                "--load-snapshot",
+                "booted",
+                "--snapshot-dir",
+                "/tmp/snapshots",
+                "--first-boot-command",
+                "x",
+            ],
+            vec!["bin", "--watchdog-action", "reset"],
+            vec!["bin", "--detach", "/tmp/vm.json", "--console"],
+            vec![
+                "bin",
+                "--detach",
+                "/tmp/vm.json",
+                "--load-snapshot",
+                "x",
+                "--snapshot-dir",
+                "/tmp/snapshots",
+            ],
+            // the disks in the state dir don't outlive the VM
+            vec!["bin", "--save-snapshot", "booted"],
+        ]
+        .iter()
+        .for_each(|args| {
+            assert!(TestArgs::try_parse_from(args).is_err(), "{args:?}");
+        });
+    }
+
+    #[test]
+    fn test_share_opts_builder() {
+        let opts = ShareOptsBuilder::default()
+            .path("/this/is/a/test")
+            .read_only(true)
+            .mount_tag("whatever")
+            .thread_pool_size(4)
+            .sandbox(VirtiofsdSandbox::None)
+            .log_level(VirtiofsdLogLevel::Debug)
+            .squash_to((1000, 100))
+            .mount_retries(3)
+            .unit_files(vec![UnitFile {
+                path: PathBuf::from("foo.service.d/override.conf"),
+                content: "[Service]\nEnvironment=FOO=1\n".to_string(),
+            }])
+            .build()
+            .expect("Failed to build ShareOpts");
+        assert_eq!(
+            opts,
+            ShareOpts {
+                path: PathBuf::from("/this/is/a/test"),
+                read_only: true,
+                mount_tag: Some("whatever".to_string()),
+                thread_pool_size: Some(4),
+                sandbox: Some(VirtiofsdSandbox::None),
+                log_level: Some(VirtiofsdLogLevel::Debug),
+                squash_to: Some((1000, 100)),
+                mount_retries: Some(3),
+                unit_files: vec![UnitFile {
+                    path: PathBuf::from("foo.service.d/override.conf"),
+                    content: "[Service]\nEnvironment=FOO=1\n".to_string(),
+                }],
+            }
+        );
+
+        // Defaults
+        assert_eq!(
+            ShareOptsBuilder::default()
+                .path("/this/is/a/test")
+                .build()
+                .expect("Failed to build ShareOpts"),
+            ShareOpts {
+                path: PathBuf::from("/this/is/a/test"),
+                ..Default::default()
+            }
+        );
+
+        assert!(matches!(
+            ShareOptsBuilder::default().build(),
+            Err(TypeError::ShareOptsBuilderError(_)),
+        ));
+        assert!(matches!(
+            ShareOptsBuilder::default().path("relative").build(),
+            Err(TypeError::RelativeSharePath(_)),
+        ));
+        assert!(matches!(
+            ShareOptsBuilder::default()
+                .path("/this/is/a/test")
+                .mount_tag("")
+                .build(),
+            Err(TypeError::InvalidMountTag(_)),
+        ));
+        assert!(matches!(
+            ShareOptsBuilder::default()
+                .path("/this/is/a/test")
+                .mount_tag("t".repeat(MAX_MOUNT_TAG_LEN + 1))
+                .build(),
+            Err(TypeError::InvalidMountTag(_)),
+        ));
+        assert!(matches!(
+            ShareOptsBuilder::default()
+                .path("/this/is/a/test")
+                .thread_pool_size(0)
+                .build(),
+            Err(TypeError::InvalidThreadPoolSize),
+        ));
+        assert!(matches!(
+            ShareOptsBuilder::default()
+                .path("/this/is/a/test")
+                .squash_to((0, INVALID_ID))
+                .build(),
+            Err(TypeError::InvalidSquashId(INVALID_ID)),
+        ));
+        [
+            "",
+            "/etc/systemd/system/foo.service",
+            "../foo.service",
+            "foo.d/../../x",
+        ]
+        .into_iter()
+        .for_each(|path| {
+            assert!(
+                matches!(
+                    ShareOptsBuilder::default()
+                        .path("/this/is/a/test")
+                        .unit_files(vec![UnitFile {
+                            path: PathBuf::from(path),
+                            content: String::new(),
+                        }])
+                        .build(),
+                    Err(TypeError::InvalidUnitFilePath(_)),
+                ),
+                "{path}"
+            );
+        });
     }
 
     #[test]
@@ -343,6 +1356,296 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_blockdev_opts() {
+        assert_eq!(
+            BlockDevOpts::from_str("/dev/sdb").expect("Failed to parse"),
+            BlockDevOpts {
+                path: "/dev/sdb".into(),
+                read_only: false,
+            }
+        );
+        assert_eq!(
+            BlockDevOpts::from_str("/tmp/disk.img:ro").expect("Failed to parse"),
+            BlockDevOpts {
+                path: "/tmp/disk.img".into(),
+                read_only: true,
+            }
+        );
+        assert!(BlockDevOpts::from_str("").is_err());
+        assert!(BlockDevOpts::from_str(":ro").is_err());
+    }
+
+    #[test]
+    fn test_initrd_file() {
+        assert_eq!(
+            InitrdFile::from_str("/tmp/key:/etc/early/key").expect("Failed to parse"),
+            InitrdFile {
+                src: "/tmp/key".into(),
+                dst: "/etc/early/key".into(),
+            }
+        );
+        assert_eq!(
+            InitrdFile::from_str("a:b:/c").expect("Failed to parse"),
+            InitrdFile {
+                src: "a:b".into(),
+                dst: "/c".into(),
+            }
+        );
+        [
+            ("/tmp/key", "expected `<host-path>:<guest-path>`"),
+            (":/etc/key", "host path must not be empty"),
+            ("/tmp/key:etc/key", "guest path must be absolute"),
+            ("/tmp/key:/", "guest path must not be `/`"),
+            ("/tmp/key:/etc/../key", "guest path may not contain `..`"),
+        ]
+        .iter()
+        .for_each(|(file, expected)| match InitrdFile::from_str(file) {
+            Err(TypeError::InvalidInitrdFile(_, reason)) => assert_eq!(&reason, expected, "{file}"),
+            other => panic!("{file} should be invalid, got {other:?}"),
+        });
+    }
+
+    #[test]
+    fn test_hostname() {
+        ["vm", "vm-1", "1vm", "vm.example.com", &"a".repeat(63)]
+            .iter()
+            .for_each(|name| {
+                assert_eq!(
+                    Hostname::from_str(name)
+                        .expect("Failed to parse hostname")
+                        .to_string(),
+                    *name
+                );
+            });
+        [
+            "",
+            "-vm",
+            "vm-",
+            "vm..com",
+            "vm.",
+            "vm_1",
+            "vm 1",
+            &"a".repeat(64),
+            &["a"; 128].join("."),
+        ]
+        .iter()
+        .for_each(|name| {
+            assert!(
+                Hostname::from_str(name).is_err(),
+                "{name} should be invalid"
+            );
+        });
+    }
+
+    #[test]
+    fn test_cpu_set() {
+        let cpus = CpuSet::from_str("8,0-3,2").expect("Failed to parse cpuset");
+        assert_eq!(cpus, CpuSet(BTreeSet::from([0, 1, 2, 3, 8])));
+        assert_eq!(cpus.to_string(), "0-3,8");
+        assert_eq!(
+            CpuSet::from_str("5").expect("Failed to parse cpuset"),
+            CpuSet(BTreeSet::from([5]))
+        );
+        ["", "0,", "-1", "3-1", "0-", "a", "0-3,x"]
+            .iter()
+            .for_each(|cpus| {
+                assert!(CpuSet::from_str(cpus).is_err(), "{cpus} should be invalid");
+            });
+
+        let online = CpuSet::from_str("0-7").expect("Failed to parse cpuset");
+        cpus.check_online(&online)
+            .expect_err("CPU 8 is out of range");
+        match CpuSet::from_str("6-9")
+            .expect("Failed to parse cpuset")
+            .check_online(&online)
+        {
+            Err(TypeError::OfflineCpus { cpus, .. }) => assert_eq!(cpus.to_string(), "8-9"),
+            other => panic!("Unexpected result: {other:?}"),
+        }
+        CpuSet::from_str("0-3,7")
+            .expect("Failed to parse cpuset")
+            .check_online(&online)
+            .expect("All CPUs are online");
+    }
+
+    #[test]
+    fn test_machine_id() {
+        assert_eq!(
+            MachineId::from_str("0123456789ABCDEF0123456789abcdef")
+                .expect("Failed to parse machine-id")
+                .to_string(),
+            "0123456789abcdef0123456789abcdef"
+        );
+        [
+            "",
+            "0123456789abcdef0123456789abcde",
+            "0123456789abcdef0123456789abcdef0",
+            "0123456789abcdef0123456789abcdeg",
+            "01234567-89ab-cdef-0123-456789abcdef",
+        ]
+        .iter()
+        .for_each(|id| {
+            assert!(MachineId::from_str(id).is_err(), "{id} should be invalid");
+        });
+    }
+
+    #[test]
+    fn test_mac_address() {
+        assert_eq!(
+            MacAddress::from_str("02:00:5E:10:00:0a")
+                .expect("Failed to parse MAC")
+                .to_string(),
+            "02:00:5e:10:00:0a"
+        );
+        [
+            ("", "expected six colon-separated hex octets"),
+            ("02:00:5e:10:00", "expected six colon-separated hex octets"),
+            (
+                "02:00:5e:10:00:01:02",
+                "expected six colon-separated hex octets",
+            ),
+            (
+                "02-00-5e-10-00-01",
+                "expected six colon-separated hex octets",
+            ),
+            (
+                "02:00:5e:10:00:1",
+                "expected six colon-separated hex octets",
+            ),
+            (
+                "02:00:5e:10:00:0g",
+                "expected six colon-separated hex octets",
+            ),
+            ("ff:ff:ff:ff:ff:ff", "broadcast address"),
+            ("01:00:5e:00:00:01", "multicast address"),
+            ("33:33:00:00:00:01", "multicast address"),
+        ]
+        .iter()
+        .for_each(|(mac, reason)| match MacAddress::from_str(mac) {
+            Err(TypeError::InvalidMacAddress(_, r)) => assert_eq!(&r, reason, "{mac}"),
+            other => panic!("{mac} should be invalid, got {other:?}"),
+        });
+    }
+
+    #[test]
+    fn test_shares_manifest() {
+        #[derive(Debug, Parser)]
+        struct TestArgs {
+            #[clap(flatten)]
+            args: VMArgs,
+        }
+
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let manifest = dir.path().join("shares.json");
+        std::fs::write(
+            &manifest,
+            r#"[
+                {"path": "/data", "read_only": true, "mount_tag": "data"},
+                {"path": "/scratch", "read_only": false, "thread_pool_size": 4}
+            ]"#,
+        )
+        .expect("Failed to write manifest");
+        let parsed = TestArgs::parse_from([
+            OsStr::new("bin"),
+            OsStr::new("--shares-manifest"),
+            manifest.as_os_str(),
+        ])
+        .args;
+        assert_eq!(
+            parsed.get_manifest_shares().expect("Invalid manifest"),
+            vec![
+                ShareOptsBuilder::default()
+                    .path("/data")
+                    .read_only(true)
+                    .mount_tag("data")
+                    .build()
+                    .expect("Failed to build ShareOpts"),
+                ShareOptsBuilder::default()
+                    .path("/scratch")
+                    .thread_pool_size(4)
+                    .build()
+                    .expect("Failed to build ShareOpts"),
+            ]
+        );
+        assert_eq!(
+            parsed.to_args(),
+            vec![OsString::from("--shares-manifest"), manifest.clone().into()]
+        );
+        assert_eq!(
+            TestArgs::parse_from(std::iter::once("bin".into()).chain(parsed.to_args())).args,
+            parsed
+        );
+
+        std::fs::write(
+            &manifest,
+            r#"[
+                {"path": "/data", "read_only": true},
+                {"path": "relative", "read_only": false}
+            ]"#,
+        )
+        .expect("Failed to write manifest");
+        let parsed = TestArgs::parse_from([
+            OsStr::new("bin"),
+            OsStr::new("--shares-manifest"),
+            manifest.as_os_str(),
+        ])
+        .args;
+        let err = parsed
+            .get_manifest_shares()
+            .expect_err("Relative path should be rejected");
+        assert_eq!(
+            err.to_string(),
+            "Invalid entry 1 (`relative`) in shares manifest: Share path `relative` must be absolute"
+        );
+    }
+
+    #[test]
+    fn test_nic_opts() {
+        assert_eq!(
+            NicOpts::from_str("").expect("Failed to parse NIC"),
+            NicOpts::default()
+        );
+        let opts = NicOpts::from_str("netdev=user,model=e1000,mac=02:00:5E:10:00:01")
+            .expect("Failed to parse NIC");
+        assert_eq!(
+            opts,
+            NicOpts {
+                model: Some("e1000".to_string()),
+                mac: Some("02:00:5e:10:00:01".parse().expect("Invalid MAC")),
+                netdev: Some(NetdevBackend::User),
+            }
+        );
+        assert_eq!(
+            opts.to_string(),
+            "model=e1000,mac=02:00:5e:10:00:01,netdev=user"
+        );
+
+        [
+            ("model", "expected key=value, got `model`"),
+            ("model=", "invalid model ``"),
+            ("model=e1000 x", "invalid model `e1000 x`"),
+            ("speed=10", "unknown key `speed`"),
+            (
+                "netdev=bridge",
+                "unknown netdev backend `bridge`, expected tap or user",
+            ),
+            (
+                "mac=01:00:5e:00:00:01",
+                "Invalid MAC address `01:00:5e:00:00:01`: multicast address",
+            ),
+            (
+                "model=e1000,model=rtl8139",
+                "`model` is specified more than once",
+            ),
+        ]
+        .iter()
+        .for_each(|(nic, expected)| match NicOpts::from_str(nic) {
+            Err(TypeError::InvalidNic(_, reason)) => assert_eq!(&reason, expected, "{nic}"),
+            other => panic!("{nic} should be invalid, got {other:?}"),
+        });
+    }
+
     #[test]
     fn test_get_container_output_dirs() {
         let args = VMArgs {
@@ -354,5 +1657,43 @@ mod test {
             args.get_container_output_dirs(),
             HashSet::from(["/foo/bar".into(), "/baz".into(), "/tmp".into(),])
         );
+        let args = VMArgs {
+            blockdev: vec![BlockDevOpts {
+                path: "/dev/sdb".into(),
+                read_only: true,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            args.get_container_output_dirs(),
+            HashSet::from(["/dev/sdb".into()])
+        );
+        let args = VMArgs {
+            initrd_file: vec![InitrdFile {
+                src: "/tmp/key".into(),
+                dst: "/etc/early/key".into(),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            args.get_container_output_dirs(),
+            HashSet::from(["/tmp/key".into()])
+        );
+        let args = VMArgs {
+            results_dir: Some("/tmp/results".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            args.get_container_output_dirs(),
+            HashSet::from(["/tmp/results".into()])
+        );
+        let args = VMArgs {
+            snapshot_dir: Some("/tmp/snapshots".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            args.get_container_output_dirs(),
+            HashSet::from(["/tmp/snapshots".into()])
+        );
     }
 }
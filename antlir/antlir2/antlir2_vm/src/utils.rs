@@ -6,30 +6,56 @@
  */
 
 use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::fs;
 use std::io::ErrorKind;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::time::Duration;
+use std::time::Instant;
 
 use image_test_lib::KvPair;
 use serde_json::json;
 use tracing::debug;
 use tracing::error;
 
+/// Placeholder for arguments that must not be logged
+pub(crate) const REDACTED: &str = "***";
+
 /// Format the Command for printing
 pub(crate) fn format_command(command: &Command) -> String {
+    format_command_redacted(command, |_| false)
+}
+
+/// Format the Command for printing, replacing every argument that `redact`
+/// returns true for
+pub(crate) fn format_command_redacted(command: &Command, redact: impl Fn(&str) -> bool) -> String {
     let program = command.get_program().to_string_lossy().to_string();
     let args: Vec<_> = command
         .get_args()
         .map(|x| x.to_string_lossy().to_string())
+        .map(|x| if redact(&x) { REDACTED.to_string() } else { x })
         .collect();
     format!("Program: `{}`. Args: `{:?}`", program, args)
 }
 
 /// Log the command being executed, unless it can't be decoded.
 pub(crate) fn log_command(command: &mut Command) -> &mut Command {
-    debug!("Executing command: {}", format_command(command));
+    log_command_redacted(command, |_| false)
+}
+
+/// Log the command being executed without any of the arguments that `redact`
+/// returns true for. The command itself is left untouched.
+pub(crate) fn log_command_redacted(
+    command: &mut Command,
+    redact: impl Fn(&str) -> bool,
+) -> &mut Command {
+    debug!(
+        "Executing command: {}",
+        format_command_redacted(command, redact)
+    );
     command
 }
 
@@ -130,6 +156,83 @@ pub(crate) fn env_names_to_kvpairs(env_names: Vec<String>) -> Vec<KvPair> {
         .collect()
 }
 
+/// Render qemu and its args as a command line that can be pasted into a shell
+/// to reproduce a run, quoting any argument that needs it
+pub(crate) fn qemu_args_to_shell(qemu: &OsStr, args: &[OsString]) -> String {
+    std::iter::once(qemu)
+        .chain(args.iter().map(OsString::as_os_str))
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            // quoting only fails for strings with nul bytes, which can't be
+            // passed as arguments anyway
+            shlex::try_quote(&arg)
+                .map(|quoted| quoted.into_owned())
+                .unwrap_or_else(|_| format!("{arg:?}"))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Logs messages through `emit`, except that repeats of the same message are
+/// only counted, so that something failing over and over doesn't drown out
+/// everything else. The count is logged as `<message> (repeated N times)` once
+/// a different message comes in, at most once per `window` while the repeats
+/// continue, and when this is dropped.
+pub(crate) struct ThrottledLog<F: FnMut(&str)> {
+    window: Duration,
+    emit: F,
+    /// Last message that was logged, when its current window started and how
+    /// many times it was repeated since
+    last: Option<(String, Instant, usize)>,
+}
+
+impl<F: FnMut(&str)> ThrottledLog<F> {
+    pub(crate) fn new(window: Duration, emit: F) -> Self {
+        Self {
+            window,
+            emit,
+            last: None,
+        }
+    }
+
+    pub(crate) fn log(&mut self, msg: &str) {
+        self.log_at(msg, Instant::now())
+    }
+
+    fn log_at(&mut self, msg: &str, now: Instant) {
+        if let Some((last, since, repeats)) = &mut self.last {
+            if last == msg {
+                *repeats += 1;
+                if now.duration_since(*since) >= self.window {
+                    (self.emit)(&format!("{last} (repeated {repeats} times)"));
+                    *since = now;
+                    *repeats = 0;
+                }
+                return;
+            }
+        }
+        self.flush();
+        (self.emit)(msg);
+        self.last = Some((msg.to_owned(), now, 0));
+    }
+
+    /// Log how many times the last message was repeated, if it was
+    pub(crate) fn flush(&mut self) {
+        if let Some((last, _, repeats)) = &mut self.last {
+            if *repeats > 0 {
+                (self.emit)(&format!("{last} (repeated {repeats} times)"));
+                *repeats = 0;
+            }
+        }
+    }
+}
+
+impl<F: FnMut(&str)> Drop for ThrottledLog<F> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
 #[cfg(test)]
 /// Helper function for converting qemu args to a single string for asserting in tests.
 /// This is usually only needed for string only functions like `contains`.
@@ -144,7 +247,6 @@ pub(crate) fn qemu_args_to_string(args: &[std::ffi::OsString]) -> String {
 mod test {
     use std::collections::HashMap;
     use std::env;
-    use std::ffi::OsString;
 
     use super::*;
 
@@ -160,6 +262,88 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_log_command_redacted() {
+        let is_secret = |arg: &str| arg.contains("data=");
+        let mut command = Command::new("echo");
+        command.args(["-object", "secret,id=sec0,data=hunter2"]);
+        assert_eq!(
+            format_command_redacted(&command, is_secret),
+            format!("Program: `echo`. Args: `{:?}`", vec!["-object", "***"]),
+        );
+        let output = log_command_redacted(&mut command, is_secret)
+            .output()
+            .expect("Failed to run echo");
+        assert_eq!(
+            String::from_utf8(output.stdout).expect("Invalid utf8"),
+            "-object secret,id=sec0,data=hunter2\n",
+        );
+    }
+
+    #[test]
+    fn test_qemu_args_to_shell() {
+        let args: Vec<OsString> = vec![
+            "-drive".into(),
+            "file=/tmp/my disk.qcow2,if=virtio".into(),
+            "-append".into(),
+            "console=ttyS0 it's".into(),
+            "-nographic".into(),
+        ];
+        let shell = qemu_args_to_shell(OsStr::new("qemu-system-x86_64"), &args);
+        assert_eq!(
+            shell,
+            r#"qemu-system-x86_64 -drive 'file=/tmp/my disk.qcow2,if=virtio' -append "console=ttyS0 it's" -nographic"#,
+        );
+        // the shell gets back exactly the args that qemu would have been given
+        assert_eq!(
+            shlex::split(&shell).expect("Failed to split"),
+            std::iter::once("qemu-system-x86_64".to_string())
+                .chain(args.iter().map(|x| x.to_string_lossy().to_string()))
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn test_throttled_log() {
+        let mut logged = vec![];
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        {
+            let mut log = ThrottledLog::new(Duration::from_secs(10), |msg: &str| {
+                logged.push(msg.to_owned())
+            });
+            for secs in 0..5 {
+                log.log_at("mount failed", at(secs));
+            }
+            log.log_at("other", at(5));
+            // a message that is not repeated has no summary
+            log.log_at("mount failed", at(6));
+            log.log_at("mount failed again", at(7));
+            // repeats that go on for longer than the window are summarized
+            // once per window
+            for secs in 8..40 {
+                log.log_at("flapping", at(secs));
+            }
+            log.log_at("flapping", at(40));
+        }
+        assert_eq!(
+            logged,
+            vec![
+                "mount failed",
+                "mount failed (repeated 4 times)",
+                "other",
+                "mount failed",
+                "mount failed again",
+                "flapping",
+                "flapping (repeated 10 times)",
+                "flapping (repeated 10 times)",
+                "flapping (repeated 10 times)",
+                // the rest is logged when dropped
+                "flapping (repeated 2 times)",
+            ]
+        );
+    }
+
     struct EnvTest {
         envs: Vec<(&'static str, &'static str)>,
         passenv: Vec<&'static str>,
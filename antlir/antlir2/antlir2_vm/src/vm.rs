@@ -8,6 +8,7 @@
 use std::collections::HashSet;
 use std::ffi::OsString;
 use std::fs;
+use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::BufRead;
 use std::io::BufReader;
@@ -16,6 +17,7 @@ use std::io::Read;
 use std::io::Write;
 use std::net::Shutdown;
 use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Child;
@@ -35,27 +37,57 @@ use tracing::info;
 use tracing::warn;
 use uuid::Uuid;
 
+use crate::agent::GuestAgentChannel;
+use crate::agent::GuestAgentError;
+use crate::bench::bench_script;
+use crate::detach::DetachError;
+use crate::detach::DetachedVm;
+use crate::detach::SERIAL_SOCKET;
+use crate::disk::QCow2Disk;
 use crate::disk::QCow2DiskError;
 use crate::disk::QCow2Disks;
+use crate::disk::RawBlockDevError;
+use crate::disk::RawBlockDevs;
+use crate::initrd::InitrdError;
+use crate::initrd::InitrdOverlay;
 use crate::isolation::Platform;
+use crate::launcher::RealQemuLauncher;
+use crate::launcher::VmmLauncher;
 use crate::net::VirtualNICError;
 use crate::net::VirtualNICs;
 use crate::pci::PCIBridgeError;
 use crate::pci::PCIBridges;
+use crate::qmp::validate_snapshot_name;
+use crate::qmp::Qmp;
+use crate::qmp::QmpChannel;
+use crate::qmp::QmpError;
+use crate::share::escape_specifiers;
+use crate::share::validate_mount_path;
 use crate::share::Share;
 use crate::share::ShareError;
 use crate::share::Shares;
 use crate::ssh::GuestSSHCommand;
 use crate::ssh::GuestSSHError;
+use crate::teardown::ChildProcess;
+use crate::teardown::RemoveFile;
+use crate::teardown::Stage;
+use crate::teardown::Teardown;
 use crate::tpm::TPMDevice;
 use crate::tpm::TPMError;
 use crate::types::CpuIsa;
+use crate::types::CpuSet;
+use crate::types::MachineId;
 use crate::types::MachineOpts;
+use crate::types::NicOpts;
 use crate::types::QemuDevice;
 use crate::types::ShareOpts;
+use crate::types::ShareOptsBuilder;
 use crate::types::TypeError;
+use crate::types::UnitFile;
 use crate::types::VMArgs;
+use crate::types::WatchdogAction;
 use crate::utils::log_command;
+use crate::watchdog::Watchdog;
 
 #[derive(Debug)]
 pub(crate) struct VM<S: Share> {
@@ -68,6 +100,11 @@ pub(crate) struct VM<S: Share> {
     /// List of writable drives created for the VM. We need to hold the ownership
     /// to prevent the temporary disks from getting cleaned up prematuresly.
     disks: QCow2Disks,
+    /// Host files and block devices passed through as-is
+    blockdevs: RawBlockDevs,
+    /// initrd with the `--initrd-file`s appended, booted instead of the one
+    /// from the machine opts
+    initrd: Option<InitrdOverlay>,
     /// All directories to be shared into the VM
     shares: Shares<S>,
     /// Virtual NICs to create and attach
@@ -78,19 +115,39 @@ pub(crate) struct VM<S: Share> {
     sidecar_handles: Vec<JoinHandle<Result<ExitStatus>>>,
     /// TPM device
     tpm: Option<TPMDevice>,
+    /// Control channel for an agent in the guest
+    agent: Option<GuestAgentChannel>,
+    /// QMP monitor, only needed for snapshots and detached VMs
+    qmp: Option<QmpChannel>,
+    /// Watchdog device the guest must keep petting
+    watchdog: Option<Watchdog>,
     /// Uuid for this VM. Randomly generated to aid debugging when multiple VMs are running
     identifier: String,
+    /// Spawns the VMM process once all args are assembled
+    launcher: Box<dyn VmmLauncher>,
+    /// Processes and files to clean up, in order, once the VM is dropped
+    teardown: Teardown,
 }
 
 #[derive(Error, Debug)]
 pub(crate) enum VMError {
     #[error("Failed to create directory for VM states")]
     StateDirError(std::io::Error),
+    #[error("{desc} `{}` is missing or unreadable: {err}", path.display())]
+    MissingInputError {
+        desc: String,
+        path: PathBuf,
+        err: std::io::Error,
+    },
     #[error(transparent)]
     PCIBridgeError(#[from] PCIBridgeError),
     #[error(transparent)]
     DiskInitError(#[from] QCow2DiskError),
     #[error(transparent)]
+    BlockDevError(#[from] RawBlockDevError),
+    #[error(transparent)]
+    InitrdError(#[from] InitrdError),
+    #[error(transparent)]
     ShareInitError(#[from] ShareError),
     #[error(transparent)]
     NICInitError(#[from] VirtualNICError),
@@ -100,10 +157,26 @@ pub(crate) enum VMError {
     TPMError(#[from] TPMError),
     #[error(transparent)]
     TypeError(#[from] TypeError),
+    #[error(transparent)]
+    GuestAgentError(#[from] GuestAgentError),
+    #[error(transparent)]
+    QmpError(#[from] QmpError),
+    #[error(transparent)]
+    DetachError(#[from] DetachError),
+    #[error("{0} requires the VM to have at least one NIC")]
+    NICRequiredError(&'static str),
+    #[error("{0} requires booting from a kernel and initrd")]
+    KernelBootRequiredError(&'static str),
+    #[error("{0} requires the VM to have at least one disk to store snapshots in")]
+    DiskRequiredError(&'static str),
+    #[error("{0} requires 9p shares (use_legacy_share), virtiofs devices can't be snapshotted")]
+    LegacyShareRequiredError(&'static str),
     #[error("Failed to spawn qemu process: `{0}`")]
     QemuProcessError(std::io::Error),
     #[error("Failed to open output file: {path}: {err}")]
     FileOutputError { path: PathBuf, err: std::io::Error },
+    #[error("Failed to create results directory `{}`: {err}", path.display())]
+    ResultsDirError { path: PathBuf, err: std::io::Error },
     #[error("Failed to start sidecar process: `{0}'")]
     SidecarError(std::io::Error),
     #[error("Failed to boot VM: {desc}: `{err}`")]
@@ -116,24 +189,101 @@ pub(crate) enum VMError {
     RunError(String),
     #[error("VM timed out")]
     TimeOutError,
+    #[error("Guest watchdog fired (action: {0}), the guest is likely hung. Check the console log")]
+    WatchdogError(WatchdogAction),
     #[error("Failed to clean up: {desc}: `{err}`")]
     CleanupError { desc: String, err: std::io::Error },
 }
 
 type Result<T> = std::result::Result<T, VMError>;
 
+/// Directory to keep all ephemeral VM state in
+pub(crate) const STATE_DIR: &str = "/run/vm_state";
+/// `systemd-escape --suffix=mount --path /etc/machine-id`
+const MACHINE_ID_MOUNT_UNIT: &str = r"etc-machine\x2did.mount";
+/// Where the guest finds the results share, regardless of its host path
+const RESULTS_GUEST_DIR: &str = "/run/antlir2/results";
+/// `systemd-escape --suffix=mount --path /run/antlir2/results`
+const RESULTS_MOUNT_UNIT: &str = "run-antlir2-results.mount";
+/// Mount tag of the results share
+const RESULTS_MOUNT_TAG: &str = "results";
+
 impl<S: Share> VM<S> {
     /// Create a new VM along with its virtual resources
     pub(crate) fn new(machine: MachineOpts, args: VMArgs) -> Result<Self> {
+        Self::validate_inputs(&machine)?;
+        if args.hostname.is_some() && machine.non_disk_boot_opts.is_none() {
+            // the only way to set it is through the kernel cmdline
+            return Err(VMError::KernelBootRequiredError("--hostname"));
+        }
+        if args.read_only_root && machine.non_disk_boot_opts.is_none() {
+            return Err(VMError::KernelBootRequiredError("--read-only-root"));
+        }
+        if !args.initrd_file.is_empty() && machine.non_disk_boot_opts.is_none() {
+            return Err(VMError::KernelBootRequiredError("--initrd-file"));
+        }
+        if let Some(cpus) = &args.cpu_affinity {
+            cpus.check_online(&CpuSet::online()?)?;
+        }
+        for (flag, name) in [
+            ("--save-snapshot", &args.save_snapshot),
+            ("--load-snapshot", &args.load_snapshot),
+        ] {
+            if let Some(name) = name {
+                validate_snapshot_name(name)?;
+                if machine.disks.is_empty() {
+                    return Err(VMError::DiskRequiredError(flag));
+                }
+                // qemu refuses to save the state of virtiofs devices
+                if !machine.use_legacy_share {
+                    return Err(VMError::LegacyShareRequiredError(flag));
+                }
+            }
+        }
+        let manifest_shares = args.get_manifest_shares()?;
         let state_dir = Self::create_state_dir()?;
-        let pci_bridges = PCIBridges::new(machine.disks.len())?;
-        let disks = QCow2Disks::new(&machine.disks, &pci_bridges, &state_dir)?;
+        let pci_bridges = PCIBridges::new(machine.disks.len() + args.blockdev.len())?;
+        let disks = QCow2Disks::new(
+            &machine.disks,
+            &pci_bridges,
+            args.snapshot_dir.as_deref().unwrap_or(&state_dir),
+            args.disk_cache,
+            args.disk_prealloc,
+            args.load_snapshot.is_some(),
+        )?;
+        let blockdevs = RawBlockDevs::new(&args.blockdev, &pci_bridges, machine.disks.len())?;
+        let initrd = match &machine.non_disk_boot_opts {
+            Some(opts) if !args.initrd_file.is_empty() => Some(InitrdOverlay::new(
+                Path::new(&opts.initrd),
+                &args.initrd_file,
+                &state_dir,
+            )?),
+            _ => None,
+        };
+        let mut share_opts = Self::get_all_shares_opts(&args.get_vm_output_dirs())?;
+        share_opts.extend(manifest_shares);
+        let unit_files_dir = state_dir.join("mount_units");
+        fs::create_dir(&unit_files_dir).map_err(VMError::StateDirError)?;
         let shares = Self::create_shares(
-            Self::get_all_shares_opts(&args.get_vm_output_dirs()),
+            share_opts,
             &state_dir,
+            unit_files_dir,
             machine.mem_mib,
+            machine.use_hugepages,
+            args.machine_id.as_ref(),
+            args.results_dir.as_deref(),
         )?;
-        let mut nics = VirtualNICs::new(machine.num_nics, machine.max_combined_channels)?;
+        let mut nic_opts = match args.nic.is_empty() {
+            true => vec![NicOpts::default(); machine.num_nics],
+            false => args.nic.clone(),
+        };
+        if let Some(mac) = args.mac {
+            match nic_opts.first_mut() {
+                Some(opts) => opts.mac = Some(mac),
+                None => return Err(VMError::NICRequiredError("--mac")),
+            }
+        }
+        let mut nics = VirtualNICs::new(&nic_opts, machine.max_combined_channels)?;
         if nics.len() > 0 {
             if let Err(e) = nics[0].try_dump_file(args.eth0_output_file.clone()) {
                 let err = format!("Failed to set eth0 dump file: {:?}", e);
@@ -151,73 +301,270 @@ impl<S: Share> VM<S> {
             true => Some(TPMDevice::new(&state_dir)?),
             false => None,
         };
+        let agent = (args.guest_agent || !args.agent_command.is_empty())
+            .then(|| GuestAgentChannel::new(&state_dir));
+        let qmp = (args.save_snapshot.is_some()
+            || args.load_snapshot.is_some()
+            || args.mode.detach.is_some())
+        .then(|| QmpChannel::new(&state_dir, "qmp"));
+        let watchdog = args
+            .watchdog
+            .then(|| Watchdog::new(args.watchdog_action.unwrap_or_default(), &state_dir));
         let identifier = Uuid::new_v4().to_string();
 
-        Ok(VM {
+        let mut vm = VM {
             machine,
             args,
             pci_bridges,
             disks,
+            blockdevs,
+            initrd,
             shares,
             nics,
             state_dir,
             sidecar_handles: vec![],
             tpm,
+            agent,
+            qmp,
+            watchdog,
             identifier,
-        })
+            launcher: Box::new(RealQemuLauncher),
+            teardown: Teardown::default(),
+        };
+        for file in vm.runtime_files() {
+            vm.teardown.register(Stage::Files, RemoveFile(file));
+        }
+        Ok(vm)
+    }
+
+    /// Files created for the VM that must not outlive it
+    fn runtime_files(&self) -> Vec<PathBuf> {
+        let disks = match self.args.snapshot_dir {
+            // they hold the snapshots for later runs
+            Some(_) => vec![],
+            None => self.disks.files(),
+        };
+        disks
+            .into_iter()
+            .chain(self.shares.runtime_files())
+            .chain(self.initrd.iter().map(|initrd| initrd.path().to_owned()))
+            .chain(
+                self.agent
+                    .iter()
+                    .map(|agent| agent.socket_path().to_owned()),
+            )
+            .chain(self.qmp.iter().map(|qmp| qmp.socket_path().to_owned()))
+            .chain(self.watchdog.iter().map(|w| w.socket_path().to_owned()))
+            .chain(
+                self.args
+                    .mode
+                    .detach
+                    .iter()
+                    .map(|_| self.state_dir.join(SERIAL_SOCKET)),
+            )
+            .collect()
     }
 
     /// Run the VM and wait for it to finish
     pub(crate) fn run(&mut self) -> Result<()> {
+        if self.args.mode.list_shares {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&self.shares.info())
+                    .expect("ShareInfo is always serializable")
+            );
+            return Ok(());
+        }
         let start_ts = Instant::now();
         self.sidecar_handles = self.spawn_sidecar_services();
         if self.args.first_boot_command.is_some() {
             info!("Booting VM for first boot command. It could take seconds to minutes...");
-            let proc = self.spawn_vm()?;
+            let mut proc = self.spawn_vm()?;
             let ssh_first_boot_cmd = self.ssh_first_boot_command()?;
-            self.wait_for_vm(proc, ssh_first_boot_cmd, true, start_ts)?;
+            let res = self.wait_for_vm(&mut proc, ssh_first_boot_cmd, true, start_ts);
+            let res = self.check_watchdog(res);
+            self.teardown
+                .register(Stage::Vmm, ChildProcess::new("qemu (first boot)", proc));
+            res?;
             thread::sleep(Duration::from_secs(1));
         }
         info!("Booting VM. It could take seconds to minutes...");
-        let proc = self.spawn_vm()?;
+        let mut proc = self.spawn_vm()?;
+        if let Some(info_file) = self.args.mode.detach.clone() {
+            return self.detach(proc, &info_file, start_ts);
+        }
         let ssh_cmd = self.ssh_command()?;
-        self.wait_for_vm(proc, ssh_cmd, false, start_ts)?;
+        let res = self.wait_for_vm(&mut proc, ssh_cmd, false, start_ts);
+        let res = self.check_watchdog(res);
+        self.teardown
+            .register(Stage::Vmm, ChildProcess::new("qemu", proc));
+        res
+    }
+
+    /// Wait for the VM to boot and then leave it running, with everything
+    /// needed to reach and stop it written to `info_file`
+    fn detach(&mut self, mut proc: Child, info_file: &Path, start_ts: Instant) -> Result<()> {
+        let res = self
+            .wait_for_notify_file(&mut proc, start_ts)
+            .and_then(|()| {
+                self.check_sidecar_services()?;
+                let socket =
+                    UnixStream::connect(self.notify_file()).map_err(|err| VMError::BootError {
+                        desc: "Failed to connect to notify socket".into(),
+                        err,
+                    })?;
+                // the guest doesn't notice the host side going away, so the
+                // socket can be closed right after
+                self.wait_for_boot_event(socket, start_ts)?;
+                Ok(self.detached_info(proc.id())?.write(info_file)?)
+            });
+        if let Err(e) = res {
+            self.teardown
+                .register(Stage::Vmm, ChildProcess::new("qemu", proc));
+            return Err(e);
+        }
+        // from now on qemu, the daemons serving it and the files they use
+        // are all owned by whoever stops the VM
+        self.teardown.forget();
+        info!(
+            "VM is running in the background as pid {}, connection details are in {}",
+            proc.id(),
+            info_file.display()
+        );
+        Ok(())
+    }
+
+    fn detached_info(&self, pid: u32) -> Result<DetachedVm> {
+        Ok(DetachedVm {
+            pid,
+            serial_socket: self.state_dir.join(SERIAL_SOCKET),
+            qmp_socket: self
+                .qmp
+                .as_ref()
+                .expect("QMP channel exists whenever the VM is detached")
+                .socket_path()
+                .to_owned(),
+            ssh_host: GuestSSHCommand::new()?.host(),
+            ssh_port: 22,
+            files: self.runtime_files(),
+        })
+    }
+
+    /// A failure caused by the watchdog firing means the guest hung, which is
+    /// reported as such instead of whatever failed as a result
+    fn check_watchdog(&self, res: Result<()>) -> Result<()> {
+        match (&self.watchdog, res) {
+            (Some(watchdog), Err(err)) if watchdog.fired() => {
+                debug!("VM failed after the watchdog fired: {err}");
+                Err(VMError::WatchdogError(watchdog.action()))
+            }
+            (_, res) => res,
+        }
+    }
+
+    /// Make sure all files the VM boots from are there before we start
+    /// creating resources. Otherwise qemu or qemu-img fails with a cryptic
+    /// error much later.
+    fn validate_inputs(machine: &MachineOpts) -> Result<()> {
+        let check = |desc: &str, path: &Path| -> Result<()> {
+            File::open(path)
+                .map(|_| ())
+                .map_err(|err| VMError::MissingInputError {
+                    desc: desc.to_string(),
+                    path: path.to_path_buf(),
+                    err,
+                })
+        };
+        if let Some(opts) = &machine.non_disk_boot_opts {
+            check("Kernel", Path::new(&opts.kernel))?;
+            check("Initrd", Path::new(&opts.initrd))?;
+        }
+        for disk in &machine.disks {
+            if let Some(image) = &disk.base_image {
+                check("Disk base image", &QCow2Disk::format_image_path(image)?)?;
+            }
+        }
         Ok(())
     }
 
+    /// Generate the unit files for every share the VM would have into
+    /// `unit_files_dir` and print them to `out`, without booting it or
+    /// starting any share daemon. Anything else the shares need goes into
+    /// `state_dir`.
+    pub(crate) fn dump_unit_files(
+        machine: &MachineOpts,
+        args: &VMArgs,
+        state_dir: &Path,
+        unit_files_dir: PathBuf,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        let mut share_opts = Self::get_all_shares_opts(&args.get_vm_output_dirs())?;
+        share_opts.extend(args.get_manifest_shares()?);
+        fs::create_dir_all(&unit_files_dir).map_err(VMError::StateDirError)?;
+        let shares = Self::create_shares(
+            share_opts,
+            state_dir,
+            unit_files_dir,
+            machine.mem_mib,
+            machine.use_hugepages,
+            args.machine_id.as_ref(),
+            args.results_dir.as_deref(),
+        )?;
+        Ok(shares.dump_unit_files(out)?)
+    }
+
     /// Create a directory to store VM state. We rely on container for clean
     /// up to simplify resource tracking.
     fn create_state_dir() -> Result<PathBuf> {
-        const STATE_DIR: &str = "/run/vm_state";
         fs::create_dir(STATE_DIR).map_err(VMError::StateDirError)?;
         Ok(PathBuf::from(STATE_DIR))
     }
 
     /// All platform paths needs to be mounted inside the VM as read-only shares.
     /// Collect them into ShareOpts along with others.
-    fn get_all_shares_opts(output_dirs: &HashSet<PathBuf>) -> Vec<ShareOpts> {
-        let mut shares: Vec<_> = Platform::get()
+    fn get_all_shares_opts(output_dirs: &HashSet<PathBuf>) -> Result<Vec<ShareOpts>> {
+        let mut shares = Platform::get()
             .iter()
-            .map(|path| ShareOpts {
-                path: path.to_path_buf(),
-                read_only: true,
-                mount_tag: None,
+            .map(|path| {
+                ShareOptsBuilder::default()
+                    .path(path.to_path_buf())
+                    .read_only(true)
+                    .build()
             })
-            .collect();
-        let mut outputs: Vec<_> = output_dirs
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let mut outputs = output_dirs
             .iter()
-            .map(|p| ShareOpts {
-                path: p.to_path_buf(),
-                read_only: false,
-                mount_tag: None,
+            .map(|p| {
+                ShareOptsBuilder::default()
+                    .path(p.to_path_buf())
+                    .read_only(false)
+                    .build()
             })
-            .collect();
+            .collect::<std::result::Result<Vec<_>, _>>()?;
         shares.append(&mut outputs);
-        shares
+        Ok(shares)
     }
 
     /// Create all shares, start virtiofsd daemon and generate necessary unit files
-    fn create_shares(shares: Vec<ShareOpts>, state_dir: &Path, mem_mb: usize) -> Result<Shares<S>> {
+    fn create_shares(
+        mut shares: Vec<ShareOpts>,
+        state_dir: &Path,
+        unit_files_dir: PathBuf,
+        mem_mb: usize,
+        hugepages: bool,
+        machine_id: Option<&MachineId>,
+        results_dir: Option<&Path>,
+    ) -> Result<Shares<S>> {
+        if let Some(machine_id) = machine_id {
+            shares.push(Self::create_machine_id_share(
+                machine_id,
+                state_dir,
+                &unit_files_dir,
+            )?);
+        }
+        if let Some(results_dir) = results_dir {
+            shares.push(Self::create_results_share(results_dir)?);
+        }
         let virtiofs_shares: Result<Vec<_>> = shares
             .into_iter()
             .enumerate()
@@ -226,13 +573,79 @@ impl<S: Share> VM<S> {
                 Ok(share)
             })
             .collect();
-        let unit_files_dir = state_dir.join("mount_units");
-        fs::create_dir(&unit_files_dir).map_err(VMError::StateDirError)?;
-        let shares = Shares::new(virtiofs_shares?, mem_mb, unit_files_dir)?;
+        let shares = Shares::new(virtiofs_shares?, mem_mb, hugepages, unit_files_dir)?;
         shares.generate_unit_files()?;
         Ok(shares)
     }
 
+    /// Share a generated machine-id file into the VM and bind mount it over
+    /// /etc/machine-id. This happens too late for PID 1 to pick it up, which is
+    /// why it is also passed on the kernel cmdline when possible.
+    fn create_machine_id_share(
+        machine_id: &MachineId,
+        state_dir: &Path,
+        unit_files_dir: &Path,
+    ) -> Result<ShareOpts> {
+        let share_dir = state_dir.join("machine_id");
+        let share_dir_str = escape_specifiers(validate_mount_path(&share_dir)?);
+        fs::create_dir(&share_dir).map_err(VMError::StateDirError)?;
+        fs::write(share_dir.join("machine-id"), format!("{machine_id}\n"))
+            .map_err(VMError::StateDirError)?;
+        let unit = format!(
+            r#"[Unit]
+Description=Bind mount machine-id from the host
+RequiresMountsFor={share_dir}
+Before=local-fs.target
+
+[Mount]
+What={share_dir}/machine-id
+Where=/etc/machine-id
+Type=none
+Options=bind,ro"#,
+            share_dir = share_dir_str,
+        );
+        fs::write(unit_files_dir.join(MACHINE_ID_MOUNT_UNIT), unit)
+            .map_err(VMError::StateDirError)?;
+        Ok(ShareOptsBuilder::default()
+            .path(share_dir)
+            .read_only(true)
+            .build()?)
+    }
+
+    /// Share `results_dir` read-write, so that the guest can leave logs and
+    /// other results for the host to pick up after it is gone. The share is
+    /// bind mounted at [RESULTS_GUEST_DIR] so that the guest doesn't need to
+    /// know where it is on the host.
+    fn create_results_share(results_dir: &Path) -> Result<ShareOpts> {
+        let results_dir_str = escape_specifiers(validate_mount_path(results_dir)?);
+        fs::create_dir_all(results_dir).map_err(|err| VMError::ResultsDirError {
+            path: results_dir.to_owned(),
+            err,
+        })?;
+        let unit = format!(
+            r#"[Unit]
+Description=Bind mount results dir from the host
+RequiresMountsFor={results_dir}
+Before=local-fs.target
+
+[Mount]
+What={results_dir}
+Where={RESULTS_GUEST_DIR}
+Type=none
+Options=bind"#,
+            results_dir = results_dir_str,
+        );
+        Ok(ShareOptsBuilder::default()
+            .path(results_dir)
+            .read_only(false)
+            .mount_tag(RESULTS_MOUNT_TAG)
+            .unit_files(vec![UnitFile {
+                path: RESULTS_MOUNT_UNIT.into(),
+                content: unit,
+            }])
+            .build()?)
+    }
+
     /// If timeout is specified, returns time until timeout, or TimeOutError
     /// if already timed out.
     fn time_left(&self, start_ts: Instant) -> Result<Duration> {
@@ -259,7 +672,7 @@ impl<S: Share> VM<S> {
 
     fn ssh_command(&self) -> Result<Command> {
         let mut ssh_cmd = GuestSSHCommand::new()?.ssh_cmd();
-        if self.args.mode.command.is_none() {
+        if self.args.mode.command.is_none() && !self.args.mode.bench_shares {
             // Force pseudo-terminal allocation for interactive use case. Or
             // ssh hang instead because we add a bash command below.
             ssh_cmd.arg("-t");
@@ -269,6 +682,14 @@ impl<S: Share> VM<S> {
         });
         if let Some(command) = &self.args.mode.command {
             ssh_cmd.args(command);
+        } else if self.args.mode.bench_shares {
+            // ssh joins all args into a single command line for the remote
+            // shell, so the script has to be quoted as a whole
+            let script = bench_script(self.shares.shares());
+            ssh_cmd.arg(format!(
+                "/bin/bash -c {}",
+                shlex::try_quote(&script).expect("bench script has no nul bytes")
+            ));
         } else {
             ssh_cmd.args(["/bin/bash", "-l"]);
         }
@@ -393,30 +814,64 @@ impl<S: Share> VM<S> {
 
     /// Spawn qemu-system process. It won't immediately start running until we connect
     /// to the notify socket.
-    fn spawn_vm(&self) -> Result<Child> {
+    fn spawn_vm(&mut self) -> Result<Child> {
         // Start virtiofsd daemons now that we are about to launch QEMU
-        self.shares.start_shares()?;
+        for daemon in self.shares.start_shares()? {
+            self.teardown.register(Stage::Daemons, daemon);
+        }
 
+        let mut command = self.qemu_command()?;
+        self.launcher
+            .launch(&mut command)
+            .map_err(VMError::QemuProcessError)
+    }
+
+    /// Assemble the full qemu-system command with all devices attached
+    fn qemu_command(&self) -> Result<Command> {
         let mut args = self.common_qemu_args()?;
         args.extend(self.non_disk_boot_qemu_args());
         args.extend(self.pci_bridges.qemu_args());
         args.extend(self.disks.qemu_args());
+        args.extend(self.blockdevs.qemu_args());
         args.extend(self.shares.qemu_args());
         args.extend(self.nics.qemu_args());
         if let Some(tpm) = &self.tpm {
             args.extend(tpm.qemu_args());
         }
+        if let Some(agent) = &self.agent {
+            args.extend(agent.qemu_args());
+        }
+        if let Some(qmp) = &self.qmp {
+            args.extend(qmp.qemu_args());
+        }
+        if let Some(watchdog) = &self.watchdog {
+            args.extend(watchdog.qemu_args());
+        }
+        if self.args.load_snapshot.is_some() {
+            // don't start booting, the snapshot replaces the whole VM state
+            args.push("-S".into());
+        }
 
-        let mut command = Command::new(match self.machine.arch {
-            CpuIsa::AARCH64 => "qemu-system-aarch64",
-            CpuIsa::X86_64 => "qemu-system-x86_64",
-        });
+        let mut command = match &self.args.cpu_affinity {
+            // threads inherit the affinity, so pinning qemu before it starts
+            // pins every vCPU thread too
+            Some(cpus) => {
+                let mut command = Command::new("taskset");
+                command
+                    .arg("--cpu-list")
+                    .arg(cpus.to_string())
+                    .arg(self.machine.arch.qemu_binary());
+                command
+            }
+            None => Command::new(self.machine.arch.qemu_binary()),
+        };
         command = self.redirect_input_output(command)?;
-        let command = command.args(&args);
-
-        log_command(command)
-            .spawn()
-            .map_err(VMError::QemuProcessError)
+        if self.args.mode.detach.is_some() {
+            // don't get killed along with the launcher, eg by ^C
+            command.process_group(0);
+        }
+        command.args(&args);
+        Ok(command)
     }
 
     /// Closing the notify socket will result in VM's termination. If VM
@@ -507,7 +962,7 @@ impl<S: Share> VM<S> {
     /// within the allowed timeout window.
     fn cleanup_vm(
         &mut self,
-        mut vm_proc: Child,
+        vm_proc: &mut Child,
         socket: &UnixStream,
         cleanup_needed: bool,
         start_ts: Instant,
@@ -592,28 +1047,12 @@ impl<S: Share> VM<S> {
     /// upon timing out.
     fn wait_for_vm(
         &mut self,
-        mut vm_proc: Child,
+        vm_proc: &mut Child,
         ssh_cmd: Command,
         cleanup_needed: bool,
         start_ts: Instant,
     ) -> Result<()> {
-        // Wait for notify file to be created by qemu
-        debug!("Waiting for notify file to be created");
-        while !self.time_left(start_ts)?.is_zero() {
-            match self.notify_file().try_exists() {
-                Ok(true) => break,
-                Ok(false) => {
-                    self.try_wait_vm_proc(&mut vm_proc)?;
-                    thread::sleep(Duration::from_millis(100));
-                }
-                Err(err) => {
-                    return Err(VMError::BootError {
-                        desc: "Unable to access notify file".into(),
-                        err,
-                    });
-                }
-            }
-        }
+        self.wait_for_notify_file(vm_proc, start_ts)?;
 
         // Connect to the notify socket. This starts the boot process.
         self.check_sidecar_services()?;
@@ -629,6 +1068,9 @@ impl<S: Share> VM<S> {
             desc: "Failed to connect to notify socket".into(),
             err,
         })?;
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.monitor()?;
+        }
 
         // Spawn container shell immediately if requested. VM is probably not
         // booting or one wouldn't be debugging this. There is also nothing to
@@ -641,7 +1083,73 @@ impl<S: Share> VM<S> {
             return Ok(());
         }
 
-        // Wait for boot notify message. We expect "READY" message once VM boots
+        let socket = match &self.args.load_snapshot {
+            // the guest already booted before the snapshot was saved, so
+            // there won't be another boot event
+            Some(name) => {
+                let mut qmp = self.connect_qmp(start_ts)?;
+                qmp.load_snapshot(name)?;
+                qmp.cont()?;
+                info!(
+                    "Restored snapshot {name} after {} seconds",
+                    start_ts.elapsed().as_secs_f32()
+                );
+                socket
+            }
+            None => self.wait_for_boot_event(socket, start_ts)?,
+        };
+
+        // VM booted
+        self.check_sidecar_services()?;
+        self.run_agent_commands(start_ts)?;
+        if let Some(name) = &self.args.save_snapshot {
+            self.connect_qmp(start_ts)?.save_snapshot(name)?;
+            info!("Saved snapshot {name}");
+        }
+        let mut exit_status = None;
+        if self.args.mode.console {
+            // Just wait for the human that's trying to debug with console
+            self.wait_for_timeout::<()>(&socket, start_ts, None)?;
+        } else if !self.args.mode.container {
+            exit_status = Some(self.run_cmd_and_wait(ssh_cmd, &socket, start_ts)?);
+        }
+        info!("VM executed for {} seconds", start_ts.elapsed().as_secs());
+
+        // We care about exit code only if we are running a command
+        if let Some(status) = exit_status {
+            if (self.args.mode.command.is_some() || self.args.mode.bench_shares)
+                && !status.success()
+            {
+                return Err(VMError::SSHCommandResultError(status));
+            }
+        }
+        self.cleanup_vm(vm_proc, &socket, cleanup_needed, start_ts)?;
+        Ok(())
+    }
+
+    /// Wait for notify file to be created by qemu
+    fn wait_for_notify_file(&self, vm_proc: &mut Child, start_ts: Instant) -> Result<()> {
+        debug!("Waiting for notify file to be created");
+        while !self.time_left(start_ts)?.is_zero() {
+            match self.notify_file().try_exists() {
+                Ok(true) => break,
+                Ok(false) => {
+                    self.try_wait_vm_proc(vm_proc)?;
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(err) => {
+                    return Err(VMError::BootError {
+                        desc: "Unable to access notify file".into(),
+                        err,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for boot notify message. We expect "READY" message once VM boots
+    fn wait_for_boot_event(&self, socket: UnixStream, start_ts: Instant) -> Result<UnixStream> {
         debug!("Waiting for boot notify message");
         if self.args.timeout_secs.is_some() {
             socket
@@ -664,26 +1172,43 @@ impl<S: Share> VM<S> {
             response.trim(),
             start_ts.elapsed().as_secs_f32()
         );
-        let socket = f.into_inner();
+        Ok(f.into_inner())
+    }
 
-        // VM booted
-        self.check_sidecar_services()?;
-        let mut exit_status = None;
-        if self.args.mode.console {
-            // Just wait for the human that's trying to debug with console
-            self.wait_for_timeout::<()>(&socket, start_ts, None)?;
-        } else if !self.args.mode.container {
-            exit_status = Some(self.run_cmd_and_wait(ssh_cmd, &socket, start_ts)?);
-        }
-        info!("VM executed for {} seconds", start_ts.elapsed().as_secs());
+    /// Connect to the QMP monitor, which only exists if snapshots are used
+    fn connect_qmp(&self, start_ts: Instant) -> Result<Qmp> {
+        let qmp = self
+            .qmp
+            .as_ref()
+            .expect("QMP channel exists whenever snapshots are used");
+        let timeout = match self.args.timeout_secs {
+            Some(_) => Some(self.time_left(start_ts)?),
+            None => None,
+        };
+        Ok(qmp.connect(timeout)?)
+    }
 
-        // We care about exit code only if we are running a command
-        if let Some(status) = exit_status {
-            if self.args.mode.command.is_some() && !status.success() {
-                return Err(VMError::SSHCommandResultError(status));
-            }
+    /// Send all `--agent-command`s to the guest agent, one by one
+    fn run_agent_commands(&self, start_ts: Instant) -> Result<()> {
+        let Some(agent) = &self.agent else {
+            return Ok(());
+        };
+        info!(
+            "Guest agent channel is available at {}",
+            agent.socket_path().display()
+        );
+        if self.args.agent_command.is_empty() {
+            return Ok(());
+        }
+        let timeout = match self.args.timeout_secs {
+            Some(_) => Some(self.time_left(start_ts)?),
+            None => None,
+        };
+        let mut client = agent.connect(timeout)?;
+        for command in &self.args.agent_command {
+            let response = client.send(command)?;
+            info!("Guest agent responded to `{command}`: {response}");
         }
-        self.cleanup_vm(vm_proc, &socket, cleanup_needed, start_ts)?;
         Ok(())
     }
 
@@ -722,8 +1247,18 @@ impl<S: Share> VM<S> {
             serial.push("-serial");
             serial.push("null");
         });
-        serial.append(&mut vec!["-serial", "mon:stdio"]);
         args.append(&mut serial.into_iter().map(|x| x.into()).collect());
+        args.push("-serial".into());
+        args.push(match &self.args.mode.detach {
+            // nobody is left to read stdio, so make the console reachable
+            Some(_) => {
+                let mut serial = OsString::from("unix:");
+                serial.push(self.state_dir.join(SERIAL_SOCKET));
+                serial.push(",server=on,wait=off");
+                serial
+            }
+            None => "mon:stdio".into(),
+        });
 
         args.append(
             &mut [
@@ -773,19 +1308,35 @@ impl<S: Share> VM<S> {
     fn non_disk_boot_qemu_args(&self) -> Vec<OsString> {
         match &self.machine.non_disk_boot_opts {
             Some(opts) => {
-                let mut args: Vec<_> = [
-                    "-initrd",
-                    &opts.initrd,
+                let initrd = match &self.initrd {
+                    Some(initrd) => initrd.path().into(),
+                    None => OsString::from(&opts.initrd),
+                };
+                let mut args = vec![
+                    "-initrd".into(),
+                    initrd,
                     // kernel
-                    "-kernel",
-                    &opts.kernel,
-                ]
-                .iter()
-                .map(|x| x.into())
-                .collect();
+                    "-kernel".into(),
+                    OsString::from(&opts.kernel),
+                ];
+                let mut cmdline = vec![];
                 if !opts.append.is_empty() {
+                    cmdline.push(opts.append.clone());
+                }
+                if let Some(hostname) = &self.args.hostname {
+                    cmdline.push(format!("systemd.hostname={hostname}"));
+                }
+                if let Some(machine_id) = &self.args.machine_id {
+                    cmdline.push(format!("systemd.machine_id={machine_id}"));
+                }
+                if self.args.read_only_root {
+                    // systemd keeps root as the kernel mounted it and puts a
+                    // tmpfs on /var
+                    cmdline.push("ro systemd.volatile=state".into());
+                }
+                if !cmdline.is_empty() {
                     args.push("-append".into());
-                    args.push(opts.append.clone().into());
+                    args.push(cmdline.join(" ").into());
                 }
                 args
             }
@@ -796,17 +1347,30 @@ impl<S: Share> VM<S> {
 
 #[cfg(test)]
 mod test {
+    use std::ffi::OsStr;
     use std::net::Shutdown;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixListener;
     use std::thread;
 
     use super::*;
+    use crate::detach::is_running;
+    use crate::detach::mock_qmp;
+    use crate::launcher::FakeLauncher;
+    use crate::share::NinePShare;
     use crate::share::VirtiofsShare;
+    use crate::types::InitrdFile;
     use crate::types::MountPlatformDecision;
     use crate::types::NonDiskBootOpts;
+    use crate::types::QCow2DiskOpts;
     use crate::types::VMArgs;
     use crate::utils::qemu_args_to_string;
 
     fn get_vm_no_disk() -> VM<VirtiofsShare> {
+        get_vm_no_disk_with_share()
+    }
+
+    fn get_vm_no_disk_with_share<S: Share>() -> VM<S> {
         let machine = MachineOpts {
             cpus: 1,
             mem_mib: 1024,
@@ -818,24 +1382,39 @@ mod test {
             path: PathBuf::from("/path"),
             read_only: true,
             mount_tag: None,
+            ..Default::default()
         };
-        let share = VirtiofsShare::new(share_opts, 1, PathBuf::from("/state"));
+        let share = S::new(share_opts, 1, std::env::temp_dir());
         let pci_bridges = PCIBridges::new(0).expect("Failed to create PCIBridges");
-        let disks = QCow2Disks::new(&[], &pci_bridges, Path::new("/state/units"))
-            .expect("Failed to create disks");
-        let nics = VirtualNICs::new(0, 0).expect("Failed to create NICs");
+        let disks = QCow2Disks::new(
+            &[],
+            &pci_bridges,
+            Path::new("/state/units"),
+            None,
+            false,
+            false,
+        )
+        .expect("Failed to create disks");
+        let nics = VirtualNICs::new(&[], 0).expect("Failed to create NICs");
         VM {
             machine,
             args,
             pci_bridges,
             disks,
-            shares: Shares::new(vec![share], 1024, PathBuf::from("/state/units"))
+            blockdevs: RawBlockDevs::default(),
+            initrd: None,
+            shares: Shares::new(vec![share], 1024, false, PathBuf::from("/state/units"))
                 .expect("Failed to create Shares"),
             nics,
             state_dir: PathBuf::from("/test/path"),
             sidecar_handles: vec![],
             tpm: None,
+            agent: None,
+            qmp: None,
+            watchdog: None,
             identifier: "one".to_string(),
+            launcher: Box::new(FakeLauncher::default()),
+            teardown: Teardown::default(),
         }
     }
 
@@ -879,6 +1458,212 @@ mod test {
         assert!(common_args.contains("none -serial null -serial null -serial mon:stdio"));
     }
 
+    #[test]
+    fn test_spawn_vm() {
+        // 9p shares have no daemon to start, so nothing real is spawned
+        let mut vm = get_vm_no_disk_with_share::<NinePShare>();
+        let launcher = FakeLauncher::default();
+        vm.launcher = Box::new(launcher.clone());
+        vm.agent = Some(GuestAgentChannel::new(Path::new("/test/path")));
+        vm.spawn_vm()
+            .expect("Failed to spawn VM")
+            .wait()
+            .expect("Failed to wait for fake VM");
+
+        let launched = launcher.launched.lock().expect("Poisoned lock");
+        assert_eq!(launched.len(), 1);
+        let (program, args) = &launched[0];
+        assert_eq!(program, "qemu-system-x86_64");
+        let args = qemu_args_to_string(args);
+        let common_args =
+            qemu_args_to_string(&vm.common_qemu_args().expect("Failed to build qemu args"));
+        assert!(args.starts_with(&common_args));
+        assert!(args.contains(&qemu_args_to_string(&vm.shares.qemu_args())));
+        assert!(args.contains("-virtfs local,path=/path,"));
+        assert!(args.contains("-numa node,memdev=mem"));
+        assert!(args.contains(
+            "-chardev socket,path=/test/path/agent.sock,id=agent,server=on,wait=off \
+            -device virtserialport,chardev=agent,name=org.antlir.agent.0"
+        ));
+    }
+
+    #[test]
+    fn test_qemu_command_cpu_affinity() {
+        let mut vm = get_vm_no_disk();
+        let command = vm.qemu_command().expect("Failed to build qemu command");
+        assert_eq!(command.get_program(), "qemu-system-x86_64");
+
+        vm.args.cpu_affinity = Some("0-1,4".parse().expect("Failed to parse cpuset"));
+        let command = vm.qemu_command().expect("Failed to build qemu command");
+        assert_eq!(command.get_program(), "taskset");
+        let args: Vec<_> = command.get_args().map(|x| x.to_owned()).collect();
+        assert_eq!(args[..3], ["--cpu-list", "0-1,4", "qemu-system-x86_64"]);
+        let common_args =
+            qemu_args_to_string(&vm.common_qemu_args().expect("Failed to build qemu args"));
+        assert!(qemu_args_to_string(&args[3..]).starts_with(&common_args));
+    }
+
+    #[test]
+    fn test_snapshot_requirements() {
+        let args = VMArgs {
+            save_snapshot: Some("booted".into()),
+            snapshot_dir: Some("/tmp/snapshots".into()),
+            ..Default::default()
+        };
+        let mut machine = MachineOpts::default();
+        assert!(matches!(
+            VM::<NinePShare>::new(machine.clone(), args.clone()),
+            Err(VMError::DiskRequiredError("--save-snapshot"))
+        ));
+        machine.disks.push(QCow2DiskOpts::default());
+        assert!(matches!(
+            VM::<VirtiofsShare>::new(machine, args),
+            Err(VMError::LegacyShareRequiredError("--save-snapshot"))
+        ));
+    }
+
+    #[test]
+    fn test_qemu_command_snapshot() {
+        let mut vm = get_vm_no_disk();
+        vm.qmp = Some(QmpChannel::new(Path::new("/test/path"), "qmp"));
+        vm.args.save_snapshot = Some("booted".into());
+        let command = vm.qemu_command().expect("Failed to build qemu command");
+        let args: Vec<_> = command.get_args().map(|x| x.to_owned()).collect();
+        let args = qemu_args_to_string(&args);
+        assert!(args.ends_with("-qmp unix:/test/path/qmp.sock,server=on,wait=off"));
+
+        // a restored VM must not start booting on its own
+        vm.args.load_snapshot = Some("booted".into());
+        let command = vm.qemu_command().expect("Failed to build qemu command");
+        let args: Vec<_> = command.get_args().map(|x| x.to_owned()).collect();
+        let args = qemu_args_to_string(&args);
+        assert!(args.ends_with("-qmp unix:/test/path/qmp.sock,server=on,wait=off -S"));
+    }
+
+    #[test]
+    fn test_detach() {
+        let mut vm = get_vm_no_disk();
+        vm.qmp = Some(QmpChannel::new(Path::new("/test/path"), "qmp"));
+        vm.args.mode.detach = Some(PathBuf::from("/tmp/vm.json"));
+        let command = vm.qemu_command().expect("Failed to build qemu command");
+        let args: Vec<_> = command.get_args().map(|x| x.to_owned()).collect();
+        let args = qemu_args_to_string(&args);
+        assert!(args.contains("-serial unix:/test/path/serial.sock,server=on,wait=off"));
+        assert!(!args.contains("mon:stdio"));
+
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let info_file = dir.path().join("vm.json");
+        vm.detached_info(1234)
+            .expect("Failed to get info")
+            .write(&info_file)
+            .expect("Failed to write info");
+        let info: DetachedVm =
+            serde_json::from_str(&fs::read_to_string(&info_file).expect("Failed to read"))
+                .expect("Invalid info file");
+        assert_eq!(info.pid, 1234);
+        assert_eq!(info.serial_socket, Path::new("/test/path/serial.sock"));
+        assert_eq!(info.qmp_socket, Path::new("/test/path/qmp.sock"));
+        assert_eq!(info.ssh_host, "root@fe80::200:ff:fe00:1%vm0");
+        assert_eq!(info.ssh_port, 22);
+        // stopping the VM cleans up its sockets
+        assert!(info.files.contains(&info.serial_socket));
+        assert!(info.files.contains(&info.qmp_socket));
+    }
+
+    #[test]
+    fn test_detach_outlives_launcher() {
+        let state_dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let info_file = state_dir.path().join("vm.json");
+        let mut vm = get_vm_no_disk_with_share::<NinePShare>();
+        vm.state_dir = state_dir.path().to_owned();
+        vm.qmp = Some(QmpChannel::new(state_dir.path(), "qmp"));
+        vm.args.mode.detach = Some(info_file.clone());
+        vm.launcher = Box::new(FakeLauncher {
+            keep_running: true,
+            ..Default::default()
+        });
+        // stands in for the guest reporting that it booted
+        let listener = UnixListener::bind(vm.notify_file()).expect("Failed to bind");
+        let guest = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().expect("Failed to accept");
+            socket.write_all(b"READY\n").expect("Failed to write");
+        });
+        vm.run().expect("Failed to detach VM");
+        guest.join().expect("Guest thread panicked");
+
+        // the launcher is gone, but the VM must not be
+        drop(vm);
+        let info: DetachedVm =
+            serde_json::from_str(&fs::read_to_string(&info_file).expect("Failed to read"))
+                .expect("Invalid info file");
+        assert!(is_running(info.pid));
+        let qmp = mock_qmp(&info.qmp_socket, info.pid);
+        info.stop().expect("Failed to stop VM");
+        assert!(!is_running(info.pid));
+        assert_eq!(
+            qmp.join().expect("QMP thread panicked"),
+            ["qmp_capabilities", "quit"]
+        );
+    }
+
+    #[test]
+    fn test_qemu_command_watchdog() {
+        let mut vm = get_vm_no_disk();
+        vm.watchdog = Some(Watchdog::new(
+            WatchdogAction::Reset,
+            Path::new("/test/path"),
+        ));
+        let command = vm.qemu_command().expect("Failed to build qemu command");
+        let args: Vec<_> = command.get_args().map(|x| x.to_owned()).collect();
+        assert!(qemu_args_to_string(&args).ends_with(
+            "-device i6300esb -watchdog-action reset \
+            -qmp unix:/test/path/watchdog.sock,server=on,wait=off"
+        ));
+
+        // nothing fired, so failures are passed through as is
+        assert!(matches!(
+            vm.check_watchdog(Err(VMError::TimeOutError)),
+            Err(VMError::TimeOutError)
+        ));
+        assert!(vm.check_watchdog(Ok(())).is_ok());
+    }
+
+    #[test]
+    fn test_validate_inputs() {
+        let mut machine = MachineOpts::default();
+        assert!(VM::<VirtiofsShare>::validate_inputs(&machine).is_ok());
+
+        let initrd = tempfile::NamedTempFile::new().expect("Failed to create initrd");
+        machine.non_disk_boot_opts = Some(NonDiskBootOpts {
+            initrd: initrd.path().to_str().expect("Invalid path").to_string(),
+            kernel: "/does/not/exist/vmlinuz".to_string(),
+            append: String::new(),
+        });
+        match VM::<VirtiofsShare>::validate_inputs(&machine) {
+            Err(e @ VMError::MissingInputError { .. }) => {
+                assert!(e.to_string().contains("Kernel `/does/not/exist/vmlinuz`"))
+            }
+            other => panic!("Expected MissingInputError, got {:?}", other),
+        }
+
+        let kernel = tempfile::NamedTempFile::new().expect("Failed to create kernel");
+        machine.non_disk_boot_opts = Some(NonDiskBootOpts {
+            initrd: initrd.path().to_str().expect("Invalid path").to_string(),
+            kernel: kernel.path().to_str().expect("Invalid path").to_string(),
+            append: String::new(),
+        });
+        assert!(VM::<VirtiofsShare>::validate_inputs(&machine).is_ok());
+
+        machine.disks = vec![QCow2DiskOpts {
+            base_image: Some(PathBuf::from("/does/not/exist/image")),
+            ..Default::default()
+        }];
+        assert!(matches!(
+            VM::<VirtiofsShare>::validate_inputs(&machine),
+            Err(VMError::MissingInputError { path, .. }) if path == Path::new("/does/not/exist/image"),
+        ));
+    }
+
     #[test]
     fn test_time_left() {
         let mut vm = get_vm_no_disk();
@@ -912,6 +1697,177 @@ mod test {
         assert!(args.contains("-initrd initrd"));
         assert!(args.contains("-kernel kernel"));
         assert!(args.contains("-append whatever"));
+
+        vm.args.hostname = Some("vm.example.com".parse().expect("Invalid hostname"));
+        vm.args.machine_id = Some(
+            "0123456789abcdef0123456789abcdef"
+                .parse()
+                .expect("Invalid machine-id"),
+        );
+        let args = vm.non_disk_boot_qemu_args();
+        assert_eq!(
+            args[args.len() - 2..],
+            [
+                OsString::from("-append"),
+                OsString::from(
+                    "whatever systemd.hostname=vm.example.com \
+                    systemd.machine_id=0123456789abcdef0123456789abcdef"
+                ),
+            ]
+        );
+
+        vm.machine.non_disk_boot_opts = Some(NonDiskBootOpts {
+            initrd: "initrd".to_string(),
+            kernel: "kernel".to_string(),
+            append: "".to_string(),
+        });
+        let args = qemu_args_to_string(&vm.non_disk_boot_qemu_args());
+        assert!(args.ends_with("-append systemd.hostname=vm.example.com systemd.machine_id=0123456789abcdef0123456789abcdef"));
+
+        vm.args.hostname = None;
+        vm.args.machine_id = None;
+        vm.args.read_only_root = true;
+        let args = qemu_args_to_string(&vm.non_disk_boot_qemu_args());
+        assert!(args.ends_with("-append ro systemd.volatile=state"));
+
+        // the initrd with extra files is booted instead
+        let state_dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let base = state_dir.path().join("base.img");
+        fs::write(&base, "initrd").expect("Failed to write");
+        let key = state_dir.path().join("key");
+        fs::write(&key, "secret").expect("Failed to write");
+        vm.initrd = Some(
+            InitrdOverlay::new(
+                &base,
+                &[InitrdFile {
+                    src: key,
+                    dst: "/etc/early/key".into(),
+                }],
+                state_dir.path(),
+            )
+            .expect("Failed to create initrd"),
+        );
+        let args = qemu_args_to_string(&vm.non_disk_boot_qemu_args());
+        assert!(
+            args.starts_with(&format!(
+                "-initrd {}/initrd.img -kernel kernel",
+                state_dir.path().display()
+            )),
+            "{args}"
+        );
+        assert!(vm
+            .runtime_files()
+            .contains(&state_dir.path().join("initrd.img")));
+    }
+
+    #[test]
+    fn test_machine_id_share() {
+        let state_dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let unit_files_dir = state_dir.path().join("mount_units");
+        fs::create_dir(&unit_files_dir).expect("Failed to create dir");
+        let machine_id = "0123456789ABCDEF0123456789abcdef"
+            .parse()
+            .expect("Invalid machine-id");
+        let opts = VM::<VirtiofsShare>::create_machine_id_share(
+            &machine_id,
+            state_dir.path(),
+            &unit_files_dir,
+        )
+        .expect("Failed to create machine-id share");
+
+        let share_dir = state_dir.path().join("machine_id");
+        assert_eq!(opts.path, share_dir);
+        assert!(opts.read_only);
+        assert_eq!(
+            fs::read_to_string(share_dir.join("machine-id")).expect("Failed to read machine-id"),
+            "0123456789abcdef0123456789abcdef\n"
+        );
+        let unit = fs::read_to_string(unit_files_dir.join(r"etc-machine\x2did.mount"))
+            .expect("Failed to read mount unit");
+        assert!(
+            unit.contains(&format!("What={}/machine-id\n", share_dir.display())),
+            "{unit}"
+        );
+        assert!(unit.contains("Where=/etc/machine-id\n"), "{unit}");
+        assert!(unit.ends_with("Options=bind,ro"), "{unit}");
+    }
+
+    #[test]
+    fn test_results_share() {
+        let state_dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let unit_files_dir = state_dir.path().join("mount_units");
+        fs::create_dir(&unit_files_dir).expect("Failed to create dir");
+        let output_dir = state_dir.path().join("output");
+        let results_dir = state_dir.path().join("results");
+        let shares = VM::<NinePShare>::create_shares(
+            vec![ShareOptsBuilder::default()
+                .path(&output_dir)
+                .build()
+                .expect("Failed to build share opts")],
+            state_dir.path(),
+            unit_files_dir.clone(),
+            1024,
+            false,
+            None,
+            Some(&results_dir),
+        )
+        .expect("Failed to create shares");
+
+        let results = shares
+            .info()
+            .into_iter()
+            .find(|share| share.path == results_dir.to_string_lossy())
+            .expect("results share is missing");
+        assert_eq!(results.tag, "results");
+        assert!(!results.read_only);
+        assert_eq!(
+            shares
+                .info()
+                .iter()
+                .filter(|share| share.tag == results.tag)
+                .count(),
+            1
+        );
+        assert!(qemu_args_to_string(&shares.qemu_args()).contains(&format!(
+            "path={},security_model=none,multidevs=remap,mount_tag=results,readonly=off",
+            results_dir.display()
+        )));
+        let unit = fs::read_to_string(unit_files_dir.join(RESULTS_MOUNT_UNIT))
+            .expect("Failed to read mount unit");
+        assert!(
+            unit.contains(&format!("What={}\n", results_dir.display())),
+            "{unit}"
+        );
+        assert!(unit.contains("Where=/run/antlir2/results\n"), "{unit}");
+        assert!(unit.ends_with("Options=bind"), "{unit}");
+
+        // 9p passes guest writes under the mountpoint straight through to the
+        // shared host directory
+        fs::write(Path::new(&results.mountpoint).join("result.txt"), "passed")
+            .expect("Failed to write result");
+        assert_eq!(
+            fs::read_to_string(results_dir.join("result.txt")).expect("Failed to read result"),
+            "passed"
+        );
+
+        // the paths end up in unit files, so they are checked like any other
+        // mountpoint instead of panicking
+        let invalid = state_dir.path().join(OsStr::from_bytes(b"results-\xff"));
+        assert!(matches!(
+            VM::<NinePShare>::create_results_share(&invalid),
+            Err(VMError::ShareInitError(ShareError::InvalidMountPath { .. }))
+        ));
+        assert!(!invalid.exists());
+        let percent = state_dir.path().join("100%");
+        let opts = VM::<NinePShare>::create_results_share(&percent)
+            .expect("Failed to create results share");
+        assert!(
+            opts.unit_files[0]
+                .content
+                .contains(&format!("What={}%\n", percent.display())),
+            "{:?}",
+            opts.unit_files
+        );
     }
 
     #[test]
@@ -1007,6 +1963,28 @@ mod test {
             .expect("Failed to shutdown sender");
     }
 
+    #[test]
+    fn test_bench_shares_ssh_command() {
+        let mut vm = get_vm_no_disk();
+        vm.args.mode.bench_shares = true;
+        let command = vm.ssh_command().expect("Failed to build ssh command");
+        let args: Vec<_> = command.get_args().collect();
+        assert!(!args.contains(&OsStr::new("-t")));
+        let remote = args
+            .last()
+            .expect("ssh has args")
+            .to_str()
+            .expect("Invalid unicode");
+        assert_eq!(
+            shlex::split(remote).expect("Failed to split remote command"),
+            vec![
+                "/bin/bash".to_string(),
+                "-c".to_string(),
+                bench_script(vm.shares.shares()),
+            ],
+        );
+    }
+
     #[test]
     fn test_run_cmd_and_wait() {
         let mut vm = get_vm_no_disk();
@@ -1064,8 +2042,10 @@ mod test {
             path: PathBuf::from("/path"),
             read_only: false,
             mount_tag: None,
+            ..Default::default()
         };
-        let all_opts = VM::<VirtiofsShare>::get_all_shares_opts(&outputs);
+        let all_opts = VM::<VirtiofsShare>::get_all_shares_opts(&outputs)
+            .expect("Failed to collect share opts");
         assert!(all_opts.contains(&opt));
     }
 
@@ -0,0 +1,205 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Emulated hardware watchdog. The guest is expected to keep petting it (eg
+//! with systemd's `RuntimeWatchdogSec=`), so that a hung guest kernel is
+//! stopped by qemu instead of running until the VM times out. qemu reports
+//! the watchdog firing as a QMP event, which is watched for in the background
+//! so that a hang can be told apart from other failures.
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde_json::Value;
+use tracing::warn;
+
+use crate::qmp::QmpChannel;
+use crate::qmp::QmpError;
+use crate::types::QemuDevice;
+use crate::types::WatchdogAction;
+
+/// qemu sends the event right before acting on it, so the monitor may still be
+/// catching up when the VM is found to have exited
+const EVENT_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub(crate) struct Watchdog {
+    action: WatchdogAction,
+    /// Dedicated QMP monitor for receiving watchdog events
+    qmp: QmpChannel,
+    /// Set once the watchdog fired
+    fired: Arc<Mutex<bool>>,
+    /// Thread receiving events from the current qemu process
+    monitor: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Watchdog {
+    pub(crate) fn new(action: WatchdogAction, state_dir: &Path) -> Self {
+        Self {
+            action,
+            qmp: QmpChannel::new(state_dir, "watchdog"),
+            fired: Arc::new(Mutex::new(false)),
+            monitor: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn action(&self) -> WatchdogAction {
+        self.action
+    }
+
+    pub(crate) fn socket_path(&self) -> &Path {
+        self.qmp.socket_path()
+    }
+
+    /// Start watching for the watchdog to fire. qemu must already be
+    /// servicing its monitors.
+    pub(crate) fn monitor(&self) -> Result<(), QmpError> {
+        let mut qmp = self.qmp.connect(None)?;
+        let fired = self.fired.clone();
+        *fired.lock().expect("Poisoned lock") = false;
+        // ends once qemu exits and closes the monitor
+        let handle = thread::spawn(move || {
+            while let Ok(event) = qmp.next_event() {
+                if event["event"] == "WATCHDOG" {
+                    let action = event
+                        .pointer("/data/action")
+                        .and_then(Value::as_str)
+                        .unwrap_or("unknown");
+                    warn!("Guest watchdog fired, qemu action: {action}");
+                    *fired.lock().expect("Poisoned lock") = true;
+                }
+            }
+        });
+        *self.monitor.lock().expect("Poisoned lock") = Some(handle);
+        Ok(())
+    }
+
+    /// Whether the watchdog fired since the monitor was started
+    pub(crate) fn fired(&self) -> bool {
+        let deadline = Instant::now() + EVENT_GRACE_PERIOD;
+        loop {
+            if *self.fired.lock().expect("Poisoned lock") {
+                return true;
+            }
+            let done = self
+                .monitor
+                .lock()
+                .expect("Poisoned lock")
+                .as_ref()
+                .is_none_or(JoinHandle::is_finished);
+            if done {
+                return *self.fired.lock().expect("Poisoned lock");
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl QemuDevice for Watchdog {
+    fn qemu_args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = [
+            "-device",
+            "i6300esb",
+            "-watchdog-action",
+            &self.action.to_string(),
+        ]
+        .iter()
+        .map(|x| x.into())
+        .collect();
+        args.extend(self.qmp.qemu_args());
+        args
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::io::Write;
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+
+    #[test]
+    fn test_qemu_args() {
+        [
+            (WatchdogAction::Poweroff, "poweroff"),
+            (WatchdogAction::Reset, "reset"),
+            (WatchdogAction::None, "none"),
+        ]
+        .into_iter()
+        .for_each(|(action, name)| {
+            let watchdog = Watchdog::new(action, Path::new("/state"));
+            assert_eq!(
+                watchdog.qemu_args().join(OsStr::new(" ")),
+                OsString::from(format!(
+                    "-device i6300esb -watchdog-action {name} \
+                    -qmp unix:/state/watchdog.sock,server=on,wait=off"
+                ))
+            );
+        });
+    }
+
+    /// Stands in for qemu, sending `events` right after the handshake and
+    /// then exiting
+    fn mock_qemu(watchdog: &Watchdog, events: &'static [&'static str]) -> JoinHandle<()> {
+        let listener = UnixListener::bind(watchdog.socket_path()).expect("Failed to bind");
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("Failed to accept");
+            let mut stream = BufReader::new(stream);
+            writeln!(stream.get_mut(), r#"{{"QMP": {{"capabilities": []}}}}"#)
+                .expect("Failed to write");
+            let mut line = String::new();
+            stream.read_line(&mut line).expect("Failed to read");
+            writeln!(stream.get_mut(), r#"{{"return": {{}}}}"#).expect("Failed to write");
+            for event in events {
+                writeln!(stream.get_mut(), "{event}").expect("Failed to write");
+            }
+        })
+    }
+
+    #[test]
+    fn test_fired() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let watchdog = Watchdog::new(WatchdogAction::Poweroff, dir.path());
+        assert!(!watchdog.fired());
+        let qemu = mock_qemu(
+            &watchdog,
+            &[
+                r#"{"event": "RESUME", "data": {}}"#,
+                r#"{"event": "WATCHDOG", "data": {"action": "poweroff"}}"#,
+                r#"{"event": "SHUTDOWN", "data": {"guest": false}}"#,
+            ],
+        );
+        watchdog.monitor().expect("Failed to monitor");
+        qemu.join().expect("Mock qemu panicked");
+        assert!(watchdog.fired());
+    }
+
+    #[test]
+    fn test_not_fired() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let watchdog = Watchdog::new(WatchdogAction::Reset, dir.path());
+        let qemu = mock_qemu(
+            &watchdog,
+            &[r#"{"event": "SHUTDOWN", "data": {"guest": true}}"#],
+        );
+        watchdog.monitor().expect("Failed to monitor");
+        qemu.join().expect("Mock qemu panicked");
+        assert!(!watchdog.fired());
+    }
+}
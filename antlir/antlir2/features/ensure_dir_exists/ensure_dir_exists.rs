@@ -68,6 +68,7 @@ impl antlir2_compile::CompileFeature for EnsureDirExists {
                 let uid = ctx.uid(&self.user)?;
                 let gid = ctx.gid(&self.group)?;
                 chown(&dst, Some(uid.into()), Some(gid.into())).map_err(std::io::Error::from)?;
+                ctx.declare_ownership(&dst, uid, gid);
                 std::fs::set_permissions(&dst, Permissions::from_mode(self.mode.0))?;
             }
             Err(e) => match e.kind() {
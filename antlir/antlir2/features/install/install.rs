@@ -365,14 +365,25 @@ impl antlir2_compile::CompileFeature for Install {
                     Some(uid.as_raw()),
                     Some(gid.as_raw()),
                 )?;
+                ctx.declare_ownership(&dst_path, uid, gid);
             }
 
             let dir_path = ctx.dst_path(&self.dst)?;
             for (key, val) in self.xattrs.iter() {
                 xattr::set(&dir_path, key, &val.0)?;
+                ctx.declare_xattr(&dir_path, key, val.0.clone());
             }
         } else {
             let dst = ctx.dst_path(&self.dst)?;
+            // the depgraph only catches conflicts between the features of
+            // this layer, anything else that is already there gets replaced
+            if dst.symlink_metadata().is_ok() {
+                ctx.warn(format!(
+                    "install of {} replaced the existing {}",
+                    self.src.display(),
+                    self.dst.display()
+                ));
+            }
 
             let dst_file = match &self.binary_info {
                 Some(binary_info) => match binary_info {
@@ -449,6 +460,7 @@ impl antlir2_compile::CompileFeature for Install {
             if let Some(dst_file) = dst_file {
                 fchown(&dst_file, Some(uid.into()), Some(gid.into()))
                     .map_err(std::io::Error::from)?;
+                ctx.declare_ownership(&dst, uid, gid);
                 dst_file.set_permissions(Permissions::from_mode(self.mode.as_raw()))?;
 
                 // Sync the file times with the source. This is not strictly necessary
@@ -462,6 +474,7 @@ impl antlir2_compile::CompileFeature for Install {
                 dst_file.set_times(times)?;
                 for (key, val) in &self.xattrs {
                     dst_file.set_xattr(key, &val.0)?;
+                    ctx.declare_xattr(&dst, key, val.0.clone());
                 }
                 if let Some(cap) = self.setcap.as_ref() {
                     // Technically we could just use self.setcap directly, but
@@ -537,6 +550,12 @@ fn add_gnu_debuglink(src: &Path, debug: &Path) -> anyhow::Result<()> {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
+    use antlir2_compile::Arch;
+    use antlir2_compile::CompileFeature;
+    use buck_label::Label;
+
     use super::*;
 
     #[test]
@@ -554,4 +573,48 @@ mod tests {
             serde_json::from_str::<XattrValue>(r#""0sYmF6""#).expect("failed to deserialize")
         );
     }
+
+    #[test]
+    fn replaced_file_warns() {
+        let src = tempfile::NamedTempFile::new().expect("failed to create tempfile");
+        std::fs::write(src.path(), "new").expect("failed to write");
+        let root = tempfile::TempDir::new().expect("failed to create tempdir");
+        let ctx = CompilerContext::new(
+            Label::new("test//test:image").expect("valid label"),
+            Arch::X86_64,
+            root.path().to_owned(),
+            HashMap::new(),
+        )
+        .expect("failed to create ctx");
+        let install: Install = serde_json::from_value(serde_json::json!({
+            "dst": "/installed",
+            "group": 0,
+            "mode": 0o644,
+            "src": src.path(),
+            "user": 0,
+            "binary_info": null,
+            "xattrs": {},
+            "setcap": null,
+            "always_use_gnu_debuglink": false,
+            "shared_libraries": null,
+        }))
+        .expect("valid install");
+
+        install.compile(&ctx).expect("failed to install");
+        assert!(ctx.warnings().is_empty(), "{:?}", ctx.warnings());
+
+        std::fs::write(root.path().join("installed"), "old").expect("failed to write");
+        install.compile(&ctx).expect("failed to install");
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("installed")).expect("failed to read"),
+            "new"
+        );
+        assert_eq!(
+            ctx.warnings(),
+            vec![format!(
+                "install of {} replaced the existing /installed",
+                src.path().display()
+            )]
+        );
+    }
 }
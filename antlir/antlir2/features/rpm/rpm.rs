@@ -22,6 +22,7 @@ use std::process::Stdio;
 
 use antlir2_compile::Arch;
 use antlir2_compile::CompilerContext;
+use antlir2_compile::InstalledPackage;
 use antlir2_depgraph_if::item::Item;
 use antlir2_depgraph_if::Requirement;
 use antlir2_features::types::BuckOutSource;
@@ -174,6 +175,8 @@ impl antlir2_compile::CompileFeature for Rpm {
             .plan("rpm")
             .context("rpm feature was not planned")?
             .context("while loading rpm plan")?;
+        let tx = plan.tx.into_inner();
+        let installed = tx.install.clone();
         run_dnf_driver(
             DriverContext::Compile {
                 ctx,
@@ -190,11 +193,19 @@ impl antlir2_compile::CompileFeature for Rpm {
             },
             &self.items,
             DriverMode::Run,
-            Some(plan.tx.into_inner()),
+            Some(tx),
             &self.internal_only_options,
         )
-        .map(|_| ())
-        .map_err(antlir2_compile::Error::from)
+        .map_err(antlir2_compile::Error::from)?;
+        for install in installed {
+            ctx.declare_package(InstalledPackage {
+                name: install.package.name.clone(),
+                version: install.package.evr(),
+                purl: install.package.purl(),
+                source: install.repo,
+            });
+        }
+        Ok(())
     }
 }
 
@@ -301,6 +312,42 @@ impl Package {
             self.name, self.epoch, self.version, self.release, self.arch
         )
     }
+
+    /// `[epoch:]version-release`, leaving out the epoch when it is 0 like rpm
+    /// itself does
+    fn evr(&self) -> String {
+        match self.epoch {
+            0 => format!("{}-{}", self.version, self.release),
+            epoch => format!("{epoch}:{}-{}", self.version, self.release),
+        }
+    }
+
+    fn purl(&self) -> String {
+        let mut purl = format!(
+            "pkg:rpm/{}@{}?arch={}",
+            purl_encode(&self.name),
+            purl_encode(&format!("{}-{}", self.version, self.release)),
+            purl_encode(&self.arch),
+        );
+        if self.epoch != 0 {
+            purl.push_str(&format!("&epoch={}", self.epoch));
+        }
+        purl
+    }
+}
+
+/// Percent-encode everything that is not unreserved in a purl component (eg
+/// the `+` in `libstdc++`)
+fn purl_encode(component: &str) -> String {
+    component
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
 }
 
 #[derive(
@@ -62,6 +62,8 @@ def _impl(ctx: AnalysisContext) -> list[Provider]:
         ctx.attrs.image_test[RunInfo],
         "spawn",
         cmd_args(spec, format = "--spec={}"),
+        "--isolate-tmp" if ctx.attrs.isolate_tmp else cmd_args(),
+        cmd_args(str(ctx.attrs.liveness_interval_s), format = "--liveness-interval={}") if ctx.attrs.liveness_interval_s else cmd_args(),
         ctx.attrs.test[ExternalRunnerTestInfo].test_type,
         ctx.attrs.test[ExternalRunnerTestInfo].command,
     )
@@ -158,8 +160,17 @@ _image_test = rule(
         ),
         "hostname": attrs.option(attrs.string(), default = None),
         "image_test": attrs.default_only(attrs.exec_dep(default = "//antlir/antlir2/testing/image_test:image-test")),
+        "isolate_tmp": attrs.bool(
+            default = False,
+            doc = "mount a fresh tmpfs on /tmp and /var/tmp for each run of the test",
+        ),
         "labels": attrs.list(attrs.string(), default = []),
         "layer": attrs.dep(providers = [LayerInfo]),
+        "liveness_interval_s": attrs.option(
+            attrs.int(),
+            default = None,
+            doc = "fail the test and dump its stacks if it goes this long without touching $ANTLIR2_IMAGE_TEST_HEARTBEAT",
+        ),
         "mount_platform": attrs.bool(
             default = True,
             doc = "Mount runtime platform (aka /usr/local/fbcode) from the host",
@@ -189,6 +200,8 @@ def _implicit_image_test(
         boot_after_units: [list[str], None] = None,
         boot_wants_units: [list[str], None] = None,
         hostname: str | None = None,
+        isolate_tmp: bool = False,
+        liveness_interval_s: int | None = None,
         _add_outer_labels: list[str] = [],
         default_os: str | None = None,
         # @oss-disable
@@ -256,6 +269,8 @@ def _implicit_image_test(
         boot_after_units = boot_after_units,
         boot_wants_units = boot_wants_units,
         hostname = hostname,
+        isolate_tmp = isolate_tmp,
+        liveness_interval_s = liveness_interval_s,
         default_os = default_os,
         # @oss-disable
         systemd = systemd or "inherit-parent",
@@ -0,0 +1,175 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! dotenv-style files of environment variables for the test.
+//!
+//! Every non-blank line that is not a `#` comment must be `KEY=VALUE`
+//! (optionally prefixed with `export `). Values may be single-quoted (taken
+//! literally) or double-quoted (where `\n`, `\t`, `\"`, `\\` and `\$` are
+//! escapes). Unquoted values are trimmed and end at a ` #` comment. When a key
+//! appears more than once, the last value wins.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+/// Read and parse the env file at `path`
+pub(crate) fn load(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("while reading env file {}", path.display()))?;
+    parse(&contents).with_context(|| format!("in env file {}", path.display()))
+}
+
+fn parse(contents: &str) -> Result<Vec<(String, String)>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(idx, line)| {
+            let line = line.trim();
+            match line.is_empty() || line.starts_with('#') {
+                true => None,
+                false => Some(parse_line(line).with_context(|| format!("line {}", idx + 1))),
+            }
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Result<(String, String)> {
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected KEY=VALUE, got '{line}'"))?;
+    let key = key.trim();
+    let mut chars = key.chars();
+    if !chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        bail!("'{key}' is not a valid variable name");
+    }
+    Ok((key.to_owned(), parse_value(value.trim_start())?))
+}
+
+fn parse_value(value: &str) -> Result<String> {
+    let (quote, rest) = match value.chars().next() {
+        Some(quote @ ('"' | '\'')) => (quote, &value[1..]),
+        _ => {
+            let end = value.find(" #").unwrap_or(value.len());
+            return Ok(value[..end].trim_end().to_owned());
+        }
+    };
+    let mut parsed = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            c if c == quote => {
+                let trailing = rest[idx + 1..].trim_start();
+                if !trailing.is_empty() && !trailing.starts_with('#') {
+                    bail!("unexpected '{trailing}' after closing quote");
+                }
+                return Ok(parsed);
+            }
+            '\\' if quote == '"' => match chars.next().map(|(_, c)| c) {
+                Some('n') => parsed.push('\n'),
+                Some('t') => parsed.push('\t'),
+                Some(c @ ('"' | '\\' | '$')) => parsed.push(c),
+                Some(c) => bail!("unknown escape '\\{c}'"),
+                None => break,
+            },
+            c => parsed.push(c),
+        }
+    }
+    bail!("missing closing {quote}")
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(
+                r#"
+# a comment
+PLAIN=value
+export EXPORTED = spaced value  # trailing comment
+EMPTY=
+SINGLE='literal $HOME \n # not a comment'
+DOUBLE="line\none \"quoted\" \$HOME" # comment
+EQUALS=a=b
+PLAIN=overridden
+"#
+            )
+            .expect("valid env file"),
+            vec![
+                ("PLAIN".to_owned(), "value".to_owned()),
+                ("EXPORTED".to_owned(), "spaced value".to_owned()),
+                ("EMPTY".to_owned(), "".to_owned()),
+                (
+                    "SINGLE".to_owned(),
+                    r"literal $HOME \n # not a comment".to_owned()
+                ),
+                ("DOUBLE".to_owned(), "line\none \"quoted\" $HOME".to_owned()),
+                ("EQUALS".to_owned(), "a=b".to_owned()),
+                ("PLAIN".to_owned(), "overridden".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (contents, err) in [
+            ("OK=1\n\nnot an assignment", "line 3: expected KEY=VALUE"),
+            ("1BAD=x", "line 1: '1BAD' is not a valid variable name"),
+            (
+                "OK=1\nBAD-KEY=x",
+                "line 2: 'BAD-KEY' is not a valid variable name",
+            ),
+            ("UNTERMINATED=\"abc", "line 1: missing closing \""),
+            (
+                "TRAILING='abc' def",
+                "line 1: unexpected 'def' after closing quote",
+            ),
+            ("ESCAPE=\"\\q\"", "line 1: unknown escape '\\q'"),
+        ] {
+            let e = parse(contents).expect_err(contents);
+            assert!(format!("{e:#}").contains(err), "{e:#}");
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let mut f = NamedTempFile::new().expect("failed to create tempfile");
+        writeln!(f, "GREETING=\"hello world\"\nexport ANSWER=42").expect("failed to write");
+        assert_eq!(
+            load(f.path()).expect("failed to load"),
+            vec![
+                ("GREETING".to_owned(), "hello world".to_owned()),
+                ("ANSWER".to_owned(), "42".to_owned()),
+            ]
+        );
+
+        writeln!(f, "oops").expect("failed to write");
+        let e = load(f.path()).expect_err("malformed line");
+        assert!(
+            format!("{e:#}").contains(&format!(
+                "in env file {}: line 3: expected KEY=VALUE",
+                f.path().display()
+            )),
+            "{e:#}"
+        );
+    }
+}
@@ -8,10 +8,13 @@
 use std::collections::BTreeMap;
 use std::ffi::OsString;
 use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::str::FromStr;
 
 use anyhow::Context;
+use anyhow::Error;
 use anyhow::Result;
 use bon::Builder;
 use clap::Parser;
@@ -19,6 +22,9 @@ use json_arg::JsonFile;
 use nix::unistd::User;
 use serde::Deserialize;
 use serde::Serialize;
+use tracing::warn;
+
+use crate::seccomp;
 
 #[derive(Debug, Clone, Builder, Serialize, Deserialize)]
 /// Specification of how to execute the test.
@@ -34,6 +40,101 @@ pub(crate) struct Spec {
     /// Set these env vars in the test environment
     #[serde(default)]
     env: BTreeMap<String, String>,
+    /// Copy artifacts out of the container after the test exits
+    #[serde(default)]
+    #[builder(default)]
+    collect: Vec<Collect>,
+    /// Only collect artifacts if the test failed
+    #[serde(default)]
+    #[builder(default)]
+    collect_on_failure: bool,
+    /// Start the test with only `env` (plus what image_test itself sets)
+    /// instead of inheriting the environment of the container
+    #[serde(default)]
+    #[builder(default)]
+    env_clear: bool,
+    /// Filter the syscalls of the test
+    #[serde(default)]
+    seccomp: Option<seccomp::Filter>,
+    /// Written to right before the test is started, so that a container that
+    /// fails before then can be told apart from a failing test
+    #[serde(default)]
+    started: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Copy files matching `glob` into `dir` after the test exits. Files keep
+/// their full path underneath `dir`.
+pub(crate) struct Collect {
+    pub(crate) glob: String,
+    pub(crate) dir: PathBuf,
+}
+
+impl FromStr for Collect {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (glob, dir) = s
+            .rsplit_once(':')
+            .with_context(|| format!("'{s}' is not of the form <glob>:<dir>"))?;
+        anyhow::ensure!(
+            !glob.is_empty() && !dir.is_empty(),
+            "'{s}' is not of the form <glob>:<dir>"
+        );
+        Ok(Self {
+            glob: glob.to_owned(),
+            dir: dir.into(),
+        })
+    }
+}
+
+/// Copy everything matched by `collect` into the destination directories.
+/// Collection is best-effort and never fails the test, so problems are only
+/// logged.
+fn collect_artifacts(collect: &[Collect]) {
+    for c in collect {
+        let paths = match glob::glob(&c.glob) {
+            Ok(paths) => paths,
+            Err(e) => {
+                warn!("invalid --collect glob '{}': {e}", c.glob);
+                continue;
+            }
+        };
+        let mut matched = false;
+        for path in paths {
+            let path = match path {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!("failed to read --collect match: {e}");
+                    continue;
+                }
+            };
+            if !path.is_file() {
+                continue;
+            }
+            matched = true;
+            let dst = c.dir.join(path.strip_prefix("/").unwrap_or(path.as_path()));
+            if let Err(e) = copy_artifact(&path, &dst) {
+                warn!(
+                    "failed to collect '{}' into '{}': {e:#}",
+                    path.display(),
+                    dst.display()
+                );
+            }
+        }
+        if !matched {
+            warn!("--collect glob '{}' did not match any files", c.glob);
+        }
+    }
+}
+
+fn copy_artifact(src: &Path, dst: &Path) -> Result<()> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("while creating '{}'", parent.display()))?;
+    }
+    std::fs::copy(src, dst)?;
+    Ok(())
 }
 
 #[derive(Debug, Parser)]
@@ -44,33 +145,151 @@ pub(crate) struct Args {
 }
 
 impl Args {
-    pub(crate) fn run(self) -> Result<()> {
+    /// Run the test, returning its exit code (if it isn't exec'd)
+    pub(crate) fn run(self) -> Result<i32> {
         let spec = self.spec.into_inner();
         std::env::set_current_dir(&spec.working_directory)
             .with_context(|| format!("while changing to '{}'", spec.working_directory.display()))?;
-        let mut env = spec.env;
-        env.insert("USER".into(), spec.user.clone());
-        env.insert(
-            "PWD".into(),
-            spec.working_directory
-                .to_str()
-                .with_context(|| {
-                    format!("pwd '{}' was not utf8", spec.working_directory.display())
-                })?
-                .into(),
+        let mut command = test_command(&spec)?;
+        if let Some(started) = &spec.started {
+            std::fs::write(started, "started\n")
+                .with_context(|| format!("while writing '{}'", started.display()))?;
+        }
+        if spec.collect.is_empty() {
+            return Err(command.exec().into());
+        }
+        // artifacts can only be collected if we stick around after the test
+        let status = command.status().context("while running test")?;
+        if !status.success() || !spec.collect_on_failure {
+            collect_artifacts(&spec.collect);
+        }
+        Ok(status.code().unwrap_or(255))
+    }
+}
+
+/// Env vars that survive [Spec::env_clear], since they are how tests know
+/// they are running under image_test
+const PRESERVED_ENV: &[&str] = &["ANTLIR2_IMAGE_TEST"];
+
+/// Build the command that runs the test as described by `spec`
+fn test_command(spec: &Spec) -> Result<Command> {
+    let mut env = spec.env.clone();
+    env.insert("USER".into(), spec.user.clone());
+    env.insert(
+        "PWD".into(),
+        spec.working_directory
+            .to_str()
+            .with_context(|| format!("pwd '{}' was not utf8", spec.working_directory.display()))?
+            .into(),
+    );
+
+    let user = User::from_name(&spec.user)
+        .context("failed to lookup user")?
+        .with_context(|| format!("no such user '{}'", spec.user))?;
+
+    let mut cmd = spec.cmd.iter();
+    let mut command = Command::new(cmd.next().context("test command was empty")?);
+    if spec.env_clear {
+        command.env_clear();
+        for key in PRESERVED_ENV {
+            if let Some(val) = std::env::var_os(key) {
+                command.env(key, val);
+            }
+        }
+    }
+    command
+        .args(cmd)
+        .envs(env)
+        .uid(user.uid.into())
+        .gid(user.gid.into());
+    if let Some(filter) = &spec.seccomp {
+        filter.apply_to(&mut command)?;
+    }
+    Ok(command)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_collect() {
+        assert_eq!(
+            "/var/log/*.log:/tmp/out"
+                .parse::<Collect>()
+                .expect("valid collect"),
+            Collect {
+                glob: "/var/log/*.log".into(),
+                dir: "/tmp/out".into(),
+            }
         );
+        assert!("/var/log/*.log".parse::<Collect>().is_err());
+        assert!(":/tmp/out".parse::<Collect>().is_err());
+    }
+
+    /// Env of the test as printed by `env`
+    fn test_env(env_clear: bool) -> String {
+        let spec = Spec::builder()
+            .cmd(vec!["env".into()])
+            .working_directory("/".into())
+            .user(
+                User::from_uid(nix::unistd::getuid())
+                    .expect("failed to lookup current user")
+                    .expect("current user exists")
+                    .name,
+            )
+            .env(BTreeMap::from([("GREETING".into(), "hello".into())]))
+            .env_clear(env_clear)
+            .build();
+        let output = test_command(&spec)
+            .expect("failed to build command")
+            .output()
+            .expect("failed to run env");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).expect("env is not utf8")
+    }
+
+    #[test]
+    fn test_env_clear() {
+        assert!(
+            std::env::var_os("PATH").is_some(),
+            "PATH is normally inherited"
+        );
+        let env = test_env(false);
+        assert!(env.lines().any(|l| l.starts_with("PATH=")), "{env}");
+        assert!(env.lines().any(|l| l == "GREETING=hello"), "{env}");
 
-        let user = User::from_name(&spec.user)
-            .context("failed to lookup user")?
-            .with_context(|| format!("no such user '{}'", spec.user))?;
-
-        let mut cmd = spec.cmd.into_iter();
-        let err = Command::new(cmd.next().context("test command was empty")?)
-            .args(cmd)
-            .envs(env)
-            .uid(user.uid.into())
-            .gid(user.gid.into())
-            .exec();
-        Err(err.into())
+        let env = test_env(true);
+        assert!(!env.lines().any(|l| l.starts_with("PATH=")), "{env}");
+        assert!(env.lines().any(|l| l == "GREETING=hello"), "{env}");
+        assert!(env.lines().any(|l| l == "PWD=/"), "{env}");
+    }
+
+    #[test]
+    fn test_collect_artifacts() {
+        let src = tempfile::tempdir().expect("failed to create tempdir");
+        let dst = tempfile::tempdir().expect("failed to create tempdir");
+        std::fs::write(src.path().join("core.1234"), "boom").expect("failed to write");
+        std::fs::write(src.path().join("other"), "ignored").expect("failed to write");
+
+        collect_artifacts(&[
+            Collect {
+                glob: format!("{}/core.*", src.path().display()),
+                dir: dst.path().to_owned(),
+            },
+            Collect {
+                glob: format!("{}/nothing-*", src.path().display()),
+                dir: dst.path().to_owned(),
+            },
+        ]);
+
+        let collected = dst
+            .path()
+            .join(src.path().strip_prefix("/").expect("tempdir is absolute"));
+        assert_eq!(
+            std::fs::read_to_string(collected.join("core.1234")).expect("not collected"),
+            "boom"
+        );
+        assert!(!collected.join("other").exists());
     }
 }
@@ -8,10 +8,29 @@
 use anyhow::Result;
 use clap::Parser;
 
+mod env_file;
 mod exec;
+mod runs;
 mod runtime;
+mod seccomp;
 mod shell_help;
 mod spawn;
+mod warm_ns;
+mod watchdog;
+
+// Exit codes let callers (like CI retry logic) tell infrastructure problems
+// apart from real test failures:
+//  * the inner test's exit code is passed through as-is
+//  * EXIT_TIMEOUT if the test ran longer than it was allowed to
+//  * EXIT_INFRA if the container could not be set up or started, including
+//    when systemd-nspawn or `image-test exec` inside the container fail
+//    before the test is started (for tests that are booted or need `image-test
+//    exec` anyway, since nothing else can tell when the test starts)
+
+/// Same as timeout(1)
+pub(crate) const EXIT_TIMEOUT: i32 = 124;
+/// Same as `docker run`/`podman run` failing to start the container
+pub(crate) const EXIT_INFRA: i32 = 125;
 
 #[derive(Parser, Debug)]
 enum Args {
@@ -20,16 +39,135 @@ enum Args {
     /// Execute the test from inside the container
     Exec(exec::Args),
     ShellHelp(shell_help::Args),
+    /// Keep a container set up for `--mount-ns=reuse` alive
+    #[clap(hide = true)]
+    HoldNs(warm_ns::HoldArgs),
+}
+
+impl Args {
+    /// Run the subcommand, returning the exit code of the test (if there is
+    /// one)
+    fn run(self) -> Result<i32> {
+        match self {
+            Self::Spawn(a) => a.run(),
+            Self::Exec(a) => a.run(),
+            Self::ShellHelp(a) => a.run().map(|()| 0),
+            Self::HoldNs(a) => a.run().map(|()| 0),
+        }
+    }
+}
+
+/// Any error that makes it back to main means the test itself never ran (or
+/// its result could not be determined), since test failures are reported as
+/// the test's own exit code.
+fn exit_code(res: &Result<i32>) -> i32 {
+    match res {
+        Ok(code) => *code,
+        Err(_) => EXIT_INFRA,
+    }
 }
 
-fn main() -> Result<()> {
+fn main() {
     tracing_subscriber::fmt::init();
 
-    let args = Args::parse();
+    let res = Args::parse().run();
+    if let Err(e) = &res {
+        eprintln!("Error: {e:?}");
+    }
+    std::process::exit(exit_code(&res))
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    /// Run `image-test exec` on a spec for `cmd`, returning the exit code and
+    /// whether the test was started
+    fn exec(cmd: &str, user: &str) -> (i32, bool) {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let started = dir.path().join("started");
+        let spec = dir.path().join("spec.json");
+        let exec_spec = exec::Spec::builder()
+            .cmd(vec!["sh".into(), "-c".into(), cmd.into()])
+            // changing to this is harmless for other tests
+            .working_directory(std::env::current_dir().expect("no cwd"))
+            .user(user.to_owned())
+            .env(Default::default())
+            // so that the test isn't exec'd, replacing the test runner
+            .collect(vec![exec::Collect {
+                glob: "/nonexistent".into(),
+                dir: dir.path().to_owned(),
+            }])
+            .started(started.clone())
+            .build();
+        std::fs::write(
+            &spec,
+            serde_json::to_string(&exec_spec).expect("failed to serialize spec"),
+        )
+        .expect("failed to write spec");
+        let args = Args::try_parse_from(["image-test".as_ref(), "exec".as_ref(), spec.as_os_str()])
+            .expect("failed to parse args");
+        (exit_code(&args.run()), started.exists())
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .expect("failed to lookup current user")
+            .expect("current user exists")
+            .name;
+        // test results are passed through as-is
+        assert_eq!(exec("exit 0", &user), (0, true));
+        assert_eq!(exec("exit 3", &user), (3, true));
+        // `image-test exec` failing to set up the test is an infra failure
+        assert_eq!(exec("exit 0", "no-such-user"), (EXIT_INFRA, false));
 
-    match args {
-        Args::Spawn(a) => a.run(),
-        Args::Exec(a) => a.run(),
-        Args::ShellHelp(a) => a.run(),
+        // so is the container failing before the test is started
+        let layer = tempfile::tempdir().expect("failed to create tempdir");
+        let spec = tempfile::NamedTempFile::new().expect("failed to create tempfile");
+        serde_json::to_writer(
+            &spec,
+            &serde_json::json!({
+                "layer": layer.path(),
+                "user": "root",
+                "boot": null,
+                "mounts": {},
+                "rootless": false,
+            }),
+        )
+        .expect("failed to write spec");
+        // stands in for systemd-nspawn (run through sudo when not root)
+        // failing to set up the container
+        let bin = tempfile::tempdir().expect("failed to create tempdir");
+        for tool in ["systemd-nspawn", "sudo"] {
+            let path = bin.path().join(tool);
+            std::fs::write(&path, "#!/bin/sh\nexit 1\n").expect("failed to write");
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+                .expect("failed to chmod");
+        }
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let mut paths = vec![bin.path().to_owned()];
+        paths.extend(std::env::split_paths(&path));
+        std::env::set_var("PATH", std::env::join_paths(paths).expect("valid PATH"));
+        let spawn = |flags: &[&str]| {
+            let args = Args::try_parse_from(
+                ["image-test", "spawn", "--spec"]
+                    .map(OsStr::new)
+                    .into_iter()
+                    .chain([spec.path().as_os_str()])
+                    .chain(flags.iter().map(OsStr::new))
+                    .chain(["custom", "true"].map(OsStr::new)),
+            )
+            .expect("failed to parse args");
+            args.run().expect("the container failing is not an error")
+        };
+        assert_eq!(spawn(&["--env-clear"]), EXIT_INFRA);
+        // a test that isn't started through `image-test exec` can't be told
+        // apart from one that failed
+        assert_eq!(spawn(&[]), 1);
+        std::env::set_var("PATH", path);
     }
 }
@@ -0,0 +1,132 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Soak testing: run the whole test (in a fresh container each time) a fixed
+//! number of times and aggregate the results.
+
+use anyhow::Result;
+
+use crate::watchdog::ContainerExit;
+
+/// Results of every run that was attempted
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Summary {
+    /// How many runs were actually executed
+    pub(crate) runs: u32,
+    /// (run index, exit code) of every run that failed
+    pub(crate) failed: Vec<(u32, i32)>,
+}
+
+impl Summary {
+    pub(crate) fn passed(&self) -> u32 {
+        self.runs - self.failed.len() as u32
+    }
+
+    /// Exit code that image-test should use to report this to its caller,
+    /// which is the code of the first failed run
+    pub(crate) fn code(&self) -> i32 {
+        self.failed.first().map_or(0, |(_, code)| *code)
+    }
+}
+
+/// Call `run` with each (1-based) run index, up to `runs` times. Errors in
+/// setting up a run are not test failures and abort immediately.
+pub(crate) fn run_repeatedly(
+    runs: u32,
+    stop_on_first_failure: bool,
+    mut run: impl FnMut(u32) -> Result<ContainerExit>,
+) -> Result<Summary> {
+    let mut summary = Summary::default();
+    for idx in 1..=runs {
+        eprintln!("image-test: starting run {idx}/{runs}");
+        let exit = run(idx)?;
+        exit.report();
+        summary.runs += 1;
+        match exit.code() {
+            0 => eprintln!("image-test: run {idx}/{runs} passed"),
+            code => {
+                eprintln!("image-test: run {idx}/{runs} failed with exit code {code}");
+                summary.failed.push((idx, code));
+                if stop_on_first_failure {
+                    break;
+                }
+            }
+        }
+    }
+    eprintln!(
+        "image-test: {}/{} runs passed",
+        summary.passed(),
+        summary.runs
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    use super::*;
+
+    /// Payload that only fails on the third run
+    fn fails_on_third(idx: u32) -> Result<ContainerExit> {
+        Ok(ContainerExit::Exited(ExitStatus::from_raw(match idx {
+            3 => 1 << 8,
+            _ => 0,
+        })))
+    }
+
+    #[test]
+    fn test_run_repeatedly() {
+        let summary = run_repeatedly(5, false, fails_on_third).expect("no infra errors");
+        assert_eq!(
+            summary,
+            Summary {
+                runs: 5,
+                failed: vec![(3, 1)],
+            }
+        );
+        assert_eq!(summary.passed(), 4);
+        assert_eq!(summary.code(), 1);
+
+        let summary = run_repeatedly(5, true, fails_on_third).expect("no infra errors");
+        assert_eq!(
+            summary,
+            Summary {
+                runs: 3,
+                failed: vec![(3, 1)],
+            }
+        );
+        assert_eq!(summary.passed(), 2);
+
+        let summary = run_repeatedly(2, true, fails_on_third).expect("no infra errors");
+        assert_eq!(summary.passed(), 2);
+        assert_eq!(summary.code(), 0);
+    }
+
+    #[test]
+    fn test_run_repeatedly_timeout() {
+        let summary = run_repeatedly(2, false, |idx| match idx {
+            1 => Ok(ContainerExit::TimedOut),
+            _ => fails_on_third(idx),
+        })
+        .expect("no infra errors");
+        assert_eq!(summary.failed, vec![(1, crate::EXIT_TIMEOUT)]);
+        assert_eq!(summary.code(), crate::EXIT_TIMEOUT);
+    }
+
+    #[test]
+    fn test_run_repeatedly_infra_error() {
+        let mut attempted = 0;
+        run_repeatedly(3, false, |_| {
+            attempted += 1;
+            Err(anyhow::anyhow!("container failed to start"))
+        })
+        .expect_err("setup errors are not test failures");
+        assert_eq!(attempted, 1);
+    }
+}
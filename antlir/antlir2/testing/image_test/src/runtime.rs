@@ -7,8 +7,13 @@
 
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::path::PathBuf;
 
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
 use serde::Deserialize;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -38,6 +43,113 @@ pub(crate) struct Spec {
     pub(crate) rootless: bool,
 }
 
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// Alternate paths for the tools that image_test runs, mostly useful for
+/// trying out local builds. Anything left unset uses the default resolution.
+pub(crate) struct RuntimeOverrides {
+    #[serde(default)]
+    /// systemctl used to inspect the image (default: from $PATH)
+    systemctl: Option<PathBuf>,
+    #[serde(default)]
+    /// image-test binary that is bound into non-booted containers when it
+    /// needs to run inside them (default: the currently running binary)
+    image_test: Option<PathBuf>,
+}
+
+impl Spec {
+    /// Make sure that the layer, the mount sources and every overridden tool
+    /// exist. All problems are reported at once, instead of failing on the
+    /// first one.
+    pub(crate) fn validate(&self, overrides: Option<&RuntimeOverrides>) -> Result<()> {
+        let mut problems = Vec::new();
+        if let Some(problem) = directory_problem(&self.layer) {
+            problems.push(format!("layer: '{}' {problem}", self.layer.display()));
+        }
+        let mut mounts: Vec<_> = self.mounts.iter().collect();
+        mounts.sort();
+        for (mountpoint, src) in mounts {
+            if let Some(problem) = existence_problem(src) {
+                problems.push(format!(
+                    "mount {}: '{}' {problem}",
+                    mountpoint.display(),
+                    src.display()
+                ));
+            }
+        }
+        if let Some(overrides) = overrides {
+            problems.extend(overrides.problems());
+        }
+        ensure!(
+            problems.is_empty(),
+            "invalid runtime spec:\n  {}",
+            problems.join("\n  ")
+        );
+        Ok(())
+    }
+}
+
+impl RuntimeOverrides {
+    /// Describe every overridden path that is not an executable file
+    fn problems(&self) -> Vec<String> {
+        [
+            ("systemctl", &self.systemctl),
+            ("image_test", &self.image_test),
+        ]
+        .into_iter()
+        .filter_map(|(tool, path)| {
+            let path = path.as_deref()?;
+            executable_problem(path)
+                .map(|problem| format!("{tool}: '{}' {problem}", path.display()))
+        })
+        .collect()
+    }
+
+    pub(crate) fn systemctl(&self) -> &Path {
+        self.systemctl
+            .as_deref()
+            .unwrap_or_else(|| Path::new("systemctl"))
+    }
+
+    pub(crate) fn image_test(&self) -> Result<PathBuf> {
+        match &self.image_test {
+            Some(path) => Ok(path.clone()),
+            None => std::env::current_exe().context("while getting argv[0]"),
+        }
+    }
+}
+
+/// Describe why `path` can't be used at all, if it can't
+fn existence_problem(path: &Path) -> Option<String> {
+    metadata_problem(path).err()
+}
+
+/// Describe why `path` is not a directory, if it isn't
+fn directory_problem(path: &Path) -> Option<String> {
+    match metadata_problem(path) {
+        Err(problem) => Some(problem),
+        Ok(meta) if !meta.is_dir() => Some("is not a directory".into()),
+        Ok(_) => None,
+    }
+}
+
+/// Describe why `path` can't be executed, if it can't
+fn executable_problem(path: &Path) -> Option<String> {
+    match metadata_problem(path) {
+        Err(problem) => Some(problem),
+        Ok(meta) if !meta.is_file() => Some("is not a file".into()),
+        Ok(meta) if meta.permissions().mode() & 0o111 == 0 => Some("is not executable".into()),
+        Ok(_) => None,
+    }
+}
+
+fn metadata_problem(path: &Path) -> std::result::Result<std::fs::Metadata, String> {
+    std::fs::metadata(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => "does not exist".into(),
+        _ => format!("could not be inspected: {e}"),
+    })
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct Boot {
     /// Add Requires= and After= dependencies on these units
@@ -47,3 +159,123 @@ pub(crate) struct Boot {
     /// Add Wants= dependencies on these units
     pub(crate) wants_units: Vec<String>,
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn test_runtime_overrides() {
+        let defaults: RuntimeOverrides = serde_json::from_str("{}").expect("valid json");
+        assert!(defaults.problems().is_empty(), "defaults are always valid");
+        assert_eq!(defaults.systemctl(), Path::new("systemctl"));
+        assert_eq!(
+            defaults.image_test().expect("current exe"),
+            std::env::current_exe().expect("current exe")
+        );
+
+        let mut systemctl = NamedTempFile::new().expect("failed to create tempfile");
+        writeln!(systemctl, "#!/bin/sh").expect("failed to write");
+        std::fs::set_permissions(systemctl.path(), std::fs::Permissions::from_mode(0o755))
+            .expect("failed to chmod");
+        let overrides: RuntimeOverrides = serde_json::from_value(serde_json::json!({
+            "systemctl": systemctl.path(),
+        }))
+        .expect("valid json");
+        assert!(overrides.problems().is_empty(), "override is executable");
+        assert_eq!(overrides.systemctl(), systemctl.path());
+        // unset entries fall back to the default
+        assert_eq!(
+            overrides.image_test().expect("current exe"),
+            std::env::current_exe().expect("current exe")
+        );
+
+        std::fs::set_permissions(systemctl.path(), std::fs::Permissions::from_mode(0o644))
+            .expect("failed to chmod");
+        assert_eq!(
+            overrides.problems(),
+            [format!(
+                "systemctl: '{}' is not executable",
+                systemctl.path().display()
+            )]
+        );
+
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let not_a_file: RuntimeOverrides = serde_json::from_value(serde_json::json!({
+            "systemctl": dir.path(),
+        }))
+        .expect("valid json");
+        assert_eq!(
+            not_a_file.problems(),
+            [format!(
+                "systemctl: '{}' is not a file",
+                dir.path().display()
+            )]
+        );
+
+        assert!(
+            serde_json::from_str::<RuntimeOverrides>(r#"{"qemu": "/usr/bin/qemu"}"#).is_err(),
+            "unknown tools are rejected"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let mut systemctl = NamedTempFile::new().expect("failed to create tempfile");
+        writeln!(systemctl, "#!/bin/sh").expect("failed to write");
+        std::fs::set_permissions(systemctl.path(), std::fs::Permissions::from_mode(0o755))
+            .expect("failed to chmod");
+        let overrides: RuntimeOverrides = serde_json::from_value(serde_json::json!({
+            "systemctl": systemctl.path(),
+            "image_test": "/does/not/exist",
+        }))
+        .expect("valid json");
+        let spec = |layer: &Path| -> Spec {
+            serde_json::from_value(serde_json::json!({
+                "layer": layer,
+                "user": "root",
+                "boot": null,
+                "mounts": {
+                    "/present": dir.path(),
+                    "/missing": "/does/not/exist/either",
+                },
+                "rootless": false,
+            }))
+            .expect("valid json")
+        };
+
+        // only the missing paths are reported, all at once
+        let err = spec(&dir.path().join("layer"))
+            .validate(Some(&overrides))
+            .expect_err("paths are missing")
+            .to_string();
+        assert_eq!(
+            err,
+            format!(
+                "invalid runtime spec:\n  layer: '{}/layer' does not exist\n  mount /missing: '/does/not/exist/either' does not exist\n  image_test: '/does/not/exist' does not exist",
+                dir.path().display()
+            )
+        );
+
+        let mut spec = spec(dir.path());
+        spec.mounts.remove(Path::new("/missing"));
+        spec.validate(None).expect("everything exists");
+        spec.validate(Some(&RuntimeOverrides::default()))
+            .expect("everything exists");
+        spec.layer = systemctl.path().to_owned();
+        assert_eq!(
+            spec.validate(None)
+                .expect_err("layer is a file")
+                .to_string(),
+            format!(
+                "invalid runtime spec:\n  layer: '{}' is not a directory",
+                systemctl.path().display()
+            )
+        );
+    }
+}
@@ -0,0 +1,1129 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Seccomp filters for the test process, loaded from Docker/OCI style JSON
+//! profiles.
+//!
+//! Profiles are resolved for the current architecture and compiled to BPF by
+//! seccompiler. Just like runc, syscall names that don't exist on the current
+//! architecture are skipped, and Docker's `includes`/`excludes` are evaluated
+//! the way Docker does for a container whose only capabilities are those of
+//! the test user. seccompiler doesn't expose its syscall table, so names are
+//! resolved with the constants from libc.
+//!
+//! A filter only ever returns a single action, so the profile is installed as
+//! a stack of filters (one for each action, and one for the default). The
+//! kernel applies the most restrictive of their results, so when rules with
+//! different actions match the same call, the most restrictive one wins.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use anyhow::Context;
+use anyhow::Result;
+use clap::ValueEnum;
+use nix::libc;
+use seccompiler::BpfProgram;
+use seccompiler::SeccompAction;
+use seccompiler::SeccompCmpArgLen;
+use seccompiler::SeccompCmpOp;
+use seccompiler::SeccompCondition;
+use seccompiler::SeccompFilter;
+use seccompiler::SeccompRule;
+use seccompiler::TargetArch;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::debug;
+
+/// Escape hatch for `--seccomp`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Mode {
+    /// Don't filter the test's syscalls at all, even if a profile is given
+    Unconfined,
+}
+
+/// Docker/OCI seccomp profile, as found in `linux.seccomp` of an OCI runtime
+/// spec or passed to `docker run --security-opt seccomp=`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Profile {
+    default_action: ProfileAction,
+    #[serde(default)]
+    default_errno_ret: Option<u16>,
+    #[serde(default)]
+    syscalls: Vec<ProfileRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProfileRule {
+    #[serde(default)]
+    names: Vec<String>,
+    /// Older profiles have one rule per syscall
+    #[serde(default)]
+    name: Option<String>,
+    action: ProfileAction,
+    #[serde(default)]
+    errno_ret: Option<u16>,
+    #[serde(default)]
+    args: Vec<Condition>,
+    /// Docker only: the rule only applies if all of these hold
+    #[serde(default)]
+    includes: ProfileConditions,
+    /// Docker only: the rule doesn't apply if any of these hold
+    #[serde(default)]
+    excludes: ProfileConditions,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProfileConditions {
+    /// Docker names for architectures (like `amd64`)
+    #[serde(default)]
+    arches: Vec<String>,
+    /// Capabilities (like `CAP_SYS_ADMIN`)
+    #[serde(default)]
+    caps: Vec<String>,
+    /// Kernel version (like `4.8`)
+    #[serde(default)]
+    min_kernel: Option<String>,
+}
+
+/// What the profile is being resolved for
+struct Target {
+    /// Docker name of the current architecture
+    arch: &'static str,
+    /// Whether the test has capabilities at all
+    privileged: bool,
+    /// Major and minor version of the running kernel
+    kernel: (u32, u32),
+}
+
+impl ProfileConditions {
+    /// Whether all of the conditions hold for `target`. With `any`, one is
+    /// enough.
+    fn hold(&self, target: &Target, any: bool) -> Result<bool> {
+        let mut holds = Vec::new();
+        if !self.arches.is_empty() {
+            holds.push(self.arches.iter().any(|arch| arch == target.arch));
+        }
+        if !self.caps.is_empty() {
+            holds.push(target.privileged);
+        }
+        if let Some(min_kernel) = &self.min_kernel {
+            holds.push(target.kernel >= parse_kernel_version(min_kernel)?);
+        }
+        Ok(match any {
+            true => holds.into_iter().any(|h| h),
+            false => holds.into_iter().all(|h| h),
+        })
+    }
+}
+
+/// Major and minor version from a kernel release (like `6.4.3-foo`)
+fn parse_kernel_version(release: &str) -> Result<(u32, u32)> {
+    let mut parts = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(str::parse::<u32>);
+    match (parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => Ok((major, minor)),
+        _ => Err(anyhow::anyhow!("invalid kernel version '{release}'")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+enum ProfileAction {
+    #[serde(rename = "SCMP_ACT_ALLOW")]
+    Allow,
+    #[serde(rename = "SCMP_ACT_ERRNO")]
+    Errno,
+    #[serde(rename = "SCMP_ACT_KILL", alias = "SCMP_ACT_KILL_THREAD")]
+    KillThread,
+    #[serde(rename = "SCMP_ACT_KILL_PROCESS")]
+    KillProcess,
+    #[serde(rename = "SCMP_ACT_TRAP")]
+    Trap,
+    #[serde(rename = "SCMP_ACT_LOG")]
+    Log,
+    #[serde(rename = "SCMP_ACT_TRACE")]
+    Trace,
+}
+
+impl ProfileAction {
+    fn compile(self, errno_ret: Option<u16>) -> Result<Action> {
+        Ok(match self {
+            Self::Allow => Action::Allow,
+            Self::Errno => Action::Errno(errno_ret.unwrap_or(libc::EPERM as u16)),
+            Self::KillThread => Action::KillThread,
+            Self::KillProcess => Action::KillProcess,
+            Self::Trap => Action::Trap,
+            Self::Log => Action::Log,
+            // there is never a tracer attached to the test
+            Self::Trace => anyhow::bail!("SCMP_ACT_TRACE is not supported"),
+        })
+    }
+}
+
+impl Profile {
+    /// Resolve the profile into a [Filter] for the current architecture and
+    /// kernel. `privileged` is whether the test runs with capabilities (as
+    /// root), which decides the rules that depend on capabilities.
+    pub(crate) fn compile(&self, privileged: bool) -> Result<Filter> {
+        let release = std::fs::read_to_string("/proc/sys/kernel/osrelease")
+            .context("while reading kernel version")?;
+        let target = Target {
+            arch: DOCKER_ARCH,
+            privileged,
+            kernel: parse_kernel_version(release.trim())?,
+        };
+        self.compile_for(&target)
+    }
+
+    fn compile_for(&self, target: &Target) -> Result<Filter> {
+        let mut syscalls: BTreeMap<i64, Vec<Rule>> = BTreeMap::new();
+        for (idx, rule) in self.syscalls.iter().enumerate() {
+            let names: Vec<_> = rule
+                .names
+                .iter()
+                .chain(&rule.name)
+                .map(String::as_str)
+                .collect();
+            let ctx = || format!("seccomp rule {idx} ({})", names.join(", "));
+            if !rule.includes.hold(target, false).with_context(ctx)?
+                || rule.excludes.hold(target, true).with_context(ctx)?
+            {
+                debug!("skipping {}, which doesn't apply here", ctx());
+                continue;
+            }
+            let new = Rule {
+                action: rule.action.compile(rule.errno_ret).with_context(ctx)?,
+                conditions: rule.args.clone(),
+            };
+            for name in &names {
+                let Some(nr) = syscall_nr(name) else {
+                    debug!("skipping seccomp rule for unknown syscall '{name}'");
+                    continue;
+                };
+                let rules = syscalls.entry(nr).or_default();
+                match rules.iter().find(|r| r.conditions == new.conditions) {
+                    Some(prev) if prev.action != new.action => {
+                        anyhow::bail!("{}: '{name}' already has a different action", ctx())
+                    }
+                    Some(_) => {}
+                    None => rules.push(new.clone()),
+                }
+            }
+        }
+        let filter = Filter {
+            default: self.default_action.compile(self.default_errno_ret)?,
+            syscalls,
+        };
+        // catch anything that seccompiler rejects before it gets to the
+        // container
+        filter.programs()?;
+        Ok(filter)
+    }
+}
+
+/// What happens when the test makes a syscall
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub(crate) enum Action {
+    Allow,
+    Errno(u16),
+    KillThread,
+    KillProcess,
+    Trap,
+    Log,
+}
+
+impl From<Action> for SeccompAction {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::Allow => Self::Allow,
+            Action::Errno(errno) => Self::Errno(errno.into()),
+            Action::KillThread => Self::KillThread,
+            Action::KillProcess => Self::KillProcess,
+            Action::Trap => Self::Trap,
+            Action::Log => Self::Log,
+        }
+    }
+}
+
+/// Condition on a syscall argument, as found in `args` of a profile rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Condition {
+    index: u8,
+    value: u64,
+    /// Only used by [CmpOp::MaskedEq], which compares the argument masked
+    /// with `value` to this
+    #[serde(default)]
+    value_two: u64,
+    op: CmpOp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum CmpOp {
+    #[serde(rename = "SCMP_CMP_EQ")]
+    Eq,
+    #[serde(rename = "SCMP_CMP_NE")]
+    Ne,
+    #[serde(rename = "SCMP_CMP_LT")]
+    Lt,
+    #[serde(rename = "SCMP_CMP_LE")]
+    Le,
+    #[serde(rename = "SCMP_CMP_GT")]
+    Gt,
+    #[serde(rename = "SCMP_CMP_GE")]
+    Ge,
+    #[serde(rename = "SCMP_CMP_MASKED_EQ")]
+    MaskedEq,
+}
+
+impl Condition {
+    fn compile(&self) -> Result<SeccompCondition> {
+        let (op, value) = match self.op {
+            CmpOp::Eq => (SeccompCmpOp::Eq, self.value),
+            CmpOp::Ne => (SeccompCmpOp::Ne, self.value),
+            CmpOp::Lt => (SeccompCmpOp::Lt, self.value),
+            CmpOp::Le => (SeccompCmpOp::Le, self.value),
+            CmpOp::Gt => (SeccompCmpOp::Gt, self.value),
+            CmpOp::Ge => (SeccompCmpOp::Ge, self.value),
+            CmpOp::MaskedEq => (SeccompCmpOp::MaskedEq(self.value), self.value_two),
+        };
+        // libseccomp compares all 64 bits, and so do Docker profiles
+        SeccompCondition::new(self.index, SeccompCmpArgLen::Qword, op, value)
+            .with_context(|| format!("invalid condition on argument {}", self.index))
+    }
+}
+
+/// A rule of a compiled profile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Rule {
+    action: Action,
+    /// All of these have to hold for the rule to match. Without any, every
+    /// call of the syscall matches.
+    conditions: Vec<Condition>,
+}
+
+/// A compiled seccomp profile, ready to be installed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Filter {
+    default: Action,
+    /// Rules for each syscall, by number
+    syscalls: BTreeMap<i64, Vec<Rule>>,
+}
+
+impl Filter {
+    /// Install the filter in the child right before it execs, after it
+    /// already switched to the test user. This means the filter must allow
+    /// `execve`.
+    pub(crate) fn apply_to(&self, command: &mut Command) -> Result<()> {
+        let programs = self.programs()?;
+        // SAFETY: only async-signal-safe syscalls happen after fork
+        unsafe {
+            command.pre_exec(move || {
+                for program in &programs {
+                    seccompiler::apply_filter(program).map_err(|e| match e {
+                        seccompiler::Error::Prctl(e) => e,
+                        _ => std::io::Error::from_raw_os_error(libc::EINVAL),
+                    })?;
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// BPF programs that are stacked to make up this filter
+    fn programs(&self) -> Result<Vec<BpfProgram>> {
+        let compile = |rules: &mut dyn Iterator<Item = &Rule>| -> Result<Vec<SeccompRule>> {
+            let mut compiled = Vec::new();
+            for rule in rules {
+                // a rule without conditions matches everything that the
+                // others could
+                if rule.conditions.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let conditions = rule
+                    .conditions
+                    .iter()
+                    .map(Condition::compile)
+                    .collect::<Result<_>>()?;
+                compiled.push(SeccompRule::new(conditions)?);
+            }
+            Ok(compiled)
+        };
+        let mut programs = X32_GUARD.iter().map(|p| p.to_vec()).collect::<Vec<_>>();
+        // one filter that only matches the calls with each action
+        let actions: BTreeSet<_> = self
+            .syscalls
+            .values()
+            .flatten()
+            .map(|rule| rule.action)
+            .filter(|action| *action != Action::Allow)
+            .collect();
+        for action in actions {
+            let mut rules = BTreeMap::new();
+            for (nr, syscall_rules) in &self.syscalls {
+                let mut matching = syscall_rules
+                    .iter()
+                    .filter(|r| r.action == action)
+                    .peekable();
+                if matching.peek().is_some() {
+                    rules.insert(*nr, compile(&mut matching)?);
+                }
+            }
+            programs.push(
+                SeccompFilter::new(rules, SeccompAction::Allow, action.into(), TARGET_ARCH)?
+                    .try_into()?,
+            );
+        }
+        // and one that lets through every call with a rule, so that
+        // everything else gets the default action
+        if self.default != Action::Allow {
+            let rules = self
+                .syscalls
+                .iter()
+                .map(|(nr, rules)| Ok((*nr, compile(&mut rules.iter())?)))
+                .collect::<Result<_>>()?;
+            programs.push(
+                SeccompFilter::new(
+                    rules,
+                    self.default.into(),
+                    SeccompAction::Allow,
+                    TARGET_ARCH,
+                )?
+                .try_into()?,
+            );
+        }
+        Ok(programs)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+const TARGET_ARCH: TargetArch = TargetArch::x86_64;
+#[cfg(target_arch = "x86_64")]
+const DOCKER_ARCH: &str = "amd64";
+#[cfg(target_arch = "aarch64")]
+const TARGET_ARCH: TargetArch = TargetArch::aarch64;
+#[cfg(target_arch = "aarch64")]
+const DOCKER_ARCH: &str = "arm64";
+
+/// x32 syscalls are reported with the same architecture as x86_64, but with
+/// this bit set in their number. None of the numbers in a filter match them,
+/// which would let them through whenever the default action is to allow, so
+/// they are killed first, the same as syscalls of any other architecture.
+#[cfg(target_arch = "x86_64")]
+const X32_GUARD: &[&[seccompiler::sock_filter]] = &[&[
+    // load the syscall number from struct seccomp_data
+    bpf(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 0, 0, 0),
+    bpf(
+        libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+        X32_SYSCALL_BIT,
+        0,
+        1,
+    ),
+    bpf(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_KILL_PROCESS,
+        0,
+        0,
+    ),
+    bpf(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW, 0, 0),
+]];
+#[cfg(not(target_arch = "x86_64"))]
+const X32_GUARD: &[&[seccompiler::sock_filter]] = &[];
+
+/// From asm/unistd.h
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+#[cfg(target_arch = "x86_64")]
+const fn bpf(code: u32, k: u32, jt: u8, jf: u8) -> seccompiler::sock_filter {
+    seccompiler::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+fn syscall_nr(name: &str) -> Option<i64> {
+    SYSCALLS
+        .iter()
+        .chain(ARCH_SYSCALLS)
+        .find(|(n, _)| n.strip_prefix("SYS_") == Some(name))
+        .map(|(_, nr)| *nr)
+}
+
+macro_rules! syscall_table {
+    ($($name:ident),* $(,)?) => {
+        &[$((stringify!($name), libc::$name as i64)),*]
+    };
+}
+
+/// Syscalls that exist on every supported architecture
+const SYSCALLS: &[(&str, i64)] = syscall_table![
+    SYS_accept,
+    SYS_accept4,
+    SYS_acct,
+    SYS_add_key,
+    SYS_adjtimex,
+    SYS_bind,
+    SYS_bpf,
+    SYS_brk,
+    SYS_capget,
+    SYS_capset,
+    SYS_chdir,
+    SYS_chroot,
+    SYS_clock_adjtime,
+    SYS_clock_getres,
+    SYS_clock_gettime,
+    SYS_clock_nanosleep,
+    SYS_clock_settime,
+    SYS_clone,
+    SYS_clone3,
+    SYS_close,
+    SYS_close_range,
+    SYS_connect,
+    SYS_copy_file_range,
+    SYS_delete_module,
+    SYS_dup,
+    SYS_dup3,
+    SYS_epoll_create1,
+    SYS_epoll_ctl,
+    SYS_epoll_pwait,
+    SYS_epoll_pwait2,
+    SYS_eventfd2,
+    SYS_execve,
+    SYS_execveat,
+    SYS_exit,
+    SYS_exit_group,
+    SYS_faccessat,
+    SYS_faccessat2,
+    SYS_fadvise64,
+    SYS_fallocate,
+    SYS_fanotify_init,
+    SYS_fanotify_mark,
+    SYS_fchdir,
+    SYS_fchmod,
+    SYS_fchmodat,
+    SYS_fchown,
+    SYS_fchownat,
+    SYS_fcntl,
+    SYS_fdatasync,
+    SYS_fgetxattr,
+    SYS_finit_module,
+    SYS_flistxattr,
+    SYS_flock,
+    SYS_fremovexattr,
+    SYS_fsconfig,
+    SYS_fsetxattr,
+    SYS_fsmount,
+    SYS_fsopen,
+    SYS_fspick,
+    SYS_fstat,
+    SYS_fstatfs,
+    SYS_fsync,
+    SYS_ftruncate,
+    SYS_futex,
+    SYS_futex_waitv,
+    SYS_get_mempolicy,
+    SYS_get_robust_list,
+    SYS_getcpu,
+    SYS_getcwd,
+    SYS_getdents64,
+    SYS_getegid,
+    SYS_geteuid,
+    SYS_getgid,
+    SYS_getgroups,
+    SYS_getitimer,
+    SYS_getpeername,
+    SYS_getpgid,
+    SYS_getpid,
+    SYS_getppid,
+    SYS_getpriority,
+    SYS_getrandom,
+    SYS_getresgid,
+    SYS_getresuid,
+    SYS_getrusage,
+    SYS_getsid,
+    SYS_getsockname,
+    SYS_getsockopt,
+    SYS_gettid,
+    SYS_gettimeofday,
+    SYS_getuid,
+    SYS_getxattr,
+    SYS_init_module,
+    SYS_inotify_add_watch,
+    SYS_inotify_init1,
+    SYS_inotify_rm_watch,
+    SYS_io_cancel,
+    SYS_io_destroy,
+    SYS_io_getevents,
+    SYS_io_setup,
+    SYS_io_submit,
+    SYS_io_uring_enter,
+    SYS_io_uring_register,
+    SYS_io_uring_setup,
+    SYS_ioctl,
+    SYS_ioprio_get,
+    SYS_ioprio_set,
+    SYS_kcmp,
+    SYS_kexec_file_load,
+    SYS_kexec_load,
+    SYS_keyctl,
+    SYS_kill,
+    SYS_landlock_add_rule,
+    SYS_landlock_create_ruleset,
+    SYS_landlock_restrict_self,
+    SYS_lgetxattr,
+    SYS_linkat,
+    SYS_listen,
+    SYS_listxattr,
+    SYS_llistxattr,
+    SYS_lookup_dcookie,
+    SYS_lremovexattr,
+    SYS_lseek,
+    SYS_lsetxattr,
+    SYS_madvise,
+    SYS_mbind,
+    SYS_membarrier,
+    SYS_memfd_create,
+    SYS_memfd_secret,
+    SYS_migrate_pages,
+    SYS_mincore,
+    SYS_mkdirat,
+    SYS_mknodat,
+    SYS_mlock,
+    SYS_mlock2,
+    SYS_mlockall,
+    SYS_mmap,
+    SYS_mount,
+    SYS_mount_setattr,
+    SYS_move_mount,
+    SYS_move_pages,
+    SYS_mprotect,
+    SYS_mq_getsetattr,
+    SYS_mq_notify,
+    SYS_mq_open,
+    SYS_mq_timedreceive,
+    SYS_mq_timedsend,
+    SYS_mq_unlink,
+    SYS_mremap,
+    SYS_msgctl,
+    SYS_msgget,
+    SYS_msgrcv,
+    SYS_msgsnd,
+    SYS_msync,
+    SYS_munlock,
+    SYS_munlockall,
+    SYS_munmap,
+    SYS_name_to_handle_at,
+    SYS_nanosleep,
+    SYS_newfstatat,
+    SYS_nfsservctl,
+    SYS_open_by_handle_at,
+    SYS_open_tree,
+    SYS_openat,
+    SYS_openat2,
+    SYS_perf_event_open,
+    SYS_personality,
+    SYS_pidfd_getfd,
+    SYS_pidfd_open,
+    SYS_pidfd_send_signal,
+    SYS_pipe2,
+    SYS_pivot_root,
+    SYS_pkey_alloc,
+    SYS_pkey_free,
+    SYS_pkey_mprotect,
+    SYS_ppoll,
+    SYS_prctl,
+    SYS_pread64,
+    SYS_preadv,
+    SYS_preadv2,
+    SYS_prlimit64,
+    SYS_process_madvise,
+    SYS_process_mrelease,
+    SYS_process_vm_readv,
+    SYS_process_vm_writev,
+    SYS_pselect6,
+    SYS_ptrace,
+    SYS_pwrite64,
+    SYS_pwritev,
+    SYS_pwritev2,
+    SYS_quotactl,
+    SYS_quotactl_fd,
+    SYS_read,
+    SYS_readahead,
+    SYS_readlinkat,
+    SYS_readv,
+    SYS_reboot,
+    SYS_recvfrom,
+    SYS_recvmmsg,
+    SYS_recvmsg,
+    SYS_remap_file_pages,
+    SYS_removexattr,
+    SYS_renameat2,
+    SYS_request_key,
+    SYS_restart_syscall,
+    SYS_rseq,
+    SYS_rt_sigaction,
+    SYS_rt_sigpending,
+    SYS_rt_sigprocmask,
+    SYS_rt_sigqueueinfo,
+    SYS_rt_sigreturn,
+    SYS_rt_sigsuspend,
+    SYS_rt_sigtimedwait,
+    SYS_rt_tgsigqueueinfo,
+    SYS_sched_get_priority_max,
+    SYS_sched_get_priority_min,
+    SYS_sched_getaffinity,
+    SYS_sched_getattr,
+    SYS_sched_getparam,
+    SYS_sched_getscheduler,
+    SYS_sched_rr_get_interval,
+    SYS_sched_setaffinity,
+    SYS_sched_setattr,
+    SYS_sched_setparam,
+    SYS_sched_setscheduler,
+    SYS_sched_yield,
+    SYS_seccomp,
+    SYS_semctl,
+    SYS_semget,
+    SYS_semop,
+    SYS_semtimedop,
+    SYS_sendfile,
+    SYS_sendmmsg,
+    SYS_sendmsg,
+    SYS_sendto,
+    SYS_set_mempolicy,
+    SYS_set_mempolicy_home_node,
+    SYS_set_robust_list,
+    SYS_set_tid_address,
+    SYS_setdomainname,
+    SYS_setfsgid,
+    SYS_setfsuid,
+    SYS_setgid,
+    SYS_setgroups,
+    SYS_sethostname,
+    SYS_setitimer,
+    SYS_setns,
+    SYS_setpgid,
+    SYS_setpriority,
+    SYS_setregid,
+    SYS_setresgid,
+    SYS_setresuid,
+    SYS_setreuid,
+    SYS_setsid,
+    SYS_setsockopt,
+    SYS_settimeofday,
+    SYS_setuid,
+    SYS_setxattr,
+    SYS_shmat,
+    SYS_shmctl,
+    SYS_shmdt,
+    SYS_shmget,
+    SYS_shutdown,
+    SYS_sigaltstack,
+    SYS_signalfd4,
+    SYS_socket,
+    SYS_socketpair,
+    SYS_splice,
+    SYS_statfs,
+    SYS_statx,
+    SYS_swapoff,
+    SYS_swapon,
+    SYS_symlinkat,
+    SYS_sync,
+    SYS_syncfs,
+    SYS_sysinfo,
+    SYS_syslog,
+    SYS_tee,
+    SYS_tgkill,
+    SYS_timer_create,
+    SYS_timer_delete,
+    SYS_timer_getoverrun,
+    SYS_timer_gettime,
+    SYS_timer_settime,
+    SYS_timerfd_create,
+    SYS_timerfd_gettime,
+    SYS_timerfd_settime,
+    SYS_times,
+    SYS_tkill,
+    SYS_truncate,
+    SYS_umask,
+    SYS_umount2,
+    SYS_uname,
+    SYS_unlinkat,
+    SYS_unshare,
+    SYS_userfaultfd,
+    SYS_utimensat,
+    SYS_vhangup,
+    SYS_vmsplice,
+    SYS_wait4,
+    SYS_waitid,
+    SYS_write,
+    SYS_writev
+];
+
+#[cfg(target_arch = "x86_64")]
+const ARCH_SYSCALLS: &[(&str, i64)] = syscall_table![
+    SYS__sysctl,
+    SYS_access,
+    SYS_afs_syscall,
+    SYS_alarm,
+    SYS_arch_prctl,
+    SYS_chmod,
+    SYS_chown,
+    SYS_creat,
+    SYS_dup2,
+    SYS_epoll_create,
+    SYS_epoll_ctl_old,
+    SYS_epoll_wait,
+    SYS_epoll_wait_old,
+    SYS_eventfd,
+    SYS_fork,
+    SYS_futimesat,
+    SYS_get_thread_area,
+    SYS_getdents,
+    SYS_getpgrp,
+    SYS_getpmsg,
+    SYS_getrlimit,
+    SYS_inotify_init,
+    SYS_ioperm,
+    SYS_iopl,
+    SYS_lchown,
+    SYS_link,
+    SYS_lstat,
+    SYS_mkdir,
+    SYS_mknod,
+    SYS_modify_ldt,
+    SYS_open,
+    SYS_pause,
+    SYS_pipe,
+    SYS_poll,
+    SYS_putpmsg,
+    SYS_readlink,
+    SYS_rename,
+    SYS_renameat,
+    SYS_rmdir,
+    SYS_security,
+    SYS_select,
+    SYS_set_thread_area,
+    SYS_setrlimit,
+    SYS_signalfd,
+    SYS_stat,
+    SYS_symlink,
+    SYS_sync_file_range,
+    SYS_sysfs,
+    SYS_time,
+    SYS_tuxcall,
+    SYS_unlink,
+    SYS_uselib,
+    SYS_ustat,
+    SYS_utime,
+    SYS_utimes,
+    SYS_vfork,
+    SYS_vserver
+];
+#[cfg(not(target_arch = "x86_64"))]
+const ARCH_SYSCALLS: &[(&str, i64)] = &[];
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CString;
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    fn profile(json: serde_json::Value) -> Result<Filter> {
+        serde_json::from_value::<Profile>(json)
+            .expect("valid profile")
+            .compile(false)
+    }
+
+    fn rule(action: Action, conditions: &[Condition]) -> Rule {
+        Rule {
+            action,
+            conditions: conditions.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_compile() {
+        let filter = profile(serde_json::json!({
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "defaultErrnoRet": 38,
+            "architectures": ["SCMP_ARCH_X86_64"],
+            "syscalls": [
+                {"names": ["read", "write", "not_a_syscall"], "action": "SCMP_ACT_ALLOW"},
+                {"name": "uname", "action": "SCMP_ACT_ERRNO", "errnoRet": 1, "args": []},
+                {"names": ["ptrace"], "action": "SCMP_ACT_KILL"},
+                {"names": ["read"], "action": "SCMP_ACT_ALLOW"},
+            ],
+        }))
+        .expect("valid profile");
+        assert_eq!(
+            filter,
+            Filter {
+                default: Action::Errno(38),
+                syscalls: BTreeMap::from([
+                    (libc::SYS_read, vec![rule(Action::Allow, &[])]),
+                    (libc::SYS_write, vec![rule(Action::Allow, &[])]),
+                    (libc::SYS_uname, vec![rule(Action::Errno(1), &[])]),
+                    (libc::SYS_ptrace, vec![rule(Action::KillThread, &[])]),
+                ]),
+            }
+        );
+        // one for each action that isn't allow, and one for the default
+        assert_eq!(
+            filter.programs().expect("failed to build").len(),
+            X32_GUARD.len() + 3
+        );
+        // the exec spec carries the compiled filter into the container
+        let json = serde_json::to_string(&filter).expect("failed to serialize");
+        assert_eq!(
+            serde_json::from_str::<Filter>(&json).expect("failed to deserialize"),
+            filter
+        );
+    }
+
+    #[test]
+    fn test_compile_conditions() {
+        let profile: Profile = serde_json::from_value(serde_json::json!({
+            "defaultAction": "SCMP_ACT_ERRNO",
+            "syscalls": [
+                {
+                    "names": ["personality"],
+                    "action": "SCMP_ACT_ALLOW",
+                    "args": [{"index": 0, "value": 0, "op": "SCMP_CMP_EQ"}],
+                },
+                {
+                    "names": ["personality"],
+                    "action": "SCMP_ACT_ALLOW",
+                    "args": [{"index": 0, "value": 8, "op": "SCMP_CMP_EQ"}],
+                },
+                {
+                    "names": ["clone"],
+                    "action": "SCMP_ACT_ALLOW",
+                    "args": [{
+                        "index": 0,
+                        "value": 2114060288,
+                        "valueTwo": 0,
+                        "op": "SCMP_CMP_MASKED_EQ",
+                    }],
+                    "excludes": {"caps": ["CAP_SYS_ADMIN"], "arches": ["s390", "s390x"]},
+                },
+                {
+                    "names": ["mount"],
+                    "action": "SCMP_ACT_ALLOW",
+                    "includes": {"caps": ["CAP_SYS_ADMIN"]},
+                },
+                {
+                    "names": ["uname"],
+                    "action": "SCMP_ACT_ALLOW",
+                    "includes": {"arches": ["amd64", "arm64"], "minKernel": "4.8"},
+                },
+                {
+                    "names": ["ptrace"],
+                    "action": "SCMP_ACT_ALLOW",
+                    "includes": {"minKernel": "99.0"},
+                },
+            ],
+        }))
+        .expect("valid profile");
+        let personality = vec![
+            rule(
+                Action::Allow,
+                &[Condition {
+                    index: 0,
+                    value: 0,
+                    value_two: 0,
+                    op: CmpOp::Eq,
+                }],
+            ),
+            rule(
+                Action::Allow,
+                &[Condition {
+                    index: 0,
+                    value: 8,
+                    value_two: 0,
+                    op: CmpOp::Eq,
+                }],
+            ),
+        ];
+        let clone = vec![rule(
+            Action::Allow,
+            &[Condition {
+                index: 0,
+                value: 2114060288,
+                value_two: 0,
+                op: CmpOp::MaskedEq,
+            }],
+        )];
+        let unprivileged = profile
+            .compile_for(&Target {
+                arch: DOCKER_ARCH,
+                privileged: false,
+                kernel: (6, 4),
+            })
+            .expect("failed to compile");
+        assert_eq!(
+            unprivileged.syscalls,
+            BTreeMap::from([
+                (libc::SYS_personality, personality.clone()),
+                (libc::SYS_clone, clone),
+                (libc::SYS_uname, vec![rule(Action::Allow, &[])]),
+            ])
+        );
+        let privileged = profile
+            .compile_for(&Target {
+                arch: DOCKER_ARCH,
+                privileged: true,
+                kernel: (4, 4),
+            })
+            .expect("failed to compile");
+        assert_eq!(
+            privileged.syscalls,
+            BTreeMap::from([
+                (libc::SYS_personality, personality),
+                (libc::SYS_mount, vec![rule(Action::Allow, &[])]),
+            ])
+        );
+        assert_eq!(parse_kernel_version("6.4.3-0_fbk1").expect("valid"), (6, 4));
+        assert!(parse_kernel_version("six").is_err());
+    }
+
+    #[test]
+    fn test_compile_unsupported() {
+        assert!(profile(serde_json::json!({
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [{
+                "names": ["personality"],
+                "action": "SCMP_ACT_ERRNO",
+                "args": [{"index": 6, "value": 0, "op": "SCMP_CMP_EQ"}],
+            }],
+        }))
+        .is_err());
+        assert!(profile(serde_json::json!({
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [{"names": ["ptrace"], "action": "SCMP_ACT_TRACE"}],
+        }))
+        .is_err());
+        let err = profile(serde_json::json!({
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [
+                {"names": ["uname"], "action": "SCMP_ACT_ALLOW"},
+                {"names": ["uname"], "action": "SCMP_ACT_KILL"},
+            ],
+        }))
+        .expect_err("conflicting actions are rejected");
+        assert_eq!(
+            err.to_string(),
+            "seccomp rule 1 (uname): 'uname' already has a different action"
+        );
+        assert!(serde_json::from_value::<Profile>(serde_json::json!({
+            "defaultAction": "SCMP_ACT_SOMETIMES",
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn test_blocked_syscall_is_killed() {
+        let filter = profile(serde_json::json!({
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [
+                {"names": ["mkdir", "mkdirat"], "action": "SCMP_ACT_KILL_PROCESS"},
+                {"names": ["uname"], "action": "SCMP_ACT_ERRNO"},
+            ],
+        }))
+        .expect("valid profile");
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+
+        let mut mkdir = Command::new("mkdir");
+        mkdir.arg(dir.path().join("blocked"));
+        filter.apply_to(&mut mkdir).expect("failed to apply");
+        let status = mkdir.status().expect("failed to run mkdir");
+        assert_eq!(status.signal(), Some(libc::SIGSYS), "{status}");
+        assert!(!dir.path().join("blocked").exists());
+
+        let mut uname = Command::new("uname");
+        filter.apply_to(&mut uname).expect("failed to apply");
+        let out = uname.output().expect("failed to run uname");
+        assert!(!out.status.success());
+        assert_eq!(out.status.signal(), None, "{}", out.status);
+
+        // everything else is untouched
+        let mut touch = Command::new("touch");
+        touch.arg(dir.path().join("allowed"));
+        filter.apply_to(&mut touch).expect("failed to apply");
+        assert!(touch.status().expect("failed to run touch").success());
+    }
+
+    #[test]
+    fn test_argument_conditions() {
+        let filter = profile(serde_json::json!({
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [{
+                "names": ["getpriority"],
+                "action": "SCMP_ACT_ERRNO",
+                "errnoRet": libc::EACCES,
+                "args": [{"index": 0, "value": libc::PRIO_PGRP, "op": "SCMP_CMP_EQ"}],
+            }],
+        }))
+        .expect("valid profile");
+        let mut cmd = Command::new("true");
+        filter.apply_to(&mut cmd).expect("failed to apply");
+        // SAFETY: only async-signal-safe syscalls
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::syscall(libc::SYS_getpriority, libc::PRIO_PROCESS, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                match libc::syscall(libc::SYS_getpriority, libc::PRIO_PGRP, 0) {
+                    -1 => Err(std::io::Error::last_os_error()),
+                    _ => Ok(()),
+                }
+            });
+        }
+        let err = cmd.status().expect_err("only PRIO_PGRP is blocked");
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES), "{err}");
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_x32_is_killed() {
+        let filter = profile(serde_json::json!({
+            "defaultAction": "SCMP_ACT_ALLOW",
+            "syscalls": [{"names": ["mkdir"], "action": "SCMP_ACT_ERRNO"}],
+        }))
+        .expect("valid profile");
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let path = CString::new(dir.path().join("x32").into_os_string().into_encoded_bytes())
+            .expect("no nul");
+        let mut cmd = Command::new("true");
+        filter.apply_to(&mut cmd).expect("failed to apply");
+        // SAFETY: only async-signal-safe syscalls
+        unsafe {
+            cmd.pre_exec(move || {
+                libc::syscall(
+                    libc::SYS_mkdir | X32_SYSCALL_BIT as i64,
+                    path.as_ptr(),
+                    0o755,
+                );
+                Ok(())
+            });
+        }
+        let status = cmd.status().expect("failed to run");
+        assert_eq!(status.signal(), Some(libc::SIGSYS), "{status}");
+        assert!(!dir.path().join("x32").exists());
+    }
+}
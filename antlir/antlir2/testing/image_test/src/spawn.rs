@@ -7,15 +7,18 @@
 
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::fs::File;
 use std::fs::Permissions;
 use std::io::Write;
 use std::os::fd::AsRawFd;
 use std::os::fd::FromRawFd;
 use std::os::unix::fs::PermissionsExt;
-use std::os::unix::process::CommandExt;
 use std::path::Path;
+use std::path::PathBuf;
 use std::process::Command;
+use std::time::Duration;
 
 use antlir2_isolate::nspawn;
 use antlir2_isolate::unshare;
@@ -26,45 +29,223 @@ use anyhow::Context;
 use anyhow::Result;
 use clap::Parser;
 use image_test_lib::Test;
+use json_arg::Json;
 use json_arg::JsonFile;
 use tempfile::NamedTempFile;
 use tracing::debug;
 use tracing::trace;
 
+use crate::env_file;
 use crate::exec;
+use crate::runs;
 use crate::runtime;
+use crate::seccomp;
+use crate::warm_ns;
+use crate::watchdog::ContainerExit;
+use crate::watchdog::Liveness;
+use crate::watchdog::Watchdog;
+use crate::watchdog::HEARTBEAT_ENV;
+use crate::watchdog::HEARTBEAT_PATH;
 
 fn make_log_files(_base: &str) -> Result<(NamedTempFile, NamedTempFile)> {
     Ok((NamedTempFile::new()?, NamedTempFile::new()?))
 }
 
+/// Temp file that the test can write to, even if it is not running as root
+fn writable_tempfile(what: &str) -> Result<NamedTempFile> {
+    let file = NamedTempFile::new().with_context(|| format!("while creating {what} file"))?;
+    file.as_file()
+        .set_permissions(Permissions::from_mode(0o666))
+        .with_context(|| format!("while making {what} file writable"))?;
+    Ok(file)
+}
+
 /// Run a unit test inside an image layer.
 #[derive(Parser, Debug)]
 pub(crate) struct Args {
     #[clap(long)]
     spec: JsonFile<runtime::Spec>,
+    #[clap(long)]
+    /// Print the fully assembled container command instead of running it
+    dry_run: bool,
+    #[clap(long)]
+    /// Bind-mount the host's /etc/resolv.conf read-only into the container
+    inherit_resolv_conf: bool,
+    #[clap(long)]
+    /// Mount a fresh tmpfs on /tmp and /var/tmp so that nothing leaks between
+    /// runs of the test
+    isolate_tmp: bool,
+    #[clap(long)]
+    /// Copy files matching `<in-image-glob>:<host-dir>` out of the container
+    /// after the test exits
+    collect: Vec<exec::Collect>,
+    #[clap(long, requires = "collect")]
+    /// Only collect artifacts if the test fails
+    collect_on_failure: bool,
+    #[clap(long)]
+    /// Kill the container and exit with [crate::EXIT_TIMEOUT] if the test
+    /// runs for longer than this
+    timeout_secs: Option<u64>,
+    #[clap(long)]
+    /// Opt in to hang detection: the test must touch the file named by
+    /// `$ANTLIR2_IMAGE_TEST_HEARTBEAT` at least this often, otherwise the
+    /// stacks of every process in the container are dumped and it is killed
+    liveness_interval: Option<u64>,
+    #[clap(long)]
+    /// Run the test in this timezone (like `America/New_York`), which must
+    /// exist under /usr/share/zoneinfo in the image
+    tz: Option<String>,
+    #[clap(long)]
+    /// Run the test with `LANG` and `LC_ALL` set to this locale
+    locale: Option<String>,
+    #[clap(long)]
+    /// Load environment variables for the test from this dotenv-style file of
+    /// `KEY=VALUE` lines. May be given multiple times, in which case later
+    /// files override earlier ones.
+    env_file: Vec<PathBuf>,
+    #[clap(long)]
+    /// Start the test with an empty environment, except for what the spec
+    /// sets, --pass-env, --env-file and --tz/--locale. Nothing is forwarded
+    /// from the test runner implicitly. Only the test process is affected,
+    /// not the container it runs in.
+    env_clear: bool,
+    #[clap(long)]
+    /// Run the test under this Docker/OCI JSON seccomp profile
+    seccomp_profile: Option<JsonFile<seccomp::Profile>>,
+    #[clap(long, value_enum)]
+    /// Nothing filters the test's syscalls by default, but `unconfined` also
+    /// ignores any --seccomp-profile
+    seccomp: Option<seccomp::Mode>,
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    /// Run the whole test this many times, each in a fresh container, and
+    /// fail if any run fails. Logs and collected artifacts of each run are
+    /// kept apart by run index.
+    runs: u32,
+    #[clap(long)]
+    /// With --runs, don't start any more runs after the first failure
+    stop_on_first_failure: bool,
+    #[clap(long, value_enum, default_value_t)]
+    /// Experimental: with `reuse`, join the mount namespace of a container
+    /// of the same image that was set up by an earlier invocation instead of
+    /// setting up a new one. The image is read-only for the test, which gets
+    /// fresh /tmp, /var/tmp, /run and /dev/shm, and new network, UTS and pid
+    /// namespaces in which it runs as pid 1.
+    mount_ns: warm_ns::Mode,
+    #[clap(long, env = "ANTLIR2_IMAGE_TEST_RUNTIME_SPEC")]
+    /// JSON overrides for the paths of the tools that image_test runs
+    runtime_spec: Option<Json<runtime::RuntimeOverrides>>,
     #[clap(subcommand)]
     test: Test,
 }
 
+/// Container command that is ready to be executed
+struct Prepared {
+    command: Command,
+    /// Only set for booted tests
+    boot: Option<PreparedBoot>,
+    /// Files that are bind-mounted into the container and need to outlive it
+    keep_alive: Vec<NamedTempFile>,
+    /// Written to by `image-test exec` right before it starts the test. Not
+    /// set when the test is not started through `image-test exec`.
+    started: Option<NamedTempFile>,
+}
+
+/// Test output of a booted container
+struct PreparedBoot {
+    test_stdout: NamedTempFile,
+    test_stderr: NamedTempFile,
+}
+
 impl Args {
-    pub(crate) fn run(self) -> Result<()> {
+    /// Run the test, returning the exit code to report
+    pub(crate) fn run(self) -> Result<i32> {
+        // catch missing paths before anything gets set up
+        self.spec.as_inner().validate(
+            self.runtime_spec
+                .as_ref()
+                .map(|overrides| overrides.as_inner()),
+        )?;
+        let dry_run = self.dry_run;
+        let heartbeat_file = self
+            .liveness_interval
+            .map(|_| writable_tempfile("heartbeat"))
+            .transpose()?;
+        let watchdog = Watchdog {
+            timeout: self.timeout_secs.map(Duration::from_secs),
+            liveness: self.liveness_interval.zip(heartbeat_file.as_ref()).map(
+                |(secs, heartbeat)| Liveness {
+                    interval: Duration::from_secs(secs),
+                    heartbeat: heartbeat.path().to_owned(),
+                },
+            ),
+        };
+        let heartbeat = heartbeat_file.as_ref().map(NamedTempFile::path);
+        // every run shares the same user namespace
+        if self.spec.as_inner().rootless {
+            antlir2_rootless::unshare_new_userns().context("while unsharing userns")?;
+        }
+        if self.runs > 1 && !dry_run {
+            return runs::run_repeatedly(self.runs, self.stop_on_first_failure, |run| {
+                wait_for_test(self.prepare(heartbeat, Some(run))?, &watchdog, Some(run))
+            })
+            .map(|summary| summary.code());
+        }
+        let prepared = self.prepare(heartbeat, None)?;
+        if dry_run {
+            // the command refers to these, so they can't be deleted when this
+            // process exits
+            let files = prepared
+                .keep_alive
+                .into_iter()
+                .chain(
+                    prepared
+                        .boot
+                        .into_iter()
+                        .flat_map(|boot| [boot.test_stdout, boot.test_stderr]),
+                )
+                .chain(prepared.started)
+                .chain(heartbeat_file);
+            for file in files {
+                let (_, path) = file.keep().context("while keeping temp file")?;
+                eprintln!("kept {} for the container command", path.display());
+            }
+            println!("{}", shell_command(&prepared.command, false));
+            return Ok(0);
+        }
+        let exit = wait_for_test(prepared, &watchdog, None)?;
+        exit.report();
+        Ok(exit.code())
+    }
+
+    /// Assemble the container command without running anything. `run` is the
+    /// index of this run when the test is run more than once.
+    fn prepare(&self, heartbeat: Option<&Path>, run: Option<u32>) -> Result<Prepared> {
         let repo =
             find_root::find_repo_root(std::env::current_exe().context("while getting argv[0]")?)
                 .context("while looking for repo root")?
                 .canonicalize()
                 .context("while canonicalizing repo root")?;
 
-        let spec = self.spec.into_inner();
-
-        if spec.rootless {
-            antlir2_rootless::unshare_new_userns().context("while unsharing userns")?;
-        }
+        let spec = self.spec.as_inner().clone();
+        let overrides = self
+            .runtime_spec
+            .as_ref()
+            .map(|overrides| overrides.as_inner().clone())
+            .unwrap_or_default();
+        let seccomp = match self.seccomp {
+            Some(seccomp::Mode::Unconfined) => None,
+            None => self
+                .seccomp_profile
+                .as_ref()
+                .map(|profile| profile.as_inner().compile(spec.user == "root"))
+                .transpose()
+                .context("while compiling --seccomp-profile")?,
+        };
 
         let mut setenv: BTreeMap<_, _> = spec.setenv.into_iter().collect();
         // forward test runner env vars to the inner test
         for (key, val) in std::env::vars() {
-            if key.starts_with("TEST_PILOT") {
+            if key.starts_with("TEST_PILOT") && !self.env_clear {
                 setenv.insert(key, val);
             }
         }
@@ -74,8 +255,23 @@ impl Args {
 
             setenv.insert(key, var);
         }
-        if let Ok(rust_log) = std::env::var("RUST_LOG") {
-            setenv.insert("RUST_LOG".into(), rust_log);
+        for path in &self.env_file {
+            setenv.extend(env_file::load(path)?);
+        }
+        // explicitly requested on the command line, so these win over the spec
+        setenv.extend(tz_locale_env(
+            &spec.layer,
+            self.tz.as_deref(),
+            self.locale.as_deref(),
+        )?);
+        // with --env-clear, only what was asked for is passed to the test
+        if !self.env_clear {
+            if let Ok(rust_log) = std::env::var("RUST_LOG") {
+                setenv.insert("RUST_LOG".into(), rust_log);
+            }
+        }
+        if heartbeat.is_some() {
+            setenv.insert(HEARTBEAT_ENV.into(), HEARTBEAT_PATH.into());
         }
 
         let working_directory = std::env::current_dir().context("while getting cwd")?;
@@ -105,6 +301,32 @@ impl Args {
         .inputs(spec.mounts)
         .setenv(("ANTLIR2_IMAGE_TEST", "1"));
 
+        if let Some(heartbeat) = heartbeat {
+            ctx.outputs((Path::new(HEARTBEAT_PATH), heartbeat));
+        }
+        if self.inherit_resolv_conf {
+            ctx.inputs(resolv_conf_bind(Path::new(RESOLV_CONF))?);
+        }
+
+        // each collection dir on the host is made available at a well-known
+        // location inside the container where `image-test exec` can copy
+        // artifacts into
+        let mut collect = Vec::new();
+        for (idx, c) in self.collect.iter().enumerate() {
+            let dir = match run {
+                Some(run) => c.dir.join(format!("run{run}")),
+                None => c.dir.clone(),
+            };
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("while creating {}", dir.display()))?;
+            let container_dir = Path::new(COLLECT_ROOT).join(idx.to_string());
+            ctx.outputs((container_dir.clone(), dir));
+            collect.push(exec::Collect {
+                glob: c.glob.clone(),
+                dir: container_dir,
+            });
+        }
+
         // XARs need /dev/fuse to run. Ideally we could just have this created
         // inside the container. Until
         // https://github.com/systemd/systemd/issues/17607 is resolved, we need to
@@ -135,6 +357,12 @@ impl Args {
             ctx.devtmpfs(Path::new("/dev"));
         }
 
+        if self.isolate_tmp {
+            for dir in ISOLATED_TMP_DIRS {
+                ctx.tmpfs(Path::new(dir));
+            }
+        }
+
         match spec.boot {
             Some(boot) => {
                 ensure!(
@@ -142,8 +370,7 @@ impl Args {
                     "TODO(T187078382): booted tests still must use systemd-nspawn and are incompatible with rootless"
                 );
 
-                let container_stdout = container_stdout_file()?;
-                let (mut test_stdout, mut test_stderr) = make_log_files("test")?;
+                let (test_stdout, test_stderr) = make_log_files("test")?;
 
                 let mut test_unit_dropin = NamedTempFile::new()?;
                 writeln!(test_unit_dropin, "[Unit]")?;
@@ -151,7 +378,7 @@ impl Args {
                 // If a test requires default.target, it really wants the _real_
                 // default.target, not the test itself which becomes default.target when
                 // we pass systemd.unit=
-                let res = Command::new("systemctl")
+                let res = Command::new(overrides.systemctl())
                     .arg("get-default")
                     .arg("--root")
                     .arg(&spec.layer)
@@ -194,33 +421,26 @@ impl Args {
                     (Path::new("/antlir2/test_stdout"), test_stdout.path()),
                     (Path::new("/antlir2/test_stderr"), test_stderr.path()),
                 ]));
+                let started = writable_tempfile("test start marker")?;
+                ctx.outputs((Path::new(STARTED), started.path()));
                 ctx.inputs((
                     Path::new("/run/systemd/system/antlir2_image_test.service.d/runtime.conf"),
                     test_unit_dropin.path(),
                 ));
 
-                let mut exec_env = setenv.clone();
-                // forward test runner env vars to the inner test
-                for (key, val) in std::env::vars() {
-                    if key.starts_with("TEST_PILOT") {
-                        exec_env.insert(key, val);
-                    }
-                }
-
                 let exec_spec = exec::Spec::builder()
-                    .cmd(self.test.into_inner_cmd())
+                    .cmd(self.test.clone().into_inner_cmd())
                     .user(spec.user)
-                    .working_directory(std::env::current_dir().context("while getting cwd")?)
-                    .env(exec_env)
+                    .working_directory(working_directory.clone())
+                    .env(setenv)
+                    .collect(collect)
+                    .collect_on_failure(self.collect_on_failure)
+                    .env_clear(self.env_clear)
+                    .maybe_seccomp(seccomp)
+                    .started(STARTED.into())
                     .build();
-                let exec_spec_file = tempfile::NamedTempFile::new()
-                    .context("while creating temp file for exec spec")?;
-                serde_json::to_writer_pretty(&exec_spec_file, &exec_spec)
-                    .context("while serializing exec spec to file")?;
-                ctx.inputs((
-                    Path::new("/__antlir2_image_test__/exec_spec.json"),
-                    exec_spec_file.path(),
-                ));
+                let exec_spec_file = write_exec_spec(&exec_spec)?;
+                ctx.inputs((Path::new(EXEC_SPEC), exec_spec_file.path()));
 
                 // Register the test container with systemd-machined so manual debugging
                 // is a easier.
@@ -231,56 +451,349 @@ impl Args {
                 isol.arg("systemd.journald.forward_to_console=1")
                     .arg("systemd.log_time=1")
                     .arg("systemd.setenv=ANTLIR2_IMAGE_TEST=1");
-                debug!("executing test in booted isolated container: {isol:?}");
-                let mut child = isol
-                    // the stdout/err of the systemd inside the container is a pipe
-                    // so that we can print it IFF the test fails
-                    .stdout(container_stdout.try_clone()?)
-                    .stderr(container_stdout.try_clone()?)
-                    .spawn()
-                    .context("while spawning systemd-nspawn")?;
-                let res = child.wait().context("while waiting for systemd-nspawn")?;
-
-                std::io::copy(&mut test_stdout, &mut std::io::stdout())?;
-                std::io::copy(&mut test_stderr, &mut std::io::stderr())?;
-
-                if !res.success() {
-                    std::process::exit(res.code().unwrap_or(255))
-                } else {
-                    Ok(())
+                Ok(Prepared {
+                    command: isol,
+                    boot: Some(PreparedBoot {
+                        test_stdout,
+                        test_stderr,
+                    }),
+                    keep_alive: vec![test_unit_dropin, exec_spec_file],
+                    started: Some(started),
+                })
+            }
+            None if self.mount_ns == warm_ns::Mode::Reuse => {
+                ensure!(
+                    !spec.rootless,
+                    "--mount-ns=reuse can't be used with rootless tests, since the namespaces have to outlive them"
+                );
+                ensure!(
+                    run.is_none(),
+                    "--mount-ns=reuse can't be used with --runs, since every run gets a fresh container"
+                );
+                ensure!(
+                    collect.is_empty() && seccomp.is_none() && heartbeat.is_none(),
+                    "--mount-ns=reuse can't be used with --collect, --seccomp-profile or --liveness-interval"
+                );
+                ensure!(
+                    !self.dry_run,
+                    "--mount-ns=reuse can't be used with --dry-run, since the container has to be set up to reuse it"
+                );
+                ctx.inputs(Path::new("/sys"));
+                ctx.inputs((PathBuf::from(IMAGE_TEST_BIN), overrides.image_test()?));
+                let mut ctx = ctx.build();
+                // these are different for every run, so they are mounted when
+                // the test joins instead
+                let outputs: Vec<_> = self.test.output_dirs().into_iter().collect();
+                for path in &outputs {
+                    ctx.outputs.remove(path.as_path());
                 }
+                let hostname = match &ctx.hostname {
+                    Some(hostname) => hostname.to_string(),
+                    // what systemd-nspawn defaults to
+                    None => ctx
+                        .layer
+                        .file_name()
+                        .context("layer has no name")?
+                        .to_string_lossy()
+                        .into_owned(),
+                };
+                let cache = warm_ns::NsCache::new(warm_ns::NS_CACHE_DIR);
+                let key = warm_ns::NsCache::key(&ctx.layer)?;
+                let ns = match cache.get(&key)? {
+                    Some(ns) => ns,
+                    None => warm_ns::warm_up(&cache, &key, nspawn(ctx)?.command(IMAGE_TEST_BIN)?)?,
+                };
+                debug!("running test in namespaces {key} ({})", ns.id()?);
+                let mut cmd = self.test.clone().into_inner_cmd().into_iter();
+                let mut isol = Command::new(cmd.next().expect("must have program arg"));
+                isol.args(cmd);
+                // same as what the container would have set up for the test
+                isol.env_clear()
+                    .env(
+                        "PATH",
+                        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+                    )
+                    .env("container", "antlir2")
+                    .env("USER", &spec.user)
+                    .envs(setenv)
+                    .env("ANTLIR2_IMAGE_TEST", "1");
+                ns.enter(
+                    &mut isol,
+                    &working_directory,
+                    warm_ns::lookup_user(&spec.layer, &spec.user)?,
+                    &hostname,
+                    &outputs,
+                )?;
+                Ok(Prepared {
+                    command: isol,
+                    boot: None,
+                    keep_alive: Vec::new(),
+                    started: None,
+                })
+            }
+            None if !collect.is_empty() || seccomp.is_some() || self.env_clear => {
+                // some systems-y tests want to read /sys
+                ctx.inputs(Path::new("/sys"));
+                // Collecting artifacts needs something that sticks around in
+                // the container after the test exits, and the seccomp filter
+                // (or clearing the environment) has to happen right before
+                // the test is exec'd, so run it through `image-test exec`
+                // just like booted tests do. That also marks when the test is
+                // started, so that a container that fails before then can be
+                // told apart from a failing test. The test binary drops
+                // privileges itself, so the container stays as root.
+                let started = writable_tempfile("test start marker")?;
+                ctx.outputs((Path::new(STARTED), started.path()));
+                let exec_spec = exec::Spec::builder()
+                    .cmd(self.test.clone().into_inner_cmd())
+                    .user(spec.user)
+                    .working_directory(working_directory.clone())
+                    .env(setenv)
+                    .collect(collect)
+                    .collect_on_failure(self.collect_on_failure)
+                    .env_clear(self.env_clear)
+                    .maybe_seccomp(seccomp)
+                    .started(STARTED.into())
+                    .build();
+                let exec_spec_file = write_exec_spec(&exec_spec)?;
+                ctx.inputs((Path::new(EXEC_SPEC), exec_spec_file.path()));
+                ctx.inputs((PathBuf::from(IMAGE_TEST_BIN), overrides.image_test()?));
+                let mut isol = match spec.rootless {
+                    false => nspawn(ctx.build())?.command(IMAGE_TEST_BIN)?,
+                    true => unshare(ctx.build())?.command(IMAGE_TEST_BIN)?,
+                };
+                isol.arg("exec");
+                Ok(Prepared {
+                    command: isol,
+                    boot: None,
+                    keep_alive: vec![exec_spec_file],
+                    started: Some(started),
+                })
             }
             None => {
                 // some systems-y tests want to read /sys
                 ctx.inputs(Path::new("/sys"));
                 ctx.user(spec.user);
-                let mut cmd = self.test.into_inner_cmd().into_iter();
+                let mut cmd = self.test.clone().into_inner_cmd().into_iter();
                 let program = cmd.next().expect("must have program arg");
                 let mut isol = match spec.rootless {
                     false => nspawn(ctx.build())?.command(program)?,
                     true => unshare(ctx.build())?.command(program)?,
                 };
                 isol.args(cmd);
-                debug!("executing test in isolated container: {isol:?}");
-                Err(anyhow::anyhow!("failed to exec test: {:?}", isol.exec()))
+                Ok(Prepared {
+                    command: isol,
+                    boot: None,
+                    keep_alive: Vec::new(),
+                    // the image may not be able to run `image-test exec`, so
+                    // nothing marks when the test is started
+                    started: None,
+                })
             }
         }
     }
 }
 
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const ZONEINFO: &str = "/usr/share/zoneinfo";
+/// Directories that get a private tmpfs with --isolate-tmp
+const ISOLATED_TMP_DIRS: [&str; 2] = ["/tmp", "/var/tmp"];
+const IMAGE_TEST_BIN: &str = "/__antlir2_image_test__/image-test";
+const EXEC_SPEC: &str = "/__antlir2_image_test__/exec_spec.json";
+const STARTED: &str = "/__antlir2_image_test__/started";
+const COLLECT_ROOT: &str = "/__antlir2_image_test__/collect";
+
+/// Run the prepared container to completion
+fn wait_for_test(
+    prepared: Prepared,
+    watchdog: &Watchdog,
+    run: Option<u32>,
+) -> Result<ContainerExit> {
+    let mut isol = prepared.command;
+    let exit = match prepared.boot {
+        Some(PreparedBoot {
+            mut test_stdout,
+            mut test_stderr,
+        }) => {
+            let container_stdout = container_stdout_file(run)?;
+            log_command("executing test in booted isolated container", &isol);
+            let child = isol
+                // the stdout/err of the systemd inside the container is a pipe
+                // so that we can print it IFF the test fails
+                .stdout(container_stdout.try_clone()?)
+                .stderr(container_stdout.try_clone()?)
+                .spawn()
+                .context("while spawning systemd-nspawn")?;
+            let res = watchdog.wait(child)?;
+
+            std::io::copy(&mut test_stdout, &mut std::io::stdout())?;
+            std::io::copy(&mut test_stderr, &mut std::io::stderr())?;
+
+            res
+        }
+        None => {
+            log_command("executing test in isolated container", &isol);
+            let child = isol.spawn().context("while spawning container")?;
+            watchdog.wait(child)?
+        }
+    };
+    // systemd-nspawn and `image-test exec` have no exit codes of their own
+    // that can't be confused with the test's, so a failure is only
+    // attributed to the test if it was actually started
+    if let (ContainerExit::Exited(status), Some(started)) = (&exit, &prepared.started) {
+        let started = std::fs::metadata(started.path())
+            .with_context(|| format!("while checking {}", started.path().display()))?
+            .len()
+            > 0;
+        if !status.success() && !started {
+            return Ok(ContainerExit::NotStarted(*status));
+        }
+    }
+    Ok(exit)
+}
+
+fn write_exec_spec(spec: &exec::Spec) -> Result<NamedTempFile> {
+    let file = NamedTempFile::new().context("while creating temp file for exec spec")?;
+    // `image-test exec` may already be running as the test user
+    file.as_file()
+        .set_permissions(Permissions::from_mode(0o644))
+        .context("while making exec spec readable")?;
+    serde_json::to_writer_pretty(&file, spec).context("while serializing exec spec to file")?;
+    Ok(file)
+}
+
+/// Bind for the host's resolv.conf into the container. Both isolation runtimes
+/// will create the mountpoint if the image does not have one.
+fn resolv_conf_bind(host: &Path) -> Result<(&'static Path, &Path)> {
+    ensure!(
+        host.exists(),
+        "--inherit-resolv-conf was given, but {} does not exist on the host",
+        host.display()
+    );
+    Ok((Path::new(RESOLV_CONF), host))
+}
+
+/// Env vars that pin the timezone and/or locale of the test. The timezone is
+/// checked against the image, since glibc silently falls back to UTC for
+/// zones it can't find.
+fn tz_locale_env(
+    layer: &Path,
+    tz: Option<&str>,
+    locale: Option<&str>,
+) -> Result<Vec<(String, String)>> {
+    let mut env = Vec::new();
+    if let Some(tz) = tz {
+        let zone = Path::new(tz);
+        ensure!(
+            !tz.is_empty()
+                && zone
+                    .components()
+                    .all(|c| matches!(c, std::path::Component::Normal(_))),
+            "--tz '{tz}' is not a timezone name"
+        );
+        let zoneinfo = Path::new(ZONEINFO).join(zone);
+        ensure!(
+            layer
+                .join(zoneinfo.strip_prefix("/").expect("zoneinfo is absolute"))
+                .is_file(),
+            "--tz '{tz}' was given, but {} does not exist in the image",
+            zoneinfo.display()
+        );
+        env.push(("TZ".into(), tz.into()));
+    }
+    if let Some(locale) = locale {
+        env.push(("LANG".into(), locale.into()));
+        env.push(("LC_ALL".into(), locale.into()));
+    }
+    Ok(env)
+}
+
+/// Quote a single shell word if it contains anything other than known-safe
+/// characters
+fn shell_quote(word: &OsStr) -> String {
+    let word = word.to_string_lossy();
+    if !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c))
+    {
+        word.into_owned()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Env var names containing any of these are considered sensitive and have
+/// their values hidden from logs
+const SENSITIVE_ENV_MARKERS: &[&str] = &["TOKEN", "SECRET", "PASSWORD", "CREDENTIAL", "AUTH"];
+
+fn is_sensitive_env(key: &str) -> bool {
+    let key = key.to_ascii_uppercase();
+    SENSITIVE_ENV_MARKERS.iter().any(|m| key.contains(m))
+}
+
+/// Replace the value of a `KEY=VALUE` pair (optionally prefixed with
+/// `--setenv=` as passed to the container runtime) if the key looks sensitive
+fn redact_env_arg(arg: &OsStr) -> OsString {
+    let s = arg.to_string_lossy();
+    let (prefix, kv) = match s.strip_prefix("--setenv=") {
+        Some(kv) => ("--setenv=", kv),
+        None => ("", s.as_ref()),
+    };
+    match kv.split_once('=') {
+        Some((key, _)) if is_sensitive_env(key) => format!("{prefix}{key}=<redacted>").into(),
+        _ => arg.to_owned(),
+    }
+}
+
+/// Render a `Command` (including any env it sets) as something that can be
+/// copy-pasted into a shell. If `redact` is set, values of sensitive-looking
+/// env vars are hidden.
+fn shell_command(command: &Command, redact: bool) -> String {
+    let env = command.get_envs().filter_map(|(k, v)| {
+        v.map(|v| {
+            let mut kv = k.to_owned();
+            kv.push("=");
+            kv.push(v);
+            match redact {
+                true => shell_quote(&redact_env_arg(&kv)),
+                false => shell_quote(&kv),
+            }
+        })
+    });
+    let argv = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| match redact {
+            true => shell_quote(&redact_env_arg(arg)),
+            false => shell_quote(arg),
+        });
+    env.chain(argv).collect::<Vec<_>>().join(" ")
+}
+
+/// Log the fully assembled container command (with sensitive env values
+/// redacted) so that failures can be reproduced by hand.
+fn log_command(msg: &str, command: &Command) {
+    debug!("{msg}: {}", shell_command(command, true));
+}
+
 /// Create a file to record container stdout into. When invoked under tpx, this
 /// will be uploaded as an artifact. The artifact metadata is set up before
-/// running the test so that it still gets uploaded even in case of a timeout
-fn container_stdout_file() -> Result<File> {
+/// running the test so that it still gets uploaded even in case of a timeout.
+/// Each of multiple runs gets its own log.
+fn container_stdout_file(run: Option<u32>) -> Result<File> {
     // if tpx has provided this artifacts dir, put the logs there so they get
     // uploaded along with the test results
     if let Some(artifacts_dir) = std::env::var_os("TEST_RESULT_ARTIFACTS_DIR") {
         std::fs::create_dir_all(&artifacts_dir)?;
-        let dst = Path::new(&artifacts_dir).join("container-stdout.txt");
+        let name = match run {
+            Some(run) => format!("container-stdout.run{run}.txt"),
+            None => "container-stdout.txt".to_owned(),
+        };
+        let dst = Path::new(&artifacts_dir).join(&name);
         if let Some(annotations_dir) = std::env::var_os("TEST_RESULT_ARTIFACT_ANNOTATIONS_DIR") {
             std::fs::create_dir_all(&annotations_dir)?;
             std::fs::write(
-                Path::new(&annotations_dir).join("container-stdout.txt.annotation"),
+                Path::new(&annotations_dir).join(format!("{name}.annotation")),
                 r#"{"type": {"generic_text_log": {}}, "description": "systemd logs"}"#,
             )?;
         }
@@ -290,3 +803,142 @@ fn container_stdout_file() -> Result<File> {
         Ok(unsafe { File::from_raw_fd(std::io::stderr().as_raw_fd()) })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shell_command() {
+        let mut cmd = Command::new("systemd-nspawn");
+        cmd.arg("--bind-ro=/repo:/repo")
+            .arg("--setenv=GREETING=hello world")
+            .arg("--")
+            .arg("/repo/buck-out/test")
+            .arg("it's")
+            .env("RUST_LOG", "debug");
+        assert_eq!(
+            shell_command(&cmd, false),
+            r#"RUST_LOG=debug systemd-nspawn --bind-ro=/repo:/repo '--setenv=GREETING=hello world' -- /repo/buck-out/test 'it'\''s'"#,
+        );
+    }
+
+    #[test]
+    fn test_resolv_conf_bind() {
+        let host = NamedTempFile::new().expect("failed to create tempfile");
+        assert_eq!(
+            resolv_conf_bind(host.path()).expect("bind should be valid"),
+            (Path::new("/etc/resolv.conf"), host.path())
+        );
+        let missing = host.path().with_extension("missing");
+        let err = resolv_conf_bind(&missing).expect_err("missing host file must fail");
+        assert!(
+            err.to_string().contains("does not exist on the host"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_tz_locale_env() {
+        let layer = tempfile::tempdir().expect("failed to create tempdir");
+        let zoneinfo = layer.path().join("usr/share/zoneinfo/America");
+        std::fs::create_dir_all(&zoneinfo).expect("failed to create zoneinfo");
+        std::fs::write(zoneinfo.join("New_York"), "TZif").expect("failed to write zone");
+
+        assert!(tz_locale_env(layer.path(), None, None)
+            .expect("nothing to check")
+            .is_empty());
+        assert_eq!(
+            tz_locale_env(layer.path(), Some("America/New_York"), Some("en_US.UTF-8"))
+                .expect("zone exists in the image"),
+            vec![
+                ("TZ".to_owned(), "America/New_York".to_owned()),
+                ("LANG".to_owned(), "en_US.UTF-8".to_owned()),
+                ("LC_ALL".to_owned(), "en_US.UTF-8".to_owned()),
+            ]
+        );
+
+        let err = tz_locale_env(layer.path(), Some("Europe/Paris"), None)
+            .expect_err("zone is missing from the image");
+        assert!(
+            err.to_string()
+                .contains("/usr/share/zoneinfo/Europe/Paris does not exist in the image"),
+            "{err}"
+        );
+        // directories and escapes out of zoneinfo are not zones
+        assert!(tz_locale_env(layer.path(), Some("America"), None).is_err());
+        assert!(tz_locale_env(layer.path(), Some("../../../etc/passwd"), None).is_err());
+        assert!(tz_locale_env(layer.path(), Some("/etc/localtime"), None).is_err());
+    }
+
+    /// Collects everything written by the tracing subscriber
+    #[derive(Clone, Default)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().expect("poisoned lock").write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_log_command() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        let mut cmd = Command::new("systemd-nspawn");
+        cmd.arg("--bind-ro=/repo:/repo")
+            .arg("--setenv=TEST_PILOT=1")
+            .arg("--setenv=GITHUB_TOKEN=hunter2")
+            .arg("/repo/buck-out/test")
+            .env("MY_PASSWORD", "hunter2");
+        tracing::subscriber::with_default(subscriber, || {
+            log_command("executing test in isolated container", &cmd)
+        });
+
+        let logs = String::from_utf8(logs.0.lock().expect("poisoned lock").clone())
+            .expect("logs are not utf8");
+        assert!(
+            logs.contains(
+                "executing test in isolated container: 'MY_PASSWORD=<redacted>' systemd-nspawn --bind-ro=/repo:/repo --setenv=TEST_PILOT=1 '--setenv=GITHUB_TOKEN=<redacted>' /repo/buck-out/test"
+            ),
+            "{logs}"
+        );
+        assert!(!logs.contains("hunter2"), "{logs}");
+    }
+
+    #[test]
+    fn test_wait_for_test_not_started() {
+        // stands in for a container that writes to the marker when the test
+        // is started, and then exits with 1
+        let code = |script: &str| {
+            let started = writable_tempfile("test start marker").expect("failed to create");
+            let mut command = Command::new("sh");
+            command.args(["-c", script]).arg(started.path());
+            let prepared = Prepared {
+                command,
+                boot: None,
+                keep_alive: Vec::new(),
+                started: Some(started),
+            };
+            let exit = wait_for_test(prepared, &Watchdog::default(), None).expect("failed to wait");
+            exit.code()
+        };
+        // systemd-nspawn (or `image-test exec`) failing on its own
+        assert_eq!(code("exit 1"), crate::EXIT_INFRA);
+        // the test failing
+        assert_eq!(code(r#"echo started > "$0"; exit 1"#), 1);
+        assert_eq!(code(r#"echo started > "$0""#), 0);
+        // a container that had nothing to do is not a failure
+        assert_eq!(code("true"), 0);
+    }
+}
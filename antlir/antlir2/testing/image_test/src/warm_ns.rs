@@ -0,0 +1,810 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Experimental reuse of a test container's mount namespace across image_test
+//! invocations, to skip setting up the container every time a test is run in
+//! a tight loop.
+//!
+//! The mount namespace of a container that was set up once is kept alive by
+//! bind mounting it into a cache directory, keyed by the identity of the image
+//! it was set up from. Later runs of the same image join it instead of
+//! starting a new container. The cache only holds on to the most recently
+//! used entries, so the ones for old versions of a rebuilt layer go away.
+//!
+//! So that nothing one test does is seen by the next, every test gets a
+//! private copy of the mount namespace, in which the image is read-only and
+//! the usual scratch directories are fresh tmpfs mounts. Everything else is
+//! new for every test: its network and UTS namespaces (with only loopback, as
+//! in a container with a private network, and the hostname of the test), and
+//! its pid namespace (and /proc), in which it is pid 1. The output dirs of the
+//! test are bind mounted into its copy when it joins, since they are different
+//! for every run. Tests that need to write to the image can't be run like
+//! this, which is one reason why it is opt-in.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::SystemTime;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+use clap::Parser;
+use clap::ValueEnum;
+use nix::errno::Errno;
+use nix::libc;
+use nix::mount::mount;
+use nix::mount::umount2;
+use nix::mount::MntFlags;
+use nix::mount::MsFlags;
+use nix::sched::setns;
+use nix::sched::unshare;
+use nix::sched::CloneFlags;
+use nix::sys::prctl::set_pdeathsig;
+use nix::sys::signal::Signal;
+use nix::sys::statfs::fstatfs;
+use nix::sys::statfs::NSFS_MAGIC;
+use nix::sys::wait::waitpid;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::fork;
+use nix::unistd::setgid;
+use nix::unistd::setgroups;
+use nix::unistd::setuid;
+use nix::unistd::ForkResult;
+use nix::unistd::Gid;
+use nix::unistd::Pid;
+use nix::unistd::Uid;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::debug;
+use tracing::warn;
+
+/// Where the namespaces are kept. This is a tmpfs, so nothing outlives a
+/// reboot of the host.
+pub(crate) const NS_CACHE_DIR: &str = "/run/antlir2/image_test/ns";
+
+/// Name of the mount namespace under `/proc/<pid>/ns`, and in a cache entry
+const MNT: &str = "mnt";
+
+/// How many entries the cache holds on to. Each of them keeps an image
+/// mounted, so this is only enough for iterating on a handful of tests.
+const MAX_ENTRIES: usize = 8;
+
+/// Directories that every test gets a fresh tmpfs on, since the rest of the
+/// image is read-only
+const SCRATCH_DIRS: [&str; 4] = ["/tmp", "/var/tmp", "/run", "/dev/shm"];
+
+// from linux/mount.h, which not every libc has caught up with
+const OPEN_TREE_CLONE: libc::c_uint = 1;
+const AT_RECURSIVE: libc::c_uint = 0x8000;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Mode {
+    /// Set up a new container for every run
+    #[default]
+    Fresh,
+    /// Join the mount namespace of an earlier container of the same image,
+    /// setting one up (and keeping it) if there is none yet
+    Reuse,
+}
+
+/// Namespaces kept alive in a cache directory
+#[derive(Debug)]
+pub(crate) struct NsCache {
+    root: PathBuf,
+}
+
+impl NsCache {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Cache key of a container of the image `layer`. This is only the
+    /// identity of the layer (so that a layer rebuilt at the same path
+    /// misses), since everything that differs between runs of the same image
+    /// is set up when a test joins.
+    pub(crate) fn key(layer: &Path) -> Result<String> {
+        let meta = std::fs::metadata(layer)
+            .with_context(|| format!("while statting {}", layer.display()))?;
+        let mut identity = Sha256::new();
+        identity.update(meta.dev().to_le_bytes());
+        identity.update(meta.ino().to_le_bytes());
+        identity.update(meta.mtime().to_le_bytes());
+        identity.update(meta.mtime_nsec().to_le_bytes());
+        Ok(hex::encode(&identity.finalize()[..16]))
+    }
+
+    /// Namespaces cached under `key`, if any. An entry that can't be used
+    /// (like one left half-created) is thrown away, so that it gets set up
+    /// from scratch.
+    pub(crate) fn get(&self, key: &str) -> Result<Option<WarmNs>> {
+        let dir = self.root.join(key);
+        if !dir.exists() {
+            return Ok(None);
+        }
+        match WarmNs::open(&dir) {
+            Ok(ns) => {
+                // the modification time of an entry is when it was last used
+                File::open(&dir)
+                    .and_then(|f| f.set_modified(SystemTime::now()))
+                    .with_context(|| format!("while touching {}", dir.display()))?;
+                Ok(Some(ns))
+            }
+            Err(e) => {
+                warn!("discarding broken namespace cache entry {key}: {e:#}");
+                self.remove(key)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Keep the mount namespace of the process `pid` under `key`. If another
+    /// invocation got there first, its namespace is used instead.
+    pub(crate) fn persist(&self, key: &str, pid: u32) -> Result<WarmNs> {
+        let staging = format!("{key}.{}", std::process::id());
+        let staging_dir = self.root.join(&staging);
+        std::fs::create_dir_all(&staging_dir)
+            .with_context(|| format!("while creating {}", staging_dir.display()))?;
+        let src = Path::new("/proc")
+            .join(pid.to_string())
+            .join("ns")
+            .join(MNT);
+        let dst = staging_dir.join(MNT);
+        File::create(&dst).with_context(|| format!("while creating {}", dst.display()))?;
+        mount(
+            Some(&src),
+            &dst,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .with_context(|| format!("while bind mounting {}", src.display()))?;
+        let dir = self.root.join(key);
+        if let Err(e) = std::fs::rename(&staging_dir, &dir) {
+            self.remove(&staging)?;
+            if !dir.exists() {
+                return Err(e).with_context(|| format!("while renaming to {}", dir.display()));
+            }
+        }
+        self.evict(key)?;
+        WarmNs::open(&dir)
+    }
+
+    /// Let go of the least recently used entries, so that no more than
+    /// [MAX_ENTRIES] are left. The entry `keep` is never evicted.
+    fn evict(&self, keep: &str) -> Result<()> {
+        let entries = match std::fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("while reading {}", self.root.display()));
+            }
+        };
+        let mut used = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            // staging dirs belong to invocations that are still running
+            if name == keep || name.contains('.') {
+                continue;
+            }
+            // other invocations may be evicting at the same time
+            if let Ok(last_used) = entry.metadata().and_then(|m| m.modified()) {
+                used.push((last_used, name.to_owned()));
+            }
+        }
+        used.sort_by(|a, b| b.cmp(a));
+        for (_, name) in used.into_iter().skip(MAX_ENTRIES - 1) {
+            debug!("evicting namespaces {name}");
+            self.remove(&name)?;
+        }
+        Ok(())
+    }
+
+    /// Let go of the namespaces under `key`
+    pub(crate) fn remove(&self, key: &str) -> Result<()> {
+        let dir = self.root.join(key);
+        // it may not have been mounted
+        let _ = umount2(&dir.join(MNT), MntFlags::MNT_DETACH);
+        match std::fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("while removing {}", dir.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Set up the container that `holder` (image-test in the container) runs in,
+/// and keep its mount namespace under `key` once it is ready
+pub(crate) fn warm_up(cache: &NsCache, key: &str, mut holder: Command) -> Result<WarmNs> {
+    debug!("setting up namespaces {key}");
+    let mut child = holder
+        .arg("hold-ns")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("while spawning container")?;
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().expect("stdout is piped"))
+        .read_line(&mut line)
+        .context("while waiting for container")?;
+    let res = match line.trim().parse() {
+        Ok(id) => find_pid(id).and_then(|pid| cache.persist(key, pid)),
+        Err(_) => Err(anyhow::anyhow!("container did not start")),
+    };
+    // the namespace is kept alive by the cache now, so the container can go
+    drop(child.stdin.take());
+    let status = child.wait().context("while waiting for container")?;
+    res.with_context(|| format!("while setting up namespaces (container exited with {status})"))
+}
+
+/// Find any process in the mount namespace `id`
+fn find_pid(id: u64) -> Result<u32> {
+    for entry in std::fs::read_dir("/proc").context("while reading /proc")? {
+        let Ok(pid) = entry?.file_name().to_string_lossy().parse::<u32>() else {
+            continue;
+        };
+        // processes come and go while looking
+        if let Ok(meta) = std::fs::metadata(format!("/proc/{pid}/ns/mnt")) {
+            if meta.ino() == id {
+                return Ok(pid);
+            }
+        }
+    }
+    bail!("no process is in mount namespace {id}")
+}
+
+/// Mount namespace that a test can join
+#[derive(Debug)]
+pub(crate) struct WarmNs {
+    mnt: File,
+}
+
+impl WarmNs {
+    fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join(MNT);
+        let mnt = File::open(&path).with_context(|| format!("while opening {}", path.display()))?;
+        let fs = fstatfs(&mnt).with_context(|| format!("while statting {}", path.display()))?;
+        ensure!(
+            fs.filesystem_type() == NSFS_MAGIC,
+            "{} is not a namespace",
+            path.display()
+        );
+        Ok(Self { mnt })
+    }
+
+    /// Identifies this namespace. This is the inode that
+    /// `readlink /proc/<pid>/ns/mnt` shows for processes in it.
+    pub(crate) fn id(&self) -> Result<u64> {
+        Ok(self
+            .mnt
+            .metadata()
+            .context("while statting mount namespace")?
+            .ino())
+    }
+
+    /// Make `command` join the mount namespace in new network, UTS and pid
+    /// namespaces of its own, with `outputs` (the same paths on the host)
+    /// mounted and `hostname` set, and then switch to `cwd` and `user` right
+    /// before it is exec'd. The command must not set its own uid, gid or
+    /// directory, since those would be applied before joining.
+    ///
+    /// `command` is pid 1 of the new pid namespace, so the process that is
+    /// spawned is a stub that waits for it and exits the same way it does.
+    pub(crate) fn enter(
+        &self,
+        command: &mut Command,
+        cwd: &Path,
+        user: (Uid, Gid),
+        hostname: &str,
+        outputs: &[PathBuf],
+    ) -> Result<()> {
+        let mnt = self
+            .mnt
+            .try_clone()
+            .context("while cloning mount namespace fd")?;
+        let outputs = outputs
+            .iter()
+            .map(|path| OutputMount::new(path))
+            .collect::<Result<Vec<_>>>()?;
+        let hostname = hostname.to_owned();
+        let cwd = cwd.to_owned();
+        let (uid, gid) = user;
+        // SAFETY: only async-signal-safe syscalls are made in the child
+        unsafe {
+            command.pre_exec(move || {
+                setns(&mnt, CloneFlags::CLONE_NEWNS)?;
+                // a private copy of the mount namespace, so that nothing
+                // mounted here is seen by the next test, and everything else
+                // new
+                unshare(
+                    CloneFlags::CLONE_NEWNS
+                        | CloneFlags::CLONE_NEWNET
+                        | CloneFlags::CLONE_NEWUTS
+                        | CloneFlags::CLONE_NEWPID,
+                )?;
+                mount(
+                    None::<&str>,
+                    "/",
+                    None::<&str>,
+                    MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                    None::<&str>,
+                )?;
+                mount(
+                    None::<&str>,
+                    "/",
+                    None::<&str>,
+                    MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+                    None::<&str>,
+                )?;
+                for dir in SCRATCH_DIRS {
+                    match mount(
+                        Some("tmpfs"),
+                        dir,
+                        Some("tmpfs"),
+                        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                        Some("mode=1777"),
+                    ) {
+                        // it can't be created in the read-only image
+                        Err(Errno::ENOENT) => {}
+                        res => res?,
+                    }
+                }
+                for output in &outputs {
+                    output.attach()?;
+                }
+                Errno::result(libc::sethostname(hostname.as_ptr().cast(), hostname.len()))?;
+                loopback_up()?;
+                // the new pid namespace is only for children
+                match fork()? {
+                    ForkResult::Parent { child } => exit_like(child),
+                    ForkResult::Child => {}
+                }
+                // don't outlive the stub if it is killed
+                set_pdeathsig(Signal::SIGKILL)?;
+                mount(
+                    Some("proc"),
+                    "/proc",
+                    Some("proc"),
+                    MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+                    None::<&str>,
+                )?;
+                nix::unistd::chdir(&cwd)?;
+                setgroups(&[gid])?;
+                setgid(gid)?;
+                setuid(uid)?;
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A copy of a mount on the host, to be mounted at the same path in the
+/// namespace of a test
+struct OutputMount {
+    tree: OwnedFd,
+    /// Directories leading up to `target`, outermost first
+    parents: Vec<CString>,
+    target: CString,
+    dir: bool,
+}
+
+impl OutputMount {
+    fn new(path: &Path) -> Result<Self> {
+        let c_path = |p: &Path| {
+            CString::new(p.as_os_str().as_bytes())
+                .with_context(|| format!("{} contains a nul byte", p.display()))
+        };
+        let target = c_path(path)?;
+        // SAFETY: the path is nul-terminated and outlives the call
+        let fd = Errno::result(unsafe {
+            libc::syscall(
+                libc::SYS_open_tree,
+                libc::AT_FDCWD,
+                target.as_ptr(),
+                OPEN_TREE_CLONE | AT_RECURSIVE | libc::O_CLOEXEC as libc::c_uint,
+            )
+        })
+        .with_context(|| format!("while cloning the mount of {}", path.display()))?;
+        let mut parents = path
+            .ancestors()
+            .skip(1)
+            .filter(|p| p.parent().is_some())
+            .map(c_path)
+            .collect::<Result<Vec<_>>>()?;
+        parents.reverse();
+        Ok(Self {
+            // SAFETY: open_tree returned a new fd that nothing else owns
+            tree: unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) },
+            parents,
+            target,
+            dir: path.is_dir(),
+        })
+    }
+
+    /// Mount this at its path in the current mount namespace, creating the
+    /// mountpoint if it is on a scratch tmpfs. Only makes async-signal-safe
+    /// syscalls.
+    fn attach(&self) -> nix::Result<()> {
+        // SAFETY: all the paths are nul-terminated and outlive the calls
+        unsafe {
+            // these fail if they already exist in the image, which is fine
+            for parent in &self.parents {
+                libc::mkdir(parent.as_ptr(), 0o755);
+            }
+            if self.dir {
+                libc::mkdir(self.target.as_ptr(), 0o755);
+            } else {
+                let fd = libc::open(
+                    self.target.as_ptr(),
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                    0o644,
+                );
+                if fd >= 0 {
+                    libc::close(fd);
+                }
+            }
+            Errno::result(libc::syscall(
+                libc::SYS_move_mount,
+                self.tree.as_raw_fd(),
+                c"".as_ptr(),
+                libc::AT_FDCWD,
+                self.target.as_ptr(),
+                MOVE_MOUNT_F_EMPTY_PATH,
+            ))
+            .map(drop)
+        }
+    }
+}
+
+/// Bring up loopback in the current network namespace, which is all that a
+/// container with a private network has. Only makes async-signal-safe
+/// syscalls.
+fn loopback_up() -> nix::Result<()> {
+    // SAFETY: ifreq is plain data, and the socket is closed on every path
+    unsafe {
+        let sock = Errno::result(libc::socket(
+            libc::AF_INET,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            0,
+        ))?;
+        let mut req: libc::ifreq = std::mem::zeroed();
+        for (dst, src) in req.ifr_name.iter_mut().zip(b"lo") {
+            *dst = *src as libc::c_char;
+        }
+        let res = Errno::result(libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut req)).and_then(|_| {
+            req.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+            Errno::result(libc::ioctl(sock, libc::SIOCSIFFLAGS, &req))
+        });
+        libc::close(sock);
+        res.map(drop)
+    }
+}
+
+/// Wait for `child` and then exit the same way it did. Only makes
+/// async-signal-safe syscalls.
+fn exit_like(child: Pid) -> ! {
+    // nothing else is needed from here on, and holding on to fds (like the one
+    // that tells the spawner that the test was exec'd) would keep them open
+    // until the test is done
+    // SAFETY: none of the fds are used by this process again
+    unsafe {
+        if libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) != 0 {
+            for fd in 3..1024 {
+                libc::close(fd);
+            }
+        }
+    }
+    loop {
+        match waitpid(child, None) {
+            // SAFETY: exiting without running anything of the parent process
+            Ok(WaitStatus::Exited(_, code)) => unsafe { libc::_exit(code) },
+            Ok(WaitStatus::Signaled(_, signal, _)) => unsafe {
+                libc::signal(signal as libc::c_int, libc::SIG_DFL);
+                libc::kill(libc::getpid(), signal as libc::c_int);
+                libc::_exit(128 + signal as libc::c_int)
+            },
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(_) => unsafe { libc::_exit(1) },
+        }
+    }
+}
+
+/// Look up `user` in the passwd file of the image `layer`, which is what
+/// processes in the container see
+pub(crate) fn lookup_user(layer: &Path, user: &str) -> Result<(Uid, Gid)> {
+    let path = layer.join("etc/passwd");
+    let passwd = std::fs::read_to_string(&path)
+        .with_context(|| format!("while reading {}", path.display()))?;
+    for line in passwd.lines() {
+        let fields: Vec<_> = line.split(':').collect();
+        if fields.len() >= 4 && fields[0] == user {
+            let uid = fields[2]
+                .parse()
+                .with_context(|| format!("invalid uid for '{user}'"))?;
+            let gid = fields[3]
+                .parse()
+                .with_context(|| format!("invalid gid for '{user}'"))?;
+            return Ok((Uid::from_raw(uid), Gid::from_raw(gid)));
+        }
+    }
+    bail!("user '{user}' does not exist in {}", path.display())
+}
+
+#[derive(Debug, Parser)]
+/// Keep a freshly set up container around until stdin is closed
+pub(crate) struct HoldArgs {}
+
+impl HoldArgs {
+    pub(crate) fn run(self) -> Result<()> {
+        let id = std::fs::metadata("/proc/self/ns/mnt")
+            .context("while statting mount namespace")?
+            .ino();
+        let mut stdout = std::io::stdout();
+        writeln!(stdout, "{id}")?;
+        stdout.flush()?;
+        std::io::stdin().read_to_end(&mut Vec::new())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Child;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// A process in a new mount namespace, standing in for a container
+    struct Container(Child);
+
+    impl Container {
+        fn spawn() -> Option<Self> {
+            let child = Command::new("unshare")
+                .arg("--mount")
+                .arg("sleep")
+                .arg("60")
+                .spawn()
+                .ok()?;
+            let container = Self(child);
+            // wait for unshare to exec sleep in the new namespace
+            for _ in 0..100 {
+                if container.mnt_ns() != mnt_ns("self") {
+                    return Some(container);
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            None
+        }
+
+        fn pid(&self) -> u32 {
+            self.0.id()
+        }
+
+        fn mnt_ns(&self) -> u64 {
+            mnt_ns(&self.pid().to_string())
+        }
+    }
+
+    impl Drop for Container {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn mnt_ns(pid: &str) -> u64 {
+        std::fs::metadata(format!("/proc/{pid}/ns/mnt"))
+            .expect("failed to stat mount namespace")
+            .ino()
+    }
+
+    /// Run `script` like a test that joins `ns`, with `outputs`
+    fn run_joined(ns: &WarmNs, script: &str, outputs: &[PathBuf]) -> String {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        ns.enter(
+            &mut command,
+            Path::new("/"),
+            (Uid::current(), Gid::current()),
+            "test-host",
+            outputs,
+        )
+        .expect("failed to enter");
+        let out = command.output().expect("failed to run sh");
+        assert!(out.status.success(), "{out:?}");
+        String::from_utf8(out.stdout)
+            .expect("utf8")
+            .trim()
+            .to_owned()
+    }
+
+    #[test]
+    fn test_reuse() {
+        // needs privileges to create namespaces and mount
+        let Some(container) = Container::spawn() else {
+            eprintln!("skipping: can't create namespaces");
+            return;
+        };
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        let cache = NsCache::new(dir.path());
+        assert!(cache.get("key").expect("failed to get").is_none());
+        let first = cache
+            .persist("key", container.pid())
+            .expect("failed to persist");
+        // the cache keeps the namespace alive by itself
+        let id = container.mnt_ns();
+        drop(container);
+
+        let second = cache
+            .get("key")
+            .expect("failed to get")
+            .expect("namespace was not cached");
+        assert_eq!(first.id().expect("no id"), id);
+        assert_eq!(second.id().expect("no id"), id);
+
+        // tests don't see what earlier tests left behind, or each other
+        assert_eq!(
+            run_joined(&first, "echo $$ && touch /tmp/leak && ! touch /leak", &[]),
+            "1"
+        );
+        run_joined(&second, "test ! -e /tmp/leak", &[]);
+
+        // containers that are set up separately don't share anything
+        let other = Container::spawn().expect("failed to create namespaces");
+        let other_ns = cache
+            .persist("other", other.pid())
+            .expect("failed to persist");
+        assert_ne!(other_ns.id().expect("no id"), id);
+
+        // whoever persists second gets what is already there
+        let late = Container::spawn().expect("failed to create namespaces");
+        let raced = cache.persist("key", late.pid()).expect("failed to persist");
+        assert_eq!(raced.id().expect("no id"), id);
+
+        drop((first, second, other_ns, raced));
+        cache.remove("key").expect("failed to remove");
+        cache.remove("other").expect("failed to remove");
+        assert!(cache.get("key").expect("failed to get").is_none());
+    }
+
+    #[test]
+    fn test_isolated_runs() {
+        // needs privileges to create namespaces and mount
+        let Some(container) = Container::spawn() else {
+            eprintln!("skipping: can't create namespaces");
+            return;
+        };
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        let cache = NsCache::new(dir.path());
+        let ns = cache
+            .persist("key", container.pid())
+            .expect("failed to persist");
+
+        // nothing about the network or hostname is shared with the host, or
+        // left behind for the next run
+        let host_net = std::fs::metadata("/proc/self/ns/net")
+            .expect("failed to stat network namespace")
+            .ino()
+            .to_string();
+        let script = "cat /proc/sys/kernel/hostname && stat -L -c %i /proc/self/ns/net && \
+            echo leaked > /proc/sys/kernel/hostname";
+        let first = run_joined(&ns, script, &[]);
+        let second = run_joined(&ns, script, &[]);
+        let (first_host, first_net) = first.split_once('\n').expect("two lines");
+        let (second_host, second_net) = second.split_once('\n').expect("two lines");
+        assert_eq!(first_host, "test-host");
+        assert_eq!(second_host, "test-host");
+        assert_ne!(first_net, second_net);
+        assert_ne!(first_net, host_net);
+        assert_ne!(second_net, host_net);
+
+        // output dirs of each run are mounted, even where they are hidden by
+        // a scratch tmpfs
+        let out = tempfile::TempDir::new_in("/tmp").expect("failed to create tempdir");
+        run_joined(
+            &ns,
+            &format!("echo hello > {}/file", out.path().display()),
+            &[out.path().to_owned()],
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.path().join("file")).expect("failed to read"),
+            "hello\n"
+        );
+
+        drop(ns);
+        cache.remove("key").expect("failed to remove");
+    }
+
+    #[test]
+    fn test_broken_entry() {
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        std::fs::create_dir(dir.path().join("key")).expect("failed to mkdir");
+        std::fs::write(dir.path().join("key").join(MNT), "").expect("failed to write");
+        let cache = NsCache::new(dir.path());
+        assert!(cache.get("key").expect("failed to get").is_none());
+        assert!(!dir.path().join("key").exists(), "entry was not discarded");
+    }
+
+    #[test]
+    fn test_key() {
+        let layer = tempfile::TempDir::new().expect("failed to create tempdir");
+        let key = |layer: &Path| NsCache::key(layer).expect("failed to compute key");
+        let base = key(layer.path());
+        assert_eq!(base, key(layer.path()));
+        let other = tempfile::TempDir::new().expect("failed to create tempdir");
+        assert_ne!(base, key(other.path()));
+        // a rebuilt layer misses
+        std::fs::write(layer.path().join("new"), "").expect("failed to write");
+        assert_ne!(base, key(layer.path()));
+    }
+
+    #[test]
+    fn test_evict() {
+        let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+        let now = SystemTime::now();
+        let entries: Vec<_> = (0..MAX_ENTRIES + 2).map(|i| format!("key{i}")).collect();
+        // key0 was used last, and the staging dir first
+        for (age, entry) in entries.iter().chain([&"key0.1234".to_owned()]).enumerate() {
+            let path = dir.path().join(entry);
+            std::fs::create_dir(&path).expect("failed to mkdir");
+            File::open(&path)
+                .and_then(|f| f.set_modified(now - Duration::from_secs(age as u64)))
+                .expect("failed to touch");
+        }
+        let cache = NsCache::new(dir.path());
+        // the one that was just persisted stays, even though it is the oldest
+        cache.evict("key9").expect("failed to evict");
+        let exists = |entry: &str| dir.path().join(entry).exists();
+        for entry in &entries[..MAX_ENTRIES - 1] {
+            assert!(exists(entry), "{entry} was evicted");
+        }
+        for entry in &entries[MAX_ENTRIES - 1..MAX_ENTRIES + 1] {
+            assert!(!exists(entry), "{entry} was not evicted");
+        }
+        assert!(exists("key9"));
+        // someone else is still setting that up
+        assert!(exists("key0.1234"));
+    }
+
+    #[test]
+    fn test_lookup_user() {
+        let layer = tempfile::TempDir::new().expect("failed to create tempdir");
+        std::fs::create_dir(layer.path().join("etc")).expect("failed to mkdir");
+        std::fs::write(
+            layer.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/bash\nnobody:x:65534:65534::/:/sbin/nologin\n",
+        )
+        .expect("failed to write");
+        assert_eq!(
+            lookup_user(layer.path(), "nobody").expect("failed to look up"),
+            (Uid::from_raw(65534), Gid::from_raw(65534))
+        );
+        assert!(lookup_user(layer.path(), "missing").is_err());
+    }
+}
@@ -0,0 +1,342 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Supervision of a running test container: overall timeouts and (opt-in)
+//! liveness checking.
+//!
+//! When liveness checking is enabled, the test is given the path to a
+//! heartbeat file in the `ANTLIR2_IMAGE_TEST_HEARTBEAT` env var and must
+//! update its mtime (eg `touch "$ANTLIR2_IMAGE_TEST_HEARTBEAT"`) at least once
+//! every liveness interval. The first heartbeat is due one interval after the
+//! container is started. If the heartbeat goes stale, the stacks of every
+//! process in the container are dumped before it is killed.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use anyhow::Result;
+use nix::sys::signal::kill;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use tracing::warn;
+
+/// Env var that tells the test where its heartbeat file is
+pub(crate) const HEARTBEAT_ENV: &str = "ANTLIR2_IMAGE_TEST_HEARTBEAT";
+/// Location of the heartbeat file inside the container
+pub(crate) const HEARTBEAT_PATH: &str = "/__antlir2_image_test__/heartbeat";
+
+/// How long to wait for the container to stop after asking it to
+const TERMINATE_GRACE_PERIOD: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How the container finished
+#[derive(Debug)]
+pub(crate) enum ContainerExit {
+    Exited(ExitStatus),
+    TimedOut,
+    /// The test stopped heartbeating. Contains the stacks of every process in
+    /// the container at the time it was detected.
+    Hung(String),
+    /// The container (or `image-test exec` inside it) failed before the test
+    /// was started
+    NotStarted(ExitStatus),
+}
+
+impl ContainerExit {
+    /// Exit code that image-test should use to report this to its caller
+    pub(crate) fn code(&self) -> i32 {
+        match self {
+            Self::TimedOut | Self::Hung(_) => crate::EXIT_TIMEOUT,
+            Self::NotStarted(_) => crate::EXIT_INFRA,
+            // same convention as shells use for signals
+            Self::Exited(status) => status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or_default()),
+        }
+    }
+
+    /// Explain failures that are not the test's own exit code
+    pub(crate) fn report(&self) {
+        match self {
+            Self::Hung(stacks) => {
+                eprintln!("test stopped heartbeating, stacks at time of hang:\n{stacks}")
+            }
+            Self::NotStarted(status) => {
+                eprintln!("container failed before the test was started: {status}")
+            }
+            Self::Exited(_) | Self::TimedOut => {}
+        }
+    }
+}
+
+/// Opt-in liveness checking
+#[derive(Debug, Clone)]
+pub(crate) struct Liveness {
+    /// The test must touch the heartbeat file at least this often
+    pub(crate) interval: Duration,
+    /// Heartbeat file on the host
+    pub(crate) heartbeat: PathBuf,
+}
+
+/// Conditions under which a running container is stopped early
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Watchdog {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) liveness: Option<Liveness>,
+}
+
+impl Watchdog {
+    /// Nothing to watch, the container can just be waited on (or exec'd)
+    pub(crate) fn is_none(&self) -> bool {
+        self.timeout.is_none() && self.liveness.is_none()
+    }
+
+    /// Wait for the container to exit, stopping it if it runs past the timeout
+    /// or stops heartbeating
+    pub(crate) fn wait(&self, mut child: Child) -> Result<ContainerExit> {
+        if self.is_none() {
+            return Ok(ContainerExit::Exited(
+                child.wait().context("while waiting for container")?,
+            ));
+        }
+        let start = Instant::now();
+        let started_at = SystemTime::now();
+        loop {
+            if let Some(status) = child.try_wait().context("while waiting for container")? {
+                return Ok(ContainerExit::Exited(status));
+            }
+            if let Some(timeout) = self.timeout {
+                if start.elapsed() >= timeout {
+                    warn!("test timed out after {timeout:?}, stopping container");
+                    terminate(&mut child)?;
+                    return Ok(ContainerExit::TimedOut);
+                }
+            }
+            if let Some(liveness) = &self.liveness {
+                let last = last_heartbeat(&liveness.heartbeat)?.max(started_at);
+                if last.elapsed().unwrap_or_default() >= liveness.interval {
+                    warn!(
+                        "test has not sent a heartbeat in {:?}, stopping container",
+                        liveness.interval
+                    );
+                    let stacks = dump_stacks(child.id());
+                    terminate(&mut child)?;
+                    return Ok(ContainerExit::Hung(stacks));
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+fn last_heartbeat(heartbeat: &Path) -> Result<SystemTime> {
+    std::fs::metadata(heartbeat)
+        .and_then(|m| m.modified())
+        .with_context(|| format!("while checking heartbeat {}", heartbeat.display()))
+}
+
+/// Stop the container, giving the container manager a chance to tear
+/// everything down before resorting to SIGKILL
+fn terminate(child: &mut Child) -> Result<()> {
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM)
+        .context("while terminating container")?;
+    let start = Instant::now();
+    while start.elapsed() < TERMINATE_GRACE_PERIOD {
+        if child
+            .try_wait()
+            .context("while waiting for container")?
+            .is_some()
+        {
+            return Ok(());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    child.kill().context("while killing container")?;
+    child.wait().context("while waiting for container")?;
+    Ok(())
+}
+
+/// `root` and all of its descendants
+fn process_tree(root: u32) -> BTreeSet<u32> {
+    let mut parents = Vec::new();
+    if let Ok(entries) = std::fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|p| p.parse().ok()) else {
+                continue;
+            };
+            // ppid is the second field after the parenthesized comm, which
+            // itself may contain spaces
+            let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+                continue;
+            };
+            let ppid = stat
+                .rsplit_once(')')
+                .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+                .and_then(|ppid| ppid.parse::<u32>().ok());
+            if let Some(ppid) = ppid {
+                parents.push((pid, ppid));
+            }
+        }
+    }
+    let mut tree = BTreeSet::from([root]);
+    loop {
+        let before = tree.len();
+        for (pid, ppid) in &parents {
+            if tree.contains(ppid) {
+                tree.insert(*pid);
+            }
+        }
+        if tree.len() == before {
+            return tree;
+        }
+    }
+}
+
+/// Best-effort dump of the kernel stacks of every thread in the container,
+/// plus userspace backtraces if gdb is available on the host
+fn dump_stacks(root: u32) -> String {
+    let mut out = String::new();
+    let gdb = Command::new("gdb")
+        .arg("--version")
+        .output()
+        .is_ok_and(|o| o.status.success());
+    for pid in process_tree(root) {
+        let comm = std::fs::read_to_string(format!("/proc/{pid}/comm")).unwrap_or_default();
+        let _ = writeln!(out, "=== pid {pid} ({}) ===", comm.trim());
+        let tasks = std::fs::read_dir(format!("/proc/{pid}/task"))
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|e| e.file_name().to_string_lossy().into_owned())
+                    .collect::<BTreeSet<_>>()
+            })
+            .unwrap_or_default();
+        for tid in tasks {
+            let _ = writeln!(out, "--- thread {tid} ---");
+            match std::fs::read_to_string(format!("/proc/{pid}/task/{tid}/stack")) {
+                Ok(stack) => out.push_str(&stack),
+                Err(e) => {
+                    let _ = writeln!(out, "<kernel stack unavailable: {e}>");
+                }
+            }
+        }
+        if gdb {
+            if let Ok(o) = Command::new("gdb")
+                .arg("-p")
+                .arg(pid.to_string())
+                .arg("-batch")
+                .arg("-ex")
+                .arg("thread apply all bt")
+                .output()
+            {
+                out.push_str(&String::from_utf8_lossy(&o.stdout));
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[test]
+    fn test_container_exit_codes() {
+        // test failures are passed through as-is
+        let child = Command::new("sh")
+            .args(["-c", "exit 3"])
+            .spawn()
+            .expect("failed to spawn");
+        let res = Watchdog::default().wait(child).expect("failed to wait");
+        assert_eq!(res.code(), 3);
+
+        let watchdog = Watchdog {
+            timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let child = Command::new("true").spawn().expect("failed to spawn");
+        let res = watchdog.wait(child).expect("failed to wait");
+        assert_eq!(res.code(), 0);
+
+        // signals are reported like a shell would
+        let child = Command::new("sh")
+            .args(["-c", "kill -9 $$"])
+            .spawn()
+            .expect("failed to spawn");
+        let res = Watchdog::default().wait(child).expect("failed to wait");
+        assert_eq!(res.code(), 128 + 9);
+
+        let watchdog = Watchdog {
+            timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let child = Command::new("sleep")
+            .arg("60")
+            .spawn()
+            .expect("failed to spawn");
+        let res = watchdog.wait(child).expect("failed to wait");
+        assert_eq!(res.code(), crate::EXIT_TIMEOUT);
+    }
+
+    #[test]
+    fn test_liveness() {
+        let heartbeat = NamedTempFile::new().expect("failed to create tempfile");
+        // heartbeats are sent far more often than they are due, so that a
+        // slow or loaded machine doesn't make the healthy child look hung
+        let watchdog = Watchdog {
+            liveness: Some(Liveness {
+                interval: Duration::from_secs(3),
+                heartbeat: heartbeat.path().to_owned(),
+            }),
+            ..Default::default()
+        };
+
+        // heartbeats for a while then exits cleanly
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"for i in $(seq 10); do touch "$0"; sleep 0.1; done"#)
+            .arg(heartbeat.path())
+            .spawn()
+            .expect("failed to spawn");
+        let res = watchdog.wait(child).expect("failed to wait");
+        assert_eq!(res.code(), 0);
+
+        // heartbeats once and then hangs
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"touch "$0"; sleep 60"#)
+            .arg(heartbeat.path())
+            .spawn()
+            .expect("failed to spawn");
+        let pid = child.id();
+        let res = watchdog.wait(child).expect("failed to wait");
+        assert_eq!(res.code(), crate::EXIT_TIMEOUT);
+        match res {
+            ContainerExit::Hung(stacks) => {
+                assert!(
+                    stacks.contains(&format!("=== pid {pid} (sh) ===")),
+                    "{stacks}"
+                );
+                assert!(stacks.contains("(sleep) ==="), "{stacks}");
+                assert!(stacks.contains("--- thread "), "{stacks}");
+            }
+            other => panic!("expected a hang, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+# Copyright (c) Meta Platforms, Inc. and affiliates.
+#
+# This source code is licensed under the MIT license found in the
+# LICENSE file in the root directory of this source tree.
+
+import os
+import unittest
+
+MARKER = "antlir2-isolate-tmp-marker"
+ISOLATED = ("/tmp", "/var/tmp")
+
+
+def mountinfo() -> dict[str, tuple[str, str, str]]:
+    """
+    Map each mountpoint to the (device, root, fstype) of the mount on top of
+    it
+    """
+    mounts = {}
+    with open("/proc/self/mountinfo") as f:
+        for line in f:
+            fields, _, rest = line.partition(" - ")
+            fields = fields.split()
+            mounts[fields[4]] = (fields[2], fields[3], rest.split()[0])
+    return mounts
+
+
+class TestIsolateTmp(unittest.TestCase):
+    def test_fresh_tmpfs(self) -> None:
+        mounts = mountinfo()
+        devices = set()
+        for d in ISOLATED:
+            self.assertIn(d, mounts)
+            device, root, fstype = mounts[d]
+            self.assertEqual(fstype, "tmpfs", d)
+            # the whole of a new tmpfs, not a bind mount of a (possibly
+            # shared) directory from somewhere else
+            self.assertEqual(root, "/", d)
+            devices.add(device)
+        # each directory gets its own tmpfs, separate from the root
+        self.assertEqual(len(devices), len(ISOLATED), mounts)
+        self.assertNotIn(mounts["/"][0], devices)
+
+    def test_nothing_left_by_previous_runs(self) -> None:
+        for d in ISOLATED:
+            path = os.path.join(d, MARKER)
+            # every run leaves this behind, so it is only missing if the
+            # previous run had its own tmpfs
+            self.assertFalse(os.path.exists(path), path)
+            with open(path, "w") as f:
+                f.write("written by a previous run")